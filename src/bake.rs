@@ -0,0 +1,625 @@
+//! Baked static layers
+//!
+//! voxelizing a large level on load takes too long, so the static layer of a volume can be baked to a .gibake file instead
+//! the file has a header with the settings of the volume it was baked for, followed by the voxels of every cascade, run length encoded
+//! it's made from the meshes with `GiStatic` with a `GiBake`, loaded through the `AssetServer`, and put on the volume as a `GiStaticLayer` once the settings are checked
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::asset::{AssetLoader, AssetServer, Assets, Handle, LoadContext, LoadState, LoadedAsset};
+use bevy::ecs::prelude::*;
+use bevy::log::warn;
+use bevy::math::{Mat4, Quat, UVec3, Vec3, Vec4};
+use bevy::pbr2::StandardMaterial;
+use bevy::reflect::TypeUuid;
+use bevy::render2::{color::Color, mesh::Mesh};
+use bevy::transform::components::GlobalTransform;
+use bevy::utils::BoxedFuture;
+
+use crate::bundle::{
+    GiAlphaMode, GiContribution, GiLayers, GiProxy, GiStatic, GiStaticLayer, GiThinGeometry, GiVolume,
+};
+use crate::render::gi_volume::MAX_GI_CASCADES;
+use crate::voxelize::{CpuVoxelizer, VoxelGrid};
+
+const MAGIC: &[u8; 4] = b"GIVB";
+const VERSION: u32 = 1;
+
+/// max resolution of a cascade along each axis, no device makes 3d textures larger than this
+const MAX_BAKE_RESOLUTION: u32 = 2048;
+
+/// max voxels in a cascade, so a broken header can't make us allocate more than 2gb per cascade
+const MAX_BAKE_VOXELS: usize = 1 << 27;
+
+/// how far the transform of the volume can be off from the baked one
+const TRANSFORM_EPSILON: f32 = 1e-4;
+
+/// how voxels are stored in the file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiBakeFormat {
+    /// exactly what's in the cascades
+    Rgba32Float = 0,
+    /// 4 times smaller, albedo and opacity fit fine in 8 bits
+    Rgba8Unorm = 1,
+}
+
+impl GiBakeFormat {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(GiBakeFormat::Rgba32Float),
+            1 => Some(GiBakeFormat::Rgba8Unorm),
+            _ => None,
+        }
+    }
+
+    fn voxel_size(&self) -> usize {
+        match self {
+            GiBakeFormat::Rgba32Float => 16,
+            GiBakeFormat::Rgba8Unorm => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BakeError {
+    Io(io::Error),
+    /// doesn't start with the magic, or is a version this can't read
+    NotBake,
+    UnknownFormat(u32),
+    /// the file ended too early, or the runs don't add up to the resolution
+    Corrupt,
+}
+
+impl fmt::Display for BakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BakeError::Io(error) => write!(f, "{}", error),
+            BakeError::NotBake => write!(f, "not a gi bake file, or an unsupported version"),
+            BakeError::UnknownFormat(format) => write!(f, "unknown voxel format {}", format),
+            BakeError::Corrupt => write!(f, "gi bake file is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for BakeError {}
+
+impl From<io::Error> for BakeError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => BakeError::Corrupt,
+            _ => BakeError::Io(error),
+        }
+    }
+}
+
+/// a baked static layer, with the settings of the volume it was baked for
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "6c1f4a1e-3b0d-4f5e-9a7c-2d8e4b1a9f03"]
+pub struct GiBakedLayer {
+    pub resolution: UVec3,
+    pub extent: Vec3,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+    pub format: GiBakeFormat,
+    pub layer: GiStaticLayer,
+}
+
+impl GiBakedLayer {
+    pub fn new(
+        volume: &GiVolume,
+        transform: &GlobalTransform,
+        layer: GiStaticLayer,
+        format: GiBakeFormat,
+    ) -> Self {
+        Self {
+            resolution: volume.resolution,
+            extent: volume.extent,
+            translation: transform.translation,
+            rotation: transform.rotation,
+            scale: transform.scale,
+            format,
+            layer,
+        }
+    }
+
+    /// why this can't be used for the volume, if it can't
+    pub fn mismatch(&self, volume: &GiVolume, transform: &GlobalTransform) -> Option<String> {
+        if self.resolution != volume.resolution {
+            return Some(format!(
+                "resolution is {}, but the volume has {}",
+                self.resolution, volume.resolution
+            ));
+        }
+
+        if self.layer.cascades.len() != volume.cascades as usize {
+            return Some(format!(
+                "has {} cascades, but the volume has {}",
+                self.layer.cascades.len(),
+                volume.cascades
+            ));
+        }
+
+        if !self.extent.abs_diff_eq(volume.extent, TRANSFORM_EPSILON) {
+            return Some(format!(
+                "extent is {}, but the volume has {}",
+                self.extent, volume.extent
+            ));
+        }
+
+        if !self.translation.abs_diff_eq(transform.translation, TRANSFORM_EPSILON)
+            || !self.rotation.abs_diff_eq(transform.rotation, TRANSFORM_EPSILON)
+            || !self.scale.abs_diff_eq(transform.scale, TRANSFORM_EPSILON)
+        {
+            return Some("the volume was moved since it was baked".to_string());
+        }
+
+        None
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BakeError> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<(), BakeError> {
+        writer.write_all(MAGIC)?;
+        write_u32(&mut writer, VERSION)?;
+
+        for value in self.resolution.to_array() {
+            write_u32(&mut writer, value)?;
+        }
+        write_u32(&mut writer, self.layer.cascades.len() as u32)?;
+        write_u32(&mut writer, self.format as u32)?;
+
+        let floats = [
+            &self.extent.to_array()[..],
+            &self.translation.to_array(),
+            &Vec4::from(self.rotation).to_array(),
+            &self.scale.to_array(),
+        ]
+        .concat();
+        for value in floats {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        for grid in self.layer.cascades.iter() {
+            write_runs(&mut writer, grid, self.format)?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self, BakeError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+            return Err(BakeError::NotBake);
+        }
+
+        let resolution = UVec3::new(
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+        );
+        let cascades = read_u32(&mut reader)?;
+        let format = read_u32(&mut reader)?;
+        let format = GiBakeFormat::from_u32(format).ok_or(BakeError::UnknownFormat(format))?;
+
+        // checked before anything is allocated for it
+        let voxels = (resolution.x as usize)
+            .checked_mul(resolution.y as usize)
+            .and_then(|voxels| voxels.checked_mul(resolution.z as usize));
+        if resolution.cmpgt(UVec3::splat(MAX_BAKE_RESOLUTION)).any()
+            || !matches!(voxels, Some(voxels) if voxels <= MAX_BAKE_VOXELS)
+            || cascades > MAX_GI_CASCADES
+        {
+            return Err(BakeError::Corrupt);
+        }
+
+        let mut floats = [0.0; 13];
+        for value in floats.iter_mut() {
+            *value = read_f32(&mut reader)?;
+        }
+
+        let layer = GiStaticLayer {
+            cascades: (0..cascades)
+                .map(|_| read_runs(&mut reader, resolution, format))
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(Self {
+            resolution,
+            extent: Vec3::new(floats[0], floats[1], floats[2]),
+            translation: Vec3::new(floats[3], floats[4], floats[5]),
+            rotation: Quat::from_xyzw(floats[6], floats[7], floats[8], floats[9]),
+            scale: Vec3::new(floats[10], floats[11], floats[12]),
+            format,
+            layer,
+        })
+    }
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    read_u32(reader).map(f32::from_bits)
+}
+
+fn write_voxel(writer: &mut impl Write, voxel: Vec4, format: GiBakeFormat) -> io::Result<()> {
+    match format {
+        GiBakeFormat::Rgba32Float => {
+            for value in voxel.to_array() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            Ok(())
+        }
+        GiBakeFormat::Rgba8Unorm => {
+            let bytes = voxel.clamp(Vec4::ZERO, Vec4::ONE) * 255.0 + Vec4::splat(0.5);
+            writer.write_all(&[bytes.x as u8, bytes.y as u8, bytes.z as u8, bytes.w as u8])
+        }
+    }
+}
+
+fn read_voxel(reader: &mut impl Read, format: GiBakeFormat) -> io::Result<Vec4> {
+    let mut bytes = [0; 16];
+    let bytes = &mut bytes[..format.voxel_size()];
+    reader.read_exact(bytes)?;
+
+    Ok(match format {
+        GiBakeFormat::Rgba32Float => {
+            let value = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+            Vec4::new(value(0), value(4), value(8), value(12))
+        }
+        GiBakeFormat::Rgba8Unorm => {
+            Vec4::new(bytes[0] as f32, bytes[1] as f32, bytes[2] as f32, bytes[3] as f32) / 255.0
+        }
+    })
+}
+
+// a run is the number of voxels, followed by the voxel they all have
+// most of a level is empty, so this makes it a lot smaller
+fn write_runs(writer: &mut impl Write, grid: &VoxelGrid, format: GiBakeFormat) -> io::Result<()> {
+    let mut voxels = grid.voxels.iter().peekable();
+
+    while let Some(voxel) = voxels.next() {
+        let mut count = 1u32;
+        while voxels.next_if_eq(&voxel).is_some() {
+            count += 1;
+        }
+
+        write_u32(writer, count)?;
+        write_voxel(writer, *voxel, format)?;
+    }
+
+    Ok(())
+}
+
+fn read_runs(
+    reader: &mut impl Read,
+    resolution: UVec3,
+    format: GiBakeFormat,
+) -> Result<VoxelGrid, BakeError> {
+    let mut grid = VoxelGrid::new(resolution);
+    let mut index = 0;
+
+    while index < grid.voxels.len() {
+        let count = read_u32(reader)? as usize;
+        let voxel = read_voxel(reader, format)?;
+
+        if count == 0 || index + count > grid.voxels.len() {
+            return Err(BakeError::Corrupt);
+        }
+
+        grid.voxels[index..index + count].fill(voxel);
+        index += count;
+    }
+
+    Ok(grid)
+}
+
+/// loads .gibake files as `GiBakedLayer`s, put the handle on the volume to use it
+#[derive(Default)]
+pub struct GiBakedLayerLoader;
+
+impl AssetLoader for GiBakedLayerLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let baked = GiBakedLayer::read(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(baked));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["gibake"]
+    }
+}
+
+/// puts the baked layer on the volume as a `GiStaticLayer` once it's loaded
+///
+/// the handle is removed afterwards, also when the bake doesn't match the volume or failed to load
+pub fn apply_baked_layers(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    baked_layers: Res<Assets<GiBakedLayer>>,
+    volumes: Query<(Entity, &GiVolume, &GlobalTransform, &Handle<GiBakedLayer>)>,
+) {
+    for (entity, volume, transform, handle) in volumes.iter() {
+        let baked = match baked_layers.get(handle) {
+            Some(baked) => baked,
+            // the asset server already logged why
+            None if asset_server.get_load_state(handle) == LoadState::Failed => {
+                commands.entity(entity).remove::<Handle<GiBakedLayer>>();
+                continue;
+            }
+            None => continue,
+        };
+
+        match baked.mismatch(volume, transform) {
+            None => {
+                commands.entity(entity).insert(baked.layer.clone());
+            }
+            Some(reason) => warn!(
+                "baked gi layer doesn't match GiVolume {:?}, {}, so it's not used",
+                entity, reason
+            ),
+        }
+
+        commands.entity(entity).remove::<Handle<GiBakedLayer>>();
+    }
+}
+
+/// bakes the static layer of the `GiVolume` on the same entity, from every mesh with `GiStatic` on the layers of the volume
+///
+/// this waits until all of those meshes are loaded, then voxelizes them on the cpu, puts the layer on the volume, and saves it to `path`
+/// the request is removed afterwards
+#[derive(Clone, Debug)]
+pub struct GiBake {
+    /// where to save the bake, nothing only puts the layer on the volume
+    pub path: Option<PathBuf>,
+
+    pub format: GiBakeFormat,
+
+    /// voxels to bake along with the meshes, with the transform from the voxels of the grid to world space, as `CpuVoxelizer::add_grid` takes them
+    pub grids: Vec<(VoxelGrid, Mat4)>,
+}
+
+impl Default for GiBake {
+    fn default() -> Self {
+        Self {
+            path: None,
+            format: GiBakeFormat::Rgba8Unorm,
+            grids: Vec::new(),
+        }
+    }
+}
+
+pub fn bake_static_layers(
+    mut commands: Commands,
+    volumes: Query<(Entity, &GiVolume, &GlobalTransform, &GiBake, ChangeTrackers<GiBake>)>,
+    meshes: Query<
+        (
+            &GlobalTransform,
+            &Handle<Mesh>,
+            Option<&GiLayers>,
+            Option<&GiContribution>,
+            Option<&GiProxy>,
+            Option<&Handle<StandardMaterial>>,
+            Option<&GiAlphaMode>,
+            Option<&GiThinGeometry>,
+        ),
+        With<GiStatic>,
+    >,
+    mesh_assets: Res<Assets<Mesh>>,
+    materials: Res<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
+) {
+    'volumes: for (entity, volume, transform, bake, tracker) in volumes.iter() {
+        // the transforms of anything spawned along with it aren't there until the end of the frame
+        if tracker.is_added() {
+            continue;
+        }
+
+        let mut voxelizer = CpuVoxelizer::new(volume, transform);
+
+        for (mesh_transform, handle, layers, contribution, proxy, material, alpha_mode, thin_geometry) in meshes.iter() {
+            let contribution = contribution.copied().unwrap_or_default();
+
+            // meshes that only give off light aren't baked, as the static layer only holds what blocks it
+            if !layers.copied().unwrap_or_default().intersects(&volume.layers) || !contribution.occludes() {
+                continue;
+            }
+
+            // same as extract_gi_meshes, the proxy is voxelized instead of the mesh
+            let mesh_handle = proxy.map_or(handle, |proxy| &proxy.0);
+            let mesh = match mesh_assets.get(mesh_handle) {
+                Some(mesh) => mesh,
+                None if asset_server.get_load_state(mesh_handle) == LoadState::Failed => {
+                    warn!("a GiStatic mesh failed to load, so it's left out of the bake");
+                    continue;
+                }
+                // waits until it's loaded
+                None => continue 'volumes,
+            };
+
+            let material = match material {
+                Some(material) => match materials.get(material) {
+                    Some(material) => Some(material),
+                    None if asset_server.get_load_state(material) == LoadState::Failed => {
+                        warn!("the material of a GiStatic mesh failed to load, so it's left out of the bake");
+                        continue;
+                    }
+                    None => continue 'volumes,
+                },
+                None => None,
+            };
+
+            // same as voxelize.wgsl, an occluder that doesn't emit is black
+            let base_color = material.map_or(Color::WHITE, |material| material.base_color);
+            let albedo = if contribution.emits() {
+                base_color
+            } else {
+                Color::rgba_linear(0.0, 0.0, 0.0, base_color.a())
+            };
+
+            voxelizer.add_mesh(
+                mesh,
+                mesh_transform,
+                albedo,
+                alpha_mode.copied().unwrap_or_default(),
+                thin_geometry.copied().unwrap_or_default(),
+            );
+        }
+
+        for (grid, grid_transform) in bake.grids.iter() {
+            voxelizer.add_grid(grid, *grid_transform);
+        }
+
+        let layer = GiStaticLayer {
+            cascades: voxelizer.finish(),
+        };
+
+        if let Some(path) = &bake.path {
+            let baked = GiBakedLayer::new(volume, transform, layer.clone(), bake.format);
+            if let Err(error) = baked.save(path) {
+                warn!("failed to save the baked gi layer to {}: {}", path.display(), error);
+            }
+        }
+
+        commands.entity(entity).insert(layer).remove::<GiBake>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{GiLayers, GiStorage};
+
+    fn test_volume(resolution: UVec3) -> GiVolume {
+        GiVolume {
+            resolution,
+            cascades: 2,
+            extent: Vec3::splat(4.0),
+            storage: GiStorage::Dense,
+            layers: GiLayers::default(),
+            temporal_blend: 0.9,
+        }
+    }
+
+    // a floor in the first cascade, and a few loose voxels in the second, so there are long and short runs
+    fn test_bake(format: GiBakeFormat) -> GiBakedLayer {
+        let volume = test_volume(UVec3::new(8, 4, 6));
+
+        let mut floor = VoxelGrid::new(volume.resolution);
+        for z in 0..6 {
+            for x in 0..8 {
+                floor.set(UVec3::new(x, 0, z), Vec4::new(0.2, 0.4, 0.6, 1.0));
+            }
+        }
+
+        let mut loose = VoxelGrid::new(volume.resolution);
+        loose.set(UVec3::new(1, 2, 3), Vec4::new(0.5, 0.0, 0.0, 0.5));
+        loose.set(UVec3::new(7, 3, 5), Vec4::ONE);
+
+        let transform = GlobalTransform::from_translation(Vec3::new(1.0, 2.0, 3.0));
+        let layer = GiStaticLayer {
+            cascades: vec![floor, loose],
+        };
+
+        GiBakedLayer::new(&volume, &transform, layer, format)
+    }
+
+    fn to_bytes(baked: &GiBakedLayer) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        baked.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let baked = test_bake(GiBakeFormat::Rgba32Float);
+        let loaded = GiBakedLayer::read(to_bytes(&baked).as_slice()).unwrap();
+
+        assert_eq!(loaded.resolution, baked.resolution);
+        assert_eq!(loaded.extent, baked.extent);
+        assert_eq!(loaded.translation, baked.translation);
+        assert_eq!(loaded.format, GiBakeFormat::Rgba32Float);
+        assert_eq!(loaded.layer.cascades, baked.layer.cascades);
+
+        // close enough in 8 bits
+        let baked = test_bake(GiBakeFormat::Rgba8Unorm);
+        let loaded = GiBakedLayer::read(to_bytes(&baked).as_slice()).unwrap();
+
+        assert_eq!(loaded.layer.cascades.len(), 2);
+        for (loaded, baked) in loaded.layer.cascades.iter().zip(baked.layer.cascades.iter()) {
+            for (loaded, baked) in loaded.voxels.iter().zip(baked.voxels.iter()) {
+                assert!((*loaded - *baked).abs().max_element() <= 0.5 / 255.0 + 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = to_bytes(&test_bake(GiBakeFormat::Rgba8Unorm));
+
+        // in the header, in the middle of the first cascade, and right before the end
+        for length in [2, 30, bytes.len() / 2, bytes.len() - 1] {
+            assert!(
+                matches!(GiBakedLayer::read(&bytes[..length]), Err(BakeError::Corrupt)),
+                "cut off after {} bytes",
+                length,
+            );
+        }
+    }
+
+    #[test]
+    fn broken_header() {
+        let bytes = to_bytes(&test_bake(GiBakeFormat::Rgba8Unorm));
+
+        // a resolution that doesn't fit in memory, or overflows when multiplied
+        for resolution in [UVec3::splat(100_000), UVec3::new(2048, 2048, 2048), UVec3::splat(u32::MAX)] {
+            let mut bytes = bytes.clone();
+            for (i, axis) in resolution.to_array().iter().enumerate() {
+                bytes[8 + i * 4..12 + i * 4].copy_from_slice(&axis.to_le_bytes());
+            }
+
+            assert!(matches!(GiBakedLayer::read(bytes.as_slice()), Err(BakeError::Corrupt)));
+        }
+
+        // too many cascades
+        let mut bytes = bytes;
+        bytes[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(GiBakedLayer::read(bytes.as_slice()), Err(BakeError::Corrupt)));
+    }
+
+    #[test]
+    fn resolution_mismatch() {
+        let baked = test_bake(GiBakeFormat::Rgba8Unorm);
+        let transform = GlobalTransform::from_translation(Vec3::new(1.0, 2.0, 3.0));
+
+        assert!(baked.mismatch(&test_volume(UVec3::new(8, 4, 6)), &transform).is_none());
+        assert!(baked.mismatch(&test_volume(UVec3::new(8, 6, 4)), &transform).is_some());
+
+        // the same volume somewhere else
+        assert!(baked
+            .mismatch(&test_volume(UVec3::new(8, 4, 6)), &GlobalTransform::default())
+            .is_some());
+    }
+}
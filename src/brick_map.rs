@@ -0,0 +1,255 @@
+//! Two level brick map, built from a list of voxel fragments
+//!
+//! this is the cpu side version of what `render::brick_map` does on the gpu
+//! a coarse grid of cells points into a pool of 8x8x8 bricks, which are only allocated where there are fragments
+
+use bevy::math::{UVec3, Vec4};
+
+use crate::octree::VoxelFragment;
+
+/// size of a brick along each axis, in voxels
+pub const BRICK_SIZE: u32 = 8;
+
+/// voxels in a single brick
+pub const BRICK_VOXELS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+
+/// number of mips that fit inside of a brick, after that the coarse grid is used
+pub const BRICK_MIPS: u32 = 4;
+
+/// max size of the grid, as fragment positions are packed in 10 bits per axis
+pub const MAX_BRICK_MAP_GRID: u32 = 1024 / BRICK_SIZE;
+
+/// hands out bricks from a fixed size pool
+///
+/// the gpu version keeps the same free list as a stack in a storage buffer
+#[derive(Clone, Debug)]
+pub struct BrickAllocator {
+    free: Vec<u32>,
+}
+
+impl BrickAllocator {
+    pub fn new(capacity: u32) -> Self {
+        // reversed, so the first claimed brick is 0
+        Self {
+            free: (0..capacity).rev().collect(),
+        }
+    }
+
+    /// takes a brick from the pool, if there are any left
+    pub fn claim(&mut self) -> Option<u32> {
+        self.free.pop()
+    }
+
+    /// gives a brick back to the pool
+    pub fn release(&mut self, brick: u32) {
+        self.free.push(brick);
+    }
+
+    /// number of bricks that can still be claimed
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
+
+/// Brick map
+///
+/// voxel values are premultiplied by opacity
+#[derive(Clone, Debug)]
+pub struct BrickMap {
+    /// number of cells along each axis, the brick map is `grid_size * BRICK_SIZE` voxels wide
+    pub grid_size: u32,
+    /// brick of each cell, if it has one
+    pub cells: Vec<Option<u32>>,
+    /// the pool of bricks, indexed by brick
+    pub bricks: Vec<[Vec4; BRICK_VOXELS]>,
+    pub allocator: BrickAllocator,
+}
+
+/// index of a voxel inside of it's brick
+fn voxel_index(position: UVec3) -> usize {
+    let local = position - position / BRICK_SIZE * BRICK_SIZE;
+
+    (local.x + local.y * BRICK_SIZE + local.z * BRICK_SIZE * BRICK_SIZE) as usize
+}
+
+impl BrickMap {
+    pub fn new(grid_size: u32, capacity: u32) -> Self {
+        assert!(
+            grid_size > 0 && grid_size <= MAX_BRICK_MAP_GRID,
+            "brick map grid size must be between 1 and {}",
+            MAX_BRICK_MAP_GRID
+        );
+
+        Self {
+            grid_size,
+            cells: vec![None; (grid_size * grid_size * grid_size) as usize],
+            bricks: vec![[Vec4::ZERO; BRICK_VOXELS]; capacity as usize],
+            allocator: BrickAllocator::new(capacity),
+        }
+    }
+
+    /// size of the brick map, in voxels
+    pub fn resolution(&self) -> u32 {
+        self.grid_size * BRICK_SIZE
+    }
+
+    fn cell_index(&self, cell: UVec3) -> usize {
+        (cell.x + cell.y * self.grid_size + cell.z * self.grid_size * self.grid_size) as usize
+    }
+
+    /// replaces the contents with the fragments, the same way the gpu does it each frame
+    ///
+    /// cells without fragments give their brick back, and cells with fragments claim one if they don't have one yet
+    /// when multiple fragments end up in the same voxel, the last one is kept
+    /// fragments outside of the brick map, or in cells that couldn't get a brick, are dropped
+    pub fn update(&mut self, fragments: &[VoxelFragment]) {
+        let resolution = self.resolution();
+        let fragments = fragments
+            .iter()
+            .filter(|fragment| fragment.position.max_element() < resolution)
+            .collect::<Vec<_>>();
+
+        // mark all cells that have a fragment in them
+        let mut used = vec![false; self.cells.len()];
+        for fragment in fragments.iter() {
+            used[self.cell_index(fragment.position / BRICK_SIZE)] = true;
+        }
+
+        // release first, so those bricks can be claimed again
+        for (cell, used) in self.cells.iter_mut().zip(used.iter()) {
+            if !used {
+                if let Some(brick) = cell.take() {
+                    self.allocator.release(brick);
+                }
+            }
+        }
+
+        for (cell, used) in self.cells.iter_mut().zip(used.iter()) {
+            if *used && cell.is_none() {
+                *cell = self.allocator.claim();
+            }
+        }
+
+        // everything is voxelized again, so clear all bricks that are in use
+        for brick in self.cells.iter().flatten() {
+            self.bricks[*brick as usize] = [Vec4::ZERO; BRICK_VOXELS];
+        }
+
+        for fragment in fragments.iter() {
+            if let Some(brick) = self.cells[self.cell_index(fragment.position / BRICK_SIZE)] {
+                let albedo = fragment.albedo;
+                self.bricks[brick as usize][voxel_index(fragment.position)] =
+                    (albedo.truncate() * albedo.w).extend(albedo.w);
+            }
+        }
+    }
+
+    /// gets a single voxel, at the finest level
+    pub fn voxel(&self, position: UVec3) -> Vec4 {
+        if position.max_element() >= self.resolution() {
+            return Vec4::ZERO;
+        }
+
+        self.cells[self.cell_index(position / BRICK_SIZE)]
+            .map(|brick| self.bricks[brick as usize][voxel_index(position)])
+            .unwrap_or(Vec4::ZERO)
+    }
+
+    /// gets the value of a voxel, where mip 0 is the finest level and every mip after that is twice as large
+    ///
+    /// `position` is in voxels of the finest level
+    /// the first `BRICK_MIPS` mips are inside the bricks, the rest come from the coarse grid, but both are the average of all voxels below
+    pub fn get(&self, position: UVec3, mip: u32) -> Vec4 {
+        let size = 1 << mip;
+        let origin = position / size * size;
+
+        let mut sum = Vec4::ZERO;
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
+                    sum += self.voxel(origin + UVec3::new(x, y, z));
+                }
+            }
+        }
+
+        sum / (size * size * size) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxelize::VoxelGrid;
+
+    fn fragment(x: u32, y: u32, z: u32, albedo: Vec4) -> VoxelFragment {
+        VoxelFragment {
+            position: UVec3::new(x, y, z),
+            albedo,
+        }
+    }
+
+    #[test]
+    fn mips_match_dense_grid() {
+        let fragments = (0..16)
+            .flat_map(|x| (0..16).map(move |z| fragment(x, 3, z, Vec4::new(0.5, x as f32 / 15.0, 1.0, 1.0))))
+            .chain((0..16).map(|y| fragment(12, y, 2, Vec4::new(1.0, 0.0, 0.0, 0.5))))
+            .collect::<Vec<_>>();
+
+        let mut brick_map = BrickMap::new(2, 8);
+        brick_map.update(&fragments);
+
+        // the same fragments in a dense cascade, none of them share a voxel
+        let mut grid = VoxelGrid::new(UVec3::splat(brick_map.resolution()));
+        for fragment in fragments.iter() {
+            let albedo = fragment.albedo;
+            grid.set(fragment.position, (albedo.truncate() * albedo.w).extend(albedo.w));
+        }
+
+        for mip in 0..5 {
+            let size = grid.resolution.x;
+            for z in 0..size {
+                for y in 0..size {
+                    for x in 0..size {
+                        let texel = UVec3::new(x, y, z);
+                        let value = brick_map.get(texel * (1 << mip), mip);
+                        assert!(
+                            (value - grid.get(texel)).abs().max_element() < 1e-5,
+                            "mip {} voxel {} is {}, the dense grid has {}",
+                            mip,
+                            texel,
+                            value,
+                            grid.get(texel)
+                        );
+                    }
+                }
+            }
+
+            grid = grid.downsample();
+        }
+    }
+
+    #[test]
+    fn only_claims_bricks_with_fragments() {
+        let mut brick_map = BrickMap::new(4, 16);
+        brick_map.update(&[fragment(1, 1, 1, Vec4::ONE), fragment(30, 17, 9, Vec4::ONE)]);
+
+        assert_eq!(brick_map.cells.iter().flatten().count(), 2);
+        assert_eq!(brick_map.allocator.available(), 14);
+        assert_eq!(brick_map.voxel(UVec3::new(30, 17, 9)), Vec4::ONE);
+        assert_eq!(brick_map.voxel(UVec3::new(30, 17, 10)), Vec4::ZERO);
+    }
+
+    #[test]
+    fn moving_releases_bricks() {
+        let mut brick_map = BrickMap::new(4, 2);
+        brick_map.update(&[fragment(1, 1, 1, Vec4::ONE), fragment(9, 1, 1, Vec4::ONE)]);
+        assert_eq!(brick_map.allocator.available(), 0);
+
+        // the first one moved into another cell, which can only get a brick because the old one was released
+        brick_map.update(&[fragment(1, 25, 1, Vec4::ONE), fragment(9, 1, 1, Vec4::ONE)]);
+        assert_eq!(brick_map.allocator.available(), 0);
+        assert_eq!(brick_map.voxel(UVec3::new(1, 1, 1)), Vec4::ZERO);
+        assert_eq!(brick_map.voxel(UVec3::new(1, 25, 1)), Vec4::ONE);
+        assert_eq!(brick_map.voxel(UVec3::new(9, 1, 1)), Vec4::ONE);
+    }
+}
@@ -1,22 +1,588 @@
-use bevy::ecs::bundle::Bundle;
+use bevy::asset::Handle;
+use bevy::ecs::{bundle::Bundle, entity::Entity};
+use bevy::math::{Mat4, UVec3, Vec3};
+use bevy::render2::mesh::Mesh;
 use bevy::transform::components::{GlobalTransform, Transform};
 
+use crate::voxelize::VoxelGrid;
+
 // TODO: should we only have one volume?
 
 /// Gi volume, for rendering global illumination via voxel cone tracing
 ///
 /// the volume is updated each frame, and support multiple cascades
-/// each cascade is twice the size of the base volume, which is a cube from -1 to 1 by default
+/// each cascade is twice the size of the base volume, which is centered on the transform
+///
+/// the volume doesn't need to be a cube, but voxels should be, so `extent / resolution` should be the same on all axes
+/// `GiVolume::from_voxel_size` takes care of that
 #[derive(Copy, Clone)]
 pub struct GiVolume {
-    /// resolution of a cascade
-    pub resolution: u8,
+    /// resolution of a cascade, along each axis
+    pub resolution: UVec3,
 
     /// number of cascades
     pub cascades: u8,
 
-    /// smallest cascade size
-    pub size: f32,
+    /// size of the smallest cascade, along each axis
+    pub extent: Vec3,
+
+    /// how the voxels are stored on the gpu
+    pub storage: GiStorage,
+
+    /// only meshes on one of these layers take part in gi
+    pub layers: GiLayers,
+
+    /// how much of the indirect light of last frame is kept, from 0 to 1
+    ///
+    /// above 0, only one diffuse cone is traced per pixel each frame, and the history smooths it out over a few frames
+    /// 0 traces all cones every frame, and keeps no history
+    pub temporal_blend: f32,
+}
+
+/// how the voxels of a `GiVolume` are stored on the gpu
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiStorage {
+    /// a dense 3d texture per cascade
+    Dense,
+
+    /// a single sparse voxel octree covering all cascades, so empty space takes up no memory
+    ///
+    /// the octree is as wide as the largest cascade, with the voxel size of the smallest cascade
+    SparseOctree,
+
+    /// a coarse grid of cells, pointing into a pool of 8x8x8 bricks that are only allocated where there is geometry
+    ///
+    /// the brick map is as wide as the largest cascade, and `resolution` is the number of bricks along each axis
+    /// so it has 8 times as many voxels as a dense cascade with the same resolution
+    BrickMap,
+}
+
+impl GiVolume {
+    /// makes a volume with cubic voxels, that covers at least `extent`
+    ///
+    /// the extent is rounded up to a whole number of voxels
+    pub fn from_voxel_size(extent: Vec3, voxel_size: f32, cascades: u8, storage: GiStorage) -> Self {
+        let resolution = (extent / voxel_size).ceil().max(Vec3::ONE);
+
+        Self {
+            resolution: resolution.as_u32(),
+            cascades,
+            extent: resolution * voxel_size,
+            storage,
+            layers: GiLayers::default(),
+            temporal_blend: 0.9,
+        }
+    }
+
+    /// size of a voxel in the smallest cascade, along each axis
+    pub fn voxel_size(&self) -> Vec3 {
+        self.extent / self.resolution.as_f32()
+    }
+}
+
+/// how precise the voxels of dense cascades are, the sparse storages always use 32 bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GiVolumeFormat {
+    /// half the memory, and plenty for colors
+    Rgba16Float,
+
+    Rgba32Float,
+}
+
+impl GiVolumeFormat {
+    pub const ALL: [GiVolumeFormat; 2] = [GiVolumeFormat::Rgba16Float, GiVolumeFormat::Rgba32Float];
+
+    pub fn bytes_per_voxel(&self) -> u32 {
+        match self {
+            GiVolumeFormat::Rgba16Float => 8,
+            GiVolumeFormat::Rgba32Float => 16,
+        }
+    }
+}
+
+impl Default for GiVolumeFormat {
+    fn default() -> Self {
+        GiVolumeFormat::Rgba32Float
+    }
+}
+
+/// everything that trades quality for speed, and how the traced light looks, as a resource used for every volume
+///
+/// `GiQuality` fills in the first part, `resolution` and `cascades` are only written to the volumes while there is one
+/// without a `GiQuality`, the volumes keep the resolution and cascades they were made with
+///
+/// the rest is only read by the tracer, and can be changed every frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GiSettings {
+    /// voxels along the longest axis of a cascade, the other axes get as many as keep the voxels cubic
+    pub resolution: u32,
+
+    pub cascades: u8,
+
+    /// number of diffuse cones traced per pixel, one straight up and the rest in a ring around it
+    pub diffuse_cones: u32,
+
+    /// step size of a cone, relative to the diameter of the cone at that point
+    pub step_multiplier: f32,
+
+    pub format: GiVolumeFormat,
+
+    /// how often light goes from voxel to voxel, 1 is only from the voxels to what's traced
+    ///
+    /// only dense cascades bounce more than once
+    pub bounces: u32,
+
+    /// tan of half the angle of the diffuse cones, the default of 0.577 makes 6 cones of 60 degrees cover the hemisphere
+    pub diffuse_aperture: f32,
+
+    /// tan of half the angle of the specular cone
+    pub specular_aperture: f32,
+
+    /// how far a cone goes before it gives up, in world space
+    pub max_trace_distance: f32,
+
+    /// scales the traced diffuse and specular light
+    pub indirect_intensity: f32,
+
+    /// scales the ambient occlusion, 0 turns it off
+    pub ao_strength: f32,
+
+    /// makes partly covered voxels block more light, so less of it leaks through thin walls
+    ///
+    /// 0 uses the opacity as voxelized, 1 doubles it
+    pub leak_bias: f32,
+
+    /// width of the border where a cascade fades into the next one, in voxels of that cascade
+    ///
+    /// hides the seam where the next cascade takes over, 0 switches at once
+    pub cascade_blend: f32,
+}
+
+impl GiSettings {
+    /// sets the resolution and cascades of a volume, keeping it about the same size
+    ///
+    /// the extent is rounded to a whole number of voxels, so the voxels stay cubic
+    pub fn apply(&self, volume: &mut GiVolume) {
+        let voxel_size = volume.extent.max_element() / self.resolution.max(1) as f32;
+        let resolution = (volume.extent / voxel_size).round().max(Vec3::ONE);
+
+        volume.resolution = resolution.as_u32();
+        volume.extent = resolution * voxel_size;
+        volume.cascades = self.cascades;
+    }
+}
+
+/// same as `GiQuality::High`
+impl Default for GiSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 128,
+            cascades: 4,
+            diffuse_cones: 6,
+            step_multiplier: 0.5,
+            format: GiVolumeFormat::Rgba32Float,
+            bounces: 1,
+            diffuse_aperture: 0.577,
+            specular_aperture: 0.1,
+            max_trace_distance: 20.0,
+            indirect_intensity: 1.0,
+            ao_strength: 1.0,
+            leak_bias: 0.0,
+            cascade_blend: 4.0,
+        }
+    }
+}
+
+/// picks everything in `GiSettings` that trades quality for speed at once, as a resource
+///
+/// changing it overwrites those settings, except for `Custom`, which leaves them to be set by hand
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiQuality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+    Custom,
+}
+
+impl GiQuality {
+    /// the settings this quality stands for with the default look, or None for `Custom`
+    pub fn settings(&self) -> Option<GiSettings> {
+        match self {
+            GiQuality::Low => Some(GiSettings {
+                resolution: 32,
+                cascades: 3,
+                diffuse_cones: 4,
+                step_multiplier: 1.0,
+                format: GiVolumeFormat::Rgba16Float,
+                bounces: 1,
+                ..GiSettings::default()
+            }),
+            GiQuality::Medium => Some(GiSettings {
+                resolution: 64,
+                cascades: 3,
+                diffuse_cones: 5,
+                step_multiplier: 0.75,
+                format: GiVolumeFormat::Rgba16Float,
+                bounces: 1,
+                ..GiSettings::default()
+            }),
+            GiQuality::High => Some(GiSettings::default()),
+            GiQuality::Ultra => Some(GiSettings {
+                resolution: 192,
+                cascades: 5,
+                diffuse_cones: 9,
+                step_multiplier: 0.35,
+                format: GiVolumeFormat::Rgba32Float,
+                bounces: 2,
+                ..GiSettings::default()
+            }),
+            GiQuality::Custom => None,
+        }
+    }
+
+    /// writes the settings of this quality, and leaves the ones that only change the look alone
+    pub fn apply(&self, settings: &mut GiSettings) {
+        if let Some(quality) = self.settings() {
+            *settings = GiSettings {
+                diffuse_aperture: settings.diffuse_aperture,
+                specular_aperture: settings.specular_aperture,
+                max_trace_distance: settings.max_trace_distance,
+                indirect_intensity: settings.indirect_intensity,
+                ao_strength: settings.ao_strength,
+                leak_bias: settings.leak_bias,
+                cascade_blend: settings.cascade_blend,
+                ..quality
+            };
+        }
+    }
+}
+
+impl Default for GiQuality {
+    fn default() -> Self {
+        GiQuality::High
+    }
+}
+
+/// voxels that don't change, written to the cascades of the `GiVolume` on the same entity before anything else
+///
+/// there is one grid per cascade, each with the resolution of the volume, as made by `voxelize::CpuVoxelizer`
+/// only dense cascades can have a static layer for now
+#[derive(Clone, Debug, Default)]
+pub struct GiStaticLayer {
+    pub cascades: Vec<VoxelGrid>,
+}
+
+impl GiStaticLayer {
+    /// whether this is written to every cascade of the volume, so the meshes in it don't need to be voxelized again
+    pub fn covers(&self, volume: &GiVolume) -> bool {
+        volume.storage == GiStorage::Dense
+            && self.cascades.len() >= volume.cascades as usize
+            && self.cascades.iter().all(|grid| grid.resolution == volume.resolution)
+    }
+}
+
+/// a mesh that doesn't move, baked into the static layer of the `GiVolume` by `bake::GiBake`
+///
+/// once the volume has a static layer that covers it, the mesh is no longer voxelized every frame, but still receives gi
+/// only meshes that block light are baked, ones that only give off light are still voxelized every frame
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GiStatic;
+
+/// a grid of irradiance probes over the smallest cascade of the `GiVolume` on the same entity
+///
+/// this replaces cone tracing from every pixel, for when that's too slow
+/// each probe traces cones in all directions from it's center, and stores the result as spherical harmonics
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GiProbeGrid {
+    /// number of probes along each axis
+    pub resolution: UVec3,
+
+    pub order: GiShOrder,
+
+    /// how many probes are traced again each frame, all of them are traced when the grid is made
+    pub probes_per_frame: u32,
+}
+
+impl Default for GiProbeGrid {
+    fn default() -> Self {
+        Self {
+            resolution: UVec3::splat(8),
+            order: GiShOrder::L2,
+            probes_per_frame: 64,
+        }
+    }
+}
+
+/// traces the diffuse cones at a lower resolution than the view, for the `GiVolume` on the same entity
+///
+/// the light is then upsampled to every pixel, from the traced pixels that are closest in depth and normal
+/// specular is still traced for every pixel, as it changes much faster over a surface
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiTraceResolution {
+    Full,
+    /// one in every 2x2 pixels
+    Half,
+    /// one in every 4x4 pixels
+    Quarter,
+}
+
+impl GiTraceResolution {
+    /// how many pixels along each axis share a traced pixel
+    pub fn scale(&self) -> u32 {
+        match self {
+            GiTraceResolution::Full => 1,
+            GiTraceResolution::Half => 2,
+            GiTraceResolution::Quarter => 4,
+        }
+    }
+}
+
+impl Default for GiTraceResolution {
+    fn default() -> Self {
+        GiTraceResolution::Full
+    }
+}
+
+/// how detailed the spherical harmonics of a probe are
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiShOrder {
+    /// 4 coefficients, only the general direction of the light
+    L1,
+    /// 9 coefficients
+    L2,
+}
+
+impl GiShOrder {
+    pub fn coefficients(&self) -> u32 {
+        match self {
+            GiShOrder::L1 => 4,
+            GiShOrder::L2 => 9,
+        }
+    }
+}
+
+/// which gi layers a mesh is on, or which ones a `GiVolume` uses, as a bit mask of 32 layers
+///
+/// meshes without this are on layer 0, so they're in every volume that has the default layers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GiLayers(pub u32);
+
+impl GiLayers {
+    pub const ALL: GiLayers = GiLayers(u32::MAX);
+    pub const NONE: GiLayers = GiLayers(0);
+
+    pub const MAX_LAYER: u8 = 31;
+
+    /// only on `layer`, which is from 0 to 31
+    ///
+    /// panics if `layer` is above [`GiLayers::MAX_LAYER`]
+    pub fn layer(layer: u8) -> Self {
+        GiLayers(Self::bit(layer))
+    }
+
+    /// also on `layer`, panics like [`GiLayers::layer`]
+    pub fn with(self, layer: u8) -> Self {
+        GiLayers(self.0 | Self::bit(layer))
+    }
+
+    /// not on `layer`, panics like [`GiLayers::layer`]
+    pub fn without(self, layer: u8) -> Self {
+        GiLayers(self.0 & !Self::bit(layer))
+    }
+
+    fn bit(layer: u8) -> u32 {
+        assert!(
+            layer <= Self::MAX_LAYER,
+            "gi layer {} is out of range, there are only {} layers",
+            layer,
+            Self::MAX_LAYER as u32 + 1
+        );
+        1 << layer
+    }
+
+    pub fn intersects(&self, other: &GiLayers) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl Default for GiLayers {
+    fn default() -> Self {
+        GiLayers::layer(0)
+    }
+}
+
+/// how a mesh adds to the voxels of a volume
+///
+/// meshes without this are `Full`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiContribution {
+    /// not voxelized at all, for gizmos, particles and the like
+    None,
+
+    /// blocks light, but doesn't give off any
+    Occluder,
+
+    /// gives off light, but doesn't block any
+    Emitter,
+
+    /// blocks and gives off light
+    Full,
+}
+
+impl GiContribution {
+    pub fn occludes(&self) -> bool {
+        matches!(self, GiContribution::Occluder | GiContribution::Full)
+    }
+
+    pub fn emits(&self) -> bool {
+        matches!(self, GiContribution::Emitter | GiContribution::Full)
+    }
+}
+
+impl Default for GiContribution {
+    fn default() -> Self {
+        GiContribution::Full
+    }
+}
+
+/// how the alpha of the material of a mesh is used when it's voxelized
+///
+/// `StandardMaterial` has no alpha mode of it's own, so it's set with this, meshes without it are opaque
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GiAlphaMode {
+    /// the alpha is ignored
+    Opaque,
+
+    /// the surface is left out where the alpha is below the cutoff, for foliage and fences
+    Mask(f32),
+
+    /// the alpha is the opacity, and light passing through is tinted by the color, for glass
+    Blend,
+}
+
+impl GiAlphaMode {
+    /// opacity of the voxels of a surface with this alpha, or nothing when it's left out
+    pub fn opacity(&self, alpha: f32) -> Option<f32> {
+        match self {
+            GiAlphaMode::Opaque => Some(1.0),
+            GiAlphaMode::Mask(cutoff) if alpha >= *cutoff => Some(1.0),
+            GiAlphaMode::Mask(_) => None,
+            GiAlphaMode::Blend if alpha > 0.0 => Some(alpha.min(1.0)),
+            GiAlphaMode::Blend => None,
+        }
+    }
+}
+
+impl Default for GiAlphaMode {
+    fn default() -> Self {
+        GiAlphaMode::Opaque
+    }
+}
+
+/// how a mesh that's thinner than a voxel is voxelized
+///
+/// meshes without this are voxelized as they are
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GiThinGeometry {
+    /// only the voxels the surface passes through the center of, so thin walls can have holes that light leaks through
+    Keep,
+
+    /// every voxel the surface touches at all
+    Dilate,
+
+    /// extruded backwards along it's normal by this much in world space, so single sided surfaces become solid
+    Solidify(f32),
+}
+
+impl Default for GiThinGeometry {
+    fn default() -> Self {
+        GiThinGeometry::Keep
+    }
+}
+
+/// keeps the cones from hitting the voxels of the surface they start on, for the `GiVolume` on the same entity
+///
+/// both are in voxels of the first cascade, larger values leak less light, but lose contact shadows
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GiTraceBias {
+    /// how far cones start away from the surface, along it's normal
+    pub normal_offset: f32,
+
+    /// how far along the cone the first sample is taken
+    pub self_intersection: f32,
+}
+
+impl Default for GiTraceBias {
+    fn default() -> Self {
+        Self {
+            normal_offset: 1.0,
+            self_intersection: 1.0,
+        }
+    }
+}
+
+/// meshes with this don't receive gi, so no cones are traced from them
+///
+/// this is separate from `GiContribution`, so a first person weapon can be lit without ending up in the voxels
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GiNoReceive;
+
+/// a simpler mesh to voxelize instead of the mesh on the same entity, it's still drawn as usual
+///
+/// `proxy::generate_proxy` makes one, or `proxy::GiAutoProxy` when the mesh is loaded
+#[derive(Clone, Debug)]
+pub struct GiProxy(pub Handle<Mesh>);
+
+/// skins the mesh on the same entity before it's voxelized, with the joint attributes in `skinning`
+///
+/// bevy doesn't skin meshes itself, so this only changes what ends up in the voxels
+#[derive(Clone, Debug, Default)]
+pub struct GiSkin {
+    /// the entities the joints follow
+    pub joints: Vec<Entity>,
+
+    /// from the mesh in bind pose to the space of each joint
+    pub inverse_bindposes: Vec<Mat4>,
+
+    /// voxelize a box around the vertices of each joint instead of the skinned mesh
+    ///
+    /// a lot cheaper, and close enough when voxels are larger than a limb
+    pub proxy: bool,
+}
+
+impl GiSkin {
+    /// from the mesh in bind pose to the mesh now, for every joint
+    ///
+    /// joints without a transform stay in bind pose
+    pub fn joint_matrices(
+        &self,
+        mesh_transform: &GlobalTransform,
+        joint_transform: impl Fn(Entity) -> Option<GlobalTransform>,
+    ) -> Vec<Mat4> {
+        let to_mesh = mesh_transform.compute_matrix().inverse();
+
+        self.joints
+            .iter()
+            .zip(self.inverse_bindposes.iter())
+            .map(|(joint, inverse_bindpose)| match joint_transform(*joint) {
+                Some(transform) => to_mesh * transform.compute_matrix() * *inverse_bindpose,
+                None => Mat4::IDENTITY,
+            })
+            .collect()
+    }
+}
+
+/// weights of the morph targets of the mesh on the same entity, see `skinning::ATTRIBUTE_MORPH_POSITIONS`
+///
+/// works with or without a `GiSkin`, the morph targets are added before skinning
+#[derive(Clone, Debug, Default)]
+pub struct GiMorphWeights(pub Vec<f32>);
+
+impl Default for GiStorage {
+    fn default() -> Self {
+        GiStorage::Dense
+    }
 }
 
 #[derive(Copy, Clone, Bundle)]
@@ -0,0 +1,664 @@
+//! Cone tracing on the cpu
+//!
+//! this is the cpu side version of `render/cone_trace.wgsl`, using the same math, so the shader can be checked against it
+//! it traces through the cascades made by `voxelize::CpuVoxelizer`
+
+use std::f32::consts::PI;
+
+use bevy::math::{Mat4, UVec3, Vec3, Vec4};
+use bevy::transform::components::GlobalTransform;
+
+use crate::bundle::{GiSettings, GiTraceBias, GiVolume};
+use crate::voxelize::{cascade_projection, cascade_scale, VoxelGrid};
+
+pub const MAX_STEPS: u32 = 64;
+
+/// how fast occluders stop counting towards ambient occlusion with distance
+pub const AO_FALLOFF: f32 = 2.0;
+
+/// number of cones traced from a probe, spread evenly over the sphere
+pub const PROBE_CONES: u32 = 32;
+
+/// each cone covers 1 / 32 of the sphere
+pub const PROBE_APERTURE: f32 = 0.36;
+
+/// direction and weight of diffuse cone `index` of `count`, 0 is straight up and the others are in a ring around it at 60 degrees
+pub fn diffuse_cone(normal: Vec3, index: u32, count: u32) -> (Vec3, f32) {
+    if count <= 1 {
+        return (normal, 1.0);
+    }
+
+    if index == 0 {
+        return (normal, 0.25);
+    }
+
+    // any vector that isn't the normal works to make the other two axes
+    let up = if normal.y.abs() > 0.99 { Vec3::X } else { Vec3::Y };
+    let tangent = up.cross(normal).normalize();
+    let bitangent = normal.cross(tangent);
+
+    let angle = (index - 1) as f32 * 2.0 * PI / (count - 1) as f32;
+    let direction = (normal * 0.5 + (tangent * angle.cos() + bitangent * angle.sin()) * 0.866).normalize();
+
+    (direction, 0.75 / (count - 1) as f32)
+}
+
+/// the 6 directions light is gathered from when it bounces between voxels, along each axis
+pub fn bounce_direction(index: u32) -> Vec3 {
+    let axis = match index / 2 {
+        0 => Vec3::X,
+        1 => Vec3::Y,
+        _ => Vec3::Z,
+    };
+
+    if index & 1 == 0 { axis } else { -axis }
+}
+
+/// real spherical harmonics up to L2, in the same order as the shader
+pub fn sh_basis(index: usize, direction: Vec3) -> f32 {
+    let d = direction;
+    match index {
+        0 => 0.282095,
+        1 => 0.488603 * d.y,
+        2 => 0.488603 * d.z,
+        3 => 0.488603 * d.x,
+        4 => 1.092548 * d.x * d.y,
+        5 => 1.092548 * d.y * d.z,
+        6 => 0.315392 * (3.0 * d.z * d.z - 1.0),
+        7 => 1.092548 * d.x * d.z,
+        _ => 0.546274 * (d.x * d.x - d.y * d.y),
+    }
+}
+
+// convolution with a cosine lobe, for each band
+fn sh_cosine_lobe(index: usize) -> f32 {
+    match index {
+        0 => PI,
+        1..=3 => 2.0 * PI / 3.0,
+        _ => PI / 4.0,
+    }
+}
+
+/// direction `index` of `count` on a fibonacci sphere
+pub fn sphere_direction(index: u32, count: u32) -> Vec3 {
+    let golden_angle = PI * (3.0 - 5f32.sqrt());
+    let z = 1.0 - (index as f32 + 0.5) * 2.0 / count as f32;
+    let radius = (1.0 - z * z).sqrt();
+    let angle = index as f32 * golden_angle;
+
+    Vec3::new(angle.cos() * radius, angle.sin() * radius, z)
+}
+
+/// the irradiance for a normal from the spherical harmonics of a probe
+///
+/// divided by pi like the shader does, so it's on the same scale as `TraceVolume::trace_diffuse`
+pub fn probe_irradiance(coefficients: &[Vec3], normal: Vec3) -> Vec3 {
+    let irradiance = coefficients
+        .iter()
+        .enumerate()
+        .fold(Vec3::ZERO, |irradiance, (index, coefficient)| {
+            irradiance + *coefficient * sh_cosine_lobe(index) * sh_basis(index, normal)
+        });
+
+    (irradiance / PI).max(Vec3::ZERO)
+}
+
+/// a cascade with all it's mips
+#[derive(Clone, Debug)]
+pub struct TraceCascade {
+    /// world space to 0 - 1 over the cascade
+    pub projection: Mat4,
+    /// size of the cascade in world space
+    pub extent: Vec3,
+    /// from the finest to a single voxel
+    pub mips: Vec<VoxelGrid>,
+}
+
+impl TraceCascade {
+    /// voxels are cubic, so any axis works
+    pub fn voxel_size(&self) -> f32 {
+        self.extent.x / self.mips[0].resolution.x as f32
+    }
+
+    /// how far `uvw` is from the closest side of the cascade, in voxels
+    pub fn border_distance(&self, uvw: Vec3) -> f32 {
+        (uvw.min(Vec3::ONE - uvw) * self.mips[0].resolution.as_f32()).min_element()
+    }
+
+    // trilinear, same as the shader does it
+    fn sample_mip(&self, uvw: Vec3, mip: usize) -> Vec4 {
+        let grid = &self.mips[mip];
+        let size = grid.resolution.as_f32();
+        let position = uvw * size - Vec3::splat(0.5);
+        let base = position.floor();
+        let t = position - base;
+
+        (0..8).fold(Vec4::ZERO, |result, i| {
+            let offset = Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32);
+            let weight = (Vec3::ONE - t) * (Vec3::ONE - offset) + t * offset;
+            let texel = (base + offset).max(Vec3::ZERO).min(size - Vec3::ONE);
+
+            result + grid.get(texel.as_u32()) * weight.x * weight.y * weight.z
+        })
+    }
+
+    /// samples the cascade at `uvw`, linear between mips
+    pub fn sample(&self, uvw: Vec3, mip: f32) -> Vec4 {
+        let max_mip = self.mips.len() - 1;
+        let level = mip.clamp(0.0, max_mip as f32);
+        let low = level.floor() as usize;
+        let high = (low + 1).min(max_mip);
+
+        self.sample_mip(uvw, low).lerp(self.sample_mip(uvw, high), level - level.floor())
+    }
+}
+
+/// what a single cone has seen
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConeResult {
+    pub color: Vec3,
+    pub occlusion: f32,
+    /// like occlusion, but only counts what's close by
+    pub ao: f32,
+    /// how much light makes it through everything the cone passed, for each channel
+    pub transmittance: Vec3,
+    pub steps: u32,
+}
+
+impl Default for ConeResult {
+    fn default() -> Self {
+        Self {
+            color: Vec3::ZERO,
+            occlusion: 0.0,
+            ao: 0.0,
+            transmittance: Vec3::ONE,
+            steps: 0,
+        }
+    }
+}
+
+/// how much light passes through a voxel, for each channel
+///
+/// voxels that aren't fully opaque, like glass, tint the light with their albedo
+pub fn voxel_transmittance(voxel: Vec4) -> Vec3 {
+    let opacity = voxel.w.min(1.0);
+    if opacity <= 0.0 {
+        return Vec3::ONE;
+    }
+
+    let albedo = voxel.truncate() / voxel.w;
+    (1.0 - opacity) * Vec3::ONE.lerp(albedo, opacity)
+}
+
+/// all cascades of a volume, ready to be traced
+#[derive(Clone, Debug)]
+pub struct TraceVolume {
+    pub cascades: Vec<TraceCascade>,
+    pub bias: GiTraceBias,
+    /// everything but the resolution, cascades and format is used, the voxels are already there
+    pub settings: GiSettings,
+}
+
+impl TraceVolume {
+    /// makes the mips for each cascade, `grids` is the finest mip of every cascade
+    pub fn new(volume: &GiVolume, transform: &GlobalTransform, grids: Vec<VoxelGrid>) -> Self {
+        let cascades = grids
+            .into_iter()
+            .enumerate()
+            .map(|(cascade, grid)| {
+                let mut mips = vec![grid];
+                while mips.last().unwrap().resolution.max_element() > 1 {
+                    let mip = mips.last().unwrap().downsample();
+                    mips.push(mip);
+                }
+
+                TraceCascade {
+                    projection: cascade_projection(volume.extent, transform, cascade as u32),
+                    extent: volume.extent * cascade_scale(cascade as u32) * transform.scale,
+                    mips,
+                }
+            })
+            .collect();
+
+        Self {
+            cascades,
+            bias: GiTraceBias::default(),
+            settings: GiSettings::default(),
+        }
+    }
+
+    pub fn with_bias(mut self, bias: GiTraceBias) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    /// also bounces the light `settings.bounces - 1` times, like the bounce pass does for dense cascades
+    pub fn with_settings(mut self, settings: GiSettings) -> Self {
+        self.settings = settings;
+
+        for _ in 1..settings.bounces {
+            self.bounce();
+        }
+
+        self
+    }
+
+    /// every voxel gathers the light around it, and adds it tinted by it's own color
+    ///
+    /// same as the bounce entry point in cone_trace.wgsl, all voxels gather from the volume as it was before
+    pub fn bounce(&mut self) {
+        let bounced = self
+            .cascades
+            .iter()
+            .map(|cascade| {
+                let grid = &cascade.mips[0];
+                let to_world = cascade.projection.inverse();
+                let mut bounced = grid.clone();
+
+                for z in 0..grid.resolution.z {
+                    for y in 0..grid.resolution.y {
+                        for x in 0..grid.resolution.x {
+                            let texel = UVec3::new(x, y, z);
+                            let voxel = grid.get(texel);
+                            if voxel.w <= 0.0 {
+                                continue;
+                            }
+
+                            let uvw = (texel.as_f32() + Vec3::splat(0.5)) / grid.resolution.as_f32();
+                            let position = to_world.transform_point3(uvw);
+
+                            // out of the voxel first, so it doesn't gather from itself
+                            let light = (0..6).fold(Vec3::ZERO, |light, index| {
+                                let direction = bounce_direction(index);
+                                let origin = position + direction * cascade.voxel_size();
+                                light + self.trace_cone(origin, direction, self.settings.diffuse_aperture).color / 6.0
+                            });
+
+                            bounced.set(texel, voxel + (voxel.truncate() * light).extend(0.0));
+                        }
+                    }
+                }
+
+                bounced
+            })
+            .collect::<Vec<_>>();
+
+        for (cascade, grid) in self.cascades.iter_mut().zip(bounced) {
+            let mut mips = vec![grid];
+            while mips.last().unwrap().resolution.max_element() > 1 {
+                let mip = mips.last().unwrap().downsample();
+                mips.push(mip);
+            }
+
+            cascade.mips = mips;
+        }
+    }
+
+    /// samples the finest cascade the position is in, that has voxels large enough for the diameter
+    ///
+    /// close to the sides of a cascade it fades into the next one, over `settings.cascade_blend` voxels
+    /// gives the value and the cascade it came from, or None when the position is outside the volume
+    pub fn sample(&self, position: Vec3, diameter: f32) -> Option<(Vec4, usize)> {
+        // the next cascade has voxels twice as large, so it takes over once this one runs out of mips
+        let index = self.cascades.iter().enumerate().position(|(index, cascade)| {
+            let uvw = cascade.projection.transform_point3(position);
+            let mip = (diameter / cascade.voxel_size()).log2();
+            !(uvw.cmplt(Vec3::ZERO).any() || uvw.cmpgt(Vec3::ONE).any())
+                && (mip < (cascade.mips.len() - 1) as f32 || index + 1 == self.cascades.len())
+        })?;
+
+        let cascade = &self.cascades[index];
+        let uvw = cascade.projection.transform_point3(position);
+        let value = cascade.sample(uvw, (diameter / cascade.voxel_size()).log2());
+
+        // every sample near the border fades into the next cascade, which is centered on this one and covers it
+        let border = cascade.border_distance(uvw);
+        match self.cascades.get(index + 1) {
+            Some(next) if border < self.settings.cascade_blend => {
+                let next_uvw = next.projection.transform_point3(position);
+                let next_value = next.sample(next_uvw, (diameter / next.voxel_size()).log2());
+                Some((next_value.lerp(value, border / self.settings.cascade_blend), index))
+            }
+            _ => Some((value, index)),
+        }
+    }
+
+    /// partly covered voxels count as more opaque with a leak bias, so less light leaks through walls thinner than a voxel
+    pub fn leak_opacity(&self, opacity: f32) -> f32 {
+        (opacity * (1.0 + self.settings.leak_bias.max(0.0))).min(1.0)
+    }
+
+    /// the voxel with the leak bias applied to its opacity, keeping its albedo, so the bias also holds back colored light
+    pub fn leak_voxel(&self, voxel: Vec4) -> Vec4 {
+        if voxel.w <= 0.0 {
+            return voxel;
+        }
+
+        let opacity = self.leak_opacity(voxel.w);
+        (voxel.truncate() / voxel.w * opacity).extend(opacity)
+    }
+
+    /// traces a single cone, compositing front to back
+    ///
+    /// `aperture` is the tan of half the angle of the cone
+    pub fn trace_cone(&self, origin: Vec3, direction: Vec3, aperture: f32) -> ConeResult {
+        let mut result = ConeResult::default();
+
+        let min_diameter = match self.cascades.first() {
+            Some(cascade) => cascade.voxel_size(),
+            None => return result,
+        };
+
+        // the first sample would otherwise be the voxels the cone starts in
+        let mut distance = min_diameter * self.bias.self_intersection.max(0.0);
+
+        while distance < self.settings.max_trace_distance && result.occlusion < 0.99 && result.steps < MAX_STEPS {
+            let diameter = min_diameter.max(2.0 * aperture * distance);
+
+            // left the volume, so nothing more to hit
+            let (sample, _) = match self.sample(origin + direction * distance, diameter) {
+                Some(sample) => sample,
+                None => break,
+            };
+
+            // the voxels are premultiplied, and what's behind them is seen through their tint
+            let weight = 1.0 - result.occlusion;
+            let opacity = self.leak_opacity(sample.w);
+            result.color += result.transmittance * sample.truncate();
+            result.occlusion += weight * opacity;
+            result.ao += weight * opacity / (1.0 + AO_FALLOFF * distance);
+            result.transmittance *= voxel_transmittance(self.leak_voxel(sample));
+            result.steps += 1;
+
+            distance += diameter * self.settings.step_multiplier;
+        }
+
+        result
+    }
+
+    /// traces the diffuse cones for a surface, one straight up and the rest around it at 60 degrees
+    ///
+    /// gives the same as the shader writes to the indirect diffuse target, the light in rgb and ambient occlusion in w, where 1 is not occluded
+    pub fn trace_diffuse(&self, position: Vec3, normal: Vec3) -> Vec4 {
+        self.trace_diffuse_cones(position, normal, 0..self.diffuse_cones(), 1.0)
+    }
+
+    /// traces only one of the diffuse cones, weighted as if it were all of them, like the shader does with a history
+    ///
+    /// the average over all `settings.diffuse_cones` cones is the same as `trace_diffuse`
+    pub fn trace_diffuse_cone(&self, position: Vec3, normal: Vec3, cone: u32) -> Vec4 {
+        let count = self.diffuse_cones();
+        let cone = cone % count;
+        self.trace_diffuse_cones(position, normal, cone..cone + 1, count as f32)
+    }
+
+    // same as the shader, there's always at least one
+    fn diffuse_cones(&self) -> u32 {
+        self.settings.diffuse_cones.max(1)
+    }
+
+    fn trace_diffuse_cones(&self, position: Vec3, normal: Vec3, cones: std::ops::Range<u32>, scale: f32) -> Vec4 {
+        let min_diameter = match self.cascades.first() {
+            Some(cascade) => cascade.voxel_size(),
+            None => return Vec4::W,
+        };
+
+        // start a bit away from the surface, so the cones don't hit the voxels of the surface itself
+        let origin = position + normal * min_diameter * self.bias.normal_offset;
+
+        let mut diffuse = Vec3::ZERO;
+        let mut ao = 0.0;
+
+        for index in cones {
+            let (direction, weight) = diffuse_cone(normal, index, self.diffuse_cones());
+            let cone = self.trace_cone(origin, direction, self.settings.diffuse_aperture);
+
+            diffuse += cone.color * weight * scale;
+            ao += cone.ao * weight * scale;
+        }
+
+        (diffuse * self.settings.indirect_intensity).extend(1.0 - (ao * self.settings.ao_strength).clamp(0.0, 1.0))
+    }
+
+    /// traces the specular cone for a surface, seen from `eye`
+    pub fn trace_specular(&self, position: Vec3, normal: Vec3, eye: Vec3) -> Vec3 {
+        let min_diameter = match self.cascades.first() {
+            Some(cascade) => cascade.voxel_size(),
+            None => return Vec3::ZERO,
+        };
+
+        let origin = position + normal * min_diameter * self.bias.normal_offset;
+        let view_direction = (position - eye).normalize();
+        let direction = view_direction - 2.0 * view_direction.dot(normal) * normal;
+
+        self.trace_cone(origin, direction, self.settings.specular_aperture).color * self.settings.indirect_intensity
+    }
+
+    /// traces the cones of a probe, and projects them to spherical harmonics with `coefficients` coefficients
+    pub fn trace_probe(&self, position: Vec3, coefficients: usize) -> Vec<Vec3> {
+        // every cone covers the same part of the sphere
+        let weight = 4.0 * PI / PROBE_CONES as f32;
+
+        (0..PROBE_CONES).fold(vec![Vec3::ZERO; coefficients], |mut result, i| {
+            let direction = sphere_direction(i, PROBE_CONES);
+            let cone = self.trace_cone(position, direction, PROBE_APERTURE);
+
+            for (index, coefficient) in result.iter_mut().enumerate() {
+                *coefficient += cone.color * sh_basis(index, direction) * weight;
+            }
+
+            result
+        })
+    }
+
+    /// the cascade a position is in, the same as the cascade index debug output
+    pub fn cascade_at(&self, position: Vec3) -> Option<usize> {
+        let min_diameter = self.cascades.first()?.voxel_size();
+
+        self.sample(position, min_diameter).map(|(_, cascade)| cascade)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{GiStorage, GiThinGeometry};
+    use crate::voxelize::CpuVoxelizer;
+
+    // 32 voxels of 0.125 over the first cascade, from -2 to 2
+    fn test_volume() -> GiVolume {
+        GiVolume::from_voxel_size(Vec3::splat(4.0), 0.125, 3, GiStorage::Dense)
+    }
+
+    // a square facing `normal`, `size` wide
+    fn quad(center: Vec3, normal: Vec3, size: f32) -> [[Vec3; 3]; 2] {
+        let tangent = if normal.y.abs() > 0.99 { Vec3::X } else { Vec3::Y }.cross(normal) * size * 0.5;
+        let bitangent = normal.cross(tangent);
+        let corners = [
+            center - tangent - bitangent,
+            center + tangent - bitangent,
+            center + tangent + bitangent,
+            center - tangent + bitangent,
+        ];
+
+        [[corners[0], corners[1], corners[2]], [corners[0], corners[2], corners[3]]]
+    }
+
+    fn trace_volume(quads: &[([[Vec3; 3]; 2], Vec4)]) -> TraceVolume {
+        let volume = test_volume();
+        let mut voxelizer = CpuVoxelizer::new(&volume, &GlobalTransform::default());
+        for (triangles, albedo) in quads.iter() {
+            voxelizer.add_triangles(triangles, Mat4::IDENTITY, *albedo, GiThinGeometry::Keep);
+        }
+
+        TraceVolume::new(&volume, &GlobalTransform::default(), voxelizer.finish())
+    }
+
+    // every voxel of every cascade is filled with `albedo`, but the ones `empty` says aren't
+    fn solid_volume(albedo: Vec4, empty: impl Fn(Vec3) -> bool) -> TraceVolume {
+        let volume = test_volume();
+        let grids = (0..volume.cascades as u32)
+            .map(|cascade| {
+                let voxels_to_world = cascade_projection(volume.extent, &GlobalTransform::default(), cascade).inverse()
+                    * Mat4::from_scale(volume.resolution.as_f32().recip());
+
+                let mut grid = VoxelGrid::new(volume.resolution);
+                for z in 0..volume.resolution.z {
+                    for y in 0..volume.resolution.y {
+                        for x in 0..volume.resolution.x {
+                            let position = UVec3::new(x, y, z);
+                            if !empty(voxels_to_world.transform_point3(position.as_f32() + Vec3::splat(0.5))) {
+                                grid.set(position, albedo);
+                            }
+                        }
+                    }
+                }
+
+                grid
+            })
+            .collect();
+
+        TraceVolume::new(&volume, &GlobalTransform::default(), grids)
+    }
+
+    // a room from -1 to 1, with thick walls all around
+    fn closed_box(albedo: Vec4) -> TraceVolume {
+        solid_volume(albedo, |position| position.abs().max_element() < 1.0)
+    }
+
+    #[test]
+    fn open_sky_is_not_occluded() {
+        // only ground below -1, so everything above it is open
+        let volume = solid_volume(Vec4::ONE, |position| position.y > -1.0);
+
+        // the wide cones at the sides see a little of the ground, less the further away it is
+        let low = volume.trace_diffuse(Vec3::new(0.0, 0.5, 0.0), Vec3::Y);
+        let high = volume.trace_diffuse(Vec3::new(0.0, 2.0, 0.0), Vec3::Y);
+        assert!(low.truncate().max_element() < 0.2 && low.w > 0.98, "{:?}", low);
+        assert!(high.x < low.x && high.w > low.w, "{:?} {:?}", low, high);
+
+        // a narrow cone straight up hardly sees it at all
+        let cone = volume.trace_cone(Vec3::ZERO, Vec3::Y, 0.1);
+        assert!(cone.occlusion < 0.01, "{:?}", cone);
+
+        // and nothing at all without the ground
+        let empty = solid_volume(Vec4::ONE, |_| true);
+        assert_eq!(empty.trace_diffuse(Vec3::ZERO, Vec3::Y), Vec4::W);
+    }
+
+    #[test]
+    fn closed_box_sees_its_albedo_everywhere() {
+        let volume = closed_box(Vec4::ONE);
+        let sky = solid_volume(Vec4::ONE, |position| position.y > -1.0).trace_diffuse(Vec3::new(0.0, -1.0, 0.0), Vec3::Y);
+
+        // every cone ends in a white wall, so all of their light comes back, and it's more occluded than under the sky
+        for position in [Vec3::ZERO, Vec3::new(0.3, -0.2, -0.4), Vec3::new(0.0, -1.0, 0.0)].iter() {
+            for normal in [Vec3::Y, -Vec3::X].iter() {
+                let diffuse = volume.trace_diffuse(*position, *normal);
+                assert!((diffuse.truncate() - Vec3::ONE).abs().max_element() < 0.01, "{:?} {:?}", position, diffuse);
+                assert!(diffuse.w < sky.w, "{:?} {:?}", diffuse, sky);
+            }
+        }
+
+        for direction in [Vec3::X, Vec3::Y, Vec3::Z, -Vec3::X, -Vec3::Y, -Vec3::Z].iter() {
+            let cone = volume.trace_cone(Vec3::ZERO, *direction, 0.577);
+            assert!(cone.occlusion >= 0.99, "{:?} {:?}", direction, cone);
+        }
+
+        // and the walls tint it
+        let colored = closed_box(Vec4::new(1.0, 0.5, 0.25, 1.0)).trace_diffuse(Vec3::ZERO, Vec3::Y);
+        assert!((colored.x - 1.0).abs() < 0.01, "{:?}", colored);
+        assert!(colored.y < 0.5 && colored.z < colored.y * 0.5, "{:?}", colored);
+    }
+
+    #[test]
+    fn cone_diameter_picks_the_mip() {
+        // a block of 2x2x2 voxels around 0.125, so it fills a single voxel of mip 1
+        let volume = test_volume();
+        let mut grid = VoxelGrid::new(volume.resolution);
+        for i in 0..8 {
+            grid.set(UVec3::new(16 + (i & 1), 16 + ((i >> 1) & 1), 16 + ((i >> 2) & 1)), Vec4::ONE);
+        }
+        let empty = VoxelGrid::new(volume.resolution);
+        let volume = TraceVolume::new(&volume, &GlobalTransform::default(), vec![grid, empty.clone(), empty]);
+
+        let position = Vec3::splat(0.125);
+        let sample = |diameter: f32| volume.sample(position, diameter).unwrap();
+
+        // the center of the block at mip 0, and the center of a voxel at mip 1
+        assert_eq!(sample(0.125), (Vec4::ONE, 0));
+        assert_eq!(sample(0.25), (Vec4::ONE, 0));
+
+        // at mip 2 the block is an eighth of a voxel, and a quarter voxel away from it's center along every axis
+        let mip_2 = Vec4::splat(0.75f32.powi(3) / 8.0);
+        assert!((sample(0.5).0 - mip_2).abs().max_element() < 1e-6);
+
+        // in between two mips it's in between both
+        let between = sample(0.25 * 2.0f32.sqrt()).0;
+        assert!((between - (Vec4::ONE + mip_2) * 0.5).abs().max_element() < 1e-6);
+    }
+
+    #[test]
+    fn cascades_take_over_further_out() {
+        let volume = closed_box(Vec4::ONE);
+
+        assert_eq!(volume.cascade_at(Vec3::ZERO), Some(0));
+        assert_eq!(volume.cascade_at(Vec3::new(3.0, 0.0, 0.0)), Some(1));
+        assert_eq!(volume.cascade_at(Vec3::new(0.0, -7.0, 0.0)), Some(2));
+        assert_eq!(volume.cascade_at(Vec3::new(0.0, 0.0, 9.0)), None);
+
+        // cones too wide for the last mip of a cascade go on to the next one
+        assert_eq!(volume.sample(Vec3::ZERO, 2.0).unwrap().1, 0);
+        assert_eq!(volume.sample(Vec3::ZERO, 4.0).unwrap().1, 1);
+    }
+
+    #[test]
+    fn composites_front_to_back() {
+        let red = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        let blue = Vec4::new(0.0, 0.0, 1.0, 1.0);
+
+        // the nearest wall hides the one behind it
+        let volume = trace_volume(&[
+            (quad(Vec3::new(0.0, 0.0, 1.0), -Vec3::Z, 4.0), red),
+            (quad(Vec3::new(0.0, 0.0, 1.5), -Vec3::Z, 4.0), blue),
+        ]);
+        let cone = volume.trace_cone(Vec3::ZERO, Vec3::Z, 0.05);
+        assert!(cone.occlusion >= 0.99);
+        assert!(cone.color.x > 0.95 && cone.color.z < 0.05, "{:?}", cone);
+        assert!(cone.transmittance.max_element() < 0.01);
+
+        // see through glass, the wall behind it shows through, tinted by the glass
+        let glass = Vec4::new(1.0, 0.0, 0.0, 0.5);
+        let volume = trace_volume(&[
+            (quad(Vec3::new(0.0, 0.0, 1.0), -Vec3::Z, 4.0), glass),
+            (quad(Vec3::new(0.0, 0.0, 1.5), -Vec3::Z, 4.0), Vec4::ONE),
+        ]);
+        let cone = volume.trace_cone(Vec3::ZERO, Vec3::Z, 0.05);
+        assert!(cone.color.x > cone.color.y && cone.color.y > 0.0, "{:?}", cone);
+    }
+
+    #[test]
+    fn settings_scale_the_light() {
+        let volume = closed_box(Vec4::splat(0.5));
+        let position = Vec3::new(0.0, -1.0, 0.0);
+        let base = volume.trace_diffuse(position, Vec3::Y);
+
+        let brighter = volume
+            .clone()
+            .with_settings(GiSettings { indirect_intensity: 2.0, ..GiSettings::default() })
+            .trace_diffuse(position, Vec3::Y);
+        assert!((brighter.truncate() - base.truncate() * 2.0).abs().max_element() < 1e-5);
+        assert_eq!(brighter.w, base.w);
+
+        let no_ao = volume
+            .clone()
+            .with_settings(GiSettings { ao_strength: 0.0, ..GiSettings::default() })
+            .trace_diffuse(position, Vec3::Y);
+        assert_eq!(no_ao.w, 1.0);
+
+        // a single cone at a time averages out to all of them
+        let cones = volume.settings.diffuse_cones;
+        let average = (0..cones).fold(Vec4::ZERO, |sum, cone| sum + volume.trace_diffuse_cone(position, Vec3::Y, cone)) / cones as f32;
+        assert!((average - base).abs().max_element() < 1e-5);
+    }
+}
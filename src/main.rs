@@ -3,36 +3,84 @@ use bevy::{
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     ecs::prelude::*,
     input::Input,
-    math::{Quat, Vec3},
+    log::warn,
+    math::{Mat4, Quat, UVec3, Vec3},
     pbr2::{AmbientLight, DirectionalLight, DirectionalLightBundle, PbrBundle, StandardMaterial},
-    prelude::{App, Assets, KeyCode, Transform},
+    prelude::{App, AssetServer, Assets, Handle, KeyCode, Transform},
     render2::{
         camera::{OrthographicProjection, PerspectiveCameraBundle},
         color::Color,
         mesh::{shape, Mesh},
+        options::WgpuOptions,
+        render_resource::Features,
     },
     PipelinedDefaultPlugins,
 };
 
+pub mod bake;
+pub mod brick_map;
 pub mod bundle;
+pub mod cone_trace;
+pub mod octree;
+pub mod proxy;
 pub mod render;
+pub mod skinning;
+pub mod vox;
+pub mod voxelize;
+
+use bake::{GiBake, GiBakeFormat, GiBakedLayer};
+use bundle::{
+    GiLayers, GiProbeGrid, GiQuality, GiSettings, GiStatic, GiStorage, GiThinGeometry, GiTraceResolution, GiVolume,
+    GiVolumeBundle,
+};
+use render::debug_output::GiDebugOutput;
+use render::debug_view::{GiDebugChannel, GiDebugView};
+use render::diagnostics::GiDiagnosticsPlugin;
+use render::readback::{GiCascadeExport, GiCascadeExportRequest};
+use render::GiPlugin;
+use voxelize::VoxelGrid;
 
 fn main() {
     App::new()
+        // the gi passes can only be timed with timestamp queries, which not every adapter has
+        .insert_resource(WgpuOptions {
+            features: timestamp_features(),
+            ..Default::default()
+        })
         .add_plugins(PipelinedDefaultPlugins)
+        .add_plugin(GiPlugin::default())
         .add_plugin(FrameTimeDiagnosticsPlugin::default())
         .add_plugin(LogDiagnosticsPlugin::default())
+        .add_plugin(GiDiagnosticsPlugin { gpu_counters: true })
         .add_startup_system(setup.system())
         .add_system(movement.system())
         .add_system(animate_light_direction.system())
+        .add_system(debug_view_controls.system())
+        .add_system(debug_output_controls.system())
+        .add_system(export_controls.system())
+        .add_system(bake_controls.system())
+        .add_system(probe_controls.system())
+        .add_system(trace_resolution_controls.system())
+        .add_system(quality_controls.system())
+        .add_system(settings_controls.system())
         .run();
 }
 
+/// timestamp queries with `--timestamps`, making the device fails on adapters without them, so they're only asked for then
+fn timestamp_features() -> Features {
+    if std::env::args().any(|arg| arg == "--timestamps") {
+        Features::TIMESTAMP_QUERY
+    } else {
+        Features::empty()
+    }
+}
+
 struct Movable;
 
 /// set up a simple 3D scene
 fn setup(
     mut commands: Commands,
+    asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
@@ -41,42 +89,52 @@ fn setup(
         brightness: 0.02,
     });
 
-    // plane
-    commands.spawn_bundle(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Plane { size: 10.0 })),
-        material: materials.add(StandardMaterial {
-            base_color: Color::WHITE,
-            perceptual_roughness: 1.0,
+    // plane, single sided, so it's made solid to keep light from coming up from below
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Plane { size: 10.0 })),
+            material: materials.add(StandardMaterial {
+                base_color: Color::WHITE,
+                perceptual_roughness: 1.0,
+                ..Default::default()
+            }),
             ..Default::default()
-        }),
-        ..Default::default()
-    });
+        })
+        .insert(GiThinGeometry::Solidify(0.2))
+        .insert(GiStatic);
 
+    // the walls are thinner than a voxel, so they are dilated to not leak light
     let mut transform = Transform::from_xyz(2.5, 2.5, 0.0);
     transform.rotate(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2));
-    commands.spawn_bundle(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Box::new(5.0, 0.15, 5.0))),
-        transform,
-        material: materials.add(StandardMaterial {
-            base_color: Color::RED,
-            perceptual_roughness: 1.0,
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Box::new(5.0, 0.15, 5.0))),
+            transform,
+            material: materials.add(StandardMaterial {
+                base_color: Color::RED,
+                perceptual_roughness: 1.0,
+                ..Default::default()
+            }),
             ..Default::default()
-        }),
-        ..Default::default()
-    });
+        })
+        .insert(GiThinGeometry::Dilate)
+        .insert(GiStatic);
 
     let mut transform = Transform::from_xyz(0.0, 2.5, -2.5);
     transform.rotate(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2));
-    commands.spawn_bundle(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Box::new(5.0, 0.15, 5.0))),
-        transform,
-        material: materials.add(StandardMaterial {
-            base_color: Color::GREEN,
-            perceptual_roughness: 1.0,
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Box::new(5.0, 0.15, 5.0))),
+            transform,
+            material: materials.add(StandardMaterial {
+                base_color: Color::GREEN,
+                perceptual_roughness: 1.0,
+                ..Default::default()
+            }),
             ..Default::default()
-        }),
-        ..Default::default()
-    });
+        })
+        .insert(GiThinGeometry::Dilate)
+        .insert(GiStatic);
 
     // cube
     commands
@@ -132,6 +190,35 @@ fn setup(
         ..Default::default()
     });
 
+    // gi volume, covering the whole scene
+    let volume = GiVolume {
+        resolution: UVec3::splat(64),
+        cascades: 3,
+        extent: Vec3::splat(10.0),
+        storage: GiStorage::Dense,
+        layers: GiLayers::default(),
+        temporal_blend: 0.9,
+    };
+    let volume_transform = Transform::from_xyz(0.0, 2.5, 0.0);
+    let mut volume_entity = commands.spawn_bundle(GiVolumeBundle {
+        volume,
+        transform: volume_transform,
+        global_transform: Default::default(),
+    });
+
+    // a baked static layer is the fastest, so load that if there is one
+    if std::path::Path::new("assets/static.gibake").exists() {
+        let baked: Handle<GiBakedLayer> = asset_server.load("static.gibake");
+        volume_entity.insert(baked);
+    } else {
+        // otherwise bake it once the meshes are loaded, so it's there next time
+        volume_entity.insert(GiBake {
+            path: Some("assets/static.gibake".into()),
+            format: GiBakeFormat::Rgba8Unorm,
+            grids: occluder_grids(&volume),
+        });
+    }
+
     // camera
     commands.spawn_bundle(PerspectiveCameraBundle {
         transform: Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
@@ -148,6 +235,179 @@ fn animate_light_direction(
     }
 }
 
+/// F1 toggles the voxel debug view, F2 switches channels, F3 cascades and F4 mips
+fn debug_view_controls(
+    input: Res<Input<KeyCode>>,
+    mut debug_view: ResMut<GiDebugView>,
+    volumes: Query<&GiVolume>,
+) {
+    if input.just_pressed(KeyCode::F1) {
+        debug_view.enabled = !debug_view.enabled;
+    }
+
+    if input.just_pressed(KeyCode::F2) {
+        debug_view.channel = match debug_view.channel {
+            GiDebugChannel::Albedo => GiDebugChannel::Opacity,
+            GiDebugChannel::Opacity => GiDebugChannel::Radiance,
+            GiDebugChannel::Radiance => GiDebugChannel::Normal,
+            GiDebugChannel::Normal => GiDebugChannel::CascadeTint,
+            GiDebugChannel::CascadeTint => GiDebugChannel::Albedo,
+        };
+    }
+
+    // wrap around after the last cascade and mip of the volume
+    if let Some(volume) = volumes.iter().next() {
+        if input.just_pressed(KeyCode::F3) {
+            debug_view.cascade = (debug_view.cascade + 1) % volume.cascades.max(1) as u32;
+        }
+
+        if input.just_pressed(KeyCode::F4) {
+            let mips = 32 - volume.resolution.max_element().max(1).leading_zeros();
+            debug_view.mip = (debug_view.mip + 1) % mips;
+        }
+    }
+}
+
+/// F5 switches between the cone tracing outputs, and back to the normal image
+fn debug_output_controls(input: Res<Input<KeyCode>>, mut debug_output: ResMut<GiDebugOutput>) {
+    if input.just_pressed(KeyCode::F5) {
+        *debug_output = match *debug_output {
+            GiDebugOutput::None => GiDebugOutput::IndirectDiffuse,
+            GiDebugOutput::IndirectDiffuse => GiDebugOutput::IndirectSpecular,
+            GiDebugOutput::IndirectSpecular => GiDebugOutput::AmbientOcclusion,
+            GiDebugOutput::AmbientOcclusion => GiDebugOutput::CascadeIndex,
+            GiDebugOutput::CascadeIndex => GiDebugOutput::ConeSteps,
+            GiDebugOutput::ConeSteps => GiDebugOutput::None,
+        };
+    }
+}
+
+/// F6 writes the cascade shown by the debug view to a MagicaVoxel file
+fn export_controls(
+    input: Res<Input<KeyCode>>,
+    debug_view: Res<GiDebugView>,
+    mut export: ResMut<GiCascadeExport>,
+) {
+    if input.just_pressed(KeyCode::F6) {
+        export.requests.push(GiCascadeExportRequest {
+            cascade: debug_view.cascade,
+            path: format!("gi_cascade_{}.vox", debug_view.cascade).into(),
+        });
+    }
+}
+
+/// hand made occluders from assets/occluders.vox, standing on the floor with the same voxel size as the volume
+fn occluder_grids(volume: &GiVolume) -> Vec<(VoxelGrid, Mat4)> {
+    match vox::load_vox("assets/occluders.vox") {
+        Ok(grid) => {
+            let voxel_size = volume.voxel_size().x;
+            let offset = Vec3::new(grid.resolution.x as f32, 0.0, grid.resolution.z as f32) * -0.5;
+            let transform = Mat4::from_scale(Vec3::splat(voxel_size)) * Mat4::from_translation(offset);
+
+            vec![(grid, transform)]
+        }
+        // it's fine to not have any
+        Err(vox::VoxError::Io(_)) => Vec::new(),
+        Err(error) => {
+            warn!("failed to load assets/occluders.vox: {}", error);
+            Vec::new()
+        }
+    }
+}
+
+/// F7 bakes the static meshes and occluders into the static layer of the volume again, and saves it to assets/static.gibake
+fn bake_controls(
+    mut commands: Commands,
+    input: Res<Input<KeyCode>>,
+    volumes: Query<(Entity, &GiVolume)>,
+) {
+    if !input.just_pressed(KeyCode::F7) {
+        return;
+    }
+
+    for (entity, volume) in volumes.iter().take(1) {
+        commands.entity(entity).insert(GiBake {
+            path: Some("assets/static.gibake".into()),
+            format: GiBakeFormat::Rgba8Unorm,
+            grids: occluder_grids(volume),
+        });
+    }
+}
+
+/// F8 switches between tracing cones per pixel and reading the irradiance probes
+fn probe_controls(
+    mut commands: Commands,
+    input: Res<Input<KeyCode>>,
+    volumes: Query<(Entity, Option<&GiProbeGrid>), With<GiVolume>>,
+) {
+    if !input.just_pressed(KeyCode::F8) {
+        return;
+    }
+
+    for (entity, probe_grid) in volumes.iter() {
+        match probe_grid {
+            Some(_) => commands.entity(entity).remove::<GiProbeGrid>(),
+            None => commands.entity(entity).insert(GiProbeGrid::default()),
+        };
+    }
+}
+
+/// F9 traces the diffuse cones at full, half and quarter resolution
+fn trace_resolution_controls(
+    mut commands: Commands,
+    input: Res<Input<KeyCode>>,
+    volumes: Query<(Entity, Option<&GiTraceResolution>), With<GiVolume>>,
+) {
+    if !input.just_pressed(KeyCode::F9) {
+        return;
+    }
+
+    for (entity, trace_resolution) in volumes.iter() {
+        let next = match trace_resolution.copied().unwrap_or_default() {
+            GiTraceResolution::Full => GiTraceResolution::Half,
+            GiTraceResolution::Half => GiTraceResolution::Quarter,
+            GiTraceResolution::Quarter => GiTraceResolution::Full,
+        };
+
+        commands.entity(entity).insert(next);
+    }
+}
+
+/// F10 goes through the quality presets, the static layer is ignored once the resolution doesn't match it anymore
+fn quality_controls(mut commands: Commands, input: Res<Input<KeyCode>>, quality: Option<Res<GiQuality>>) {
+    if !input.just_pressed(KeyCode::F10) {
+        return;
+    }
+
+    // the volume is set up by hand, so the first press starts at the lowest
+    let next = match quality.map(|quality| *quality) {
+        None | Some(GiQuality::Ultra) | Some(GiQuality::Custom) => GiQuality::Low,
+        Some(GiQuality::Low) => GiQuality::Medium,
+        Some(GiQuality::Medium) => GiQuality::High,
+        Some(GiQuality::High) => GiQuality::Ultra,
+    };
+
+    commands.insert_resource(next);
+}
+
+/// page up and down change the indirect intensity while held, home and end the ambient occlusion strength
+fn settings_controls(input: Res<Input<KeyCode>>, time: Res<Time>, mut settings: ResMut<GiSettings>) {
+    let change = time.delta_seconds();
+
+    if input.pressed(KeyCode::PageUp) {
+        settings.indirect_intensity += change;
+    }
+    if input.pressed(KeyCode::PageDown) {
+        settings.indirect_intensity = (settings.indirect_intensity - change).max(0.0);
+    }
+    if input.pressed(KeyCode::Home) {
+        settings.ao_strength += change;
+    }
+    if input.pressed(KeyCode::End) {
+        settings.ao_strength = (settings.ao_strength - change).max(0.0);
+    }
+}
+
 fn movement(
     input: Res<Input<KeyCode>>,
     time: Res<Time>,
@@ -0,0 +1,354 @@
+//! Sparse voxel octree, built from a list of voxel fragments
+//!
+//! this is the cpu side version of what `render::sparse_octree` builds on the gpu
+//! it uses the same node layout and the same build steps, so both can be built from the same fragment list and compared
+
+use bevy::math::{UVec3, Vec4};
+
+/// a node without children, the root is always node 0 so no child can point there
+pub const NO_CHILDREN: u32 = 0;
+
+/// max depth of the octree, as fragment positions are packed in 10 bits per axis
+pub const MAX_OCTREE_DEPTH: u32 = 10;
+
+/// a single voxel written by voxelization
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VoxelFragment {
+    /// position in voxels, at the finest level
+    pub position: UVec3,
+    /// albedo and opacity
+    pub albedo: Vec4,
+}
+
+impl VoxelFragment {
+    /// packs the fragment the same way the gpu stores it, 10 bits per axis and rgba8 color
+    pub fn pack(&self) -> [u32; 2] {
+        let position = (self.position.x & 0x3ff)
+            | (self.position.y & 0x3ff) << 10
+            | (self.position.z & 0x3ff) << 20;
+
+        let color = self.albedo.clamp(Vec4::ZERO, Vec4::ONE) * 255.0 + Vec4::splat(0.5);
+        let albedo = color.x as u32
+            | (color.y as u32) << 8
+            | (color.z as u32) << 16
+            | (color.w as u32) << 24;
+
+        [position, albedo]
+    }
+
+    pub fn unpack(packed: [u32; 2]) -> Self {
+        let [position, albedo] = packed;
+
+        Self {
+            position: UVec3::new(
+                position & 0x3ff,
+                (position >> 10) & 0x3ff,
+                (position >> 20) & 0x3ff,
+            ),
+            albedo: Vec4::new(
+                (albedo & 0xff) as f32,
+                ((albedo >> 8) & 0xff) as f32,
+                ((albedo >> 16) & 0xff) as f32,
+                (albedo >> 24) as f32,
+            ) / 255.0,
+        }
+    }
+}
+
+/// a node in the octree
+///
+/// children are always allocated 8 at a time, so only the first one needs to be stored
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OctreeNode {
+    /// index of the first child, or `NO_CHILDREN`
+    pub child: u32,
+}
+
+/// Sparse voxel octree
+///
+/// every node has a brick with the values of it's 8 octants, premultiplied by opacity
+/// the octants of the leaf nodes are single voxels, the ones above are the averages of the brick of the child
+#[derive(Clone, Debug)]
+pub struct SparseOctree {
+    /// number of levels, the octree is 2^depth voxels wide
+    pub depth: u32,
+    pub nodes: Vec<OctreeNode>,
+    /// one brick per node, indexed by octant
+    pub bricks: Vec<[Vec4; 8]>,
+    /// index of the first node of each level, plus the total node count at the end
+    pub level_start: Vec<u32>,
+}
+
+/// which of the 8 children of a node at `level` contains `position`
+pub fn octant(position: UVec3, level: u32, depth: u32) -> usize {
+    let shift = depth - 1 - level;
+
+    (((position.x >> shift) & 1)
+        | ((position.y >> shift) & 1) << 1
+        | ((position.z >> shift) & 1) << 2) as usize
+}
+
+impl SparseOctree {
+    /// builds the octree from a list of fragments, the same way the gpu does it
+    ///
+    /// fragments outside of the octree are ignored
+    pub fn build(fragments: &[VoxelFragment], depth: u32) -> Self {
+        assert!(
+            depth > 0 && depth <= MAX_OCTREE_DEPTH,
+            "octree depth must be between 1 and {}",
+            MAX_OCTREE_DEPTH
+        );
+
+        let size = 1 << depth;
+        let fragments = fragments
+            .iter()
+            .filter(|fragment| fragment.position.max_element() < size)
+            .collect::<Vec<_>>();
+
+        let mut octree = Self {
+            depth,
+            nodes: vec![OctreeNode::default()],
+            bricks: vec![[Vec4::ZERO; 8]],
+            level_start: vec![0, 1],
+        };
+
+        // subdivide one level at a time
+        for level in 0..depth - 1 {
+            // flag all nodes that have a fragment in them
+            let mut flagged = vec![false; octree.nodes.len()];
+            for fragment in fragments.iter() {
+                if let Some(node) = octree.find_node(fragment.position, level) {
+                    flagged[node] = true;
+                }
+            }
+
+            // and give them children
+            let level_nodes =
+                octree.level_start[level as usize]..octree.level_start[level as usize + 1];
+            for node in level_nodes {
+                if flagged[node as usize] {
+                    octree.nodes[node as usize].child = octree.nodes.len() as u32;
+                    octree.nodes.extend_from_slice(&[OctreeNode::default(); 8]);
+                    octree.bricks.extend_from_slice(&[[Vec4::ZERO; 8]; 8]);
+                }
+            }
+
+            octree.level_start.push(octree.nodes.len() as u32);
+        }
+
+        // write the fragments into the bricks of the leaves, averaging them if they end up in the same voxel
+        let mut sums = vec![[(Vec4::ZERO, 0u32); 8]; octree.nodes.len()];
+        for fragment in fragments.iter() {
+            let leaf = octree
+                .find_node(fragment.position, depth - 1)
+                .expect("leaf should be allocated for every fragment");
+
+            let (sum, count) = &mut sums[leaf][octant(fragment.position, depth - 1, depth)];
+            *sum += fragment.albedo;
+            *count += 1;
+        }
+
+        for (brick, sums) in octree.bricks.iter_mut().zip(sums.iter()) {
+            for (value, (sum, count)) in brick.iter_mut().zip(sums.iter()) {
+                if *count > 0 {
+                    let albedo = *sum / *count as f32;
+                    *value = (albedo.truncate() * albedo.w).extend(albedo.w);
+                }
+            }
+        }
+
+        // and mipmap, bottom up
+        for level in (0..depth - 1).rev() {
+            let level_nodes =
+                octree.level_start[level as usize]..octree.level_start[level as usize + 1];
+            for node in level_nodes {
+                let child = octree.nodes[node as usize].child;
+                if child == NO_CHILDREN {
+                    continue;
+                }
+
+                for octant in 0..8 {
+                    let child_brick = &octree.bricks[child as usize + octant];
+                    octree.bricks[node as usize][octant] = child_brick
+                        .iter()
+                        .fold(Vec4::ZERO, |sum, value| sum + *value)
+                        / 8.0;
+                }
+            }
+        }
+
+        octree
+    }
+
+    /// size of the octree, in voxels at the finest level
+    pub fn resolution(&self) -> u32 {
+        1 << self.depth
+    }
+
+    /// finds the node at `level` that contains `position`, if it's allocated
+    pub fn find_node(&self, position: UVec3, level: u32) -> Option<usize> {
+        let mut node = 0;
+
+        for parent_level in 0..level {
+            let child = self.nodes[node].child;
+            if child == NO_CHILDREN {
+                return None;
+            }
+
+            node = child as usize + octant(position, parent_level, self.depth);
+        }
+
+        Some(node)
+    }
+
+    /// gets the value of a voxel, where mip 0 is the finest level and every mip after that is twice as large
+    ///
+    /// `position` is in voxels of the finest level, unallocated space is empty
+    pub fn get(&self, position: UVec3, mip: u32) -> Vec4 {
+        if mip >= self.depth || position.max_element() >= self.resolution() {
+            return Vec4::ZERO;
+        }
+
+        let level = self.depth - 1 - mip;
+        self.find_node(position, level)
+            .map(|node| self.bricks[node][octant(position, level, self.depth)])
+            .unwrap_or(Vec4::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxelize::VoxelGrid;
+
+    // a few surfaces with different colors and opacities, some of them in the same voxel
+    fn test_fragments() -> Vec<VoxelFragment> {
+        let mut fragments = Vec::new();
+
+        // a floor
+        for z in 0..16 {
+            for x in 0..16 {
+                fragments.push(VoxelFragment {
+                    position: UVec3::new(x, 0, z),
+                    albedo: Vec4::new(x as f32 / 15.0, 0.5, z as f32 / 15.0, 1.0),
+                });
+            }
+        }
+
+        // a see through pillar, and a second surface inside of it
+        for y in 1..12 {
+            fragments.push(VoxelFragment {
+                position: UVec3::new(5, y, 9),
+                albedo: Vec4::new(0.2, 0.4, 0.8, 0.5),
+            });
+            fragments.push(VoxelFragment {
+                position: UVec3::new(5, y, 9),
+                albedo: Vec4::new(1.0, 1.0, 0.0, 1.0),
+            });
+        }
+
+        fragments
+    }
+
+    // what the gpu writes to the fragment list, and reads back when building the octree
+    fn through_gpu_layout(fragments: &[VoxelFragment]) -> Vec<VoxelFragment> {
+        fragments
+            .iter()
+            .map(|fragment| VoxelFragment::unpack(fragment.pack()))
+            .collect()
+    }
+
+    // the dense grid a dense cascade would hold for the same fragments, averaged and premultiplied
+    fn dense_grid(fragments: &[VoxelFragment], resolution: u32) -> VoxelGrid {
+        let mut sums = VoxelGrid::new(UVec3::splat(resolution));
+        let mut counts = vec![0.0; sums.voxels.len()];
+
+        for fragment in fragments {
+            let index = (fragment.position.x
+                + fragment.position.y * resolution
+                + fragment.position.z * resolution * resolution) as usize;
+            sums.voxels[index] += fragment.albedo;
+            counts[index] += 1.0;
+        }
+
+        for (voxel, count) in sums.voxels.iter_mut().zip(counts) {
+            if count > 0.0 {
+                let albedo = *voxel / count;
+                *voxel = (albedo.truncate() * albedo.w).extend(albedo.w);
+            }
+        }
+
+        sums
+    }
+
+    #[test]
+    fn packing_keeps_position_and_albedo() {
+        let fragment = VoxelFragment {
+            position: UVec3::new(1023, 512, 7),
+            albedo: Vec4::new(1.0, 0.0, 128.0 / 255.0, 64.0 / 255.0),
+        };
+
+        let [position, albedo] = fragment.pack();
+        assert_eq!(position, 1023 | 512 << 10 | 7 << 20);
+        // same byte order as pack4x8unorm
+        assert_eq!(albedo, 0xff | 128 << 16 | 64 << 24);
+        assert_eq!(VoxelFragment::unpack([position, albedo]), fragment);
+    }
+
+    #[test]
+    fn matches_dense_grid_from_same_fragments() {
+        let fragments = through_gpu_layout(&test_fragments());
+        let octree = SparseOctree::build(&fragments, 4);
+        let mut grid = dense_grid(&fragments, octree.resolution());
+
+        for mip in 0..octree.depth {
+            for (index, expected) in grid.voxels.iter().enumerate() {
+                let resolution = grid.resolution.x as usize;
+                let texel = UVec3::new(
+                    (index % resolution) as u32,
+                    (index / resolution % resolution) as u32,
+                    (index / (resolution * resolution)) as u32,
+                );
+
+                let value = octree.get(texel * (1 << mip), mip);
+                assert!(
+                    (value - *expected).abs().max_element() < 1e-5,
+                    "mip {} voxel {} is {}, the dense grid has {}",
+                    mip,
+                    texel,
+                    value,
+                    expected
+                );
+            }
+
+            grid = grid.downsample();
+        }
+    }
+
+    #[test]
+    fn only_subdivides_where_there_are_fragments() {
+        let fragments = [VoxelFragment {
+            position: UVec3::new(3, 12, 7),
+            albedo: Vec4::ONE,
+        }];
+        let octree = SparseOctree::build(&fragments, 5);
+
+        // the root, and 8 children for a single node on every level above the leaves
+        assert_eq!(octree.nodes.len(), 1 + 8 * 4);
+        assert_eq!(octree.get(UVec3::new(3, 12, 7), 0), Vec4::ONE);
+        assert_eq!(octree.get(UVec3::new(4, 12, 7), 0), Vec4::ZERO);
+        assert_eq!(octree.get(UVec3::new(0, 12, 4), 2), Vec4::ONE / 64.0);
+    }
+
+    #[test]
+    fn ignores_fragments_outside() {
+        let fragments = [VoxelFragment {
+            position: UVec3::new(16, 0, 0),
+            albedo: Vec4::ONE,
+        }];
+        let octree = SparseOctree::build(&fragments, 4);
+
+        assert_eq!(octree.nodes.len(), 1);
+        assert_eq!(octree.get(UVec3::new(15, 0, 0), 0), Vec4::ZERO);
+    }
+}
@@ -0,0 +1,380 @@
+//! Proxy meshes for voxelization
+//!
+//! detailed meshes take long to voxelize, and most of the detail is lost at the size of a voxel anyway
+//! these make a simpler mesh that covers the original, to put in a `GiProxy`
+//! either by merging all vertices in the same cell of a grid, or by taking the convex hull
+
+use bevy::asset::{Assets, Handle};
+use bevy::ecs::prelude::*;
+use bevy::math::Vec3;
+use bevy::render2::{
+    mesh::{Indices, Mesh},
+    render_resource::PrimitiveTopology,
+};
+use bevy::utils::{HashMap, HashSet};
+
+use crate::bundle::GiProxy;
+use crate::voxelize::mesh_triangles;
+
+/// how a proxy is made from a mesh
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GiProxyShape {
+    /// merges all vertices in the same cell of a grid, and drops the triangles that collapse
+    ///
+    /// keeps the shape, a cell size around the size of a voxel works well
+    /// the merged vertices are pushed out along their normals, as far as the vertices they replace were in front of them, up to half a cell
+    /// this keeps it around most of the mesh, but it isn't guaranteed to cover all of it, only `ConvexHull` is
+    Clustered { cell_size: f32 },
+
+    /// the convex hull of the mesh, always covers all of it, but also fills in holes and corners
+    ConvexHull,
+}
+
+/// makes a proxy for `mesh`, for voxelization only, so it only has positions and normals
+pub fn generate_proxy(mesh: &Mesh, shape: GiProxyShape) -> Mesh {
+    let triangles = mesh_triangles(mesh);
+
+    let (positions, indices) = match shape {
+        GiProxyShape::Clustered { cell_size } => cluster(&triangles, cell_size),
+        GiProxyShape::ConvexHull => {
+            let points: Vec<Vec3> = triangles.iter().flatten().copied().collect();
+            convex_hull(&points)
+        }
+    };
+
+    proxy_mesh(positions, indices)
+}
+
+/// area weighted, so the normals are close to the faces around them
+fn vertex_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for triangle in indices.chunks_exact(3) {
+        let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+        let normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        normals[a] += normal;
+        normals[b] += normal;
+        normals[c] += normal;
+    }
+
+    normals.iter().map(|normal| normal.normalize_or_zero()).collect()
+}
+
+fn proxy_mesh(positions: Vec<Vec3>, indices: Vec<u32>) -> Mesh {
+    let normals: Vec<[f32; 3]> = vertex_normals(&positions, &indices)
+        .iter()
+        .map(|normal| normal.to_array())
+        .collect();
+
+    // the same attributes as the shapes, so the proxy works with the same pipelines
+    let uvs = vec![[0.0f32; 2]; positions.len()];
+    let positions: Vec<[f32; 3]> = positions.iter().map(|position| position.to_array()).collect();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+/// vertex clustering, every vertex moves to the average of the vertices in it's cell, and then out along it's normal
+fn cluster(triangles: &[[Vec3; 3]], cell_size: f32) -> (Vec<Vec3>, Vec<u32>) {
+    let cell_size = cell_size.max(f32::EPSILON);
+
+    // the vertex of every cell, and the sum of the positions in it
+    let mut cells: HashMap<[i32; 3], u32> = HashMap::default();
+    let mut sums: Vec<(Vec3, f32)> = Vec::new();
+    // every vertex that went in, and the vertex it was merged into
+    let mut merged: Vec<(u32, Vec3)> = Vec::new();
+
+    let mut vertex = |position: Vec3| {
+        let cell = (position / cell_size).floor();
+        let cell = [cell.x as i32, cell.y as i32, cell.z as i32];
+
+        let index = *cells.entry(cell).or_insert_with(|| {
+            sums.push((Vec3::ZERO, 0.0));
+            sums.len() as u32 - 1
+        });
+
+        let sum = &mut sums[index as usize];
+        sum.0 += position;
+        sum.1 += 1.0;
+        merged.push((index, position));
+        index
+    };
+
+    let mut indices = Vec::new();
+    let mut seen = HashSet::default();
+    for triangle in triangles {
+        let triangle = [vertex(triangle[0]), vertex(triangle[1]), vertex(triangle[2])];
+
+        // collapsed
+        if triangle[0] == triangle[1] || triangle[1] == triangle[2] || triangle[2] == triangle[0] {
+            continue;
+        }
+
+        // the same triangle can come out of many, only keep one of each side
+        let rotation = (0..3).min_by_key(|i| triangle[*i]).unwrap();
+        let key = [triangle[rotation], triangle[(rotation + 1) % 3], triangle[(rotation + 2) % 3]];
+        if seen.insert(key) {
+            indices.extend_from_slice(&triangle);
+        }
+    }
+
+    let mut positions: Vec<Vec3> = sums.iter().map(|(sum, count)| *sum / *count).collect();
+
+    // averaging moves the vertices inside curved surfaces, so they're pushed out again
+    let normals = vertex_normals(&positions, &indices);
+    let mut offsets = vec![0.0f32; positions.len()];
+    for (index, position) in merged.iter() {
+        let index = *index as usize;
+        offsets[index] = offsets[index].max(normals[index].dot(*position - positions[index]));
+    }
+
+    for ((position, normal), offset) in positions.iter_mut().zip(normals.iter()).zip(offsets.iter()) {
+        *position += *normal * offset.min(cell_size * 0.5);
+    }
+
+    (positions, indices)
+}
+
+/// the convex hull of a set of points, as positions and indices facing out
+///
+/// empty when the points are all on a plane, points that aren't finite are left out
+pub fn convex_hull(points: &[Vec3]) -> (Vec<Vec3>, Vec<u32>) {
+    let points: Vec<Vec3> = points.iter().copied().filter(|point| point.is_finite()).collect();
+    if points.len() < 4 {
+        return (Vec::new(), Vec::new());
+    }
+
+    let (min, max) = points
+        .iter()
+        .fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), point| (min.min(*point), max.max(*point)));
+    let epsilon = (max - min).max_element() * 1e-5;
+
+    // a first tetrahedron, from points as far apart as possible
+    let farthest = |score: &dyn Fn(Vec3) -> f32| {
+        (0..points.len())
+            .max_by(|a, b| score(points[*a]).partial_cmp(&score(points[*b])).unwrap())
+            .unwrap()
+    };
+
+    let a = farthest(&|point| -point.x);
+    let b = farthest(&|point| point.distance(points[a]));
+    let c = farthest(&|point| (point - points[a]).cross(points[b] - points[a]).length());
+    let normal = (points[b] - points[a]).cross(points[c] - points[a]);
+    let d = farthest(&|point| normal.dot(point - points[a]).abs());
+
+    if normal.length() <= epsilon * epsilon || normal.normalize().dot(points[d] - points[a]).abs() <= epsilon {
+        return (Vec::new(), Vec::new());
+    }
+
+    let mut faces = if normal.dot(points[d] - points[a]) > 0.0 {
+        vec![[a, c, b], [a, b, d], [b, c, d], [c, a, d]]
+    } else {
+        vec![[a, b, c], [a, d, b], [b, d, c], [c, d, a]]
+    };
+
+    let distance = |face: &[usize; 3], point: Vec3| {
+        let normal = (points[face[1]] - points[face[0]]).cross(points[face[2]] - points[face[0]]).normalize();
+        normal.dot(point - points[face[0]])
+    };
+
+    // adds the points one at a time, replacing the faces they can see
+    for (index, point) in points.iter().enumerate() {
+        let (visible, hidden): (Vec<[usize; 3]>, Vec<[usize; 3]>) =
+            faces.drain(..).partition(|face| distance(face, *point) > epsilon);
+        faces = hidden;
+
+        if visible.is_empty() {
+            continue;
+        }
+
+        // the edges between visible and hidden faces, only visible faces have them once
+        let mut edges = HashSet::default();
+        for face in visible.iter() {
+            for i in 0..3 {
+                let edge = (face[i], face[(i + 1) % 3]);
+                if !edges.remove(&(edge.1, edge.0)) {
+                    edges.insert(edge);
+                }
+            }
+        }
+
+        faces.extend(edges.iter().map(|(from, to)| [*from, *to, index]));
+    }
+
+    // only keep the points the hull uses
+    let mut remap = HashMap::default();
+    let mut positions = Vec::new();
+    let indices = faces
+        .iter()
+        .flatten()
+        .map(|point| {
+            *remap.entry(*point).or_insert_with(|| {
+                positions.push(points[*point]);
+                positions.len() as u32 - 1
+            })
+        })
+        .collect();
+
+    (positions, indices)
+}
+
+/// makes a proxy for the mesh on the same entity once it's loaded, and puts it in a `GiProxy`
+#[derive(Copy, Clone, Debug)]
+pub struct GiAutoProxy(pub GiProxyShape);
+
+pub fn generate_gi_proxies(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    entities: Query<(Entity, &Handle<Mesh>, &GiAutoProxy)>,
+    // meshes used by many entities only get one proxy
+    mut proxies: Local<HashMap<(Handle<Mesh>, [u32; 2]), Handle<Mesh>>>,
+) {
+    for (entity, handle, auto_proxy) in entities.iter() {
+        // the shape is part of the key, bit for bit
+        let shape_key = match auto_proxy.0 {
+            GiProxyShape::Clustered { cell_size } => [0, cell_size.to_bits()],
+            GiProxyShape::ConvexHull => [1, 0],
+        };
+        let key = (handle.clone_weak(), shape_key);
+
+        let proxy = match proxies.get(&key) {
+            Some(proxy) => proxy.clone(),
+            None => {
+                let mesh = match meshes.get(handle) {
+                    Some(mesh) => mesh,
+                    // not loaded yet
+                    None => continue,
+                };
+
+                let proxy = meshes.add(generate_proxy(mesh, auto_proxy.0));
+                proxies.insert(key, proxy.clone());
+                proxy
+            }
+        };
+
+        commands
+            .entity(entity)
+            .insert(GiProxy(proxy))
+            .remove::<GiAutoProxy>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the corners of a cube from -1 to 1
+    fn cube_corners() -> Vec<Vec3> {
+        (0..8)
+            .map(|corner| {
+                Vec3::new(
+                    if corner & 1 == 0 { -1.0 } else { 1.0 },
+                    if corner & 2 == 0 { -1.0 } else { 1.0 },
+                    if corner & 4 == 0 { -1.0 } else { 1.0 },
+                )
+            })
+            .collect()
+    }
+
+    // points all over a box, always the same ones
+    fn scattered(count: usize, size: f32) -> Vec<Vec3> {
+        let mut state = 12345u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0
+        };
+
+        (0..count).map(|_| Vec3::new(next(), next(), next()) * size).collect()
+    }
+
+    // the triangles of a sphere, as rings around y
+    fn sphere(radius: f32, rings: u32) -> Vec<[Vec3; 3]> {
+        let point = |ring: u32, segment: u32| {
+            let theta = std::f32::consts::PI * ring as f32 / rings as f32;
+            let phi = std::f32::consts::TAU * segment as f32 / (rings * 2) as f32;
+            Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()) * radius
+        };
+
+        let mut triangles = Vec::new();
+        for ring in 0..rings {
+            for segment in 0..rings * 2 {
+                let (a, b) = (point(ring, segment), point(ring, segment + 1));
+                let (c, d) = (point(ring + 1, segment), point(ring + 1, segment + 1));
+                triangles.push([a, d, c]);
+                triangles.push([a, b, d]);
+            }
+        }
+        triangles
+    }
+
+    // how far `point` is in front of the faces of the hull, the most of all of them
+    fn outside(positions: &[Vec3], indices: &[u32], point: Vec3) -> f32 {
+        indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let (a, b, c) = (positions[triangle[0] as usize], positions[triangle[1] as usize], positions[triangle[2] as usize]);
+                (b - a).cross(c - a).normalize().dot(point - a)
+            })
+            .fold(f32::MIN, f32::max)
+    }
+
+    #[test]
+    fn cube_hull_has_every_corner_and_faces_out() {
+        let (positions, indices) = convex_hull(&cube_corners());
+        assert_eq!(positions.len(), 8);
+        assert_eq!(indices.len(), 12 * 3);
+
+        // the cube is around the origin, so every face points away from it
+        for triangle in indices.chunks_exact(3) {
+            let (a, b, c) = (positions[triangle[0] as usize], positions[triangle[1] as usize], positions[triangle[2] as usize]);
+            assert!((b - a).cross(c - a).dot(a + b + c) > 0.0);
+        }
+    }
+
+    #[test]
+    fn hull_has_every_point_inside() {
+        let points = scattered(500, 3.0);
+        let (positions, indices) = convex_hull(&points);
+        assert!(!indices.is_empty());
+
+        for point in points.iter() {
+            assert!(outside(&positions, &indices, *point) <= 1e-4);
+        }
+    }
+
+    #[test]
+    fn coplanar_hull_is_empty() {
+        let points: Vec<Vec3> = scattered(100, 1.0).iter().map(|point| Vec3::new(point.x, 0.5, point.z)).collect();
+        let (positions, indices) = convex_hull(&points);
+        assert!(positions.is_empty());
+        assert!(indices.is_empty());
+    }
+
+    #[test]
+    fn hull_leaves_out_points_that_arent_finite() {
+        let mut points = cube_corners();
+        points.push(Vec3::new(f32::NAN, 0.0, 0.0));
+        points.push(Vec3::splat(f32::INFINITY));
+
+        let (positions, indices) = convex_hull(&points);
+        assert_eq!(positions.len(), 8);
+        assert_eq!(indices.len(), 12 * 3);
+    }
+
+    #[test]
+    fn clustered_sphere_stays_around_the_surface() {
+        let triangles = sphere(1.0, 32);
+        let (positions, indices) = cluster(&triangles, 0.25);
+
+        // far fewer vertices, and none of them pulled inside the sphere much
+        // the averages would be up to a few hundredths inside, the flat triangles of the sphere are already a bit inside
+        assert!(!indices.is_empty());
+        assert!(positions.len() < triangles.len() / 4);
+        for position in positions.iter() {
+            assert!(position.length() >= 0.995, "{} is inside the sphere", position);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+// HOW IT WORKS
+// with more than one bounce in the GiSettings, the voxels of dense cascades gather light from each other before tracing
+// every voxel traces 6 cones through the cascades, and writes itself plus what it gathered to a copy of it's cascade
+// the cascades can't be read and written in the same pass, so the copies are copied back into the cascades afterwards
+// and the mips are made again from them, so the next bounce and the tracer see the light in every mip
+// that's repeated once for every extra bounce, see BOUNCES in cone_trace.wgsl
+
+use crevice::std140::AsStd140;
+
+use crate::bundle::{GiStorage, GiVolumeFormat};
+use crate::voxelize::cascade_projection;
+
+use super::cone_trace::{ConeTraceMeta, ConeTraceShaders, MAX_TRACED_CASCADES};
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+use super::gi_volume::{
+	mipmap_cascades, volume_format_shader, volume_texture_format, ExtractedGiVolume, GiCascadeMeta, GiShaders,
+	ViewGiVolumeBindGroups, ViewGiVolumes,
+};
+
+use bevy::ecs::prelude::*;
+use bevy::math::Mat4;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice},
+	texture::{CachedTexture, TextureCache},
+	view::{ExtractedView, ViewUniformOffset},
+};
+use bevy::utils::HashMap;
+
+// same as in cone_trace.wgsl
+const BOUNCE_WORKGROUP_SIZE: u32 = 4;
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiBounce {
+	to_world: Mat4,
+	cascade: u32,
+}
+
+/// the parts of `GiBounceShaders` that depend on the format of the cascades
+pub struct GiBouncePipeline {
+	pub pipeline: ComputePipeline,
+	pub write_layout: BindGroupLayout,
+}
+
+pub struct GiBounceShaders {
+	/// one for every `GiVolumeFormat`, as the copies are written as storage textures of that format
+	pub pipelines: HashMap<GiVolumeFormat, GiBouncePipeline>,
+	/// the cascades to gather from
+	pub read_layout: BindGroupLayout,
+}
+
+impl FromWorld for GiBounceShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let trace_shaders = world.get_resource::<ConeTraceShaders>().unwrap();
+
+		// the same bindings as the cascades in the trace layout
+		let read_entries = (0..MAX_TRACED_CASCADES as u32)
+			.map(|binding| BindGroupLayoutEntry {
+				binding,
+				visibility: ShaderStage::COMPUTE,
+				ty: BindingType::Texture {
+					multisampled: false,
+					sample_type: TextureSampleType::Float { filterable: false },
+					view_dimension: TextureViewDimension::D3,
+				},
+				count: None,
+			})
+			.collect::<Vec<_>>();
+
+		let read_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &read_entries,
+			label: None,
+		});
+
+		let pipelines = GiVolumeFormat::ALL
+			.iter()
+			.map(|&format| {
+				let write_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+					entries: &[
+						BindGroupLayoutEntry {
+							binding: 7,
+							visibility: ShaderStage::COMPUTE,
+							ty: BindingType::Buffer {
+								ty: BufferBindingType::Uniform,
+								has_dynamic_offset: true,
+								min_binding_size: BufferSize::new(GpuGiBounce::std140_size_static() as u64),
+							},
+							count: None,
+						},
+						BindGroupLayoutEntry {
+							binding: 8,
+							visibility: ShaderStage::COMPUTE,
+							ty: BindingType::StorageTexture {
+								access: StorageTextureAccess::WriteOnly,
+								format: volume_texture_format(format),
+								view_dimension: TextureViewDimension::D3,
+							},
+							count: None,
+						},
+					],
+					label: None,
+				});
+
+				// in the same shader as the cone tracer, so it can trace the same way
+				let shader = volume_format_shader(include_str!("cone_trace.wgsl"), format);
+				let shader_module = render_device.create_shader_module(&shader);
+
+				let layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+					label: None,
+					push_constant_ranges: &[],
+					bind_group_layouts: &[
+						&trace_shaders.view_layout,
+						&gi_shaders.cascades_layout,
+						&read_layout,
+						&write_layout,
+					],
+				});
+
+				let pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+					label: None,
+					layout: Some(&layout),
+					entry_point: "bounce_light",
+					module: &shader_module,
+				});
+
+				(format, GiBouncePipeline { pipeline, write_layout })
+			})
+			.collect();
+
+		GiBounceShaders {
+			pipelines,
+			read_layout,
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct GiBounceMeta {
+	pub uniforms: DynamicUniformVec<GpuGiBounce>,
+	/// into `uniforms` for every cascade, empty when there's nothing to bounce
+	pub offsets: Vec<u32>,
+}
+
+/// a copy of every cascade of a view to write the bounced light to
+pub struct ViewGiBounceTargets {
+	pub cascades: Vec<CachedTexture>,
+}
+
+pub struct ViewGiBounceBindGroups {
+	pub read: BindGroup,
+	/// one for every cascade
+	pub write: Vec<BindGroup>,
+}
+
+pub fn prepare_gi_bounces(
+	mut commands: Commands,
+	mut texture_cache: ResMut<TextureCache>,
+	render_device: Res<RenderDevice>,
+	mut bounce_meta: ResMut<GiBounceMeta>,
+	views: Query<Entity, With<ExtractedView>>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	bounce_meta.offsets.clear();
+
+	// only dense cascades can bounce, and a single bounce is what tracing does already
+	let volume = match volume {
+		Some(volume) if volume.storage == GiStorage::Dense && volume.settings.bounces > 1 => volume,
+		_ => return,
+	};
+
+	// the tracer only reads this many, so the rest can't gather anything
+	let num_cascades = (volume.cascades as usize).min(MAX_TRACED_CASCADES);
+
+	bounce_meta.uniforms.reserve_and_clear(num_cascades, &render_device);
+
+	for cascade in 0..num_cascades {
+		let projection = cascade_projection(volume.extent, &volume.transform, cascade as u32);
+
+		let offset = bounce_meta.uniforms.push(GpuGiBounce {
+			to_world: projection.inverse(),
+			cascade: cascade as u32,
+		});
+
+		bounce_meta.offsets.push(offset);
+	}
+
+	bounce_meta.uniforms.write_to_staging_buffer(&render_device);
+
+	for entity in views.iter() {
+		let cascades = (0..num_cascades)
+			.map(|_| {
+				texture_cache.get(
+					&render_device,
+					TextureDescriptor {
+						size: Extent3d {
+							width: volume.resolution.x,
+							height: volume.resolution.y,
+							depth_or_array_layers: volume.resolution.z,
+						},
+						mip_level_count: 1,
+						sample_count: 1,
+						dimension: TextureDimension::D3,
+						format: volume_texture_format(volume.settings.format),
+						usage: TextureUsage::STORAGE | TextureUsage::COPY_SRC,
+						label: None,
+					},
+				)
+			})
+			.collect();
+
+		commands.entity(entity).insert(ViewGiBounceTargets { cascades });
+	}
+}
+
+pub fn queue_gi_bounce_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	bounce_shaders: Res<GiBounceShaders>,
+	bounce_meta: Res<GiBounceMeta>,
+	volume: Option<Res<ExtractedGiVolume>>,
+	views: Query<(Entity, &ViewGiVolumes, &ViewGiBounceTargets)>,
+) {
+	let (volume, uniforms) = match (volume, bounce_meta.uniforms.binding()) {
+		(Some(volume), Some(uniforms)) if !bounce_meta.offsets.is_empty() => (volume, uniforms),
+		_ => return,
+	};
+
+	let write_layout = &bounce_shaders.pipelines[&volume.settings.format].write_layout;
+
+	for (entity, view_volumes, targets) in views.iter() {
+
+		// every slot needs something bound, so the unused ones get the last cascade
+		let last_cascade = match view_volumes.cascades.len().checked_sub(1) {
+			Some(last_cascade) => last_cascade,
+			None => continue,
+		};
+
+		let read_entries = (0..MAX_TRACED_CASCADES)
+			.map(|index| BindGroupEntry {
+				binding: index as u32,
+				resource: BindingResource::TextureView(&view_volumes.cascades[index.min(last_cascade)].texture_view),
+			})
+			.collect::<Vec<_>>();
+
+		let read = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &read_entries,
+			label: None,
+			layout: &bounce_shaders.read_layout,
+		});
+
+		let write = targets
+			.cascades
+			.iter()
+			.map(|target| {
+				render_device.create_bind_group(&BindGroupDescriptor {
+					entries: &[
+						BindGroupEntry {
+							binding: 7,
+							resource: uniforms.clone(),
+						},
+						BindGroupEntry {
+							binding: 8,
+							resource: BindingResource::TextureView(&target.default_view),
+						},
+					],
+					label: None,
+					layout: write_layout,
+				})
+			})
+			.collect();
+
+		commands.entity(entity).insert(ViewGiBounceBindGroups { read, write });
+	}
+}
+
+/// bounces the light between the voxels, after voxelization and before tracing
+pub struct GiBouncePassNode {
+	view_query: QueryState<(
+		&'static ViewUniformOffset,
+		&'static ViewGiVolumes,
+		&'static ViewGiBounceTargets,
+		&'static ViewGiBounceBindGroups,
+		&'static ViewGiVolumeBindGroups,
+	)>,
+}
+
+impl GiBouncePassNode {
+	pub const IN_VIEW: &'static str = "view";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiBouncePassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(GiBouncePassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+
+		// with a single bounce the views don't have bounce targets, so there's nothing to do
+		let (view_uniform_offset, view_volumes, targets, bind_groups, volume_bind_groups) = match self.view_query.get_manual(world, view_entity) {
+			Ok(query) => query,
+			Err(_) => return Ok(()),
+		};
+
+		let volume = match world.get_resource::<ExtractedGiVolume>() {
+			Some(volume) => volume,
+			None => return Ok(()),
+		};
+
+		let bounce_shaders = world.get_resource::<GiBounceShaders>().unwrap();
+		let bounce_meta = world.get_resource::<GiBounceMeta>().unwrap();
+		let trace_meta = world.get_resource::<ConeTraceMeta>().unwrap();
+		let cascade_meta = world.get_resource::<GiCascadeMeta>().unwrap();
+
+		let (view_bind_group, cascades_bind_group) = match (&trace_meta.view_bind_group, &cascade_meta.bind_group) {
+			(Some(view), Some(cascades)) => (view, cascades),
+			_ => return Ok(()),
+		};
+
+		bounce_meta
+			.uniforms
+			.write_to_uniform_buffer(&mut render_context.command_encoder);
+
+		let pipeline = &bounce_shaders.pipelines[&volume.settings.format].pipeline;
+		let resolution = volume.resolution;
+		let workgroups = (resolution + BOUNCE_WORKGROUP_SIZE - 1) / BOUNCE_WORKGROUP_SIZE;
+
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let volume_pipelines = gi_shaders.volume_pipelines(volume.settings.format);
+
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Bounce);
+
+		for _ in 1..volume.settings.bounces {
+			{
+				let mut pass = render_context
+					.command_encoder
+					.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_bounce") });
+
+				pass.set_pipeline(pipeline);
+				pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+				pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+				pass.set_bind_group(2, &bind_groups.read, &[]);
+
+				for (write, offset) in bind_groups.write.iter().zip(bounce_meta.offsets.iter()) {
+					pass.set_bind_group(3, write, &[*offset]);
+					pass.dispatch(workgroups.x, workgroups.y, workgroups.z);
+				}
+			}
+
+			// and back, so the next bounce and the tracer see it
+			for (target, cascade) in targets.cascades.iter().zip(view_volumes.cascades.iter()) {
+				render_context.command_encoder.copy_texture_to_texture(
+					ImageCopyTexture {
+						texture: &target.texture,
+						mip_level: 0,
+						origin: Origin3d::ZERO,
+					},
+					ImageCopyTexture {
+						texture: &cascade.texture,
+						mip_level: 0,
+						origin: Origin3d::ZERO,
+					},
+					Extent3d {
+						width: resolution.x,
+						height: resolution.y,
+						depth_or_array_layers: resolution.z,
+					},
+				);
+			}
+
+			mipmap_cascades(&mut render_context.command_encoder, volume_pipelines, volume_bind_groups, resolution);
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Bounce);
+
+		Ok(())
+	}
+}
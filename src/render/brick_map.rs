@@ -0,0 +1,551 @@
+// HOW IT WORKS
+// a coarse grid of cells, each of which can point to a brick of 8x8x8 voxels in the brick pool
+// bricks are only claimed for cells that voxelization wrote fragments to (see voxel_fragments.rs)
+// and given back to the free list once they are empty, so moving objects don't leak bricks
+// see brick_map.wgsl for the passes, and brick_map.rs for the same thing on the cpu
+
+use crevice::std140::{AsStd140, Std140};
+
+use crate::brick_map::{BRICK_MIPS, BRICK_SIZE, MAX_BRICK_MAP_GRID};
+use crate::bundle::GiStorage;
+
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+use super::gi_volume::{texture_bytes, ExtractedGiVolume};
+use super::voxel_fragments::VoxelFragmentMeta;
+
+use bevy::ecs::prelude::*;
+use bevy::math::UVec3;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+};
+
+/// bricks along each axis of the brick pool
+pub const BRICK_POOL_SIZE: u32 = 16;
+
+/// max number of bricks that can be in use at the same time
+pub const MAX_BRICKS: u32 = BRICK_POOL_SIZE * BRICK_POOL_SIZE * BRICK_POOL_SIZE;
+
+const BRICK_POOL_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+
+// bytes in a texel of BRICK_POOL_FORMAT
+const BRICK_POOL_TEXEL_SIZE: u64 = 16;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuBrickMapInfo {
+	grid_size: u32,
+	pool_size: u32,
+}
+
+pub struct BrickMapShaders {
+	prepare_fragment_args_pipeline: ComputePipeline,
+	mark_cells_pipeline: ComputePipeline,
+	release_bricks_pipeline: ComputePipeline,
+	claim_bricks_pipeline: ComputePipeline,
+	clear_bricks_pipeline: ComputePipeline,
+	write_voxels_pipeline: ComputePipeline,
+	mipmap_bricks_pipeline: ComputePipeline,
+	/// from mipmap.wgsl
+	mipmap_coarse_pipeline: ComputePipeline,
+	brick_map_layout: BindGroupLayout,
+	info_layout: BindGroupLayout,
+	coarse_mip_layout: BindGroupLayout,
+}
+
+fn storage_buffer_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Storage { read_only },
+			has_dynamic_offset: false,
+			min_binding_size: None,
+		},
+		count: None,
+	}
+}
+
+fn storage_texture_entry(binding: u32) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::StorageTexture {
+			access: StorageTextureAccess::ReadWrite,
+			format: BRICK_POOL_FORMAT,
+			view_dimension: TextureViewDimension::D3,
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for BrickMapShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let shader = Shader::from_wgsl(include_str!("brick_map.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let brick_map_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				// fragments
+				storage_buffer_entry(0, true),
+				// fragment count
+				storage_buffer_entry(1, true),
+				// cells
+				storage_buffer_entry(2, false),
+				// free list
+				storage_buffer_entry(3, false),
+				// dispatch args
+				storage_buffer_entry(4, false),
+				// brick pool, one per mip
+				storage_texture_entry(5),
+				storage_texture_entry(6),
+				storage_texture_entry(7),
+				storage_texture_entry(8),
+				// coarse grid
+				storage_texture_entry(9),
+			],
+			label: None,
+		});
+
+		let info_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: false,
+						min_binding_size: BufferSize::new(GpuBrickMapInfo::std140_size_static() as u64),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let coarse_mip_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				storage_texture_entry(0),
+				storage_texture_entry(1),
+			],
+			label: None,
+		});
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&brick_map_layout, &info_layout],
+		});
+
+		let mipmap_shader = Shader::from_wgsl(include_str!("mipmap.wgsl"));
+		let mipmap_shader_module = render_device.create_shader_module(&mipmap_shader);
+
+		let mipmap_pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&coarse_mip_layout],
+		});
+
+		let pipeline = |entry_point| {
+			render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+				label: Some(entry_point),
+				layout: Some(&pipeline_layout),
+				entry_point,
+				module: &shader_module,
+			})
+		};
+
+		BrickMapShaders {
+			prepare_fragment_args_pipeline: pipeline("prepare_fragment_args"),
+			mark_cells_pipeline: pipeline("mark_cells"),
+			release_bricks_pipeline: pipeline("release_bricks"),
+			claim_bricks_pipeline: pipeline("claim_bricks"),
+			clear_bricks_pipeline: pipeline("clear_bricks"),
+			write_voxels_pipeline: pipeline("write_voxels"),
+			mipmap_bricks_pipeline: pipeline("mipmap_bricks"),
+			mipmap_coarse_pipeline: render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+				label: Some("mipmap_coarse"),
+				layout: Some(&mipmap_pipeline_layout),
+				entry_point: "mipmap",
+				module: &mipmap_shader_module,
+			}),
+			brick_map_layout,
+			info_layout,
+			coarse_mip_layout,
+		}
+	}
+}
+
+/// the brick map on the gpu, only allocated when the volume uses `GiStorage::BrickMap`
+///
+/// this is kept between frames, as the free list and cells need to stay around
+pub struct GpuBrickMap {
+	pub grid_size: u32,
+	pub cells: Buffer,
+	pub free_list: Buffer,
+	pub dispatch_args: Buffer,
+	pub info: Buffer,
+	pub brick_pool: Texture,
+	/// all mips, for sampling
+	pub brick_pool_view: TextureView,
+	/// one per mip, for writing
+	pub brick_pool_mip_views: Vec<TextureView>,
+	pub coarse: Texture,
+	pub coarse_view: TextureView,
+	pub coarse_mip_views: Vec<TextureView>,
+}
+
+fn mip_views(texture: &Texture, mip_count: u32) -> Vec<TextureView> {
+	(0..mip_count)
+		.map(|mip| {
+			texture.create_view(&TextureViewDescriptor {
+				label: None,
+				format: None,
+				dimension: Some(TextureViewDimension::D3),
+				aspect: TextureAspect::All,
+				base_mip_level: mip,
+				mip_level_count: std::num::NonZeroU32::new(1),
+				base_array_layer: 0,
+				array_layer_count: None,
+			})
+		})
+		.collect()
+}
+
+impl GpuBrickMap {
+	fn new(render_device: &RenderDevice, render_queue: &RenderQueue, grid_size: u32) -> Self {
+
+		// every cell starts out empty, which is 0, and new buffers are zeroed
+		let cells = render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: grid_size as u64 * grid_size as u64 * grid_size as u64 * 4,
+			usage: BufferUsage::STORAGE,
+			mapped_at_creation: false,
+		});
+
+		// all bricks start on the free list, reversed so the first claimed brick is 0
+		let free_list = render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: 4 + MAX_BRICKS as u64 * 4,
+			usage: BufferUsage::STORAGE | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		});
+
+		let free_list_data = std::iter::once(MAX_BRICKS)
+			.chain((0..MAX_BRICKS).rev())
+			.flat_map(|x| x.to_le_bytes())
+			.collect::<Vec<u8>>();
+
+		render_queue.write_buffer(&free_list, 0, &free_list_data);
+
+		let dispatch_args = render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: 4 * 3,
+			usage: BufferUsage::STORAGE | BufferUsage::INDIRECT,
+			mapped_at_creation: false,
+		});
+
+		let info = render_device.create_buffer_with_data(&BufferInitDescriptor {
+			label: None,
+			contents: GpuBrickMapInfo {
+				grid_size,
+				pool_size: BRICK_POOL_SIZE,
+			}
+			.as_std140()
+			.as_bytes(),
+			usage: BufferUsage::UNIFORM,
+		});
+
+		let brick_pool = render_device.create_texture(&TextureDescriptor {
+			size: Extent3d {
+				width: BRICK_POOL_SIZE * BRICK_SIZE,
+				height: BRICK_POOL_SIZE * BRICK_SIZE,
+				depth_or_array_layers: BRICK_POOL_SIZE * BRICK_SIZE,
+			},
+			mip_level_count: BRICK_MIPS,
+			sample_count: 1,
+			dimension: TextureDimension::D3,
+			format: BRICK_POOL_FORMAT,
+			usage: TextureUsage::SAMPLED | TextureUsage::STORAGE,
+			label: None,
+		});
+
+		let coarse_mip_count = 32 - grid_size.leading_zeros();
+		let coarse = render_device.create_texture(&TextureDescriptor {
+			size: Extent3d {
+				width: grid_size,
+				height: grid_size,
+				depth_or_array_layers: grid_size,
+			},
+			mip_level_count: coarse_mip_count,
+			sample_count: 1,
+			dimension: TextureDimension::D3,
+			format: BRICK_POOL_FORMAT,
+			usage: TextureUsage::SAMPLED | TextureUsage::STORAGE,
+			label: None,
+		});
+
+		Self {
+			grid_size,
+			cells,
+			free_list,
+			dispatch_args,
+			info,
+			brick_pool_view: brick_pool.create_view(&TextureViewDescriptor::default()),
+			brick_pool_mip_views: mip_views(&brick_pool, BRICK_MIPS),
+			brick_pool,
+			coarse_view: coarse.create_view(&TextureViewDescriptor::default()),
+			coarse_mip_views: mip_views(&coarse, coarse_mip_count),
+			coarse,
+		}
+	}
+
+	/// memory taken by the buffers, the brick pool and the coarse grid
+	pub fn memory_bytes(&self) -> u64 {
+		let cells = self.grid_size as u64 * self.grid_size as u64 * self.grid_size as u64 * 4;
+		let free_list = 4 + MAX_BRICKS as u64 * 4;
+		let coarse_mip_count = 32 - self.grid_size.leading_zeros();
+
+		cells
+			+ free_list
+			+ 4 * 3
+			+ GpuBrickMapInfo::std140_size_static() as u64
+			+ texture_bytes(UVec3::splat(BRICK_POOL_SIZE * BRICK_SIZE), BRICK_MIPS, BRICK_POOL_TEXEL_SIZE)
+			+ texture_bytes(UVec3::splat(self.grid_size), coarse_mip_count, BRICK_POOL_TEXEL_SIZE)
+	}
+}
+
+#[derive(Default)]
+pub struct BrickMapMeta {
+	pub brick_map: Option<GpuBrickMap>,
+	pub bind_group: Option<BindGroup>,
+	pub info_bind_group: Option<BindGroup>,
+	/// reads mip N of the coarse grid, and writes mip N + 1
+	pub coarse_mip_bind_groups: Vec<BindGroup>,
+}
+
+pub fn prepare_brick_map(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut brick_map_meta: ResMut<BrickMapMeta>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	// only needed when the volume actually uses it
+	let volume = match volume {
+		Some(volume) if volume.storage == GiStorage::BrickMap => volume,
+		_ => {
+			brick_map_meta.brick_map = None;
+			return;
+		}
+	};
+
+	// the resolution is in bricks for the brick map, and the grid is a cube that fits the longest axis
+	let grid_size = volume.resolution.max_element().clamp(1, MAX_BRICK_MAP_GRID);
+
+	// starts over when the size changes, otherwise the cells and free list are kept
+	let outdated = brick_map_meta
+		.brick_map
+		.as_ref()
+		.map(|brick_map| brick_map.grid_size != grid_size)
+		.unwrap_or(true);
+
+	if outdated {
+		brick_map_meta.brick_map = Some(GpuBrickMap::new(&render_device, &render_queue, grid_size));
+	}
+}
+
+pub fn queue_brick_map_bind_groups(
+	render_device: Res<RenderDevice>,
+	brick_map_shaders: Res<BrickMapShaders>,
+	fragment_meta: Res<VoxelFragmentMeta>,
+	mut brick_map_meta: ResMut<BrickMapMeta>,
+) {
+	let brick_map_meta = &mut *brick_map_meta;
+
+	let (brick_map, fragments) = match (&brick_map_meta.brick_map, &fragment_meta.fragments) {
+		(Some(brick_map), Some(fragments)) => (brick_map, fragments),
+		_ => return,
+	};
+
+	let mip_view = |mip: usize| BindingResource::TextureView(&brick_map.brick_pool_mip_views[mip]);
+
+	brick_map_meta.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: fragments.fragments.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 1,
+				resource: fragments.fragment_count.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 2,
+				resource: brick_map.cells.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 3,
+				resource: brick_map.free_list.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 4,
+				resource: brick_map.dispatch_args.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 5,
+				resource: mip_view(0),
+			},
+			BindGroupEntry {
+				binding: 6,
+				resource: mip_view(1),
+			},
+			BindGroupEntry {
+				binding: 7,
+				resource: mip_view(2),
+			},
+			BindGroupEntry {
+				binding: 8,
+				resource: mip_view(3),
+			},
+			BindGroupEntry {
+				binding: 9,
+				resource: BindingResource::TextureView(&brick_map.coarse_mip_views[0]),
+			},
+		],
+		label: None,
+		layout: &brick_map_shaders.brick_map_layout,
+	}));
+
+	brick_map_meta.info_bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: brick_map.info.as_entire_binding(),
+			},
+		],
+		label: None,
+		layout: &brick_map_shaders.info_layout,
+	}));
+
+	brick_map_meta.coarse_mip_bind_groups = brick_map
+		.coarse_mip_views
+		.windows(2)
+		.map(|mips| {
+			render_device.create_bind_group(&BindGroupDescriptor {
+				entries: &[
+					BindGroupEntry {
+						binding: 0,
+						resource: BindingResource::TextureView(&mips[0]),
+					},
+					BindGroupEntry {
+						binding: 1,
+						resource: BindingResource::TextureView(&mips[1]),
+					},
+				],
+				label: None,
+				layout: &brick_map_shaders.coarse_mip_layout,
+			})
+		})
+		.collect();
+}
+
+/// updates the brick map from the fragments written during voxelization
+pub struct BrickMapPassNode;
+
+impl BrickMapPassNode {
+	pub const IN_VIEW: &'static str = "view";
+}
+
+impl Node for BrickMapPassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(BrickMapPassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn run(&self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let brick_map_meta = world.get_resource::<BrickMapMeta>().unwrap();
+		let brick_map_shaders = world.get_resource::<BrickMapShaders>().unwrap();
+
+		let (brick_map, bind_group, info_bind_group) = match (
+			&brick_map_meta.brick_map,
+			&brick_map_meta.bind_group,
+			&brick_map_meta.info_bind_group,
+		) {
+			(Some(brick_map), Some(bind_group), Some(info_bind_group)) => (brick_map, bind_group, info_bind_group),
+			_ => return Ok(()),
+		};
+
+		let grid_size = brick_map.grid_size;
+		let cell_workgroups = (grid_size * grid_size * grid_size + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+
+		// updating the brick map is part of voxelization
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("update_brick_map") });
+
+			pass.set_bind_group(0, bind_group, &[]);
+			pass.set_bind_group(1, info_bind_group, &[]);
+
+			// the fragment count is only known on the gpu
+			pass.set_pipeline(&brick_map_shaders.prepare_fragment_args_pipeline);
+			pass.dispatch(1, 1, 1);
+
+			pass.set_pipeline(&brick_map_shaders.mark_cells_pipeline);
+			pass.dispatch_indirect(&brick_map.dispatch_args, 0);
+
+			// release before claiming, so the released bricks can be reused right away
+			pass.set_pipeline(&brick_map_shaders.release_bricks_pipeline);
+			pass.dispatch(cell_workgroups, 1, 1);
+
+			pass.set_pipeline(&brick_map_shaders.claim_bricks_pipeline);
+			pass.dispatch(cell_workgroups, 1, 1);
+
+			// one workgroup per cell
+			pass.set_pipeline(&brick_map_shaders.clear_bricks_pipeline);
+			pass.dispatch(grid_size, grid_size, grid_size);
+
+			pass.set_pipeline(&brick_map_shaders.write_voxels_pipeline);
+			pass.dispatch_indirect(&brick_map.dispatch_args, 0);
+		}
+
+		// a pass of its own, so it can be timed on its own
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Mip);
+
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("mipmap_brick_map") });
+
+			pass.set_bind_group(0, bind_group, &[]);
+			pass.set_bind_group(1, info_bind_group, &[]);
+
+			pass.set_pipeline(&brick_map_shaders.mipmap_bricks_pipeline);
+			pass.dispatch(grid_size, grid_size, grid_size);
+
+			// and the coarse grid, one mip at a time
+			pass.set_pipeline(&brick_map_shaders.mipmap_coarse_pipeline);
+			for (mip, coarse_mip_bind_group) in brick_map_meta.coarse_mip_bind_groups.iter().enumerate() {
+				let size = (grid_size >> (mip + 1)).max(1);
+				let workgroups = (size + 3) / 4;
+
+				pass.set_bind_group(0, coarse_mip_bind_group, &[]);
+				pass.dispatch(workgroups, workgroups, workgroups);
+			}
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Mip);
+
+		Ok(())
+	}
+}
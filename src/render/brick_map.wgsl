@@ -0,0 +1,329 @@
+// HOW IT WORKS
+// a coarse grid of cells, each of which can point to a 8x8x8 brick in the brick pool
+// every frame:
+//   mark the cells that have a fragment in them
+//   cells that aren't marked release their brick to the free list
+//   marked cells without a brick claim one from the free list
+//   clear the bricks, and write the fragments to them
+//   mipmap the bricks, and write their average to the coarse grid
+//   mipmap the coarse grid, with mipmap.wgsl
+// this is the same as brick_map.rs does on the cpu
+
+// same as VoxelFragment::pack
+struct Fragment {
+    position: u32;
+    albedo: u32;
+};
+
+[[block]]
+struct Fragments {
+    data: [[stride(8)]] array<Fragment>;
+};
+
+[[block]]
+struct FragmentCounter {
+    fragment_count: u32;
+};
+
+// brick + 1 for each cell, or EMPTY_CELL, with USED_FLAG set if there's a fragment in it this frame
+[[block]]
+struct Cells {
+    data: [[stride(4)]] array<atomic<u32>>;
+};
+
+// stack of bricks that can still be claimed
+[[block]]
+struct FreeList {
+    count: atomic<i32>;
+    bricks: [[stride(4)]] array<u32>;
+};
+
+// x, y and z for a dispatch_indirect
+[[block]]
+struct DispatchArgs {
+    data: [[stride(4)]] array<u32>;
+};
+
+[[block]]
+struct BrickMapInfo {
+    // cells along each axis
+    grid_size: u32;
+    // bricks along each axis in the brick pool
+    pool_size: u32;
+};
+
+[[group(0), binding(0)]]
+var<storage, read> fragments: Fragments;
+
+[[group(0), binding(1)]]
+var<storage, read> fragment_counter: FragmentCounter;
+
+[[group(0), binding(2)]]
+var<storage, read_write> cells: Cells;
+
+[[group(0), binding(3)]]
+var<storage, read_write> free_list: FreeList;
+
+[[group(0), binding(4)]]
+var<storage, read_write> dispatch_args: DispatchArgs;
+
+// the mips of the brick pool, bricks are 8 aligned so the first 4 mips never mix bricks
+[[group(0), binding(5)]]
+var brick_pool_0: texture_storage_3d<rgba32float, read_write>;
+
+[[group(0), binding(6)]]
+var brick_pool_1: texture_storage_3d<rgba32float, read_write>;
+
+[[group(0), binding(7)]]
+var brick_pool_2: texture_storage_3d<rgba32float, read_write>;
+
+[[group(0), binding(8)]]
+var brick_pool_3: texture_storage_3d<rgba32float, read_write>;
+
+// one texel per cell, the average of it's brick
+[[group(0), binding(9)]]
+var coarse: texture_storage_3d<rgba32float, read_write>;
+
+[[group(1), binding(0)]]
+var<uniform> info: BrickMapInfo;
+
+let EMPTY_CELL: u32 = 0u;
+let USED_FLAG: u32 = 0x80000000u;
+let BRICK_SIZE: u32 = 8u;
+let WORKGROUP_SIZE: u32 = 64u;
+
+fn fragment_position(fragment: Fragment) -> vec3<u32> {
+    return vec3<u32>(
+        fragment.position & 0x3ffu,
+        (fragment.position >> 10u) & 0x3ffu,
+        (fragment.position >> 20u) & 0x3ffu,
+    );
+}
+
+// voxelization keeps counting once the list is full, so this can't be larger than the list
+fn fragment_count() -> u32 {
+    return min(fragment_counter.fragment_count, arrayLength(&fragments.data));
+}
+
+fn cell_count() -> u32 {
+    return info.grid_size * info.grid_size * info.grid_size;
+}
+
+fn cell_index(cell: vec3<u32>) -> u32 {
+    return cell.x + cell.y * info.grid_size + cell.z * info.grid_size * info.grid_size;
+}
+
+// the brick of a cell, or EMPTY_CELL
+fn cell_brick(cell: u32) -> u32 {
+    return atomicLoad(&cells.data[cell]) & ~USED_FLAG;
+}
+
+fn brick_origin(brick: u32) -> vec3<u32> {
+    return vec3<u32>(
+        brick % info.pool_size,
+        (brick / info.pool_size) % info.pool_size,
+        brick / (info.pool_size * info.pool_size),
+    ) * BRICK_SIZE;
+}
+
+// sets up the dispatch for the fragments
+[[stage(compute), workgroup_size(1)]]
+fn prepare_fragment_args() {
+    dispatch_args.data[0] = (fragment_count() + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+    dispatch_args.data[1] = 1u;
+    dispatch_args.data[2] = 1u;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn mark_cells([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= fragment_count()) {
+        return;
+    }
+
+    let position = fragment_position(fragments.data[id.x]);
+    if (any(position >= vec3<u32>(info.grid_size * BRICK_SIZE))) {
+        return;
+    }
+
+    atomicOr(&cells.data[cell_index(position / BRICK_SIZE)], USED_FLAG);
+}
+
+// this runs before claiming, so the released bricks can be claimed again
+[[stage(compute), workgroup_size(64)]]
+fn release_bricks([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= cell_count()) {
+        return;
+    }
+
+    let cell = atomicLoad(&cells.data[id.x]);
+    if ((cell & USED_FLAG) != 0u || cell == EMPTY_CELL) {
+        return;
+    }
+
+    let slot = atomicAdd(&free_list.count, 1);
+    free_list.bricks[slot] = cell - 1u;
+
+    atomicStore(&cells.data[id.x], EMPTY_CELL);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn claim_bricks([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= cell_count()) {
+        return;
+    }
+
+    let cell = atomicLoad(&cells.data[id.x]);
+    if ((cell & USED_FLAG) == 0u || (cell & ~USED_FLAG) != EMPTY_CELL) {
+        return;
+    }
+
+    let slot = atomicSub(&free_list.count, 1) - 1;
+
+    // out of bricks, so this cell stays empty
+    if (slot < 0) {
+        atomicAdd(&free_list.count, 1);
+        atomicStore(&cells.data[id.x], EMPTY_CELL);
+        return;
+    }
+
+    atomicStore(&cells.data[id.x], (free_list.bricks[slot] + 1u) | USED_FLAG);
+}
+
+// one workgroup per cell, every thread clears 2x2x2 voxels
+[[stage(compute), workgroup_size(4, 4, 4)]]
+fn clear_bricks(
+    [[builtin(workgroup_id)]] cell: vec3<u32>,
+    [[builtin(local_invocation_id)]] local: vec3<u32>,
+) {
+    let index = cell_index(cell);
+    let brick = cell_brick(index);
+
+    // the flag isn't needed anymore after this
+    if (all(local == vec3<u32>(0u))) {
+        atomicAnd(&cells.data[index], ~USED_FLAG);
+    }
+
+    if (brick == EMPTY_CELL) {
+        return;
+    }
+
+    let origin = brick_origin(brick - 1u) + local * 2u;
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        textureStore(brick_pool_0, vec3<i32>(origin + offset), vec4<f32>(0.0));
+    }
+}
+
+// when multiple fragments end up in the same voxel, one of them is kept
+[[stage(compute), workgroup_size(64)]]
+fn write_voxels([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= fragment_count()) {
+        return;
+    }
+
+    let fragment = fragments.data[id.x];
+    let position = fragment_position(fragment);
+    if (any(position >= vec3<u32>(info.grid_size * BRICK_SIZE))) {
+        return;
+    }
+
+    let brick = cell_brick(cell_index(position / BRICK_SIZE));
+    if (brick == EMPTY_CELL) {
+        return;
+    }
+
+    // premultiplied, so mipmapping can just average
+    let albedo = unpack4x8unorm(fragment.albedo);
+    let texel = brick_origin(brick - 1u) + position % vec3<u32>(BRICK_SIZE);
+
+    textureStore(brick_pool_0, vec3<i32>(texel), vec4<f32>(albedo.rgb * albedo.a, albedo.a));
+}
+
+fn average_0(texel: vec3<u32>) -> vec4<f32> {
+    var sum = vec4<f32>(0.0);
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        sum = sum + textureLoad(brick_pool_0, vec3<i32>(texel * 2u + offset));
+    }
+    return sum / 8.0;
+}
+
+fn average_1(texel: vec3<u32>) -> vec4<f32> {
+    var sum = vec4<f32>(0.0);
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        sum = sum + textureLoad(brick_pool_1, vec3<i32>(texel * 2u + offset));
+    }
+    return sum / 8.0;
+}
+
+fn average_2(texel: vec3<u32>) -> vec4<f32> {
+    var sum = vec4<f32>(0.0);
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        sum = sum + textureLoad(brick_pool_2, vec3<i32>(texel * 2u + offset));
+    }
+    return sum / 8.0;
+}
+
+// one workgroup per cell, mips 1 to 3 of the brick, and the texel in the coarse grid
+[[stage(compute), workgroup_size(4, 4, 4)]]
+fn mipmap_bricks(
+    [[builtin(workgroup_id)]] cell: vec3<u32>,
+    [[builtin(local_invocation_id)]] local: vec3<u32>,
+) {
+    let brick = cell_brick(cell_index(cell));
+
+    if (brick == EMPTY_CELL) {
+        if (all(local == vec3<u32>(0u))) {
+            textureStore(coarse, vec3<i32>(cell), vec4<f32>(0.0));
+        }
+        return;
+    }
+
+    let origin = brick_origin(brick - 1u);
+
+    textureStore(brick_pool_1, vec3<i32>(origin / 2u + local), average_0(origin / 2u + local));
+    storageBarrier();
+
+    if (all(local < vec3<u32>(2u))) {
+        textureStore(brick_pool_2, vec3<i32>(origin / 4u + local), average_1(origin / 4u + local));
+    }
+    storageBarrier();
+
+    if (all(local == vec3<u32>(0u))) {
+        let average = average_2(origin / 8u);
+        textureStore(brick_pool_3, vec3<i32>(origin / 8u), average);
+        textureStore(coarse, vec3<i32>(cell), average);
+    }
+}
+
+// for cone tracing
+// gets the voxel at position (0 to 1 over the whole brick map), where mip 0 is the finest level, up to mip 3
+// the first 3 mips are read through the cells, mip 3 is the coarse grid
+// the mips above that are the mips of the coarse grid, which can be sampled directly
+fn sample_brick_map(position: vec3<f32>, mip: u32) -> vec4<f32> {
+    if (any(position < vec3<f32>(0.0)) || any(position >= vec3<f32>(1.0))) {
+        return vec4<f32>(0.0);
+    }
+
+    let voxel = vec3<u32>(position * f32(info.grid_size * BRICK_SIZE));
+
+    if (mip == 3u) {
+        return textureLoad(coarse, vec3<i32>(voxel / BRICK_SIZE));
+    }
+
+    let brick = cell_brick(cell_index(voxel / BRICK_SIZE));
+    if (brick == EMPTY_CELL) {
+        return vec4<f32>(0.0);
+    }
+
+    let texel = (brick_origin(brick - 1u) + voxel % vec3<u32>(BRICK_SIZE)) >> vec3<u32>(mip);
+
+    if (mip == 0u) {
+        return textureLoad(brick_pool_0, vec3<i32>(texel));
+    } elseif (mip == 1u) {
+        return textureLoad(brick_pool_1, vec3<i32>(texel));
+    }
+    return textureLoad(brick_pool_2, vec3<i32>(texel));
+}
@@ -0,0 +1,208 @@
+// HOW IT WORKS
+// adds the indirect diffuse and specular light to the final color, after the main pass
+// the cone tracer and the probes both write the light to the same targets, so this doesn't care which one it came from
+// the diffuse light is multiplied with the albedo the gbuffer pass wrote and the ambient occlusion, and blended on top of what the main pass lit
+// the specular light is tinted by the albedo as much as the material is metallic
+// pixels without a gi mesh have no albedo, so nothing is added there
+
+use super::cone_trace::{ViewGiTraceBindGroup, ViewGiTraceTargets};
+
+use bevy::ecs::prelude::*;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice},
+	shader::Shader,
+	texture::BevyDefault,
+};
+
+pub struct GiCompositeShaders {
+	pipeline: RenderPipeline,
+	composite_layout: BindGroupLayout,
+}
+
+fn texture_entry(binding: u32) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::FRAGMENT,
+		ty: BindingType::Texture {
+			multisampled: false,
+			sample_type: TextureSampleType::Float { filterable: false },
+			view_dimension: TextureViewDimension::D2,
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for GiCompositeShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let shader = Shader::from_wgsl(include_str!("composite.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let composite_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				// indirect diffuse
+				texture_entry(0),
+				// albedo
+				texture_entry(1),
+				// indirect specular
+				texture_entry(2),
+			],
+			label: None,
+		});
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&composite_layout],
+		});
+
+		let pipeline = render_device.create_render_pipeline(&RenderPipelineDescriptor {
+			label: None,
+			vertex: VertexState {
+				buffers: &[],
+				module: &shader_module,
+				entry_point: "vertex",
+			},
+			fragment: Some(FragmentState {
+				module: &shader_module,
+				entry_point: "fragment",
+				// added to the color, and the alpha is kept
+				targets: &[ColorTargetState {
+					format: TextureFormat::bevy_default(),
+					blend: Some(BlendState {
+						color: BlendComponent {
+							src_factor: BlendFactor::One,
+							dst_factor: BlendFactor::One,
+							operation: BlendOperation::Add,
+						},
+						alpha: BlendComponent {
+							src_factor: BlendFactor::Zero,
+							dst_factor: BlendFactor::One,
+							operation: BlendOperation::Add,
+						},
+					}),
+					write_mask: ColorWrite::ALL,
+				}],
+			}),
+			depth_stencil: None,
+			layout: Some(&pipeline_layout),
+			multisample: MultisampleState::default(),
+			primitive: PrimitiveState {
+				topology: PrimitiveTopology::TriangleList,
+				strip_index_format: None,
+				front_face: FrontFace::Ccw,
+				cull_mode: None,
+				polygon_mode: PolygonMode::Fill,
+				clamp_depth: false,
+				conservative: false,
+			},
+		});
+
+		GiCompositeShaders {
+			pipeline,
+			composite_layout,
+		}
+	}
+}
+
+pub struct ViewGiCompositeBindGroup {
+	pub bind_group: BindGroup,
+}
+
+pub fn queue_gi_composite_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	composite_shaders: Res<GiCompositeShaders>,
+	views: Query<(Entity, &ViewGiTraceTargets)>,
+) {
+	for (entity, targets) in views.iter() {
+		let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: BindingResource::TextureView(&targets.indirect_diffuse),
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: BindingResource::TextureView(&targets.gbuffer_albedo),
+				},
+				BindGroupEntry {
+					binding: 2,
+					resource: BindingResource::TextureView(&targets.indirect_specular),
+				},
+			],
+			label: None,
+			layout: &composite_shaders.composite_layout,
+		});
+
+		commands.entity(entity).insert(ViewGiCompositeBindGroup { bind_group });
+	}
+}
+
+/// adds the indirect light to the final color
+pub struct GiCompositeNode {
+	view_query: QueryState<&'static ViewGiCompositeBindGroup, With<ViewGiTraceBindGroup>>,
+}
+
+impl GiCompositeNode {
+	pub const IN_VIEW: &'static str = "view";
+	pub const IN_COLOR_ATTACHMENT: &'static str = "color_attachment";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiCompositeNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![
+			SlotInfo::new(GiCompositeNode::IN_VIEW, SlotType::Entity),
+			SlotInfo::new(GiCompositeNode::IN_COLOR_ATTACHMENT, SlotType::TextureView),
+		]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+		let color_attachment = graph.get_input_texture(Self::IN_COLOR_ATTACHMENT)?;
+
+		// without the bind groups of the cone tracer, the gbuffer wasn't drawn either
+		let bind_group = match self.view_query.get_manual(world, view_entity) {
+			Ok(bind_group) => bind_group,
+			Err(_) => return Ok(()),
+		};
+
+		let composite_shaders = world.get_resource::<GiCompositeShaders>().unwrap();
+
+		let mut pass = render_context
+			.command_encoder
+			.begin_render_pass(&RenderPassDescriptor {
+				label: Some("gi_composite"),
+				color_attachments: &[RenderPassColorAttachment {
+					view: color_attachment,
+					resolve_target: None,
+					ops: Operations {
+						load: LoadOp::Load,
+						store: true,
+					},
+				}],
+				depth_stencil_attachment: None,
+			});
+
+		pass.set_pipeline(&composite_shaders.pipeline);
+		pass.set_bind_group(0, &bind_group.bind_group, &[]);
+		pass.draw(0..3, 0..1);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,45 @@
+// adds the indirect light to the lit image, see composite.rs
+
+[[group(0), binding(0)]]
+var indirect_diffuse: texture_2d<f32>;
+
+[[group(0), binding(1)]]
+var gbuffer_albedo: texture_2d<f32>;
+
+[[group(0), binding(2)]]
+var indirect_specular: texture_2d<f32>;
+
+// same as the reflectance of 0.5 pbr defaults to
+let DIELECTRIC_SPECULAR: f32 = 0.04;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+};
+
+// a single triangle that covers the screen, same as in debug_output.wgsl
+[[stage(vertex)]]
+fn vertex([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+// blended additively, so pixels without a gi mesh have no albedo and add nothing
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let pixel = vec2<i32>(in.clip_position.xy);
+
+    // metallic in w
+    let albedo = textureLoad(gbuffer_albedo, pixel, 0);
+    // ambient occlusion in w, already scaled by the ao strength
+    let diffuse = textureLoad(indirect_diffuse, pixel, 0);
+    let specular = textureLoad(indirect_specular, pixel, 0).rgb;
+
+    // metals have no diffuse, and tint what they reflect
+    let diffuse_color = albedo.rgb * (1.0 - albedo.w);
+    let specular_color = mix(vec3<f32>(DIELECTRIC_SPECULAR), albedo.rgb, albedo.w);
+
+    return vec4<f32>(diffuse_color * diffuse.rgb * diffuse.a + specular_color * specular, 0.0);
+}
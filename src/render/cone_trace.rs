@@ -0,0 +1,707 @@
+// HOW IT WORKS
+// render the positions, normals, motion and albedo of all gi meshes to a gbuffer, from the view
+// then trace cones from every pixel of it through the cascades, see cone_trace.wgsl
+// a sparse storage is traced the same way, as a single cascade with it's bricks bound in place of the textures
+// this happens before the main pass, so the main pass can use the result
+// when the volume has a probe grid, the probes are updated and read instead of tracing from every pixel
+// at a lower trace resolution, only the gbuffer is rendered here, and low_res.rs does the tracing
+// either way, what's traced is then blended with the history, see temporal.rs
+// and after the main pass, composite.rs adds it to the lit image
+
+use crevice::std140::AsStd140;
+
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+use super::gi_meshes::{ExtractedGiMeshes, GiMeshMeta, GiMeshShaders, GpuGiMaterial};
+use super::gi_volume::{ExtractedGiVolume, GiCascadeMeta, GiShaders, ViewGiVolumes};
+use super::low_res::ViewGiLowResTargets;
+use super::probes::{GiProbeMeta, GiProbeShaders, PROBE_WORKGROUP_SIZE};
+use super::brick_map::BrickMapMeta;
+use super::sparse_octree::SparseOctreeMeta;
+use super::temporal::{temporal_uniform_entry, GiTemporalMeta, GiTemporalShaders, MOTION_FORMAT};
+
+use bevy::ecs::prelude::*;
+use bevy::render2::{
+	color::Color,
+	mesh::Mesh,
+	render_asset::RenderAssets,
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+	texture::TextureCache,
+	view::{ExtractedView, ViewMeta, ViewUniformOffset},
+};
+use bevy::utils::HashSet;
+
+/// max number of cascades the cone tracer reads from, cascades after that are ignored
+pub const MAX_TRACED_CASCADES: usize = 8;
+
+const GBUFFER_POSITION_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+const GBUFFER_NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const GBUFFER_ALBEDO_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+const GBUFFER_DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+pub const TRACE_OUTPUT_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+// same as in cone_trace.wgsl and temporal.wgsl
+pub const TRACE_WORKGROUP_SIZE: u32 = 8;
+
+pub struct ConeTraceShaders {
+	gbuffer_pipeline: RenderPipeline,
+	trace_pipeline: ComputePipeline,
+	pub view_layout: BindGroupLayout,
+	pub trace_layout: BindGroupLayout,
+	/// the temporal uniform and the material of the mesh, for the gbuffer pass
+	pub temporal_layout: BindGroupLayout,
+}
+
+fn texture_entry(binding: u32, view_dimension: TextureViewDimension) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Texture {
+			multisampled: false,
+			sample_type: TextureSampleType::Float { filterable: false },
+			view_dimension,
+		},
+		count: None,
+	}
+}
+
+fn output_entry(binding: u32) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::StorageTexture {
+			access: StorageTextureAccess::WriteOnly,
+			format: TRACE_OUTPUT_FORMAT,
+			view_dimension: TextureViewDimension::D2,
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for ConeTraceShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let mesh_shaders = world.get_resource::<GiMeshShaders>().unwrap();
+
+		let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::VERTEX | ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						// same as in GiShaders
+						min_binding_size: BufferSize::new(80),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		// the cascades, then the gbuffer, then the outputs, then the temporal uniform
+		let mut trace_entries = (0..MAX_TRACED_CASCADES as u32)
+			.map(|binding| texture_entry(binding, TextureViewDimension::D3))
+			.collect::<Vec<_>>();
+
+		let first_gbuffer = MAX_TRACED_CASCADES as u32;
+		trace_entries.push(texture_entry(first_gbuffer, TextureViewDimension::D2));
+		trace_entries.push(texture_entry(first_gbuffer + 1, TextureViewDimension::D2));
+		trace_entries.push(output_entry(first_gbuffer + 2));
+		trace_entries.push(output_entry(first_gbuffer + 3));
+		trace_entries.push(output_entry(first_gbuffer + 4));
+		trace_entries.push(temporal_uniform_entry(first_gbuffer + 5, ShaderStage::COMPUTE));
+
+		let trace_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &trace_entries,
+			label: None,
+		});
+
+		let temporal_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				temporal_uniform_entry(0, ShaderStage::VERTEX),
+				// picked with the same dynamic offset as for voxelization
+				BindGroupLayoutEntry {
+					binding: 1,
+					visibility: ShaderStage::FRAGMENT,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						min_binding_size: BufferSize::new(GpuGiMaterial::std140_size_static() as u64),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let gbuffer_shader = Shader::from_wgsl(include_str!("gbuffer.wgsl"));
+		let gbuffer_shader_module = render_device.create_shader_module(&gbuffer_shader);
+
+		let gbuffer_pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			// the transform of the mesh, then the one from last frame
+			bind_group_layouts: &[
+				&view_layout,
+				&mesh_shaders.transform_layout,
+				&mesh_shaders.transform_layout,
+				&temporal_layout,
+			],
+		});
+
+		let gbuffer_pipeline = render_device.create_render_pipeline(&RenderPipelineDescriptor {
+			label: None,
+			vertex: VertexState {
+				buffers: &[VertexBufferLayout {
+					array_stride: 32,
+					step_mode: InputStepMode::Vertex,
+					attributes: &[
+						// Position (GOTCHA! Vertex_Position isn't first in the buffer due to how Mesh sorts attributes (alphabetically))
+						VertexAttribute {
+							format: VertexFormat::Float32x3,
+							offset: 12,
+							shader_location: 0,
+						},
+						// Normal
+						VertexAttribute {
+							format: VertexFormat::Float32x3,
+							offset: 0,
+							shader_location: 1,
+						},
+					],
+				}],
+				module: &gbuffer_shader_module,
+				entry_point: "vertex",
+			},
+			fragment: Some(FragmentState {
+				module: &gbuffer_shader_module,
+				entry_point: "fragment",
+				targets: &[
+					ColorTargetState {
+						format: GBUFFER_POSITION_FORMAT,
+						blend: None,
+						write_mask: ColorWrite::ALL,
+					},
+					ColorTargetState {
+						format: GBUFFER_NORMAL_FORMAT,
+						blend: None,
+						write_mask: ColorWrite::ALL,
+					},
+					ColorTargetState {
+						format: MOTION_FORMAT,
+						blend: None,
+						write_mask: ColorWrite::ALL,
+					},
+					ColorTargetState {
+						format: GBUFFER_ALBEDO_FORMAT,
+						blend: None,
+						write_mask: ColorWrite::ALL,
+					},
+				],
+			}),
+			depth_stencil: Some(DepthStencilState {
+				format: GBUFFER_DEPTH_FORMAT,
+				depth_write_enabled: true,
+				depth_compare: CompareFunction::Less,
+				stencil: StencilState::default(),
+				bias: DepthBiasState::default(),
+			}),
+			layout: Some(&gbuffer_pipeline_layout),
+			multisample: MultisampleState::default(),
+			primitive: PrimitiveState {
+				topology: PrimitiveTopology::TriangleList,
+				strip_index_format: None,
+				front_face: FrontFace::Ccw,
+				cull_mode: Some(Face::Back),
+				polygon_mode: PolygonMode::Fill,
+				clamp_depth: false,
+				conservative: false,
+			},
+		});
+
+		let trace_shader = Shader::from_wgsl(include_str!("cone_trace.wgsl"));
+		let trace_shader_module = render_device.create_shader_module(&trace_shader);
+
+		let trace_pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&view_layout, &gi_shaders.cascades_layout, &trace_layout],
+		});
+
+		let trace_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+			label: None,
+			layout: Some(&trace_pipeline_layout),
+			entry_point: "trace",
+			module: &trace_shader_module,
+		});
+
+		ConeTraceShaders {
+			gbuffer_pipeline,
+			trace_pipeline,
+			view_layout,
+			trace_layout,
+			temporal_layout,
+		}
+	}
+}
+
+/// where the cone tracer reads from and writes to, for a single view
+pub struct ViewGiTraceTargets {
+	pub width: u32,
+	pub height: u32,
+	pub gbuffer_position: TextureView,
+	pub gbuffer_normal: TextureView,
+	pub gbuffer_depth: TextureView,
+	/// see `temporal::MOTION_FORMAT`
+	pub gbuffer_motion: TextureView,
+	/// base color of the material, so the indirect light can be composited, see composite.rs
+	pub gbuffer_albedo: TextureView,
+	/// what was traced this frame, before it's blended with the history
+	pub traced_diffuse: TextureView,
+	pub traced_specular: TextureView,
+	/// rgb is the indirect diffuse light, a the ambient occlusion, where 1 is not occluded
+	///
+	/// this and the specular are the history of this frame, so they're only valid until the next frame
+	pub indirect_diffuse: TextureView,
+	pub indirect_specular: TextureView,
+	/// distance to the camera of every pixel, for the history of next frame
+	pub depth: TextureView,
+	/// the same as the three above, from last frame
+	pub history_diffuse: TextureView,
+	pub history_specular: TextureView,
+	pub history_depth: TextureView,
+	pub temporal_uniform: Buffer,
+	/// r is the cascade the pixel is in, or -1 when it's outside the volume, g the number of steps taken by all cones
+	pub trace_info: TextureView,
+}
+
+pub struct ViewGiTraceBindGroup {
+	pub bind_group: BindGroup,
+	/// the temporal uniform and the materials for the gbuffer pass
+	pub temporal_bind_group: BindGroup,
+	pub resolve_bind_group: BindGroup,
+}
+
+#[derive(Default)]
+pub struct ConeTraceMeta {
+	pub view_bind_group: Option<BindGroup>,
+}
+
+pub fn prepare_cone_trace_targets(
+	mut commands: Commands,
+	mut texture_cache: ResMut<TextureCache>,
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut temporal_meta: ResMut<GiTemporalMeta>,
+	views: Query<(Entity, &ExtractedView)>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	let volume = match volume {
+		Some(volume) => volume,
+		None => {
+			temporal_meta.views.clear();
+			return;
+		}
+	};
+
+	let mut seen = HashSet::default();
+
+	for (entity, view) in views.iter() {
+		let mut target = |format: TextureFormat, usage: TextureUsage| {
+			texture_cache
+				.get(
+					&render_device,
+					TextureDescriptor {
+						size: Extent3d {
+							width: view.width,
+							height: view.height,
+							depth_or_array_layers: 1,
+						},
+						mip_level_count: 1,
+						sample_count: 1,
+						dimension: TextureDimension::D2,
+						format,
+						usage,
+						label: None,
+					},
+				)
+				.default_view
+		};
+
+		let gbuffer_usage = TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED;
+		let output_usage = TextureUsage::STORAGE | TextureUsage::SAMPLED;
+
+		let gbuffer_position = target(GBUFFER_POSITION_FORMAT, gbuffer_usage);
+		let gbuffer_normal = target(GBUFFER_NORMAL_FORMAT, gbuffer_usage);
+		let gbuffer_depth = target(GBUFFER_DEPTH_FORMAT, TextureUsage::RENDER_ATTACHMENT);
+		let gbuffer_motion = target(MOTION_FORMAT, gbuffer_usage);
+		let gbuffer_albedo = target(GBUFFER_ALBEDO_FORMAT, gbuffer_usage);
+		let traced_diffuse = target(TRACE_OUTPUT_FORMAT, output_usage);
+		let traced_specular = target(TRACE_OUTPUT_FORMAT, output_usage);
+		let trace_info = target(TRACE_OUTPUT_FORMAT, output_usage);
+
+		// the history is kept between frames, so it's not from the texture cache
+		let history = temporal_meta.prepare_view(entity, view, volume.temporal_blend, &render_device, &render_queue);
+		seen.insert(entity);
+
+		commands.entity(entity).insert(ViewGiTraceTargets {
+			width: view.width,
+			height: view.height,
+			gbuffer_position,
+			gbuffer_normal,
+			gbuffer_depth,
+			gbuffer_motion,
+			gbuffer_albedo,
+			traced_diffuse,
+			traced_specular,
+			indirect_diffuse: history.current().diffuse.clone(),
+			indirect_specular: history.current().specular.clone(),
+			depth: history.current().depth.clone(),
+			history_diffuse: history.previous().diffuse.clone(),
+			history_specular: history.previous().specular.clone(),
+			history_depth: history.previous().depth.clone(),
+			temporal_uniform: history.uniform.clone(),
+			trace_info,
+		});
+	}
+
+	temporal_meta.retain_views(&seen);
+}
+
+pub fn queue_cone_trace_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	trace_shaders: Res<ConeTraceShaders>,
+	temporal_shaders: Res<GiTemporalShaders>,
+	view_meta: Res<ViewMeta>,
+	mut trace_meta: ResMut<ConeTraceMeta>,
+	octree_meta: Res<SparseOctreeMeta>,
+	brick_map_meta: Res<BrickMapMeta>,
+	mesh_meta: Res<GiMeshMeta>,
+	views: Query<(Entity, &ViewGiVolumes, &ViewGiTraceTargets)>,
+) {
+	let (view_binding, material_binding) = match (view_meta.uniforms.binding(), mesh_meta.material_uniforms.binding()) {
+		(Some(view_binding), Some(material_binding)) => (view_binding, material_binding),
+		_ => return,
+	};
+
+	trace_meta.view_bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: view_binding,
+			},
+		],
+		label: None,
+		layout: &trace_shaders.view_layout,
+	}));
+
+	for (entity, view_volumes, targets) in views.iter() {
+
+		// the cascades, or the bricks of a sparse storage, which has no cascades of it's own
+		let volume_views = match (&octree_meta.octree, &brick_map_meta.brick_map) {
+			(Some(octree), _) if view_volumes.cascades.is_empty() => vec![&octree.brick_pool_view],
+			(_, Some(brick_map)) if view_volumes.cascades.is_empty() => vec![&brick_map.brick_pool_view, &brick_map.coarse_view],
+			_ => view_volumes.cascades.iter().map(|cascade| &cascade.texture_view).collect(),
+		};
+
+		// every slot needs something bound, so the unused ones get the last one
+		let last_view = match volume_views.len().checked_sub(1) {
+			Some(last_view) => last_view,
+			None => continue,
+		};
+
+		let mut entries = (0..MAX_TRACED_CASCADES)
+			.map(|index| BindGroupEntry {
+				binding: index as u32,
+				resource: BindingResource::TextureView(volume_views[index.min(last_view)]),
+			})
+			.collect::<Vec<_>>();
+
+		// the tracer writes to the traced targets, the resolve then blends them with the history
+		let views = [
+			&targets.gbuffer_position,
+			&targets.gbuffer_normal,
+			&targets.traced_diffuse,
+			&targets.traced_specular,
+			&targets.trace_info,
+		];
+
+		for (index, view) in views.iter().enumerate() {
+			entries.push(BindGroupEntry {
+				binding: (MAX_TRACED_CASCADES + index) as u32,
+				resource: BindingResource::TextureView(view),
+			});
+		}
+
+		entries.push(BindGroupEntry {
+			binding: (MAX_TRACED_CASCADES + views.len()) as u32,
+			resource: targets.temporal_uniform.as_entire_binding(),
+		});
+
+		let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &entries,
+			label: None,
+			layout: &trace_shaders.trace_layout,
+		});
+
+		let temporal_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: targets.temporal_uniform.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: material_binding.clone(),
+				},
+			],
+			label: None,
+			layout: &trace_shaders.temporal_layout,
+		});
+
+		let mut resolve_entries = vec![BindGroupEntry {
+			binding: 0,
+			resource: targets.temporal_uniform.as_entire_binding(),
+		}];
+
+		// same order as in temporal.wgsl
+		let resolve_views = [
+			&targets.gbuffer_position,
+			&targets.gbuffer_motion,
+			&targets.traced_diffuse,
+			&targets.traced_specular,
+			&targets.history_diffuse,
+			&targets.history_specular,
+			&targets.history_depth,
+		];
+
+		for (index, view) in resolve_views.iter().enumerate() {
+			resolve_entries.push(BindGroupEntry {
+				binding: index as u32 + 1,
+				resource: BindingResource::TextureView(view),
+			});
+		}
+
+		resolve_entries.push(BindGroupEntry {
+			binding: 8,
+			resource: BindingResource::Sampler(&temporal_shaders.sampler),
+		});
+
+		let outputs = [&targets.indirect_diffuse, &targets.indirect_specular, &targets.depth];
+
+		for (index, view) in outputs.iter().enumerate() {
+			resolve_entries.push(BindGroupEntry {
+				binding: index as u32 + 9,
+				resource: BindingResource::TextureView(view),
+			});
+		}
+
+		let resolve_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &resolve_entries,
+			label: None,
+			layout: &temporal_shaders.resolve_layout,
+		});
+
+		commands.entity(entity).insert(ViewGiTraceBindGroup {
+			bind_group,
+			temporal_bind_group,
+			resolve_bind_group,
+		});
+	}
+}
+
+/// renders the gbuffer, and traces cones from it at full resolution
+pub struct ConeTracePassNode {
+	view_query: QueryState<(
+		&'static ViewUniformOffset,
+		&'static ViewGiVolumes,
+		&'static ViewGiTraceTargets,
+		&'static ViewGiTraceBindGroup,
+		Option<&'static ViewGiLowResTargets>,
+	)>,
+}
+
+impl ConeTracePassNode {
+	pub const IN_VIEW: &'static str = "view";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for ConeTracePassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(ConeTracePassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+
+		let (view_uniform_offset, view_volumes, targets, trace_bind_group, low_res_targets) = match self.view_query.get_manual(world, view_entity) {
+			Ok(query) => query,
+			Err(_) => return Ok(()),
+		};
+
+		let trace_shaders = world.get_resource::<ConeTraceShaders>().unwrap();
+		let trace_meta = world.get_resource::<ConeTraceMeta>().unwrap();
+		let cascade_meta = world.get_resource::<GiCascadeMeta>().unwrap();
+		let mesh_meta = world.get_resource::<GiMeshMeta>().unwrap();
+		let extracted_meshes = world.get_resource::<ExtractedGiMeshes>().unwrap();
+		let meshes = world.get_resource::<RenderAssets<Mesh>>().unwrap();
+
+		let (view_bind_group, cascades_bind_group, transform_bind_group, previous_transform_bind_group) = match (
+			&trace_meta.view_bind_group,
+			&cascade_meta.bind_group,
+			&mesh_meta.transform_bind_group,
+			&mesh_meta.previous_transform_bind_group,
+		) {
+			(Some(view), Some(cascades), Some(transform), Some(previous_transform)) => {
+				(view, cascades, transform, previous_transform)
+			}
+			_ => return Ok(()),
+		};
+
+		// the low res and temporal passes time their own segments of it
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Trace);
+
+		// voxelization already copied the transforms, the previous ones are only used here
+		mesh_meta
+			.previous_transform_uniforms
+			.write_to_uniform_buffer(&mut render_context.command_encoder);
+
+		{
+			let clear = Operations {
+				load: LoadOp::Clear(Color::NONE.into()),
+				store: true,
+			};
+
+			let mut pass = render_context
+				.command_encoder
+				.begin_render_pass(&RenderPassDescriptor {
+					label: Some("gi_gbuffer"),
+					color_attachments: &[
+						RenderPassColorAttachment {
+							view: &targets.gbuffer_position,
+							resolve_target: None,
+							ops: clear,
+						},
+						RenderPassColorAttachment {
+							view: &targets.gbuffer_normal,
+							resolve_target: None,
+							ops: clear,
+						},
+						RenderPassColorAttachment {
+							view: &targets.gbuffer_motion,
+							resolve_target: None,
+							ops: clear,
+						},
+						RenderPassColorAttachment {
+							view: &targets.gbuffer_albedo,
+							resolve_target: None,
+							ops: clear,
+						},
+					],
+					depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+						view: &targets.gbuffer_depth,
+						depth_ops: Some(Operations {
+							load: LoadOp::Clear(1.0),
+							store: true,
+						}),
+						stencil_ops: None,
+					}),
+				});
+
+			pass.set_pipeline(&trace_shaders.gbuffer_pipeline);
+			pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+
+			// meshes that don't receive gi are left out, so the cones come from whatever is behind them
+			for mesh in extracted_meshes.meshes.iter().filter(|mesh| mesh.receiver) {
+				let gpu_mesh = match meshes.get(&mesh.mesh) {
+					Some(gpu_mesh) => gpu_mesh,
+					None => continue,
+				};
+
+				// same as pbr, only indexed meshes are drawn
+				let index_info = match &gpu_mesh.index_info {
+					Some(index_info) => index_info,
+					None => continue,
+				};
+
+				pass.set_bind_group(1, transform_bind_group, &[mesh.transform_binding_offset]);
+				pass.set_bind_group(2, previous_transform_bind_group, &[mesh.previous_transform_binding_offset]);
+				pass.set_bind_group(3, &trace_bind_group.temporal_bind_group, &[mesh.material_binding_offset]);
+				pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+				pass.set_index_buffer(index_info.buffer.slice(..), IndexFormat::Uint32);
+				pass.draw_indexed(0..index_info.count, 0, 0..1);
+			}
+		}
+
+		let pixel_workgroups = (
+			(targets.width + TRACE_WORKGROUP_SIZE - 1) / TRACE_WORKGROUP_SIZE,
+			(targets.height + TRACE_WORKGROUP_SIZE - 1) / TRACE_WORKGROUP_SIZE,
+		);
+
+		let probe_meta = world.get_resource::<GiProbeMeta>().unwrap();
+
+		if let (Some(probes), Some(write_bind_group), Some(read_bind_group)) = (
+			&probe_meta.probes,
+			&probe_meta.write_bind_group,
+			&probe_meta.read_bind_group,
+		) {
+			let probe_shaders = world.get_resource::<GiProbeShaders>().unwrap();
+
+			// separate passes, as the probes are written in the first and read in the second
+			{
+				let mut pass = render_context
+					.command_encoder
+					.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_update_probes") });
+
+				pass.set_pipeline(&probe_shaders.update_pipeline);
+				pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+				pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+				pass.set_bind_group(2, &trace_bind_group.bind_group, &[]);
+				pass.set_bind_group(3, write_bind_group, &[]);
+				pass.dispatch((probes.probe_count + PROBE_WORKGROUP_SIZE - 1) / PROBE_WORKGROUP_SIZE, 1, 1);
+			}
+
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_resolve_probes") });
+
+			pass.set_pipeline(&probe_shaders.resolve_pipeline);
+			pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+			pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+			pass.set_bind_group(2, &trace_bind_group.bind_group, &[]);
+			pass.set_bind_group(3, read_bind_group, &[]);
+			pass.dispatch(pixel_workgroups.0, pixel_workgroups.1, 1);
+		} else if low_res_targets.is_none() {
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_cone_trace") });
+
+			pass.set_pipeline(&trace_shaders.trace_pipeline);
+			pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+			pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+			pass.set_bind_group(2, &trace_bind_group.bind_group, &[]);
+			pass.dispatch(pixel_workgroups.0, pixel_workgroups.1, 1);
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Trace);
+
+		Ok(())
+	}
+}
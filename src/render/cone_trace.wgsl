@@ -0,0 +1,957 @@
+// HOW IT WORKS
+// for every pixel in the gbuffer, trace a few wide cones for diffuse, and one narrow cone for specular
+// a cone is a line of samples that get larger with distance, so they are taken from higher mips
+// once the mip is too large for a cascade, the next cascade takes over, as it has voxels twice as large
+// samples are composited front to back, the voxels are premultiplied so that's just a sum
+// the sparse storages are traced as a single cascade, only loading a voxel is different, see SPARSE STORAGES below
+// with a probe grid, cones are traced from the probes instead, and the pixels read the probes, see PROBES below
+// at a lower trace resolution, the diffuse cones are traced for fewer pixels and upsampled, see LOW RESOLUTION below
+// with more than one bounce, the voxels gather light from each other before any of this, see BOUNCES below
+// the voxels that aren't empty can be counted for the diagnostics, see COUNTERS below
+
+[[block]]
+struct View {
+    view_proj: mat4x4<f32>;
+    world_position: vec3<f32>;
+};
+
+// same layout as GpuGiCascade
+struct GiCascade {
+    // world space to 0 - 1 over the cascade
+    projection: mat4x4<f32>;
+    resolution: vec3<f32>;
+    texture_index: u32;
+    // size of the cascade in world space
+    extent: vec3<f32>;
+};
+
+[[block]]
+struct GiCascades {
+    num_cascades: u32;
+    // in voxels of the first cascade, from GiTraceBias
+    normal_offset: f32;
+    self_intersection_bias: f32;
+    // one of the STORAGE_ constants, the sparse storages only have a single cascade
+    storage: u32;
+    cascades: [[stride(96)]] array<GiCascade>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> view: View;
+
+[[group(1), binding(0)]]
+var<storage, read> gi_cascades: GiCascades;
+
+// same layout as GpuGiSettings
+[[block]]
+struct GiSettings {
+    diffuse_cones: u32;
+    // step size of a cone, relative to the diameter of the cone at that point
+    step_multiplier: f32;
+    // tan of half the angle of the cones
+    diffuse_aperture: f32;
+    specular_aperture: f32;
+    // in world space
+    max_trace_distance: f32;
+    indirect_intensity: f32;
+    ao_strength: f32;
+    // how much more opaque partly covered voxels are, against light leaking through thin walls
+    leak_bias: f32;
+    // in voxels of the cascade that fades out
+    cascade_blend: f32;
+};
+
+[[group(1), binding(1)]]
+var<uniform> gi_settings: GiSettings;
+
+// the nodes of the octree, or the cells of the brick map, when that's the storage
+[[block]]
+struct SparseNodes {
+    data: [[stride(4)]] array<u32>;
+};
+
+[[group(1), binding(2)]]
+var<storage, read> sparse_nodes: SparseNodes;
+
+// one per cascade, indexed by texture_index
+// unused ones are bound to the last cascade, a sparse storage has it's bricks in cascade_0
+// and the brick map it's coarse grid in cascade_1
+[[group(2), binding(0)]]
+var cascade_0: texture_3d<f32>;
+
+[[group(2), binding(1)]]
+var cascade_1: texture_3d<f32>;
+
+[[group(2), binding(2)]]
+var cascade_2: texture_3d<f32>;
+
+[[group(2), binding(3)]]
+var cascade_3: texture_3d<f32>;
+
+[[group(2), binding(4)]]
+var cascade_4: texture_3d<f32>;
+
+[[group(2), binding(5)]]
+var cascade_5: texture_3d<f32>;
+
+[[group(2), binding(6)]]
+var cascade_6: texture_3d<f32>;
+
+[[group(2), binding(7)]]
+var cascade_7: texture_3d<f32>;
+
+[[group(2), binding(8)]]
+var gbuffer_position: texture_2d<f32>;
+
+[[group(2), binding(9)]]
+var gbuffer_normal: texture_2d<f32>;
+
+// rgb is the light, a is the ambient occlusion, where 1 is not occluded
+[[group(2), binding(10)]]
+var indirect_diffuse: texture_storage_2d<rgba16float, write>;
+
+[[group(2), binding(11)]]
+var indirect_specular: texture_storage_2d<rgba16float, write>;
+
+// r is the cascade the pixel is in, or -1 if it's outside the volume, g is the number of steps taken by all cones
+[[group(2), binding(12)]]
+var trace_info: texture_storage_2d<rgba16float, write>;
+
+// same layout as GpuGiTemporal
+[[block]]
+struct Temporal {
+    previous_view_proj: mat4x4<f32>;
+    // how much of the history is kept, only one diffuse cone is traced when it's above 0
+    blend: f32;
+    frame: u32;
+    history_valid: u32;
+};
+
+[[group(2), binding(13)]]
+var<uniform> temporal: Temporal;
+
+// same layout as GpuGiProbeGrid
+[[block]]
+struct ProbeGrid {
+    world_to_grid: mat4x4<f32>;
+    grid_to_world: mat4x4<f32>;
+    // number of probes along each axis
+    resolution: vec3<f32>;
+    // the coefficients of a probe are packed in this many texels, texel t of a probe is at probe.x + t * resolution.x
+    texels_per_probe: u32;
+    coefficients: u32;
+    // probes to update this frame
+    first_probe: u32;
+    probe_count: u32;
+};
+
+[[group(3), binding(0)]]
+var<uniform> probe_grid: ProbeGrid;
+
+// only for updating
+[[group(3), binding(1)]]
+var probes_out: texture_storage_3d<rgba16float, write>;
+
+// only for reading
+[[group(3), binding(2)]]
+var probes: texture_3d<f32>;
+
+[[group(3), binding(3)]]
+var probe_sampler: sampler;
+
+// same as MAX_TRACED_CASCADES
+let MAX_TRACED_CASCADES: u32 = 8u;
+let NO_CASCADE: u32 = 0xffffffffu;
+
+let MAX_STEPS: u32 = 64u;
+// how fast occluders stop counting towards ambient occlusion with distance
+let AO_FALLOFF: f32 = 2.0;
+
+let PI: f32 = 3.14159265;
+
+// same as in cone_trace.rs
+let PROBE_CONES: u32 = 32u;
+// each cone covers 1 / 32 of the sphere
+let PROBE_APERTURE: f32 = 0.36;
+
+fn load_cascade(texture_index: u32, texel: vec3<i32>, mip: i32) -> vec4<f32> {
+    if (texture_index == 0u) {
+        return textureLoad(cascade_0, texel, mip);
+    } elseif (texture_index == 1u) {
+        return textureLoad(cascade_1, texel, mip);
+    } elseif (texture_index == 2u) {
+        return textureLoad(cascade_2, texel, mip);
+    } elseif (texture_index == 3u) {
+        return textureLoad(cascade_3, texel, mip);
+    } elseif (texture_index == 4u) {
+        return textureLoad(cascade_4, texel, mip);
+    } elseif (texture_index == 5u) {
+        return textureLoad(cascade_5, texel, mip);
+    } elseif (texture_index == 6u) {
+        return textureLoad(cascade_6, texel, mip);
+    }
+    return textureLoad(cascade_7, texel, mip);
+}
+
+// SPARSE STORAGES
+// the sparse storages are traced as a single cascade, a cube with the voxels of the finest level of the storage
+// their bricks are bound as cascade_0, and what points into them as sparse_nodes
+// so the cones go through them the same way, only finding the texel of a voxel is different
+
+// same as GpuGiCascades::push
+let STORAGE_DENSE: u32 = 0u;
+let STORAGE_SPARSE_OCTREE: u32 = 1u;
+let STORAGE_BRICK_MAP: u32 = 2u;
+
+// same as in sparse_octree.wgsl
+let NO_CHILDREN: u32 = 0u;
+let SUBDIVIDE_FLAG: u32 = 0x80000000u;
+let OCTREE_BRICKS_PER_AXIS: u32 = 64u;
+
+// the octree is 2^depth voxels wide, which is the resolution of it's cascade
+fn octree_depth(cascade: GiCascade) -> u32 {
+    return u32(round(log2(cascade.resolution.x)));
+}
+
+// same as in sparse_octree.wgsl
+fn octant(position: vec3<u32>, level: u32, depth: u32) -> u32 {
+    let shift = depth - 1u - level;
+    let bits = (position >> vec3<u32>(shift)) & vec3<u32>(1u);
+    return bits.x | (bits.y << 1u) | (bits.z << 2u);
+}
+
+// the same as sample_octree in sparse_octree.wgsl, but for a texel of a mip, so it can be filtered like a cascade
+fn load_octree(cascade: GiCascade, texel: vec3<i32>, mip: i32) -> vec4<f32> {
+    let depth = octree_depth(cascade);
+    let level = depth - 1u - u32(mip);
+
+    // in voxels of the leaves, the bits below the mip don't pick a node
+    let position = vec3<u32>(texel) << vec3<u32>(u32(mip));
+
+    var node = 0u;
+    for (var parent_level = 0u; parent_level < level; parent_level = parent_level + 1u) {
+        let child = sparse_nodes.data[node] & ~SUBDIVIDE_FLAG;
+
+        // nothing was voxelized here
+        if (child == NO_CHILDREN) {
+            return vec4<f32>(0.0);
+        }
+
+        node = child + octant(position, parent_level, depth);
+    }
+
+    let voxel_octant = octant(position, level, depth);
+    let brick = vec3<u32>(
+        node % OCTREE_BRICKS_PER_AXIS,
+        (node / OCTREE_BRICKS_PER_AXIS) % OCTREE_BRICKS_PER_AXIS,
+        node / (OCTREE_BRICKS_PER_AXIS * OCTREE_BRICKS_PER_AXIS),
+    );
+    let offset = vec3<u32>(voxel_octant & 1u, (voxel_octant >> 1u) & 1u, (voxel_octant >> 2u) & 1u);
+
+    return textureLoad(cascade_0, vec3<i32>(brick * 2u + offset), 0);
+}
+
+// same as in brick_map.wgsl
+let EMPTY_CELL: u32 = 0u;
+let USED_FLAG: u32 = 0x80000000u;
+let BRICK_SIZE: u32 = 8u;
+
+// same as BRICK_POOL_SIZE in brick_map.rs
+let BRICK_POOL_SIZE: u32 = 16u;
+
+// the same as sample_brick_map in brick_map.wgsl, but for a texel of a mip
+// the first 3 mips are in the bricks, found through the cells, the rest are the mips of the coarse grid
+fn load_brick_map(cascade: GiCascade, texel: vec3<i32>, mip: i32) -> vec4<f32> {
+    if (mip >= 3) {
+        return textureLoad(cascade_1, texel, mip - 3);
+    }
+
+    let grid_size = u32(cascade.resolution.x) / BRICK_SIZE;
+    let position = vec3<u32>(texel) << vec3<u32>(u32(mip));
+    let cell = position / BRICK_SIZE;
+
+    let brick = sparse_nodes.data[cell.x + cell.y * grid_size + cell.z * grid_size * grid_size] & ~USED_FLAG;
+
+    // no fragments were written here
+    if (brick == EMPTY_CELL) {
+        return vec4<f32>(0.0);
+    }
+
+    let origin = vec3<u32>(
+        (brick - 1u) % BRICK_POOL_SIZE,
+        ((brick - 1u) / BRICK_POOL_SIZE) % BRICK_POOL_SIZE,
+        (brick - 1u) / (BRICK_POOL_SIZE * BRICK_POOL_SIZE),
+    ) * BRICK_SIZE;
+
+    return textureLoad(cascade_0, vec3<i32>((origin + position % vec3<u32>(BRICK_SIZE)) >> vec3<u32>(u32(mip))), mip);
+}
+
+// a texel of a mip of a cascade, from whatever storage the volume uses
+fn load_voxel(cascade: GiCascade, texel: vec3<i32>, mip: i32) -> vec4<f32> {
+    if (gi_cascades.storage == STORAGE_SPARSE_OCTREE) {
+        return load_octree(cascade, texel, mip);
+    } elseif (gi_cascades.storage == STORAGE_BRICK_MAP) {
+        return load_brick_map(cascade, texel, mip);
+    }
+    return load_cascade(cascade.texture_index, texel, mip);
+}
+
+fn mip_count(cascade: GiCascade) -> i32 {
+    let full_chain = i32(floor(log2(max(cascade.resolution.x, max(cascade.resolution.y, cascade.resolution.z))))) + 1;
+
+    // the root of the octree has a brick of 2x2x2, and nothing above that
+    if (gi_cascades.storage == STORAGE_SPARSE_OCTREE) {
+        return full_chain - 1;
+    }
+    return full_chain;
+}
+
+// voxels are cubic, so any axis works
+fn voxel_size(cascade: GiCascade) -> f32 {
+    return cascade.extent.x / cascade.resolution.x;
+}
+
+// trilinear, as rgba32float can't be filtered by a sampler
+fn sample_mip(cascade: GiCascade, uvw: vec3<f32>, mip: i32) -> vec4<f32> {
+    let size = max(vec3<i32>(cascade.resolution) >> vec3<u32>(u32(mip)), vec3<i32>(1));
+    let position = uvw * vec3<f32>(size) - 0.5;
+    let base = vec3<i32>(floor(position));
+    let t = position - floor(position);
+
+    var result = vec4<f32>(0.0);
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        let weight = mix(1.0 - t, t, vec3<f32>(offset));
+        let texel = clamp(base + vec3<i32>(offset), vec3<i32>(0), size - vec3<i32>(1));
+        result = result + load_voxel(cascade, texel, mip) * weight.x * weight.y * weight.z;
+    }
+
+    return result;
+}
+
+// and linear between mips
+fn sample_cascade(cascade: GiCascade, uvw: vec3<f32>, mip: f32) -> vec4<f32> {
+    let max_mip = mip_count(cascade) - 1;
+    let level = clamp(mip, 0.0, f32(max_mip));
+    let low = i32(floor(level));
+    let high = min(low + 1, max_mip);
+
+    return mix(sample_mip(cascade, uvw, low), sample_mip(cascade, uvw, high), level - floor(level));
+}
+
+struct VolumeSample {
+    value: vec4<f32>;
+    // NO_CASCADE when the position is outside the volume
+    cascade: u32;
+};
+
+// how far the position is from the closest side of the cascade, in voxels of the cascade
+fn cascade_border_distance(cascade: GiCascade, uvw: vec3<f32>) -> f32 {
+    let to_side = min(uvw, 1.0 - uvw) * cascade.resolution;
+    return min(to_side.x, min(to_side.y, to_side.z));
+}
+
+// samples the finest cascade the position is in, that has voxels large enough for the diameter
+// close to the sides of a cascade, it fades into the next one, so there's no seam where that takes over
+fn sample_volume(position: vec3<f32>, diameter: f32) -> VolumeSample {
+    var result: VolumeSample;
+    result.value = vec4<f32>(0.0);
+    result.cascade = NO_CASCADE;
+
+    let num_cascades = min(gi_cascades.num_cascades, MAX_TRACED_CASCADES);
+    for (var i = 0u; i < num_cascades; i = i + 1u) {
+        let uvw = (gi_cascades.cascades[i].projection * vec4<f32>(position, 1.0)).xyz;
+        if (any(uvw < vec3<f32>(0.0)) || any(uvw > vec3<f32>(1.0))) {
+            continue;
+        }
+
+        // the next cascade has voxels twice as large, so it takes over once this one runs out of mips
+        let mip = log2(diameter / voxel_size(gi_cascades.cascades[i]));
+        if (mip < f32(mip_count(gi_cascades.cascades[i]) - 1) || i + 1u == num_cascades) {
+            result.cascade = i;
+            break;
+        }
+    }
+
+    // left the volume
+    if (result.cascade == NO_CASCADE) {
+        return result;
+    }
+
+    let cascade = gi_cascades.cascades[result.cascade];
+    let uvw = (cascade.projection * vec4<f32>(position, 1.0)).xyz;
+    result.value = sample_cascade(cascade, uvw, log2(diameter / voxel_size(cascade)));
+
+    // every sample near the border fades into the next cascade, which is centered on this one and covers it
+    let border = cascade_border_distance(cascade, uvw);
+    if (result.cascade + 1u < num_cascades && border < gi_settings.cascade_blend) {
+        let next = gi_cascades.cascades[result.cascade + 1u];
+        let next_uvw = (next.projection * vec4<f32>(position, 1.0)).xyz;
+        let next_value = sample_cascade(next, next_uvw, log2(diameter / voxel_size(next)));
+        result.value = mix(next_value, result.value, border / gi_settings.cascade_blend);
+    }
+
+    return result;
+}
+
+struct ConeResult {
+    color: vec3<f32>;
+    occlusion: f32;
+    // like occlusion, but only counts what's close by
+    ao: f32;
+    // how much light makes it through everything the cone passed, for each channel
+    transmittance: vec3<f32>;
+    steps: u32;
+};
+
+// voxels that aren't fully opaque, like glass, tint the light with their albedo
+// same as voxel_transmittance in cone_trace.rs
+fn voxel_transmittance(voxel: vec4<f32>) -> vec3<f32> {
+    let opacity = min(voxel.a, 1.0);
+    if (opacity <= 0.0) {
+        return vec3<f32>(1.0);
+    }
+
+    let albedo = voxel.rgb / voxel.a;
+    return (1.0 - opacity) * mix(vec3<f32>(1.0), albedo, vec3<f32>(opacity));
+}
+
+// partly covered voxels count as more opaque, so less light leaks through walls thinner than a voxel
+// same as leak_opacity in cone_trace.rs
+fn leak_opacity(opacity: f32) -> f32 {
+    return min(opacity * (1.0 + max(gi_settings.leak_bias, 0.0)), 1.0);
+}
+
+// the voxel with the leak bias applied to its opacity, keeping its albedo
+// same as TraceVolume::leak_voxel in cone_trace.rs
+fn leak_voxel(voxel: vec4<f32>) -> vec4<f32> {
+    if (voxel.a <= 0.0) {
+        return voxel;
+    }
+
+    let opacity = leak_opacity(voxel.a);
+    return vec4<f32>(voxel.rgb / voxel.a * opacity, opacity);
+}
+
+fn trace_cone(origin: vec3<f32>, direction: vec3<f32>, aperture: f32) -> ConeResult {
+    var result: ConeResult;
+    result.color = vec3<f32>(0.0);
+    result.occlusion = 0.0;
+    result.ao = 0.0;
+    result.transmittance = vec3<f32>(1.0);
+    result.steps = 0u;
+
+    // no point in sampling smaller than a voxel
+    let min_diameter = voxel_size(gi_cascades.cascades[0]);
+    // the first sample would otherwise be the voxels the cone starts in
+    var distance = min_diameter * max(gi_cascades.self_intersection_bias, 0.0);
+
+    loop {
+        if (distance >= gi_settings.max_trace_distance || result.occlusion >= 0.99 || result.steps >= MAX_STEPS) {
+            break;
+        }
+
+        let diameter = max(min_diameter, 2.0 * aperture * distance);
+        let sample = sample_volume(origin + direction * distance, diameter);
+
+        // left the volume, so nothing more to hit
+        if (sample.cascade == NO_CASCADE) {
+            break;
+        }
+
+        // front to back, and what's behind is seen through the tint of what's in front
+        let weight = 1.0 - result.occlusion;
+        let opacity = leak_opacity(sample.value.a);
+        result.color = result.color + result.transmittance * sample.value.rgb;
+        result.occlusion = result.occlusion + weight * opacity;
+        result.ao = result.ao + weight * opacity / (1.0 + AO_FALLOFF * distance);
+        result.transmittance = result.transmittance * voxel_transmittance(leak_voxel(sample.value));
+        result.steps = result.steps + 1u;
+
+        distance = distance + diameter * gi_settings.step_multiplier;
+    }
+
+    return result;
+}
+
+// one cone straight up, and the rest in a ring around it at 60 degrees, same as diffuse_cone in cone_trace.rs
+fn diffuse_cone_count() -> u32 {
+    return max(gi_settings.diffuse_cones, 1u);
+}
+
+fn diffuse_cone_direction(index: u32, normal: vec3<f32>, tangent: vec3<f32>, bitangent: vec3<f32>) -> vec3<f32> {
+    let count = diffuse_cone_count();
+    if (index == 0u || count == 1u) {
+        return normal;
+    }
+
+    let angle = f32(index - 1u) * 2.0 * PI / f32(count - 1u);
+    return normalize(normal * 0.5 + (tangent * cos(angle) + bitangent * sin(angle)) * 0.866);
+}
+
+fn diffuse_cone_weight(index: u32) -> f32 {
+    let count = diffuse_cone_count();
+    if (count == 1u) {
+        return 1.0;
+    }
+
+    return select(0.75 / f32(count - 1u), 0.25, index == 0u);
+}
+
+// start a bit away from the surface, so the cones don't hit the voxels of the surface itself
+fn cone_origin(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    return position + normal * voxel_size(gi_cascades.cascades[0]) * gi_cascades.normal_offset;
+}
+
+struct DiffuseResult {
+    // the light in rgb, and the ambient occlusion in a, where 1 is not occluded
+    diffuse: vec4<f32>;
+    steps: u32;
+};
+
+// `pixel` only picks the cone when there's history, so pixels next to each other trace different ones
+fn trace_diffuse(pixel: vec2<i32>, position: vec3<f32>, normal: vec3<f32>) -> DiffuseResult {
+    let origin = cone_origin(position, normal);
+
+    // any vector that isn't the normal works to make the other two axes
+    let up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(normal.y) > 0.99);
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+
+    // with history, one cone per pixel, a different one every frame and for every pixel next to it
+    // it's weighted as if it were all of them, so it averages out to the same over as many frames as there are cones
+    var first_cone = 0u;
+    var cone_count = diffuse_cone_count();
+    var cone_scale = 1.0;
+    if (temporal.blend > 0.0) {
+        first_cone = (u32(pixel.x) + u32(pixel.y) * 3u + temporal.frame) % cone_count;
+        cone_scale = f32(cone_count);
+        cone_count = 1u;
+    }
+
+    var diffuse = vec3<f32>(0.0);
+    var ao = 0.0;
+    var steps = 0u;
+
+    for (var i = first_cone; i < first_cone + cone_count; i = i + 1u) {
+        let cone = trace_cone(origin, diffuse_cone_direction(i, normal, tangent, bitangent), gi_settings.diffuse_aperture);
+        let weight = diffuse_cone_weight(i) * cone_scale;
+
+        diffuse = diffuse + cone.color * weight;
+        ao = ao + cone.ao * weight;
+        steps = steps + cone.steps;
+    }
+
+    var result: DiffuseResult;
+    result.diffuse = vec4<f32>(diffuse * gi_settings.indirect_intensity, 1.0 - clamp(ao * gi_settings.ao_strength, 0.0, 1.0));
+    result.steps = steps;
+    return result;
+}
+
+fn trace_specular_cone(position: vec3<f32>, normal: vec3<f32>) -> ConeResult {
+    // TODO: use the roughness for the aperture, once that's in the gbuffer
+    let view_direction = normalize(position - view.world_position);
+    var cone = trace_cone(cone_origin(position, normal), reflect(view_direction, normal), gi_settings.specular_aperture);
+    cone.color = cone.color * gi_settings.indirect_intensity;
+    return cone;
+}
+
+fn store_trace_info(pixel: vec2<i32>, position: vec3<f32>, steps: u32) {
+    let cascade = sample_volume(position, voxel_size(gi_cascades.cascades[0])).cascade;
+    textureStore(trace_info, pixel, vec4<f32>(select(f32(cascade), -1.0, cascade == NO_CASCADE), f32(steps), 0.0, 0.0));
+}
+
+[[stage(compute), workgroup_size(8, 8)]]
+fn trace([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy);
+    if (any(pixel >= textureDimensions(gbuffer_position))) {
+        return;
+    }
+
+    let position = textureLoad(gbuffer_position, pixel, 0);
+
+    // nothing was drawn here
+    if (position.w == 0.0 || gi_cascades.num_cascades == 0u) {
+        textureStore(indirect_diffuse, pixel, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+        textureStore(indirect_specular, pixel, vec4<f32>(0.0));
+        textureStore(trace_info, pixel, vec4<f32>(-1.0, 0.0, 0.0, 0.0));
+        return;
+    }
+
+    let normal = normalize(textureLoad(gbuffer_normal, pixel, 0).xyz);
+
+    let diffuse = trace_diffuse(pixel, position.xyz, normal);
+    let specular = trace_specular_cone(position.xyz, normal);
+
+    textureStore(indirect_diffuse, pixel, diffuse.diffuse);
+    textureStore(indirect_specular, pixel, vec4<f32>(specular.color, 1.0));
+    store_trace_info(pixel, position.xyz, diffuse.steps + specular.steps);
+}
+
+// LOW RESOLUTION
+// diffuse light changes slowly over a surface, so it's only traced for one pixel in every 2x2 or 4x4 block
+// each low res pixel traces from the pixel in the middle of it's block, and then every pixel blends the low res pixels around it
+// the blend is weighted by how close they are in depth and normal, so light doesn't bleed over edges
+// specular is still traced for every pixel, by trace_specular
+
+// same layout as GpuGiLowRes
+[[block]]
+struct LowRes {
+    // pixels along each axis for every low res pixel
+    scale: u32;
+};
+
+[[group(3), binding(4)]]
+var<uniform> low_res: LowRes;
+
+// only for tracing
+[[group(3), binding(5)]]
+var low_res_diffuse_out: texture_storage_2d<rgba16float, write>;
+
+// only for upsampling
+[[group(3), binding(6)]]
+var low_res_diffuse: texture_2d<f32>;
+
+// how much the distance to the camera can differ, relative to the distance, before a low res pixel stops counting
+let UPSAMPLE_DEPTH_TOLERANCE: f32 = 0.05;
+// how sharply the weight falls off as the normals diverge
+let UPSAMPLE_NORMAL_POWER: f32 = 8.0;
+
+// the full res pixel a low res pixel was traced from
+fn low_res_source(low_res_pixel: vec2<i32>) -> vec2<i32> {
+    let scale = i32(low_res.scale);
+    return min(low_res_pixel * scale + scale / 2, textureDimensions(gbuffer_position) - 1);
+}
+
+[[stage(compute), workgroup_size(8, 8)]]
+fn trace_specular([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy);
+    if (any(pixel >= textureDimensions(gbuffer_position))) {
+        return;
+    }
+
+    let position = textureLoad(gbuffer_position, pixel, 0);
+
+    // nothing was drawn here
+    if (position.w == 0.0 || gi_cascades.num_cascades == 0u) {
+        textureStore(indirect_specular, pixel, vec4<f32>(0.0));
+        textureStore(trace_info, pixel, vec4<f32>(-1.0, 0.0, 0.0, 0.0));
+        return;
+    }
+
+    let normal = normalize(textureLoad(gbuffer_normal, pixel, 0).xyz);
+    let specular = trace_specular_cone(position.xyz, normal);
+
+    textureStore(indirect_specular, pixel, vec4<f32>(specular.color, 1.0));
+    store_trace_info(pixel, position.xyz, specular.steps);
+}
+
+[[stage(compute), workgroup_size(8, 8)]]
+fn trace_low_res([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy);
+    if (any(pixel >= textureDimensions(low_res_diffuse_out))) {
+        return;
+    }
+
+    let source = low_res_source(pixel);
+    let position = textureLoad(gbuffer_position, source, 0);
+
+    // nothing was drawn here
+    if (position.w == 0.0 || gi_cascades.num_cascades == 0u) {
+        textureStore(low_res_diffuse_out, pixel, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+        return;
+    }
+
+    let normal = normalize(textureLoad(gbuffer_normal, source, 0).xyz);
+
+    textureStore(low_res_diffuse_out, pixel, trace_diffuse(pixel, position.xyz, normal).diffuse);
+}
+
+// joint bilateral upsampling, with the gbuffer as the guide
+[[stage(compute), workgroup_size(8, 8)]]
+fn upsample([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy);
+    if (any(pixel >= textureDimensions(gbuffer_position))) {
+        return;
+    }
+
+    let position = textureLoad(gbuffer_position, pixel, 0);
+
+    // nothing was drawn here
+    if (position.w == 0.0) {
+        textureStore(indirect_diffuse, pixel, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+        return;
+    }
+
+    let normal = normalize(textureLoad(gbuffer_normal, pixel, 0).xyz);
+    let depth = distance(position.xyz, view.world_position);
+
+    // the 4 low res pixels around this one, with their bilinear weights
+    let low_res_size = textureDimensions(low_res_diffuse);
+    let low_res_position = (vec2<f32>(pixel) + 0.5) / f32(low_res.scale) - 0.5;
+    let first = vec2<i32>(floor(low_res_position));
+    let fraction = low_res_position - floor(low_res_position);
+
+    var sum = vec4<f32>(0.0);
+    var weight_sum = 0.0;
+    // without the guide, for when none of them are on the same surface
+    var bilinear_sum = vec4<f32>(0.0);
+
+    for (var i = 0; i < 4; i = i + 1) {
+        let offset = vec2<i32>(i % 2, i / 2);
+        let low_res_pixel = clamp(first + offset, vec2<i32>(0), low_res_size - 1);
+        let bilinear = mix(1.0 - fraction.x, fraction.x, f32(offset.x)) * mix(1.0 - fraction.y, fraction.y, f32(offset.y));
+
+        let sample = textureLoad(low_res_diffuse, low_res_pixel, 0);
+        bilinear_sum = bilinear_sum + sample * bilinear;
+
+        let source = low_res_source(low_res_pixel);
+        let source_position = textureLoad(gbuffer_position, source, 0);
+        if (source_position.w != 0.0) {
+            let source_normal = normalize(textureLoad(gbuffer_normal, source, 0).xyz);
+            let source_depth = distance(source_position.xyz, view.world_position);
+
+            let depth_weight = exp(-abs(source_depth - depth) / (depth * UPSAMPLE_DEPTH_TOLERANCE));
+            let normal_weight = pow(max(dot(normal, source_normal), 0.0), UPSAMPLE_NORMAL_POWER);
+            let weight = bilinear * depth_weight * normal_weight;
+
+            sum = sum + sample * weight;
+            weight_sum = weight_sum + weight;
+        }
+    }
+
+    if (weight_sum > 0.0001) {
+        textureStore(indirect_diffuse, pixel, sum / weight_sum);
+    } else {
+        textureStore(indirect_diffuse, pixel, bilinear_sum);
+    }
+}
+
+// PROBES
+// every probe traces cones evenly spread over the sphere, and projects what they see to spherical harmonics
+// the pixels then read the probes around them trilinearly, and turn that into irradiance for their normal
+// only a few probes are updated each frame, starting at first_probe and wrapping around
+
+// real spherical harmonics, up to L2
+fn sh_basis(index: u32, direction: vec3<f32>) -> f32 {
+    let d = direction;
+    if (index == 0u) {
+        return 0.282095;
+    } elseif (index == 1u) {
+        return 0.488603 * d.y;
+    } elseif (index == 2u) {
+        return 0.488603 * d.z;
+    } elseif (index == 3u) {
+        return 0.488603 * d.x;
+    } elseif (index == 4u) {
+        return 1.092548 * d.x * d.y;
+    } elseif (index == 5u) {
+        return 1.092548 * d.y * d.z;
+    } elseif (index == 6u) {
+        return 0.315392 * (3.0 * d.z * d.z - 1.0);
+    } elseif (index == 7u) {
+        return 1.092548 * d.x * d.z;
+    }
+    return 0.546274 * (d.x * d.x - d.y * d.y);
+}
+
+// convolution with a cosine lobe, for each band
+fn sh_cosine_lobe(index: u32) -> f32 {
+    if (index == 0u) {
+        return PI;
+    } elseif (index < 4u) {
+        return 2.0 * PI / 3.0;
+    }
+    return PI / 4.0;
+}
+
+// fibonacci sphere
+fn sphere_direction(index: u32, count: u32) -> vec3<f32> {
+    let golden_angle = PI * (3.0 - sqrt(5.0));
+    let z = 1.0 - (f32(index) + 0.5) * 2.0 / f32(count);
+    let radius = sqrt(1.0 - z * z);
+    let angle = f32(index) * golden_angle;
+    return vec3<f32>(cos(angle) * radius, sin(angle) * radius, z);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn update_probes([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= probe_grid.probe_count || gi_cascades.num_cascades == 0u) {
+        return;
+    }
+
+    let size = vec3<u32>(probe_grid.resolution);
+    let index = (probe_grid.first_probe + id.x) % (size.x * size.y * size.z);
+    let probe = vec3<u32>(index % size.x, (index / size.x) % size.y, index / (size.x * size.y));
+    let origin = (probe_grid.grid_to_world * vec4<f32>(vec3<f32>(probe) + 0.5, 1.0)).xyz;
+
+    // 3 floats per coefficient, 9 coefficients at most, and room to round up to a whole texel
+    var packed: array<f32, 28>;
+    for (var i = 0u; i < 28u; i = i + 1u) {
+        packed[i] = 0.0;
+    }
+
+    // every cone covers the same part of the sphere
+    let weight = 4.0 * PI / f32(PROBE_CONES);
+
+    for (var i = 0u; i < PROBE_CONES; i = i + 1u) {
+        let direction = sphere_direction(i, PROBE_CONES);
+        let cone = trace_cone(origin, direction, PROBE_APERTURE);
+
+        for (var k = 0u; k < probe_grid.coefficients; k = k + 1u) {
+            let value = cone.color * sh_basis(k, direction) * weight;
+            packed[k * 3u] = packed[k * 3u] + value.r;
+            packed[k * 3u + 1u] = packed[k * 3u + 1u] + value.g;
+            packed[k * 3u + 2u] = packed[k * 3u + 2u] + value.b;
+        }
+    }
+
+    for (var t = 0u; t < probe_grid.texels_per_probe; t = t + 1u) {
+        let texel = vec4<f32>(packed[t * 4u], packed[t * 4u + 1u], packed[t * 4u + 2u], packed[t * 4u + 3u]);
+        textureStore(probes_out, vec3<i32>(probe + vec3<u32>(t * size.x, 0u, 0u)), texel);
+    }
+}
+
+fn probe_texel(grid_position: vec3<f32>, texel: u32) -> vec4<f32> {
+    let size = probe_grid.resolution;
+
+    // clamped to the centers of the outer probes, so it doesn't blend with the texels of the next coefficient
+    let position = clamp(grid_position, vec3<f32>(0.5), size - 0.5) + vec3<f32>(f32(texel) * size.x, 0.0, 0.0);
+    let texture_size = vec3<f32>(size.x * f32(probe_grid.texels_per_probe), size.y, size.z);
+
+    return textureSampleLevel(probes, probe_sampler, position / texture_size, 0.0);
+}
+
+[[stage(compute), workgroup_size(8, 8)]]
+fn resolve_probes([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy);
+    if (any(pixel >= textureDimensions(gbuffer_position))) {
+        return;
+    }
+
+    let position = textureLoad(gbuffer_position, pixel, 0);
+
+    // nothing was drawn here
+    if (position.w == 0.0 || gi_cascades.num_cascades == 0u) {
+        textureStore(indirect_diffuse, pixel, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+        textureStore(indirect_specular, pixel, vec4<f32>(0.0));
+        textureStore(trace_info, pixel, vec4<f32>(-1.0, 0.0, 0.0, 0.0));
+        return;
+    }
+
+    let normal = normalize(textureLoad(gbuffer_normal, pixel, 0).xyz);
+    let grid_position = (probe_grid.world_to_grid * vec4<f32>(position.xyz, 1.0)).xyz;
+
+    var packed: array<f32, 28>;
+    for (var t = 0u; t < probe_grid.texels_per_probe; t = t + 1u) {
+        let texel = probe_texel(grid_position, t);
+        packed[t * 4u] = texel.x;
+        packed[t * 4u + 1u] = texel.y;
+        packed[t * 4u + 2u] = texel.z;
+        packed[t * 4u + 3u] = texel.w;
+    }
+
+    var irradiance = vec3<f32>(0.0);
+    for (var k = 0u; k < probe_grid.coefficients; k = k + 1u) {
+        let coefficient = vec3<f32>(packed[k * 3u], packed[k * 3u + 1u], packed[k * 3u + 2u]);
+        irradiance = irradiance + coefficient * sh_cosine_lobe(k) * sh_basis(k, normal);
+    }
+
+    let cascade = sample_volume(position.xyz, voxel_size(gi_cascades.cascades[0])).cascade;
+
+    // divided by pi, so it's on the same scale as the diffuse cones
+    // probes have no ambient occlusion or specular, so those are left empty
+    textureStore(indirect_diffuse, pixel, vec4<f32>(max(irradiance / PI, vec3<f32>(0.0)) * gi_settings.indirect_intensity, 1.0));
+    textureStore(indirect_specular, pixel, vec4<f32>(0.0));
+    textureStore(trace_info, pixel, vec4<f32>(select(f32(cascade), -1.0, cascade == NO_CASCADE), 0.0, 0.0, 0.0));
+}
+
+// BOUNCES
+// every voxel of a dense cascade gathers the light around it, from 6 cones along the axes
+// that's added to the voxel, tinted by it's color, so the light has bounced once more when it's traced from the view
+// the voxels are written to a copy of the cascade, as the cascades are read while gathering
+
+// same layout as GpuGiBounce
+[[block]]
+struct Bounce {
+    // 0 - 1 over the cascade to world space
+    to_world: mat4x4<f32>;
+    cascade: u32;
+};
+
+[[group(3), binding(7)]]
+var<uniform> bounce: Bounce;
+
+// in the format of the cascades, which is changed before compiling
+[[group(3), binding(8)]]
+var bounce_out: texture_storage_3d<rgba32float, write>;
+
+// same as bounce_direction in cone_trace.rs
+fn bounce_direction(index: u32) -> vec3<f32> {
+    let axis = index / 2u;
+    let direction = vec3<f32>(select(0.0, 1.0, axis == 0u), select(0.0, 1.0, axis == 1u), select(0.0, 1.0, axis == 2u));
+    return select(direction, -direction, (index & 1u) == 1u);
+}
+
+[[stage(compute), workgroup_size(4, 4, 4)]]
+fn bounce_light([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let cascade = gi_cascades.cascades[bounce.cascade];
+    let texel = vec3<i32>(id);
+    if (any(texel >= vec3<i32>(cascade.resolution))) {
+        return;
+    }
+
+    let voxel = load_cascade(cascade.texture_index, texel, 0);
+    if (voxel.a <= 0.0) {
+        textureStore(bounce_out, texel, voxel);
+        return;
+    }
+
+    let uvw = (vec3<f32>(texel) + 0.5) / cascade.resolution;
+    let position = (bounce.to_world * vec4<f32>(uvw, 1.0)).xyz;
+
+    // out of the voxel first, so it doesn't gather from itself
+    var light = vec3<f32>(0.0);
+    for (var i = 0u; i < 6u; i = i + 1u) {
+        let direction = bounce_direction(i);
+        let cone = trace_cone(position + direction * voxel_size(cascade), direction, gi_settings.diffuse_aperture);
+        light = light + cone.color / 6.0;
+    }
+
+    textureStore(bounce_out, texel, vec4<f32>(voxel.rgb + voxel.rgb * light, voxel.a));
+}
+
+// COUNTERS
+// with GiDiagnosticsPlugin::gpu_counters, every voxel of the first mip of the dense cascades that isn't empty is counted
+// this runs after the bounces, so it sees what the tracer sees
+
+// same layout as in diagnostics.rs, voxelization counts the triangles, the cascades are written on the cpu
+[[block]]
+struct GiCounters {
+    triangles: atomic<u32>;
+    cascades_rebuilt: u32;
+    cascades_skipped: u32;
+    // for every traced cascade
+    voxels: array<atomic<u32>, 8>;
+};
+
+[[group(3), binding(9)]]
+var<storage, read_write> gi_counters: GiCounters;
+
+// every cascade has the same resolution, so one dispatch covers all of them
+[[stage(compute), workgroup_size(4, 4, 4)]]
+fn count_voxels([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let num_cascades = min(gi_cascades.num_cascades, MAX_TRACED_CASCADES);
+    let texel = vec3<i32>(id);
+
+    for (var i = 0u; i < num_cascades; i = i + 1u) {
+        let cascade = gi_cascades.cascades[i];
+        if (any(texel >= vec3<i32>(cascade.resolution))) {
+            return;
+        }
+
+        if (load_cascade(cascade.texture_index, texel, 0).a > 0.0) {
+            let previous = atomicAdd(&gi_counters.voxels[i], 1u);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+// HOW IT WORKS
+// replaces the final color with one of the outputs of the cone tracer, after the main pass
+// this is a single triangle covering the screen, that reads the trace targets of the view
+
+use crevice::std140::{AsStd140, Std140};
+
+use super::cone_trace::ViewGiTraceTargets;
+
+use bevy::ecs::prelude::*;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+	texture::BevyDefault,
+};
+
+/// what to show instead of the final color, to see what the cone tracer does
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiDebugOutput {
+	/// the normal final color
+	None = 0,
+	IndirectDiffuse = 1,
+	IndirectSpecular = 2,
+	/// ambient occlusion from the diffuse cones, where white is not occluded
+	AmbientOcclusion = 3,
+	/// a different color for every cascade the pixel is in, black when it's outside the volume
+	CascadeIndex = 4,
+	/// number of steps taken by all cones of a pixel, from blue (few) to red (many)
+	ConeSteps = 5,
+}
+
+impl Default for GiDebugOutput {
+	fn default() -> Self {
+		GiDebugOutput::None
+	}
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiDebugOutput {
+	mode: u32,
+}
+
+pub struct GiDebugOutputShaders {
+	pipeline: RenderPipeline,
+	output_layout: BindGroupLayout,
+}
+
+fn texture_entry(binding: u32) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::FRAGMENT,
+		ty: BindingType::Texture {
+			multisampled: false,
+			sample_type: TextureSampleType::Float { filterable: false },
+			view_dimension: TextureViewDimension::D2,
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for GiDebugOutputShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let shader = Shader::from_wgsl(include_str!("debug_output.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let output_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::FRAGMENT,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: false,
+						min_binding_size: BufferSize::new(GpuGiDebugOutput::std140_size_static() as u64),
+					},
+					count: None,
+				},
+				// indirect diffuse
+				texture_entry(1),
+				// indirect specular
+				texture_entry(2),
+				// trace info
+				texture_entry(3),
+			],
+			label: None,
+		});
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&output_layout],
+		});
+
+		let pipeline = render_device.create_render_pipeline(&RenderPipelineDescriptor {
+			label: None,
+			vertex: VertexState {
+				buffers: &[],
+				module: &shader_module,
+				entry_point: "vertex",
+			},
+			fragment: Some(FragmentState {
+				module: &shader_module,
+				entry_point: "fragment",
+				targets: &[ColorTargetState {
+					format: TextureFormat::bevy_default(),
+					blend: None,
+					write_mask: ColorWrite::ALL,
+				}],
+			}),
+			depth_stencil: None,
+			layout: Some(&pipeline_layout),
+			multisample: MultisampleState::default(),
+			primitive: PrimitiveState {
+				topology: PrimitiveTopology::TriangleList,
+				strip_index_format: None,
+				front_face: FrontFace::Ccw,
+				cull_mode: None,
+				polygon_mode: PolygonMode::Fill,
+				clamp_depth: false,
+				conservative: false,
+			},
+		});
+
+		GiDebugOutputShaders {
+			pipeline,
+			output_layout,
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct GiDebugOutputMeta {
+	pub uniform: Option<Buffer>,
+}
+
+pub struct ViewGiDebugOutputBindGroup {
+	pub bind_group: BindGroup,
+}
+
+pub fn extract_gi_debug_output(mut commands: Commands, debug_output: Res<GiDebugOutput>) {
+	commands.insert_resource(*debug_output);
+}
+
+pub fn prepare_gi_debug_output(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	debug_output: Res<GiDebugOutput>,
+	mut output_meta: ResMut<GiDebugOutputMeta>,
+) {
+	if *debug_output == GiDebugOutput::None {
+		return;
+	}
+
+	let uniform = output_meta.uniform.get_or_insert_with(|| {
+		render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: GpuGiDebugOutput::std140_size_static() as u64,
+			usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		})
+	});
+
+	render_queue.write_buffer(
+		uniform,
+		0,
+		GpuGiDebugOutput {
+			mode: *debug_output as u32,
+		}
+		.as_std140()
+		.as_bytes(),
+	);
+}
+
+pub fn queue_gi_debug_output_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	output_shaders: Res<GiDebugOutputShaders>,
+	debug_output: Res<GiDebugOutput>,
+	output_meta: Res<GiDebugOutputMeta>,
+	views: Query<(Entity, &ViewGiTraceTargets)>,
+) {
+	let uniform = match &output_meta.uniform {
+		Some(uniform) if *debug_output != GiDebugOutput::None => uniform,
+		_ => return,
+	};
+
+	for (entity, targets) in views.iter() {
+		let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: uniform.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: BindingResource::TextureView(&targets.indirect_diffuse),
+				},
+				BindGroupEntry {
+					binding: 2,
+					resource: BindingResource::TextureView(&targets.indirect_specular),
+				},
+				BindGroupEntry {
+					binding: 3,
+					resource: BindingResource::TextureView(&targets.trace_info),
+				},
+			],
+			label: None,
+			layout: &output_shaders.output_layout,
+		});
+
+		commands.entity(entity).insert(ViewGiDebugOutputBindGroup { bind_group });
+	}
+}
+
+/// draws the chosen cone tracing output over the final color
+pub struct GiDebugOutputNode {
+	view_query: QueryState<&'static ViewGiDebugOutputBindGroup>,
+}
+
+impl GiDebugOutputNode {
+	pub const IN_VIEW: &'static str = "view";
+	pub const IN_COLOR_ATTACHMENT: &'static str = "color_attachment";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiDebugOutputNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![
+			SlotInfo::new(GiDebugOutputNode::IN_VIEW, SlotType::Entity),
+			SlotInfo::new(GiDebugOutputNode::IN_COLOR_ATTACHMENT, SlotType::TextureView),
+		]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+		let color_attachment = graph.get_input_texture(Self::IN_COLOR_ATTACHMENT)?;
+
+		// no bind group when it's off, or when nothing was traced
+		let bind_group = match self.view_query.get_manual(world, view_entity) {
+			Ok(bind_group) => bind_group,
+			Err(_) => return Ok(()),
+		};
+
+		let output_shaders = world.get_resource::<GiDebugOutputShaders>().unwrap();
+
+		let mut pass = render_context
+			.command_encoder
+			.begin_render_pass(&RenderPassDescriptor {
+				label: Some("gi_debug_output"),
+				// everything is drawn over
+				color_attachments: &[RenderPassColorAttachment {
+					view: color_attachment,
+					resolve_target: None,
+					ops: Operations {
+						load: LoadOp::Load,
+						store: true,
+					},
+				}],
+				depth_stencil_attachment: None,
+			});
+
+		pass.set_pipeline(&output_shaders.pipeline);
+		pass.set_bind_group(0, &bind_group.bind_group, &[]);
+		pass.draw(0..3, 0..1);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,87 @@
+// draws one of the cone tracing outputs over the whole screen
+
+[[block]]
+struct GiDebugOutput {
+    mode: u32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> debug_output: GiDebugOutput;
+
+[[group(0), binding(1)]]
+var indirect_diffuse: texture_2d<f32>;
+
+[[group(0), binding(2)]]
+var indirect_specular: texture_2d<f32>;
+
+[[group(0), binding(3)]]
+var trace_info: texture_2d<f32>;
+
+// same as GiDebugOutput
+let MODE_INDIRECT_DIFFUSE: u32 = 1u;
+let MODE_INDIRECT_SPECULAR: u32 = 2u;
+let MODE_AMBIENT_OCCLUSION: u32 = 3u;
+let MODE_CASCADE_INDEX: u32 = 4u;
+let MODE_CONE_STEPS: u32 = 5u;
+
+// this many steps or more is fully red
+let HEATMAP_MAX_STEPS: f32 = 256.0;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+};
+
+// a single triangle that covers the screen
+[[stage(vertex)]]
+fn vertex([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+// same as in debug_view.wgsl
+fn cascade_tint(cascade: u32) -> vec3<f32> {
+    let index = cascade % 4u;
+    if (index == 0u) {
+        return vec3<f32>(1.0, 0.2, 0.2);
+    } elseif (index == 1u) {
+        return vec3<f32>(0.2, 1.0, 0.2);
+    } elseif (index == 2u) {
+        return vec3<f32>(0.2, 0.2, 1.0);
+    }
+    return vec3<f32>(1.0, 1.0, 0.2);
+}
+
+// blue to green to red
+fn heatmap(value: f32) -> vec3<f32> {
+    let t = clamp(value, 0.0, 1.0);
+    if (t < 0.5) {
+        return mix(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), t * 2.0);
+    }
+    return mix(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), t * 2.0 - 1.0);
+}
+
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let pixel = vec2<i32>(in.clip_position.xy);
+
+    var color = vec3<f32>(0.0);
+    if (debug_output.mode == MODE_INDIRECT_DIFFUSE) {
+        color = textureLoad(indirect_diffuse, pixel, 0).rgb;
+    } elseif (debug_output.mode == MODE_INDIRECT_SPECULAR) {
+        color = textureLoad(indirect_specular, pixel, 0).rgb;
+    } elseif (debug_output.mode == MODE_AMBIENT_OCCLUSION) {
+        color = vec3<f32>(textureLoad(indirect_diffuse, pixel, 0).a);
+    } elseif (debug_output.mode == MODE_CASCADE_INDEX) {
+        let cascade = textureLoad(trace_info, pixel, 0).r;
+        if (cascade >= 0.0) {
+            color = cascade_tint(u32(cascade));
+        }
+    } elseif (debug_output.mode == MODE_CONE_STEPS) {
+        color = heatmap(textureLoad(trace_info, pixel, 0).g / HEATMAP_MAX_STEPS);
+    }
+
+    return vec4<f32>(color, 1.0);
+}
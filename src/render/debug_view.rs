@@ -0,0 +1,388 @@
+// HOW IT WORKS
+// draws every voxel of one mip of one cascade as a cube, after the main pass
+// the cube is made in the vertex shader from the vertex and instance index, so there are no buffers needed
+// empty voxels are moved outside of the screen
+// only dense cascades have textures to look at, so this does nothing for the sparse storages
+
+use crevice::std140::{AsStd140, Std140};
+
+use crate::bundle::GiStorage;
+use crate::voxelize::cascade_scale;
+
+use super::gi_volume::{cascade_mip_count, ExtractedGiVolume, ViewGiVolumes};
+
+use bevy::ecs::prelude::*;
+use bevy::math::{Mat4, UVec3};
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+	texture::BevyDefault,
+	view::{ViewMeta, ViewUniformOffset},
+};
+
+/// what to show of a voxel in the debug view
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiDebugChannel {
+	/// color of the voxel, without opacity
+	Albedo = 0,
+	/// opacity of the voxel, as grayscale
+	Opacity = 1,
+	/// the light stored in the voxel, which is what the cone tracer sees
+	Radiance = 2,
+	/// surface normal, from how the opacity changes around the voxel
+	Normal = 3,
+	/// a different color for every cascade, to see where they are
+	CascadeTint = 4,
+}
+
+/// draws the voxels of a cascade as cubes, to see what got voxelized
+///
+/// only works for `GiStorage::Dense`, as the sparse storages don't have a texture per cascade
+#[derive(Copy, Clone, Debug)]
+pub struct GiDebugView {
+	pub enabled: bool,
+	/// cascade to show, limited to the cascades the volume has
+	pub cascade: u32,
+	/// mip to show, limited to the mips the cascade has
+	pub mip: u32,
+	pub channel: GiDebugChannel,
+}
+
+impl Default for GiDebugView {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			cascade: 0,
+			mip: 0,
+			channel: GiDebugChannel::Albedo,
+		}
+	}
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiDebugView {
+	// voxels of the mip to world space
+	voxel_to_world: Mat4,
+	cascade: u32,
+	mip: u32,
+	channel: u32,
+}
+
+pub struct GiDebugViewShaders {
+	pipeline: RenderPipeline,
+	view_layout: BindGroupLayout,
+	debug_layout: BindGroupLayout,
+}
+
+impl FromWorld for GiDebugViewShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let shader = Shader::from_wgsl(include_str!("debug_view.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::VERTEX,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						// same as in GiShaders
+						min_binding_size: BufferSize::new(80),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let debug_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::VERTEX,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: false,
+						min_binding_size: BufferSize::new(GpuGiDebugView::std140_size_static() as u64),
+					},
+					count: None,
+				},
+				// the cascade, with all mips
+				BindGroupLayoutEntry {
+					binding: 1,
+					visibility: ShaderStage::VERTEX,
+					ty: BindingType::Texture {
+						multisampled: false,
+						// rgba32float can't be filtered, but it's only loaded from here
+						sample_type: TextureSampleType::Float { filterable: false },
+						view_dimension: TextureViewDimension::D3,
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&view_layout, &debug_layout],
+		});
+
+		let pipeline = render_device.create_render_pipeline(&RenderPipelineDescriptor {
+			label: None,
+			vertex: VertexState {
+				buffers: &[],
+				module: &shader_module,
+				entry_point: "vertex",
+			},
+			fragment: Some(FragmentState {
+				module: &shader_module,
+				entry_point: "fragment",
+				targets: &[ColorTargetState {
+					format: TextureFormat::bevy_default(),
+					blend: None,
+					write_mask: ColorWrite::ALL,
+				}],
+			}),
+			// tested against the main pass, so the cubes end up inside the scene
+			depth_stencil: Some(DepthStencilState {
+				format: TextureFormat::Depth32Float,
+				depth_write_enabled: true,
+				depth_compare: CompareFunction::Less,
+				stencil: StencilState::default(),
+				bias: DepthBiasState::default(),
+			}),
+			layout: Some(&pipeline_layout),
+			multisample: MultisampleState::default(),
+			primitive: PrimitiveState {
+				topology: PrimitiveTopology::TriangleList,
+				strip_index_format: None,
+				front_face: FrontFace::Ccw,
+				cull_mode: Some(Face::Back),
+				polygon_mode: PolygonMode::Fill,
+				clamp_depth: false,
+				conservative: false,
+			},
+		});
+
+		GiDebugViewShaders {
+			pipeline,
+			view_layout,
+			debug_layout,
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct GiDebugViewMeta {
+	pub uniform: Option<Buffer>,
+	/// cascade that is shown, or None when there is nothing to show
+	pub cascade: Option<u32>,
+	/// number of voxels along each axis in the mip that is shown
+	pub mip_resolution: UVec3,
+	pub view_bind_group: Option<BindGroup>,
+}
+
+/// bind group with the cascade that is shown for a view
+pub struct ViewGiDebugBindGroup {
+	pub bind_group: BindGroup,
+}
+
+pub fn extract_gi_debug_view(mut commands: Commands, debug_view: Res<GiDebugView>) {
+	commands.insert_resource(*debug_view);
+}
+
+pub fn prepare_gi_debug_view(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	debug_view: Res<GiDebugView>,
+	mut debug_meta: ResMut<GiDebugViewMeta>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	let volume = match volume {
+		Some(volume) if debug_view.enabled && volume.storage == GiStorage::Dense && volume.cascades > 0 => volume,
+		_ => {
+			debug_meta.cascade = None;
+			return;
+		}
+	};
+
+	// there's nothing to show past the last cascade or mip, so show those instead
+	let cascade = debug_view.cascade.min(volume.cascades as u32 - 1);
+	let mip = debug_view.mip.min(cascade_mip_count(volume.resolution) - 1);
+	let mip_resolution = UVec3::new(
+		(volume.resolution.x >> mip).max(1),
+		(volume.resolution.y >> mip).max(1),
+		(volume.resolution.z >> mip).max(1),
+	);
+
+	// same as the projection in prepare_gi_cascades, but the other way around, and in voxels
+	let cascade_extent = volume.extent * cascade_scale(cascade);
+	let voxel_to_world = volume.transform.compute_matrix()
+		* Mat4::from_translation(cascade_extent * -0.5)
+		* Mat4::from_scale(cascade_extent / mip_resolution.as_f32());
+
+	let uniform = debug_meta.uniform.get_or_insert_with(|| {
+		render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: GpuGiDebugView::std140_size_static() as u64,
+			usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		})
+	});
+
+	render_queue.write_buffer(
+		uniform,
+		0,
+		GpuGiDebugView {
+			voxel_to_world,
+			cascade,
+			mip,
+			channel: debug_view.channel as u32,
+		}
+		.as_std140()
+		.as_bytes(),
+	);
+
+	debug_meta.cascade = Some(cascade);
+	debug_meta.mip_resolution = mip_resolution;
+}
+
+pub fn queue_gi_debug_view_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	debug_shaders: Res<GiDebugViewShaders>,
+	view_meta: Res<ViewMeta>,
+	mut debug_meta: ResMut<GiDebugViewMeta>,
+	views: Query<(Entity, &ViewGiVolumes)>,
+) {
+	let debug_meta = &mut *debug_meta;
+
+	let (cascade, uniform, view_binding) = match (debug_meta.cascade, &debug_meta.uniform, view_meta.uniforms.binding()) {
+		(Some(cascade), Some(uniform), Some(view_binding)) => (cascade, uniform, view_binding),
+		_ => return,
+	};
+
+	debug_meta.view_bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: view_binding,
+			},
+		],
+		label: None,
+		layout: &debug_shaders.view_layout,
+	}));
+
+	for (entity, view_volumes) in views.iter() {
+		let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: uniform.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: BindingResource::TextureView(&view_volumes.cascade(cascade).texture_view),
+				},
+			],
+			label: None,
+			layout: &debug_shaders.debug_layout,
+		});
+
+		commands.entity(entity).insert(ViewGiDebugBindGroup { bind_group });
+	}
+}
+
+/// draws the voxels on top of the main pass
+pub struct GiDebugViewNode {
+	view_query: QueryState<(&'static ViewUniformOffset, &'static ViewGiDebugBindGroup)>,
+}
+
+impl GiDebugViewNode {
+	pub const IN_VIEW: &'static str = "view";
+	pub const IN_COLOR_ATTACHMENT: &'static str = "color_attachment";
+	pub const IN_DEPTH: &'static str = "depth";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiDebugViewNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![
+			SlotInfo::new(GiDebugViewNode::IN_VIEW, SlotType::Entity),
+			SlotInfo::new(GiDebugViewNode::IN_COLOR_ATTACHMENT, SlotType::TextureView),
+			SlotInfo::new(GiDebugViewNode::IN_DEPTH, SlotType::TextureView),
+		]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+		let color_attachment = graph.get_input_texture(Self::IN_COLOR_ATTACHMENT)?;
+		let depth = graph.get_input_texture(Self::IN_DEPTH)?;
+
+		let debug_meta = world.get_resource::<GiDebugViewMeta>().unwrap();
+		let debug_shaders = world.get_resource::<GiDebugViewShaders>().unwrap();
+
+		// disabled, or nothing to show
+		let view_bind_group = match (debug_meta.cascade, &debug_meta.view_bind_group) {
+			(Some(_), Some(view_bind_group)) => view_bind_group,
+			_ => return Ok(()),
+		};
+
+		let (view_uniform_offset, debug_bind_group) = match self.view_query.get_manual(world, view_entity) {
+			Ok(query) => query,
+			Err(_) => return Ok(()),
+		};
+
+		let mut pass = render_context
+			.command_encoder
+			.begin_render_pass(&RenderPassDescriptor {
+				label: Some("gi_debug_view"),
+				// keep what the main pass drew
+				color_attachments: &[RenderPassColorAttachment {
+					view: color_attachment,
+					resolve_target: None,
+					ops: Operations {
+						load: LoadOp::Load,
+						store: true,
+					},
+				}],
+				depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+					view: depth,
+					depth_ops: Some(Operations {
+						load: LoadOp::Load,
+						store: true,
+					}),
+					stencil_ops: None,
+				}),
+			});
+
+		pass.set_pipeline(&debug_shaders.pipeline);
+		pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+		pass.set_bind_group(1, &debug_bind_group.bind_group, &[]);
+
+		// 6 faces of 2 triangles for every voxel
+		let resolution = debug_meta.mip_resolution;
+		pass.draw(0..36, 0..resolution.x * resolution.y * resolution.z);
+
+		Ok(())
+	}
+}
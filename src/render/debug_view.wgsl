@@ -0,0 +1,156 @@
+// draws every voxel of a cascade as a cube
+// the instance is the voxel, and the vertex is one of the 36 corners of the triangles of the cube
+
+[[block]]
+struct View {
+    view_proj: mat4x4<f32>;
+    world_position: vec3<f32>;
+};
+
+[[block]]
+struct GiDebugView {
+    // voxels of the mip to world space
+    voxel_to_world: mat4x4<f32>;
+    cascade: u32;
+    mip: u32;
+    channel: u32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> view: View;
+
+[[group(1), binding(0)]]
+var<uniform> debug_view: GiDebugView;
+
+[[group(1), binding(1)]]
+var cascade_texture: texture_3d<f32>;
+
+// same as GiDebugChannel
+let CHANNEL_ALBEDO: u32 = 0u;
+let CHANNEL_OPACITY: u32 = 1u;
+let CHANNEL_RADIANCE: u32 = 2u;
+let CHANNEL_NORMAL: u32 = 3u;
+let CHANNEL_CASCADE_TINT: u32 = 4u;
+
+// voxels with less opacity than this aren't drawn
+let MIN_OPACITY: f32 = 0.01;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] color: vec4<f32>;
+};
+
+fn load_voxel(voxel: vec3<i32>) -> vec4<f32> {
+    let size = textureDimensions(cascade_texture, i32(debug_view.mip));
+    return textureLoad(cascade_texture, clamp(voxel, vec3<i32>(0), size - vec3<i32>(1)), i32(debug_view.mip));
+}
+
+// points away from where the opacity increases
+fn voxel_normal(voxel: vec3<i32>, face_normal: vec3<f32>) -> vec3<f32> {
+    let gradient = vec3<f32>(
+        load_voxel(voxel + vec3<i32>(1, 0, 0)).a - load_voxel(voxel - vec3<i32>(1, 0, 0)).a,
+        load_voxel(voxel + vec3<i32>(0, 1, 0)).a - load_voxel(voxel - vec3<i32>(0, 1, 0)).a,
+        load_voxel(voxel + vec3<i32>(0, 0, 1)).a - load_voxel(voxel - vec3<i32>(0, 0, 1)).a,
+    );
+
+    // surrounded by the same opacity on all sides, so there's no surface to get a normal from
+    if (dot(gradient, gradient) < 0.0001) {
+        return face_normal;
+    }
+
+    return -normalize(gradient);
+}
+
+fn cascade_tint(cascade: u32) -> vec3<f32> {
+    let index = cascade % 4u;
+    if (index == 0u) {
+        return vec3<f32>(1.0, 0.2, 0.2);
+    } elseif (index == 1u) {
+        return vec3<f32>(0.2, 1.0, 0.2);
+    } elseif (index == 2u) {
+        return vec3<f32>(0.2, 0.2, 1.0);
+    }
+    return vec3<f32>(1.0, 1.0, 0.2);
+}
+
+[[stage(vertex)]]
+fn vertex(
+    [[builtin(vertex_index)]] vertex_index: u32,
+    [[builtin(instance_index)]] instance_index: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+
+    let size = vec3<u32>(textureDimensions(cascade_texture, i32(debug_view.mip)));
+    let voxel = vec3<u32>(
+        instance_index % size.x,
+        (instance_index / size.x) % size.y,
+        instance_index / (size.x * size.y),
+    );
+
+    let value = load_voxel(vec3<i32>(voxel));
+
+    // empty, so move it out of view
+    if (value.a < MIN_OPACITY) {
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+        out.color = vec4<f32>(0.0);
+        return out;
+    }
+
+    // which face, and which corner of the 2 triangles of it
+    let face = vertex_index / 6u;
+    let axis = face / 2u;
+    let side = f32(face % 2u);
+    var corner = vertex_index % 6u;
+    if (corner >= 3u) {
+        // second triangle is 0 2 3 of the quad
+        corner = select(corner - 1u, 0u, corner == 3u);
+    }
+
+    var uv = vec2<f32>(select(0.0, 1.0, corner == 1u || corner == 2u), select(0.0, 1.0, corner >= 2u));
+
+    // flip the winding for the faces pointing in the negative direction, so they stay counter clockwise from the outside
+    if (side == 0.0) {
+        uv = uv.yx;
+    }
+
+    var local: vec3<f32>;
+    var face_normal: vec3<f32>;
+    if (axis == 0u) {
+        local = vec3<f32>(side, uv.x, uv.y);
+        face_normal = vec3<f32>(side * 2.0 - 1.0, 0.0, 0.0);
+    } elseif (axis == 1u) {
+        local = vec3<f32>(uv.y, side, uv.x);
+        face_normal = vec3<f32>(0.0, side * 2.0 - 1.0, 0.0);
+    } else {
+        local = vec3<f32>(uv.x, uv.y, side);
+        face_normal = vec3<f32>(0.0, 0.0, side * 2.0 - 1.0);
+    }
+
+    let world_position = debug_view.voxel_to_world * vec4<f32>(vec3<f32>(voxel) + local, 1.0);
+    out.clip_position = view.view_proj * world_position;
+
+    // a bit of shading per face, so the cubes can be told apart
+    let shade = 0.6 + 0.4 * abs(dot(face_normal, normalize(vec3<f32>(0.3, 1.0, 0.6))));
+
+    // voxels are premultiplied
+    var color: vec3<f32>;
+    if (debug_view.channel == CHANNEL_ALBEDO) {
+        color = value.rgb / value.a * shade;
+    } elseif (debug_view.channel == CHANNEL_OPACITY) {
+        color = vec3<f32>(value.a);
+    } elseif (debug_view.channel == CHANNEL_RADIANCE) {
+        color = value.rgb;
+    } elseif (debug_view.channel == CHANNEL_NORMAL) {
+        color = voxel_normal(vec3<i32>(voxel), face_normal) * 0.5 + 0.5;
+    } else {
+        color = cascade_tint(debug_view.cascade) * shade;
+    }
+
+    out.color = vec4<f32>(color, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return in.color;
+}
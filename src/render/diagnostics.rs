@@ -0,0 +1,757 @@
+// HOW IT WORKS
+// the nodes write a timestamp before and after their part of the gi, with begin_gi_timer and end_gi_timer
+// a part can be spread over a few nodes or passes, each of them is timed as a segment, and the segments are added up
+// which queries were written is kept in a bitset, given to the readback with the buffer, so parts that didn't run aren't measured
+// the query set grows to fit a segment for every slab of every cascade in every view, and warns once when a part still runs out
+// after the frame, the timestamps are resolved to a buffer and read back a few frames later, without waiting for the gpu
+// the times go to the app world through a resource both worlds share, where they're added to the diagnostics
+// the memory of the cascades and the sparse storages is added up in the render world, where they're allocated, and shared the same way
+// with gpu_counters, voxelization counts the triangles and a pass after the bounces the voxels into a buffer, which is read back like the timestamps
+// the cascades rebuilt and skipped are decided on the cpu, and written into that buffer when it's cleared, so they come back with the rest of the frame
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::bounce::ViewGiBounceTargets;
+use super::cone_trace::{ConeTraceMeta, ConeTraceShaders, ViewGiTraceBindGroup, MAX_TRACED_CASCADES};
+use super::draw_3d_graph;
+use super::brick_map::BrickMapMeta;
+use super::gi_volume::{cascade_texture_bytes, voxel_sum_layers, ExtractedGiVolume, GiCascadeLimit, GiCascadeMeta, GiShaders, ViewGiVolumes};
+use super::readback::GiAsyncReadback;
+use super::sparse_octree::SparseOctreeMeta;
+use super::voxel_fragments::VoxelFragmentMeta;
+
+use bevy::app::prelude::*;
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::ecs::prelude::*;
+use bevy::log::{info, warn};
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::{CommandEncoder, CommandEncoderDescriptor, Features, QuerySet, QuerySetDescriptor, QueryType, *},
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	view::{ExtractedView, ViewUniformOffset},
+	RenderStage,
+};
+
+/// adds how long each part of the gi takes on the gpu to the diagnostics, in milliseconds, and how much memory the cascades take
+///
+/// the device has to be made with `Features::TIMESTAMP_QUERY`, without it there are no times
+/// bevy doesn't ask for it, so timing is off unless the app opts in through `WgpuOptions::features`, and the adapter has it
+/// has to be added after `GiPlugin`
+#[derive(Default)]
+pub struct GiDiagnosticsPlugin {
+	/// also count the triangles voxelized, the cascades rebuilt and skipped, and the voxels that aren't empty
+	///
+	/// this takes an extra pass over the dense cascades every frame
+	pub gpu_counters: bool,
+}
+
+impl GiDiagnosticsPlugin {
+	pub const VOXELIZE_MS: DiagnosticId = DiagnosticId::from_u128(101716235823458927340983724101258329761);
+	pub const INJECT_MS: DiagnosticId = DiagnosticId::from_u128(179302846159273608415926037481920563718);
+	pub const BOUNCE_MS: DiagnosticId = DiagnosticId::from_u128(218945327740268437021358392046135876230);
+	pub const MIP_MS: DiagnosticId = DiagnosticId::from_u128(64238175903462219867153024876510923417);
+	pub const TRACE_MS: DiagnosticId = DiagnosticId::from_u128(287634095162380459123760451289034672109);
+	/// all cascades of the volume, including the copies the bounces write to, the sparse storages and what voxelization needs
+	pub const MEMORY_MB: DiagnosticId = DiagnosticId::from_u128(153908267415390826731094582637104598213);
+	pub const TRIANGLES: DiagnosticId = DiagnosticId::from_u128(92746103582946710358291046381057293846);
+	pub const CASCADES_REBUILT: DiagnosticId = DiagnosticId::from_u128(240581937460192837465019283746510293847);
+	pub const CASCADES_SKIPPED: DiagnosticId = DiagnosticId::from_u128(176093845710293846571029384657102938465);
+
+	// the ids of the cascades follow these
+	const CASCADE_MEMORY_MB: u128 = 31859027461038579201746385920174638500;
+	const CASCADE_VOXELS: u128 = 260419385720194857302918475610293847500;
+
+	/// the memory of a single cascade
+	pub fn cascade_memory_id(cascade: u32) -> DiagnosticId {
+		DiagnosticId::from_u128(Self::CASCADE_MEMORY_MB + cascade as u128)
+	}
+
+	/// the voxels that aren't empty in a single cascade, only for the traced cascades
+	pub fn cascade_voxels_id(cascade: u32) -> DiagnosticId {
+		DiagnosticId::from_u128(Self::CASCADE_VOXELS + cascade as u128)
+	}
+}
+
+impl Plugin for GiDiagnosticsPlugin {
+	fn build(&self, app: &mut App) {
+		let times = GiGpuTimes::default();
+		let stats = GiGpuStats::default();
+
+		app.insert_resource(times.clone())
+			.insert_resource(stats.clone())
+			.add_startup_system(setup_gi_diagnostics.system())
+			.add_system(add_gi_diagnostics.system())
+			.add_system(add_gi_stat_diagnostics.system());
+
+		let render_app = app.sub_app_mut(0);
+
+		render_app
+			.insert_resource(stats)
+			// after prepare, so the views have their cascades
+			.add_system_to_stage(RenderStage::Queue, measure_gi_memory.system());
+
+		if self.gpu_counters {
+			render_app
+				.init_resource::<GiCounters>()
+				// after prepare_gi_cascades, which decides what's skipped
+				.add_system_to_stage(RenderStage::Queue, prepare_gi_counters.system())
+				.add_system_to_stage(RenderStage::Cleanup, read_gi_counters.system());
+
+			let count_node = GiCountPassNode::new(&mut render_app.world);
+			let mut graph = render_app.world.get_resource_mut::<RenderGraph>().unwrap();
+
+			// after the bounces, so the voxels are counted as they're traced
+			let draw_3d_graph = graph
+				.get_sub_graph_mut(bevy_core_pipeline::draw_3d_graph::NAME)
+				.unwrap();
+			draw_3d_graph.add_node(draw_3d_graph::node::GI_COUNT_PASS, count_node);
+			for node in [draw_3d_graph::node::VOXELIZE_PASS, draw_3d_graph::node::GI_BOUNCE_PASS] {
+				draw_3d_graph
+					.add_node_edge(node, draw_3d_graph::node::GI_COUNT_PASS)
+					.unwrap();
+			}
+			draw_3d_graph
+				.add_node_edge(draw_3d_graph::node::GI_COUNT_PASS, draw_3d_graph::node::CONE_TRACE_PASS)
+				.unwrap();
+			draw_3d_graph
+				.add_slot_edge(
+					draw_3d_graph.input_node().unwrap().id,
+					bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+					draw_3d_graph::node::GI_COUNT_PASS,
+					GiCountPassNode::IN_VIEW,
+				)
+				.unwrap();
+		}
+
+		let features = render_app.world.get_resource::<RenderDevice>().unwrap().wgpu_device().features();
+		if !features.contains(Features::TIMESTAMP_QUERY) {
+			info!("the device doesn't support timestamp queries, so the gi passes aren't timed");
+			return;
+		}
+
+		render_app
+			.insert_resource(times)
+			.init_resource::<GiTimestamps>()
+			.add_system_to_stage(RenderStage::Prepare, prepare_gi_timestamps.system())
+			// after rendering, so all timestamps are written
+			.add_system_to_stage(
+				RenderStage::Cleanup,
+				resolve_gi_timestamps.system(),
+			);
+	}
+}
+
+/// the parts of the gi that are timed on the gpu
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GiTimedPass {
+	/// skinning, voxelization, and building the sparse storages
+	Voxelize,
+	/// turning what voxelization added up into the light the voxels of the dense cascades hold
+	Inject,
+	/// bouncing light between the voxels, with the mips made again after every bounce
+	Bounce,
+	/// mipmapping the dense cascades and the sparse storages
+	Mip,
+	/// the gbuffer, tracing, upsampling and the temporal resolve
+	Trace,
+}
+
+impl GiTimedPass {
+	pub const ALL: [GiTimedPass; 5] = [
+		GiTimedPass::Voxelize,
+		GiTimedPass::Inject,
+		GiTimedPass::Bounce,
+		GiTimedPass::Mip,
+		GiTimedPass::Trace,
+	];
+
+	pub fn diagnostic_id(&self) -> DiagnosticId {
+		match self {
+			GiTimedPass::Voxelize => GiDiagnosticsPlugin::VOXELIZE_MS,
+			GiTimedPass::Inject => GiDiagnosticsPlugin::INJECT_MS,
+			GiTimedPass::Bounce => GiDiagnosticsPlugin::BOUNCE_MS,
+			GiTimedPass::Mip => GiDiagnosticsPlugin::MIP_MS,
+			GiTimedPass::Trace => GiDiagnosticsPlugin::TRACE_MS,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			GiTimedPass::Voxelize => "gi/voxelize_ms",
+			GiTimedPass::Inject => "gi/inject_ms",
+			GiTimedPass::Bounce => "gi/bounce_ms",
+			GiTimedPass::Mip => "gi/mip_ms",
+			GiTimedPass::Trace => "gi/trace_ms",
+		}
+	}
+
+	// the start of a segment, the end is the one after it
+	fn query(&self, segment: u32, segments_per_pass: u32) -> u32 {
+		(*self as u32 * segments_per_pass + segment) * 2
+	}
+}
+
+// segments every part can time in a frame before it knows the volume
+const MIN_TIMER_SEGMENTS: u32 = 8;
+
+// the most queries a query set can have in wgpu
+const MAX_QUERIES: u32 = 8192;
+
+// a timestamp is a u64
+const QUERY_SIZE: u64 = 8;
+
+/// which queries were written, a bit each, and how many segments every part had then, as that's where the queries are
+#[derive(Default)]
+struct WrittenQueries {
+	bits: Vec<u64>,
+	segments_per_pass: u32,
+}
+
+impl WrittenQueries {
+	fn contains(&self, query: u32) -> bool {
+		self.bits
+			.get(query as usize / 64)
+			.map_or(false, |bits| bits & (1 << (query % 64)) != 0)
+	}
+}
+
+/// the last times read back from the gpu, in milliseconds, shared between the app and render world
+///
+/// parts that didn't run that frame are None
+#[derive(Clone, Default)]
+pub struct GiGpuTimes(Arc<Mutex<Option<[Option<f64>; GiTimedPass::ALL.len()]>>>);
+
+/// the query set the nodes write the timestamps to, only there when the device supports it
+pub struct GiTimestamps {
+	query_set: QuerySet,
+	/// how many segments every part can time in a frame, grown to fit the slabs of the volume in `prepare_gi_timestamps`
+	segments_per_pass: u32,
+	/// a bit for every query written this frame, so parts that didn't run aren't measured
+	written: Vec<AtomicU64>,
+	/// the segments every part began this frame
+	segments: [AtomicU32; GiTimedPass::ALL.len()],
+	/// set when a part began more segments than there's room for, which are then left out of it's time
+	overflowed: AtomicBool,
+	warned: bool,
+	/// nanoseconds per tick
+	period: f32,
+	readback: GiAsyncReadback<WrittenQueries>,
+}
+
+impl FromWorld for GiTimestamps {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let render_queue = world.get_resource::<RenderQueue>().unwrap();
+
+		let mut timestamps = Self {
+			query_set: create_gi_query_set(render_device, 0),
+			segments_per_pass: 0,
+			written: Vec::new(),
+			segments: Default::default(),
+			overflowed: AtomicBool::new(false),
+			warned: false,
+			period: render_queue.get_timestamp_period(),
+			readback: GiAsyncReadback::new("gi_timestamp_readback", 0),
+		};
+		timestamps.resize(render_device, MIN_TIMER_SEGMENTS);
+
+		timestamps
+	}
+}
+
+fn create_gi_query_set(render_device: &RenderDevice, count: u32) -> QuerySet {
+	render_device.wgpu_device().create_query_set(&QuerySetDescriptor {
+		label: Some("gi_timestamps"),
+		ty: QueryType::Timestamp,
+		// a query set can't be empty
+		count: count.max(1),
+	})
+}
+
+impl GiTimestamps {
+	fn query_count(&self) -> u32 {
+		GiTimedPass::ALL.len() as u32 * self.segments_per_pass * 2
+	}
+
+	// the readbacks still in flight are dropped, as they have the old size
+	fn resize(&mut self, render_device: &RenderDevice, segments_per_pass: u32) {
+		self.segments_per_pass = segments_per_pass;
+
+		let query_count = self.query_count();
+		self.query_set = create_gi_query_set(render_device, query_count);
+		self.written = (0..(query_count as usize + 63) / 64).map(|_| AtomicU64::new(0)).collect();
+		self.readback = GiAsyncReadback::new("gi_timestamp_readback", query_count as u64 * QUERY_SIZE);
+	}
+
+	fn write(&self, encoder: &mut CommandEncoder, query: u32) {
+		encoder.write_timestamp(&self.query_set, query);
+		self.written[query as usize / 64].fetch_or(1 << (query % 64), Ordering::Relaxed);
+	}
+}
+
+/// makes room for a segment of voxelization and injection for every slab of every cascade, in every view
+pub fn prepare_gi_timestamps(
+	render_device: Res<RenderDevice>,
+	volume: Option<Res<ExtractedGiVolume>>,
+	views: Query<Entity, With<ExtractedView>>,
+	mut timestamps: ResMut<GiTimestamps>,
+) {
+	let volume = match volume {
+		Some(volume) => volume,
+		None => return,
+	};
+
+	let slab_layers = voxel_sum_layers(volume.resolution);
+	let slabs = (volume.resolution.z + slab_layers - 1) / slab_layers;
+
+	// skinning and building the sparse storages also time voxelization, and tracing takes a few nodes
+	let per_view = volume.cascades as u32 * slabs + 3;
+	let max_segments = MAX_QUERIES / (GiTimedPass::ALL.len() as u32 * 2);
+	let segments_per_pass = (views.iter().count() as u32 * per_view).clamp(MIN_TIMER_SEGMENTS, max_segments);
+
+	if segments_per_pass > timestamps.segments_per_pass {
+		timestamps.resize(&render_device, segments_per_pass);
+	}
+}
+
+/// writes the timestamp for the start of a segment of a part of the gi, when `GiDiagnosticsPlugin` is timing it
+///
+/// has to be followed by `end_gi_timer` in the same node
+pub fn begin_gi_timer(world: &World, encoder: &mut CommandEncoder, pass: GiTimedPass) {
+	if let Some(timestamps) = world.get_resource::<GiTimestamps>() {
+		let segment = timestamps.segments[pass as usize].fetch_add(1, Ordering::Relaxed);
+		if segment < timestamps.segments_per_pass {
+			timestamps.write(encoder, pass.query(segment, timestamps.segments_per_pass));
+		} else {
+			timestamps.overflowed.store(true, Ordering::Relaxed);
+		}
+	}
+}
+
+/// writes the timestamp for the end of the segment `begin_gi_timer` started
+pub fn end_gi_timer(world: &World, encoder: &mut CommandEncoder, pass: GiTimedPass) {
+	if let Some(timestamps) = world.get_resource::<GiTimestamps>() {
+		let segment = timestamps.segments[pass as usize].load(Ordering::Relaxed).wrapping_sub(1);
+		if segment < timestamps.segments_per_pass {
+			timestamps.write(encoder, pass.query(segment, timestamps.segments_per_pass) + 1);
+		}
+	}
+}
+
+/// resolves the timestamps of this frame, and turns the ones that are read back into times
+pub fn resolve_gi_timestamps(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut timestamps: ResMut<GiTimestamps>,
+	times: Res<GiGpuTimes>,
+) {
+	let timestamps = &mut *timestamps;
+	let written = WrittenQueries {
+		bits: timestamps
+			.written
+			.iter_mut()
+			.map(|word| std::mem::replace(word.get_mut(), 0))
+			.collect(),
+		segments_per_pass: timestamps.segments_per_pass,
+	};
+	for segments in timestamps.segments.iter_mut() {
+		*segments.get_mut() = 0;
+	}
+
+	if std::mem::replace(timestamps.overflowed.get_mut(), false) && !timestamps.warned {
+		timestamps.warned = true;
+		warn!(
+			"a part of the gi took more than the {} timed segments there's room for, so it's time is too low",
+			timestamps.segments_per_pass,
+		);
+	}
+
+	if written.bits.iter().any(|bits| *bits != 0) {
+		let query_count = timestamps.query_count();
+
+		// when the gpu is too far behind, this frame is skipped
+		if let Some(buffer) = timestamps.readback.next_buffer(&render_device, written) {
+			let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+				label: Some("gi_resolve_timestamps"),
+			});
+			encoder.resolve_query_set(&timestamps.query_set, 0..query_count, buffer, 0);
+			render_queue.submit(vec![encoder.finish()]);
+
+			timestamps.readback.map();
+		}
+	}
+
+	let (data, written) = match timestamps.readback.poll(&render_device) {
+		Some(readback) => readback,
+		None => return,
+	};
+
+	let ticks: Vec<u64> = data
+		.chunks_exact(QUERY_SIZE as usize)
+		.map(|bytes| {
+			let mut tick = [0; 8];
+			tick.copy_from_slice(bytes);
+			u64::from_le_bytes(tick)
+		})
+		.collect();
+
+	let mut measured = [None; GiTimedPass::ALL.len()];
+	for (index, pass) in GiTimedPass::ALL.iter().enumerate() {
+		for segment in 0..written.segments_per_pass {
+			let begin = pass.query(segment, written.segments_per_pass);
+			let end = begin + 1;
+
+			if written.contains(begin) && written.contains(end) && (end as usize) < ticks.len() {
+				let elapsed = ticks[end as usize].saturating_sub(ticks[begin as usize]);
+				let time = elapsed as f64 * timestamps.period as f64 / 1_000_000.0;
+				measured[index] = Some(measured[index].unwrap_or(0.0) + time);
+			}
+		}
+	}
+
+	*times.0.lock().unwrap() = Some(measured);
+}
+
+fn setup_gi_diagnostics(limit: Res<GiCascadeLimit>, mut diagnostics: ResMut<Diagnostics>) {
+	for pass in GiTimedPass::ALL.iter() {
+		diagnostics.add(Diagnostic::new(pass.diagnostic_id(), pass.name(), 20).with_suffix("ms"));
+	}
+
+	diagnostics.add(Diagnostic::new(GiDiagnosticsPlugin::MEMORY_MB, "gi/memory_mb", 20).with_suffix("MB"));
+	for cascade in 0..limit.0 {
+		diagnostics.add(
+			Diagnostic::new(GiDiagnosticsPlugin::cascade_memory_id(cascade), format!("gi/cascade_{}_mb", cascade), 20)
+				.with_suffix("MB"),
+		);
+	}
+
+	// only measured with gpu_counters
+	diagnostics.add(Diagnostic::new(GiDiagnosticsPlugin::CASCADES_REBUILT, "gi/cascades_rebuilt", 20));
+	diagnostics.add(Diagnostic::new(GiDiagnosticsPlugin::CASCADES_SKIPPED, "gi/cascades_skipped", 20));
+	diagnostics.add(Diagnostic::new(GiDiagnosticsPlugin::TRIANGLES, "gi/triangles", 20));
+	for cascade in 0..limit.0.min(MAX_TRACED_CASCADES as u32) {
+		diagnostics.add(Diagnostic::new(
+			GiDiagnosticsPlugin::cascade_voxels_id(cascade),
+			format!("gi/cascade_{}_voxels", cascade),
+			20,
+		));
+	}
+}
+
+/// adds the times that came back since the last frame
+fn add_gi_diagnostics(times: Res<GiGpuTimes>, mut diagnostics: ResMut<Diagnostics>) {
+	let measured = match times.0.lock().unwrap().take() {
+		Some(measured) => measured,
+		None => return,
+	};
+
+	for (pass, time) in GiTimedPass::ALL.iter().zip(measured.iter()) {
+		if let Some(time) = time {
+			diagnostics.add_measurement(pass.diagnostic_id(), *time);
+		}
+	}
+}
+
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+
+/// what's counted in a frame, read back from `GiCounters`
+///
+/// the voxels are counted for every view, so with more views they add up, like the memory does
+#[derive(Copy, Clone, Default, Debug)]
+pub struct GiCounts {
+	/// for the first view only, every view voxelizes the same ones
+	pub triangles: u32,
+	/// the cascades voxelized, the sparse storages count as one
+	pub cascades_rebuilt: u32,
+	/// the cascades that weren't voxelized, as the static layer has everything in them and no dynamic mesh reaches into them
+	pub cascades_skipped: u32,
+	/// the voxels that aren't empty, in the first mip of every traced cascade
+	pub voxels: [u32; MAX_TRACED_CASCADES],
+}
+
+#[derive(Default)]
+struct GiStats {
+	/// bytes of texture memory of every cascade, over all views
+	memory: Option<Vec<u64>>,
+	/// bytes of the sparse storages and the buffers voxelization writes to, which aren't per cascade
+	storage_memory: u64,
+	counts: Option<GiCounts>,
+}
+
+/// the memory and counts of the gi, shared between the app and render world
+#[derive(Clone, Default)]
+pub struct GiGpuStats(Arc<Mutex<GiStats>>);
+
+/// adds up the texture memory of the cascades, the copies the bounces write to, and the sparse storages
+pub fn measure_gi_memory(
+	volume: Option<Res<ExtractedGiVolume>>,
+	views: Query<(&ViewGiVolumes, Option<&ViewGiBounceTargets>)>,
+	cascade_meta: Res<GiCascadeMeta>,
+	fragment_meta: Res<VoxelFragmentMeta>,
+	octree_meta: Res<SparseOctreeMeta>,
+	brick_map_meta: Res<BrickMapMeta>,
+	stats: Res<GiGpuStats>,
+) {
+	let volume = match volume {
+		Some(volume) => volume,
+		None => return,
+	};
+
+	let cascade_bytes = cascade_texture_bytes(volume.resolution, volume.settings.format);
+	// the copies only have the first mip
+	let bounce_bytes = volume.resolution.x as u64
+		* volume.resolution.y as u64
+		* volume.resolution.z as u64
+		* volume.settings.format.bytes_per_voxel() as u64;
+
+	// every view has it's own, the sparse storages have none
+	let mut memory = vec![0; volume.cascades as usize];
+	for (view_volumes, bounce_targets) in views.iter() {
+		for bytes in memory.iter_mut().take(view_volumes.cascades.len()) {
+			*bytes += cascade_bytes;
+		}
+
+		if let Some(bounce_targets) = bounce_targets {
+			for bytes in memory.iter_mut().take(bounce_targets.cascades.len()) {
+				*bytes += bounce_bytes;
+			}
+		}
+	}
+
+	// only the one for the storage the volume uses is allocated
+	let storage_memory = cascade_meta.voxel_sums.as_ref().map_or(0, |_| cascade_meta.voxel_sums_size)
+		+ fragment_meta.fragments.as_ref().map_or(0, |fragments| fragments.memory_bytes())
+		+ octree_meta.octree.as_ref().map_or(0, |octree| octree.memory_bytes())
+		+ brick_map_meta.brick_map.as_ref().map_or(0, |brick_map| brick_map.memory_bytes());
+
+	let mut stats = stats.0.lock().unwrap();
+	stats.memory = Some(memory);
+	stats.storage_memory = storage_memory;
+}
+
+// the triangles, the cascades rebuilt and skipped, then the voxels of every traced cascade, all u32
+const COUNTER_COUNT: usize = 3 + MAX_TRACED_CASCADES;
+const COUNTERS_SIZE: u64 = COUNTER_COUNT as u64 * 4;
+
+// same as in cone_trace.wgsl
+const COUNT_WORKGROUP_SIZE: u32 = 4;
+
+/// the buffer voxelization and the count pass count into, cleared every frame, only there with `gpu_counters`
+pub struct GiCounters {
+	pub buffer: Buffer,
+	/// binding 9 at group 3 in cone_trace.wgsl, voxelize.wgsl binds the same buffer in the voxelize bind group of `GiCascadeMeta`
+	pub layout: BindGroupLayout,
+	pub bind_group: BindGroup,
+	count_pipeline: ComputePipeline,
+	readback: GiAsyncReadback,
+}
+
+impl FromWorld for GiCounters {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let trace_shaders = world.get_resource::<ConeTraceShaders>().unwrap();
+
+		let buffer = render_device.create_buffer(&BufferDescriptor {
+			label: Some("gi_counters"),
+			size: COUNTERS_SIZE,
+			usage: BufferUsage::STORAGE | BufferUsage::COPY_SRC | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		});
+
+		let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[BindGroupLayoutEntry {
+				binding: 9,
+				visibility: ShaderStage::COMPUTE,
+				ty: BindingType::Buffer {
+					ty: BufferBindingType::Storage { read_only: false },
+					has_dynamic_offset: false,
+					min_binding_size: BufferSize::new(COUNTERS_SIZE),
+				},
+				count: None,
+			}],
+			label: None,
+		});
+
+		let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[BindGroupEntry {
+				binding: 9,
+				resource: buffer.as_entire_binding(),
+			}],
+			label: None,
+			layout: &layout,
+		});
+
+		// reads the cascades the same way the tracer does, so it's in the same shader
+		let shader = Shader::from_wgsl(include_str!("cone_trace.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[
+				&trace_shaders.view_layout,
+				&gi_shaders.cascades_layout,
+				&trace_shaders.trace_layout,
+				&layout,
+			],
+		});
+
+		let count_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+			label: None,
+			layout: Some(&pipeline_layout),
+			entry_point: "count_voxels",
+			module: &shader_module,
+		});
+
+		Self {
+			buffer,
+			layout,
+			bind_group,
+			count_pipeline,
+			readback: GiAsyncReadback::new("gi_counter_readback", COUNTERS_SIZE),
+		}
+	}
+}
+
+pub fn prepare_gi_counters(render_queue: Res<RenderQueue>, counters: Res<GiCounters>, cascade_meta: Res<GiCascadeMeta>) {
+	// start counting from 0 every frame, the cascades are already known
+	let mut values = [0u32; COUNTER_COUNT];
+	values[1] = cascade_meta.cascades_rebuilt;
+	values[2] = cascade_meta.cascades_skipped;
+
+	let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+	render_queue.write_buffer(&counters.buffer, 0, &bytes);
+}
+
+/// copies what was counted this frame for reading back, and shares what came back
+pub fn read_gi_counters(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut counters: ResMut<GiCounters>,
+	stats: Res<GiGpuStats>,
+) {
+	let counters = &mut *counters;
+
+	// when the gpu is too far behind, this frame is skipped
+	if let Some(buffer) = counters.readback.next_buffer(&render_device, 0) {
+		let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+			label: Some("gi_copy_counters"),
+		});
+		encoder.copy_buffer_to_buffer(&counters.buffer, 0, buffer, 0, COUNTERS_SIZE);
+		render_queue.submit(vec![encoder.finish()]);
+
+		counters.readback.map();
+	}
+
+	let data = match counters.readback.poll(&render_device) {
+		Some((data, _)) => data,
+		None => return,
+	};
+
+	let values: Vec<u32> = data
+		.chunks_exact(4)
+		.map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+		.collect();
+
+	let mut counts = GiCounts {
+		triangles: values[0],
+		cascades_rebuilt: values[1],
+		cascades_skipped: values[2],
+		..Default::default()
+	};
+	counts.voxels.copy_from_slice(&values[3..]);
+
+	stats.0.lock().unwrap().counts = Some(counts);
+}
+
+/// counts the voxels that aren't empty in the dense cascades, with `gpu_counters`
+pub struct GiCountPassNode {
+	view_query: QueryState<(&'static ViewUniformOffset, &'static ViewGiVolumes, &'static ViewGiTraceBindGroup)>,
+}
+
+impl GiCountPassNode {
+	pub const IN_VIEW: &'static str = "view";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiCountPassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(GiCountPassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+
+		let (view_uniform_offset, view_volumes, trace_bind_group) = match self.view_query.get_manual(world, view_entity) {
+			Ok(query) => query,
+			Err(_) => return Ok(()),
+		};
+
+		// the sparse storages have no cascades to count
+		let volume = match world.get_resource::<ExtractedGiVolume>() {
+			Some(volume) if !view_volumes.cascades.is_empty() => volume,
+			_ => return Ok(()),
+		};
+
+		let counters = world.get_resource::<GiCounters>().unwrap();
+		let trace_meta = world.get_resource::<ConeTraceMeta>().unwrap();
+		let cascade_meta = world.get_resource::<GiCascadeMeta>().unwrap();
+
+		let (view_bind_group, cascades_bind_group) = match (&trace_meta.view_bind_group, &cascade_meta.bind_group) {
+			(Some(view), Some(cascades)) => (view, cascades),
+			_ => return Ok(()),
+		};
+
+		let workgroups = (volume.resolution + COUNT_WORKGROUP_SIZE - 1) / COUNT_WORKGROUP_SIZE;
+
+		let mut pass = render_context
+			.command_encoder
+			.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_count_voxels") });
+
+		pass.set_pipeline(&counters.count_pipeline);
+		pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+		pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+		pass.set_bind_group(2, &trace_bind_group.bind_group, &[]);
+		pass.set_bind_group(3, &counters.bind_group, &[]);
+		pass.dispatch(workgroups.x, workgroups.y, workgroups.z);
+
+		Ok(())
+	}
+}
+
+/// adds the memory and the counts that came back since the last frame
+fn add_gi_stat_diagnostics(stats: Res<GiGpuStats>, mut diagnostics: ResMut<Diagnostics>) {
+	let mut stats = stats.0.lock().unwrap();
+
+	if let Some(memory) = stats.memory.take() {
+		let total = memory.iter().sum::<u64>() + stats.storage_memory;
+		diagnostics.add_measurement(GiDiagnosticsPlugin::MEMORY_MB, total as f64 / BYTES_PER_MB);
+
+		for (cascade, bytes) in memory.iter().enumerate() {
+			diagnostics.add_measurement(GiDiagnosticsPlugin::cascade_memory_id(cascade as u32), *bytes as f64 / BYTES_PER_MB);
+		}
+	}
+
+	if let Some(counts) = stats.counts.take() {
+		diagnostics.add_measurement(GiDiagnosticsPlugin::CASCADES_REBUILT, counts.cascades_rebuilt as f64);
+		diagnostics.add_measurement(GiDiagnosticsPlugin::CASCADES_SKIPPED, counts.cascades_skipped as f64);
+		diagnostics.add_measurement(GiDiagnosticsPlugin::TRIANGLES, counts.triangles as f64);
+
+		for (cascade, voxels) in counts.voxels.iter().enumerate() {
+			diagnostics.add_measurement(GiDiagnosticsPlugin::cascade_voxels_id(cascade as u32), *voxels as f64);
+		}
+	}
+}
@@ -0,0 +1,102 @@
+// positions and normals of everything the camera sees, so cone tracing knows where to start from
+// w of the position is 1 where something was drawn, and stays 0 otherwise
+// the motion is how far the pixel moved on screen since last frame, and how far from the camera it was, for the history
+// the albedo is the base color of the material, which the indirect light is multiplied with when it's composited, with the metallic in w
+
+[[block]]
+struct View {
+    view_proj: mat4x4<f32>;
+    world_position: vec3<f32>;
+};
+
+[[block]]
+struct Mesh {
+    transform: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> view: View;
+
+// same layout as GpuGiTemporal
+[[block]]
+struct Temporal {
+    previous_view_proj: mat4x4<f32>;
+    blend: f32;
+    frame: u32;
+    history_valid: u32;
+};
+
+[[group(1), binding(0)]]
+var<uniform> mesh: Mesh;
+
+// the transform of the mesh last frame
+[[group(2), binding(0)]]
+var<uniform> previous_mesh: Mesh;
+
+[[group(3), binding(0)]]
+var<uniform> temporal: Temporal;
+
+// same layout as GpuGiMaterial, see voxelize.wgsl
+[[block]]
+struct Material {
+    base_color: vec4<f32>;
+    alpha_mode: u32;
+    alpha_cutoff: f32;
+    thin_geometry: u32;
+    thickness: f32;
+    contribution: u32;
+    metallic: f32;
+};
+
+[[group(3), binding(1)]]
+var<uniform> material: Material;
+
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] normal: vec3<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] world_position: vec3<f32>;
+    [[location(1)]] world_normal: vec3<f32>;
+    [[location(2)]] current_clip: vec4<f32>;
+    [[location(3)]] previous_clip: vec4<f32>;
+};
+
+struct FragmentOutput {
+    [[location(0)]] position: vec4<f32>;
+    [[location(1)]] normal: vec4<f32>;
+    [[location(2)]] motion: vec4<f32>;
+    [[location(3)]] albedo: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let world_position = mesh.transform * vec4<f32>(vertex.position, 1.0);
+
+    let previous_world_position = previous_mesh.transform * vec4<f32>(vertex.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * world_position;
+    out.current_clip = out.clip_position;
+    out.previous_clip = temporal.previous_view_proj * previous_world_position;
+    out.world_position = world_position.xyz;
+    // same as pbr, this is only right for uniform scale
+    out.world_normal = (mesh.transform * vec4<f32>(vertex.normal, 0.0)).xyz;
+    return out;
+}
+
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    out.position = vec4<f32>(in.world_position, 1.0);
+    out.normal = vec4<f32>(normalize(in.world_normal), 0.0);
+
+    // in uv, so y goes down
+    let current = in.current_clip.xy / in.current_clip.w;
+    let previous = in.previous_clip.xy / in.previous_clip.w;
+    out.motion = vec4<f32>((current - previous) * vec2<f32>(0.5, -0.5), in.previous_clip.w, 1.0);
+    out.albedo = vec4<f32>(material.base_color.rgb, material.metallic);
+    return out;
+}
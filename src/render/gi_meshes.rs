@@ -0,0 +1,495 @@
+// the meshes that take part in gi
+// these are extracted seperately from the pbr ones, so gi can decide for itself which meshes to use
+// meshes that aren't on the layers of the volume, or that neither add to the voxels nor receive gi, aren't extracted at all
+// the triangles voxelization reads are copied from the mesh asset once, and kept on the gpu until the mesh changes, like skinning does
+
+use bevy::asset::{AssetEvent, Assets, Handle};
+use bevy::ecs::prelude::*;
+use bevy::math::{Mat4, Vec3, Vec4};
+use bevy::utils::{HashMap, HashSet};
+use bevy::pbr2::StandardMaterial;
+use bevy::render2::{
+	mesh::{Indices, Mesh, VertexAttributeValues},
+	render_resource::*,
+	renderer::RenderDevice,
+};
+use bevy::transform::components::GlobalTransform;
+
+use crate::bundle::{
+	GiAlphaMode, GiContribution, GiLayers, GiNoReceive, GiProxy, GiStatic, GiStaticLayer, GiThinGeometry, GiVolume,
+};
+
+use crevice::std140::AsStd140;
+
+pub struct ExtractedGiMesh {
+	/// skinned meshes are looked up with this in GiSkinningMeta
+	pub entity: Entity,
+	pub transform: Mat4,
+	/// the transform last frame, for motion vectors, the same as `transform` the first frame
+	pub previous_transform: Mat4,
+	pub mesh: Handle<Mesh>,
+	/// what voxelization reads, the `GiProxy` if there is one, otherwise the same as `mesh`
+	///
+	/// a proxy also takes the place of skinning
+	pub voxel_mesh: Handle<Mesh>,
+	pub transform_binding_offset: u32,
+	pub previous_transform_binding_offset: u32,
+	pub material_binding_offset: u32,
+	/// linear base color of the material, white without one
+	pub base_color: Vec4,
+	/// metallic of the material, for how much of the specular light is composited, 0 without one
+	pub metallic: f32,
+	/// voxelization applies this to every texel, as the base color texture can mask out more
+	pub alpha_mode: GiAlphaMode,
+	/// how voxelization handles surfaces thinner than a voxel
+	pub thin_geometry: GiThinGeometry,
+	/// blocks light in the voxels
+	pub occluder: bool,
+	/// gives off light in the voxels
+	pub emitter: bool,
+	/// drawn to the gbuffer, so cones are traced from it
+	pub receiver: bool,
+}
+
+#[derive(Default)]
+pub struct ExtractedGiMeshes {
+	pub meshes: Vec<ExtractedGiMesh>,
+	/// the triangles of voxel meshes that are new or changed since the last frame
+	pub geometry: Vec<(Handle<Mesh>, GiMeshGeometry)>,
+	/// voxel meshes that changed, so the render world drops what it has for them
+	pub changed: Vec<Handle<Mesh>>,
+}
+
+/// the triangles of a mesh, as voxelization reads them
+pub struct GiMeshGeometry {
+	/// a vec4 for every vertex, the same as the skinned positions
+	pub positions: Vec<Vec4>,
+	/// three for every triangle, meshes without indices are a triangle list
+	pub indices: Vec<u32>,
+}
+
+impl GiMeshGeometry {
+	/// nothing for meshes without positions
+	pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+		let positions: Vec<Vec4> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+			Some(VertexAttributeValues::Float32x3(positions)) => {
+				positions.iter().map(|position| Vec3::from(*position).extend(1.0)).collect()
+			}
+			_ => return None,
+		};
+
+		Some(Self {
+			indices: mesh_indices(mesh, positions.len() as u32),
+			positions,
+		})
+	}
+}
+
+/// the indices of a mesh as u32s, or a triangle list over `vertex_count` vertices when it has none
+pub fn mesh_indices(mesh: &Mesh, vertex_count: u32) -> Vec<u32> {
+	match mesh.indices() {
+		Some(Indices::U16(indices)) => indices.iter().map(|index| *index as u32).collect(),
+		Some(Indices::U32(indices)) => indices.clone(),
+		None => (0..vertex_count).collect(),
+	}
+}
+
+/// the material of a mesh, as voxelize.wgsl reads it
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiMaterial {
+	base_color: Vec4,
+	alpha_mode: u32,
+	alpha_cutoff: f32,
+	thin_geometry: u32,
+	thickness: f32,
+	contribution: u32,
+	metallic: f32,
+}
+
+impl GpuGiMaterial {
+	pub fn new(mesh: &ExtractedGiMesh) -> Self {
+		// same as the ALPHA_MODE_ constants in voxelize.wgsl
+		let (alpha_mode, alpha_cutoff) = match mesh.alpha_mode {
+			GiAlphaMode::Opaque => (0, 0.0),
+			GiAlphaMode::Mask(cutoff) => (1, cutoff),
+			GiAlphaMode::Blend => (2, 0.0),
+		};
+
+		// same as the THIN_GEOMETRY_ constants
+		let (thin_geometry, thickness) = match mesh.thin_geometry {
+			GiThinGeometry::Keep => (0, 0.0),
+			GiThinGeometry::Dilate => (1, 0.0),
+			GiThinGeometry::Solidify(thickness) => (2, thickness),
+		};
+
+		// same as the CONTRIBUTION_ constants
+		let contribution = mesh.occluder as u32 | (mesh.emitter as u32) << 1;
+
+		Self {
+			base_color: mesh.base_color,
+			alpha_mode,
+			alpha_cutoff,
+			thin_geometry,
+			thickness,
+			contribution,
+			metallic: mesh.metallic,
+		}
+	}
+}
+
+pub struct GiMeshShaders {
+	/// the transform of a single mesh, picked with a dynamic offset
+	pub transform_layout: BindGroupLayout,
+	/// the material of a single mesh for voxelization, also picked with a dynamic offset
+	pub material_layout: BindGroupLayout,
+	/// the positions and indices of a single mesh for voxelization
+	pub geometry_layout: BindGroupLayout,
+}
+
+impl FromWorld for GiMeshShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let transform_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::VERTEX | ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						min_binding_size: BufferSize::new(Mat4::std140_size_static() as u64),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let material_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT | ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						min_binding_size: BufferSize::new(GpuGiMaterial::std140_size_static() as u64),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let geometry_storage_entry = |binding: u32, min_binding_size: u64| BindGroupLayoutEntry {
+			binding,
+			visibility: ShaderStage::COMPUTE,
+			ty: BindingType::Buffer {
+				ty: BufferBindingType::Storage { read_only: true },
+				has_dynamic_offset: false,
+				min_binding_size: BufferSize::new(min_binding_size),
+			},
+			count: None,
+		};
+
+		let geometry_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				// positions, a vec4 each
+				geometry_storage_entry(0, 16),
+				// indices
+				geometry_storage_entry(1, 4),
+			],
+			label: None,
+		});
+
+		GiMeshShaders {
+			transform_layout,
+			material_layout,
+			geometry_layout,
+		}
+	}
+}
+
+/// the triangles of a voxel mesh on the gpu
+pub struct GpuGiMeshGeometry {
+	pub positions: Buffer,
+	pub indices: Buffer,
+	pub triangle_count: u32,
+	pub bind_group: Option<BindGroup>,
+	/// the box around the positions, so cascades it doesn't reach into can be skipped
+	pub min: Vec3,
+	pub max: Vec3,
+}
+
+#[derive(Default)]
+pub struct GiMeshMeta {
+	pub transform_uniforms: DynamicUniformVec<Mat4>,
+	pub transform_bind_group: Option<BindGroup>,
+	/// same layout as the transforms
+	pub previous_transform_uniforms: DynamicUniformVec<Mat4>,
+	pub previous_transform_bind_group: Option<BindGroup>,
+	pub material_uniforms: DynamicUniformVec<GpuGiMaterial>,
+	pub material_bind_group: Option<BindGroup>,
+	/// for every voxel mesh, meshes without triangles don't have one
+	pub geometry: HashMap<Handle<Mesh>, GpuGiMeshGeometry>,
+}
+
+pub fn extract_gi_meshes(
+	mut commands: Commands,
+	materials: Res<Assets<StandardMaterial>>,
+	volumes: Query<(&GiVolume, Option<&GiStaticLayer>)>,
+	meshes: Query<(
+		Entity,
+		&GlobalTransform,
+		&Handle<Mesh>,
+		Option<&GiLayers>,
+		Option<&GiContribution>,
+		Option<&GiNoReceive>,
+		Option<&GiProxy>,
+		Option<&Handle<StandardMaterial>>,
+		Option<&GiAlphaMode>,
+		Option<&GiThinGeometry>,
+		Option<&GiStatic>,
+	)>,
+	mesh_assets: Res<Assets<Mesh>>,
+	mut mesh_events: EventReader<AssetEvent<Mesh>>,
+	mut previous_transforms: Local<HashMap<Entity, Mat4>>,
+	// voxel meshes the render world already has the triangles of
+	mut extracted_geometry: Local<HashSet<Handle<Mesh>>>,
+) {
+	let mut changed = Vec::new();
+	for event in mesh_events.iter() {
+		match event {
+			AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+				if extracted_geometry.remove(handle) {
+					changed.push(handle.clone_weak());
+				}
+			}
+			AssetEvent::Created { .. } => {}
+		}
+	}
+
+	// same volume as extract_gi_cascades
+	let (volume_layers, baked) = match volumes.iter().next() {
+		// static meshes that block light are already in the static layer
+		Some((volume, static_layer)) => (volume.layers, static_layer.map_or(false, |layer| layer.covers(volume))),
+		None => {
+			commands.insert_resource(ExtractedGiMeshes {
+				changed,
+				..Default::default()
+			});
+			previous_transforms.clear();
+			return;
+		}
+	};
+
+	let meshes: Vec<ExtractedGiMesh> = meshes
+		.iter()
+		.filter(|(_, _, _, layers, ..)| layers.copied().unwrap_or_default().intersects(&volume_layers))
+		.map(|(entity, transform, handle, _, contribution, no_receive, proxy, material, alpha_mode, thin_geometry, static_mesh)| {
+			let contribution = contribution.copied().unwrap_or_default();
+			let alpha_mode = alpha_mode.copied().unwrap_or_default();
+			let baked = baked && static_mesh.is_some() && contribution.occludes();
+
+			let material = material.and_then(|material| materials.get(material));
+			let base_color = material.map_or(Vec4::ONE, |material| Vec4::from(material.base_color.as_linear_rgba_f32()));
+
+			// a mesh that's masked out as a whole, or already in the static layer, doesn't add anything to the voxels
+			let voxelized = alpha_mode.opacity(base_color.w).is_some() && !baked;
+
+			let transform = transform.compute_matrix();
+
+			ExtractedGiMesh {
+				entity,
+				transform,
+				previous_transform: previous_transforms.get(&entity).copied().unwrap_or(transform),
+				mesh: handle.clone_weak(),
+				voxel_mesh: proxy.map_or(handle, |proxy| &proxy.0).clone_weak(),
+				transform_binding_offset: 0,
+				previous_transform_binding_offset: 0,
+				material_binding_offset: 0,
+				base_color,
+				metallic: material.map_or(0.0, |material| material.metallic),
+				alpha_mode,
+				thin_geometry: thin_geometry.copied().unwrap_or_default(),
+				occluder: voxelized && contribution.occludes(),
+				emitter: voxelized && contribution.emits(),
+				receiver: no_receive.is_none(),
+			}
+		})
+		.filter(|mesh| mesh.occluder || mesh.emitter || mesh.receiver)
+		.collect();
+
+	// meshes that are gone don't need their transform anymore
+	previous_transforms.clear();
+	previous_transforms.extend(meshes.iter().map(|mesh| (mesh.entity, mesh.transform)));
+
+	let mut geometry = Vec::new();
+	for mesh in meshes.iter().filter(|mesh| mesh.occluder || mesh.emitter) {
+		if extracted_geometry.contains(&mesh.voxel_mesh) {
+			continue;
+		}
+
+		// not loaded yet
+		if let Some(mesh_geometry) = mesh_assets.get(&mesh.voxel_mesh).and_then(GiMeshGeometry::from_mesh) {
+			geometry.push((mesh.voxel_mesh.clone_weak(), mesh_geometry));
+			extracted_geometry.insert(mesh.voxel_mesh.clone_weak());
+		}
+	}
+
+	commands.insert_resource(ExtractedGiMeshes {
+		meshes,
+		geometry,
+		changed,
+	});
+}
+
+pub fn prepare_gi_meshes(
+	render_device: Res<RenderDevice>,
+	mut mesh_meta: ResMut<GiMeshMeta>,
+	mut extracted_meshes: ResMut<ExtractedGiMeshes>,
+) {
+	for handle in extracted_meshes.changed.iter() {
+		mesh_meta.geometry.remove(handle);
+	}
+
+	for (handle, geometry) in extracted_meshes.geometry.drain(..) {
+		// storage buffers can't be empty, and there's nothing to voxelize anyway
+		let triangle_count = geometry.indices.len() as u32 / 3;
+		if triangle_count == 0 || geometry.positions.is_empty() {
+			continue;
+		}
+
+		let positions: Vec<u8> = geometry
+			.positions
+			.iter()
+			.flat_map(|position| position.to_array())
+			.flat_map(|value| value.to_le_bytes())
+			.collect();
+		let indices: Vec<u8> = geometry.indices[..triangle_count as usize * 3]
+			.iter()
+			.flat_map(|index| index.to_le_bytes())
+			.collect();
+
+		let min = geometry.positions.iter().fold(Vec3::splat(f32::MAX), |min, position| min.min(position.truncate()));
+		let max = geometry.positions.iter().fold(Vec3::splat(f32::MIN), |max, position| max.max(position.truncate()));
+
+		mesh_meta.geometry.insert(
+			handle,
+			GpuGiMeshGeometry {
+				positions: render_device.create_buffer_with_data(&BufferInitDescriptor {
+					label: None,
+					contents: &positions,
+					usage: BufferUsage::STORAGE,
+				}),
+				indices: render_device.create_buffer_with_data(&BufferInitDescriptor {
+					label: None,
+					contents: &indices,
+					usage: BufferUsage::STORAGE,
+				}),
+				triangle_count,
+				bind_group: None,
+				min,
+				max,
+			},
+		);
+	}
+
+	mesh_meta
+		.transform_uniforms
+		.reserve_and_clear(extracted_meshes.meshes.len(), &render_device);
+
+	mesh_meta
+		.previous_transform_uniforms
+		.reserve_and_clear(extracted_meshes.meshes.len(), &render_device);
+
+	mesh_meta
+		.material_uniforms
+		.reserve_and_clear(extracted_meshes.meshes.len(), &render_device);
+
+	for mesh in extracted_meshes.meshes.iter_mut() {
+		mesh.transform_binding_offset = mesh_meta.transform_uniforms.push(mesh.transform);
+		mesh.previous_transform_binding_offset = mesh_meta.previous_transform_uniforms.push(mesh.previous_transform);
+		mesh.material_binding_offset = mesh_meta
+			.material_uniforms
+			.push(GpuGiMaterial::new(mesh));
+	}
+
+	// copied to the uniform buffer by the first node that needs them
+	mesh_meta
+		.transform_uniforms
+		.write_to_staging_buffer(&render_device);
+	mesh_meta
+		.previous_transform_uniforms
+		.write_to_staging_buffer(&render_device);
+	mesh_meta
+		.material_uniforms
+		.write_to_staging_buffer(&render_device);
+}
+
+pub fn queue_gi_mesh_bind_group(
+	render_device: Res<RenderDevice>,
+	mesh_shaders: Res<GiMeshShaders>,
+	mut mesh_meta: ResMut<GiMeshMeta>,
+) {
+	let mesh_meta = &mut *mesh_meta;
+
+	mesh_meta.transform_bind_group = mesh_meta.transform_uniforms.binding().map(|binding| {
+		render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: binding,
+				},
+			],
+			label: None,
+			layout: &mesh_shaders.transform_layout,
+		})
+	});
+
+	mesh_meta.previous_transform_bind_group = mesh_meta.previous_transform_uniforms.binding().map(|binding| {
+		render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: binding,
+				},
+			],
+			label: None,
+			layout: &mesh_shaders.transform_layout,
+		})
+	});
+
+	mesh_meta.material_bind_group = mesh_meta.material_uniforms.binding().map(|binding| {
+		render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: binding,
+				},
+			],
+			label: None,
+			layout: &mesh_shaders.material_layout,
+		})
+	});
+
+	// the buffers never change, so these only have to be made once
+	for geometry in mesh_meta.geometry.values_mut().filter(|geometry| geometry.bind_group.is_none()) {
+		geometry.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: geometry.positions.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: geometry.indices.as_entire_binding(),
+				},
+			],
+			label: None,
+			layout: &mesh_shaders.geometry_layout,
+		}));
+	}
+}
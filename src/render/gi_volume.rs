@@ -1,41 +1,44 @@
 // HOW IT WORKS
-// render the world from 3 orthographic cameras
-// if the angle to the camera with the triangle is good enough, rasterize it to the volume
-// set opacity to 1, color to the albedo at that point * shadow
-// generate mipmaps
-// pass the volume texture(s)? to the
-
-// single pass for the orthographic pass
-
-// THIS FILE:
-// extract all cascades
-// make the pipeline for voxelization
-// prepare voxelization, make list of projection, resolution and textures
-// do voxelization
-
-// OTHER FILE:
-// pass volumes to PBR shader
+// every cascade is voxelized on the gpu every frame, a triangle per invocation, see voxelize.wgsl
+// the triangles add their albedo to a buffer of sums, which are averaged into the first mip of the cascade
+// that average is the light injected into the voxels, and has it's own pass after every slab, so it can be timed
+// cascades the static layer is copied into are only voxelized again when a dynamic mesh reaches into them
+// the rest of the mips are then made with mipmap.wgsl, a mip at a time
+// with a sparse storage, the triangles are appended as fragments instead, and the storage is built from those
+// that's done once for a single cube around the volume, see sparse_cube, which is also what's traced
+// the cascades are passed to the cone tracer, see cone_trace.rs
 
 use crevice::std140::AsStd140;
+use crevice::std430::{AsStd430, Std430};
+
+use crate::bundle::{GiQuality, GiSettings, GiStorage, GiThinGeometry, GiTraceBias, GiTraceResolution, GiVolume, GiVolumeFormat};
+use crate::brick_map::{BRICK_SIZE, MAX_BRICK_MAP_GRID};
+use crate::voxelize::{box_in_cascade, cascade_projection, cascade_scale};
 
-use crate::bundle::GiVolume;
+use super::cone_trace::MAX_TRACED_CASCADES;
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiCounters, GiTimedPass};
+use super::gi_meshes::{ExtractedGiMeshes, GiMeshMeta, GiMeshShaders};
+use super::skinning::{ExtractedGiSkins, GiSkinningMeta};
+use super::brick_map::BrickMapMeta;
+use super::sparse_octree::{octree_depth, SparseOctreeMeta};
+use super::static_layer::{restore_gi_static_layer, ExtractedGiStaticLayer};
+use super::voxel_fragments::{VoxelFragmentMeta, FRAGMENT_SIZE};
 
 use bevy::transform::components::{GlobalTransform, Transform};
-use bevy_pbr2::{ExtractedMeshes, MeshMeta, PbrShaders};
 
-use bevy::ecs::{prelude::*, system::SystemState};
-use bevy::math::{const_vec3, Mat4, Vec3, Vec4};
+use bevy::ecs::prelude::*;
+use bevy::log::warn;
+use bevy::math::{const_vec3, Mat4, UVec3, Vec3, Vec4};
 use bevy::render2::{
 	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
-    render_asset::RenderAssets,
-    render_phase::{Draw, DrawFunctions, RenderPhase, TrackedRenderPass},
+    render_phase::RenderPhase,
     render_resource::*,
     renderer::{RenderContext, RenderDevice, RenderQueue},
     shader::Shader,
     texture::*,
-	mesh::Mesh,
 	view::{ExtractedView, ViewUniformOffset},
 };
+use bevy::utils::HashMap;
 
 use bevy_core_pipeline::Transparent3dPhase;
 
@@ -43,249 +46,523 @@ use bevy_core_pipeline::Transparent3dPhase;
 
 // info for the cascade
 pub struct ExtractedGiVolume {
-    transform: GlobalTransform, // origin and scale
-    resolution: u32,
-    cascades: u8, // how many lod levels we have
-    size: f32, // size of the first lod
+    pub transform: GlobalTransform, // origin and scale
+    pub resolution: UVec3,
+    pub cascades: u8, // how many lod levels we have
+    pub extent: Vec3, // size of the first lod
+	pub storage: GiStorage,
+	pub bias: GiTraceBias,
+	pub temporal_blend: f32,
+	pub trace_resolution: GiTraceResolution,
+	pub settings: GiSettings,
 }
 
 // this is for *one* projection for a cascade
 #[repr(C)]
-#[derive(Copy, Clone, AsStd140, Default, Debug)]
+#[derive(Copy, Clone, AsStd430, Default, Debug)]
 pub struct GpuGiCascade {
+	// world space to 0 - 1 over the cascade, so it can be used as texture coordinates directly
     projection: Mat4,
-    resolution: u32,
+    resolution: Vec3, // stored as floats, as crevice can't lay out integer vectors
 	texture_index: u32, // which part of the texture to use
+	extent: Vec3, // size of the cascade in world space
+}
+
+/// the parts of `GiSettings` the tracer reads, in a uniform so they can be changed without compiling the shaders again
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiSettings {
+	diffuse_cones: u32,
+	step_multiplier: f32,
+	diffuse_aperture: f32,
+	specular_aperture: f32,
+	max_trace_distance: f32,
+	indirect_intensity: f32,
+	ao_strength: f32,
+	leak_bias: f32,
+	cascade_blend: f32,
 }
 
-// max number of cascades allowed in the world at the same time
-const MAX_CASCADE_NUM: usize = 8;
+impl From<&GiSettings> for GpuGiSettings {
+	fn from(settings: &GiSettings) -> Self {
+		Self {
+			diffuse_cones: settings.diffuse_cones,
+			step_multiplier: settings.step_multiplier,
+			diffuse_aperture: settings.diffuse_aperture,
+			specular_aperture: settings.specular_aperture,
+			max_trace_distance: settings.max_trace_distance,
+			indirect_intensity: settings.indirect_intensity,
+			ao_strength: settings.ao_strength,
+			leak_bias: settings.leak_bias,
+			cascade_blend: settings.cascade_blend,
+		}
+	}
+}
+
+/// max number of cascades allowed in the world at the same time
+///
+/// set from `GiPlugin::max_cascades`, available in both the app and render world
+#[derive(Copy, Clone, Debug)]
+pub struct GiCascadeLimit(pub u32);
+
+/// most cascades `GiPlugin::max_cascades` can be set to
+///
+/// the last cascade is already 2^15 times as large as the first at this point
+pub const MAX_GI_CASCADES: u32 = 16;
+
+// dynamic offsets into a storage buffer need to be aligned to this
+const STORAGE_OFFSET_ALIGNMENT: u64 = 256;
+
+// num_cascades, the trace bias and the storage, padded to the alignment of a cascade
+const CASCADES_HEADER_SIZE: u64 = 16;
+
+// same as in voxelize.wgsl
+const VOXELIZE_WORKGROUP_SIZE: u32 = 64;
+
+// the sums of the premultiplied albedo and opacity, and the number of triangles, as an u32 each
+const VOXEL_SUM_SIZE: u64 = 5 * 4;
+
+// the sums only hold this much of a cascade at once, larger cascades are voxelized in more slabs
+const MAX_VOXEL_SUM_BYTES: u64 = 64 << 20;
+
+// voxels around the box of a mesh it can still add to, from conservative rasterization and dilation
+const SKIP_MARGIN_VOXELS: f32 = 2.0;
+
+/// which part of the volume a voxelize pass writes to, as voxelize.wgsl reads it
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuVoxelizeParams {
+	cascade: u32,
+	first_layer: u32,
+	layers: u32,
+	keep_existing: u32,
+	fragments: u32,
+	count_triangles: u32,
+}
 
 // holds all cascades
 // used for passing to the pbr shader
-#[repr(C)]
-#[derive(Copy, Clone, AsStd140, Default, Debug)]
+//
+// the max number of cascades isn't known at compile time, so this is a storage buffer instead of a fixed size array
+// every view gets a block with num_cascades, the bias and the storage followed by the cascades, which is picked with a dynamic offset
 pub struct GpuGiCascades {
-    num_cascades: u32,
-    cascades: [GpuGiCascade; MAX_CASCADE_NUM], 
+	max_cascades: u32,
+	block_size: u64,
+	data: Vec<u8>,
+	buffer: Option<Buffer>,
+	capacity: usize,
 }
 
+impl GpuGiCascades {
+	pub fn new(max_cascades: u32) -> Self {
+		let size = CASCADES_HEADER_SIZE + GpuGiCascade::std430_size_static() as u64 * max_cascades.max(1) as u64;
 
-pub struct GiShaders {
-    //vertex_pipeline: ComputePipeline,
+		Self {
+			max_cascades,
+			block_size: (size + STORAGE_OFFSET_ALIGNMENT - 1) / STORAGE_OFFSET_ALIGNMENT * STORAGE_OFFSET_ALIGNMENT,
+			data: Vec::new(),
+			buffer: None,
+			capacity: 0,
+		}
+	}
+
+	pub fn max_cascades(&self) -> u32 {
+		self.max_cascades
+	}
+
+	/// smallest size a binding can be, the header and a single cascade
+	pub fn min_binding_size() -> BufferSize {
+		BufferSize::new(CASCADES_HEADER_SIZE + GpuGiCascade::std430_size_static() as u64).unwrap()
+	}
+
+	/// makes sure there's room for this many views, and removes the old ones
+	pub fn reserve_and_clear(&mut self, views: usize, render_device: &RenderDevice) {
+		self.data.clear();
+
+		if views > self.capacity || self.buffer.is_none() {
+			self.capacity = views.max(1);
+			self.buffer = Some(render_device.create_buffer(&BufferDescriptor {
+				label: None,
+				size: self.block_size * self.capacity as u64,
+				usage: BufferUsage::STORAGE | BufferUsage::COPY_DST,
+				mapped_at_creation: false,
+			}));
+		}
+	}
+
+	/// adds the cascades for one view, and gives the dynamic offset to get them
+	pub fn push(&mut self, cascades: &[GpuGiCascade], bias: GiTraceBias, storage: GiStorage) -> u32 {
+		let offset = self.data.len();
+		let num_cascades = cascades.len().min(self.max_cascades as usize);
+
+		// same as the STORAGE_ constants in cone_trace.wgsl
+		let storage: u32 = match storage {
+			GiStorage::Dense => 0,
+			GiStorage::SparseOctree => 1,
+			GiStorage::BrickMap => 2,
+		};
+
+		self.data.extend_from_slice(&(num_cascades as u32).to_le_bytes());
+		self.data.extend_from_slice(&bias.normal_offset.to_le_bytes());
+		self.data.extend_from_slice(&bias.self_intersection.to_le_bytes());
+		self.data.extend_from_slice(&storage.to_le_bytes());
+		self.data.resize(offset + CASCADES_HEADER_SIZE as usize, 0);
+
+		for cascade in &cascades[..num_cascades] {
+			self.data.extend_from_slice(cascade.as_std430().as_bytes());
+		}
+
+		self.data.resize(offset + self.block_size as usize, 0);
+
+		offset as u32
+	}
+
+	pub fn write_buffer(&self, render_queue: &RenderQueue) {
+		if let Some(buffer) = &self.buffer {
+			if !self.data.is_empty() {
+				render_queue.write_buffer(buffer, 0, &self.data);
+			}
+		}
+	}
+
+	pub fn binding(&self) -> Option<BindingResource> {
+		self.buffer.as_ref().map(|buffer| {
+			BindingResource::Buffer(BufferBinding {
+				buffer,
+				offset: 0,
+				size: BufferSize::new(self.block_size),
+			})
+		})
+	}
+}
+
+
+/// the parts of `GiShaders` that depend on the format of the cascades, as they're bound as storage textures
+pub struct GiVolumePipelines {
 	voxelize_pipeline: ComputePipeline,
+	resolve_pipeline: ComputePipeline,
 	mipmap_pipeline: ComputePipeline,
-    view_layout: BindGroupLayout,
-	mesh_layout: BindGroupLayout,
-	mesh_model_layout: BindGroupLayout,
-	volume_layout: BindGroupLayout,
-	volume_sampler: Sampler,
+	/// the first mip of a single cascade, for resolving the voxels into
+	pub volume_layout: BindGroupLayout,
+	/// a mip of a cascade and the one below it, from mipmap.wgsl
+	pub mip_layout: BindGroupLayout,
+}
+
+pub struct GiShaders {
+	// one for every GiVolumeFormat, so switching formats doesn't need to compile anything
+	volume_pipelines: HashMap<GiVolumeFormat, GiVolumePipelines>,
+	/// the cascades, the params of a voxelize pass, and where the voxels go
+	pub voxelize_layout: BindGroupLayout,
+	pub cascades_layout: BindGroupLayout,
+}
+
+impl GiShaders {
+	pub fn volume_pipelines(&self, format: GiVolumeFormat) -> &GiVolumePipelines {
+		&self.volume_pipelines[&format]
+	}
+}
+
+/// the texture format of dense cascades
+pub fn volume_texture_format(format: GiVolumeFormat) -> TextureFormat {
+	match format {
+		GiVolumeFormat::Rgba16Float => TextureFormat::Rgba16Float,
+		GiVolumeFormat::Rgba32Float => TextureFormat::Rgba32Float,
+	}
+}
+
+/// a shader that declares the cascades as rgba32float storage textures, changed to the format of the cascades
+///
+/// wgsl can't be specialised on a texture format, so it's done to the source before compiling
+pub fn volume_format_shader(source: &'static str, format: GiVolumeFormat) -> Shader {
+	match format {
+		GiVolumeFormat::Rgba16Float => Shader::from_wgsl(source.replace("<rgba32float,", "<rgba16float,")),
+		GiVolumeFormat::Rgba32Float => Shader::from_wgsl(source),
+	}
+}
+
+fn compute_storage_entry(binding: u32, read_only: bool, min_binding_size: u64) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Storage { read_only },
+			has_dynamic_offset: false,
+			min_binding_size: BufferSize::new(min_binding_size),
+		},
+		count: None,
+	}
+}
+
+fn volume_storage_entry(binding: u32, format: GiVolumeFormat) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::StorageTexture {
+			access: StorageTextureAccess::ReadWrite,
+			format: volume_texture_format(format),
+			view_dimension: TextureViewDimension::D3,
+		},
+		count: None,
+	}
 }
 
 impl FromWorld for GiShaders {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.get_resource::<RenderDevice>().unwrap();
-        let pbr_shaders = world.get_resource::<PbrShaders>().unwrap();
-
-        // make the shader
-        let shader = Shader::from_wgsl(include_str!("voxelize.wgsl"));
-        let shader_module = render_device.create_shader_module(&shader);
+		let mesh_shaders = world.get_resource::<GiMeshShaders>().unwrap();
 
-        let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            entries: &[
-                // view
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStage::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: true,
-                        // TODO: change this to ViewUniform::std140_size_static once crevice fixes this!
-                        // Context: https://github.com/LPGhatguy/crevice/issues/29
-                        min_binding_size: BufferSize::new(80), // TODO
-                    },
-                    count: None,
-                },
-            ],
-            label: None,
-        });
-
-        // TODO grab from pbr
-        let mesh_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            entries: &[
-                BindGroupLayoutEntry {
+		// only the cascades, for passes that read from the volume instead of writing to it (pbr)
+		let cascades_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStage::COMPUTE,
+                    visibility: ShaderStage::COMPUTE | ShaderStage::FRAGMENT,
                     ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
+                        ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: true,
-                        min_binding_size: BufferSize::new(Mat4::std140_size_static() as u64), // TODO
+                        min_binding_size: Some(GpuGiCascades::min_binding_size()),
                     },
                     count: None,
                 },
-            ], 
-            label: None,
-        });
-
-		// and for the voxelizer
-		let volume_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-			entries: &[ // TODO: add a buffer so we can store the gpugicascades
+				// the settings, the same for every view
 				BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStage::COMPUTE,
-                    ty: BindingType::StorageTexture {
-                        access: StorageTextureAccess::ReadWrite,
-                        format: TextureFormat::Rgba32Float,
-                        view_dimension: TextureViewDimension::D3,
-                    },
-                    count: None,
-                },
+					binding: 1,
+					visibility: ShaderStage::COMPUTE | ShaderStage::FRAGMENT,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: false,
+						min_binding_size: BufferSize::new(GpuGiSettings::std140_size_static() as u64),
+					},
+					count: None,
+				},
+				// the nodes of the octree or the cells of the brick map, so a sparse storage can be traced
+				compute_storage_entry(2, true, 4),
 			],
 			label: None,
 		});
 
-		// vertex and index buffers
-		let mesh_model_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+		let voxelize_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
 			entries: &[
+				// all cascades of the view, so we know where to write
 				BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStage::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true},
-                        has_dynamic_offset: true,
-                        min_binding_size: BufferSize::new(Mat4::std140_size_static() as u64), // TODO
-                    },
-                    count: None,
-                },
+					binding: 0,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Storage { read_only: true },
+						has_dynamic_offset: true,
+						min_binding_size: Some(GpuGiCascades::min_binding_size()),
+					},
+					count: None,
+				},
+				// which cascade, and which layers of it
 				BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStage::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true},
-                        has_dynamic_offset: true,
-                        min_binding_size: BufferSize::new(Mat4::std140_size_static() as u64), // TODO
-                    },
-                    count: None,
-                },
+					binding: 1,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						min_binding_size: BufferSize::new(GpuVoxelizeParams::std140_size_static() as u64),
+					},
+					count: None,
+				},
+				// the triangles voxelized, for GiDiagnosticsPlugin::gpu_counters
+				compute_storage_entry(2, false, 4),
+				// the sums of a slab, for dense cascades
+				compute_storage_entry(3, false, VOXEL_SUM_SIZE),
+				// the fragments and their count, for the sparse storages
+				compute_storage_entry(4, false, FRAGMENT_SIZE),
+				compute_storage_entry(5, false, 4),
 			],
 			label: None,
 		});
-		
-		// we'll need index buffer, vertex buffer layouts, + mesh transform, mesh material, lights and the cascades as inputs
-        let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: None,
-            push_constant_ranges: &[],
-            bind_group_layouts: &[&view_layout, &mesh_layout, &volume_layout],
-        });
-
-		let voxelize_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
-			label: None,
-			layout: Some(&pipeline_layout),
-			entry_point: "vertex",
-			module: &shader_module,
-		});
-		/*
-		let vertex_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
-			label: None,
-			layout: Some(&pipeline_layout),
-			entry_point: "voxelize",
-			module: &shader_module,
-		});
-		*/
-		let mipmap_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
-			label: None,
-			layout: Some(&pipeline_layout),
-			entry_point: "mipmap",
-			module: &shader_module,
-		});
 
-		/*
-        let pipeline = render_device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: None,
-            vertex: VertexState {
-                buffers: &[VertexBufferLayout {
-                    array_stride: 32,
-                    step_mode: InputStepMode::Vertex,
-                    attributes: &[
-                        // Position (GOTCHA! Vertex_Position isn't first in the buffer due to how Mesh sorts attributes (alphabetically))
-                        VertexAttribute {
-                            format: VertexFormat::Float32x3,
-                            offset: 12,
-                            shader_location: 0,
-                        },
-                        // Normal
-                        VertexAttribute {
-                            format: VertexFormat::Float32x3,
-                            offset: 0,
-                            shader_location: 1,
-                        },
-                        // Uv
-                        VertexAttribute {
-                            format: VertexFormat::Float32x2,
-                            offset: 24,
-                            shader_location: 2,
-                        },
-                    ],
-                }],
-                module: &shader_module,
-                entry_point: "vertex",
-            },
-            fragment: Some(FragmentState {
-                module: &shader_module,
-                entry_point: "fragment",
-                targets: &[], // no targets needed here, we just need to write to the volume texture inside the shader
-            }),
-            depth_stencil: None,
-            layout: Some(&pipeline_layout),
-            multisample: MultisampleState::default(),
-            primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: FrontFace::Ccw,
-                cull_mode: None,
-                polygon_mode: PolygonMode::Fill,
-                clamp_depth: false,
-                conservative: false,
-            },
-        });
-		*/
+		let volume_pipelines = GiVolumeFormat::ALL
+			.iter()
+			.map(|&format| {
+				let shader = volume_format_shader(include_str!("voxelize.wgsl"), format);
+				let shader_module = render_device.create_shader_module(&shader);
+
+				let volume_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+					entries: &[volume_storage_entry(1, format)],
+					label: None,
+				});
+
+				// a triangle at a time, with the mesh it's from
+				let triangle_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+					label: None,
+					push_constant_ranges: &[],
+					bind_group_layouts: &[
+						&voxelize_layout,
+						&mesh_shaders.transform_layout,
+						&mesh_shaders.material_layout,
+						&mesh_shaders.geometry_layout,
+					],
+				});
+
+				let voxelize_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+					label: None,
+					layout: Some(&triangle_layout),
+					entry_point: "voxelize",
+					module: &shader_module,
+				});
+
+				let resolve_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+					label: None,
+					push_constant_ranges: &[],
+					bind_group_layouts: &[&voxelize_layout, &volume_layout],
+				});
+
+				let resolve_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+					label: None,
+					layout: Some(&resolve_layout),
+					entry_point: "resolve_voxels",
+					module: &shader_module,
+				});
+
+				let mip_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+					entries: &[volume_storage_entry(0, format), volume_storage_entry(1, format)],
+					label: None,
+				});
+
+				let mipmap_shader = volume_format_shader(include_str!("mipmap.wgsl"), format);
+				let mipmap_module = render_device.create_shader_module(&mipmap_shader);
+
+				let mipmap_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+					label: None,
+					push_constant_ranges: &[],
+					bind_group_layouts: &[&mip_layout],
+				});
+
+				let mipmap_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+					label: None,
+					layout: Some(&mipmap_layout),
+					entry_point: "mipmap",
+					module: &mipmap_module,
+				});
+
+				(format, GiVolumePipelines {
+					voxelize_pipeline,
+					resolve_pipeline,
+					mipmap_pipeline,
+					volume_layout,
+					mip_layout,
+				})
+			})
+			.collect();
 
         GiShaders {
-            //vertex_pipeline,
-			voxelize_pipeline,
-			mipmap_pipeline,
-			mesh_model_layout,
-            view_layout,
-			mesh_layout,
-			volume_layout,
-			volume_sampler: render_device.create_sampler(&SamplerDescriptor {
-				address_mode_u: AddressMode::ClampToEdge,
-				address_mode_v: AddressMode::ClampToEdge,
-				address_mode_w: AddressMode::ClampToEdge,
-				mag_filter: FilterMode::Linear,
-				min_filter: FilterMode::Linear,
-				mipmap_filter: FilterMode::Nearest,
-				..Default::default()
-			})
+			volume_pipelines,
+			voxelize_layout,
+			cascades_layout,
         }
     }
 }
 
+/// warns when a volume asks for more cascades than the plugin allows
+pub fn check_gi_cascade_limit(
+	limit: Res<GiCascadeLimit>,
+	volumes: Query<(Entity, &GiVolume), Changed<GiVolume>>,
+) {
+	for (entity, volume) in volumes.iter() {
+		if volume.cascades as u32 > limit.0 {
+			warn!(
+				"GiVolume {:?} requests {} cascades, but GiPlugin only allows {}, only the first {} will be used",
+				entity, volume.cascades, limit.0, limit.0,
+			);
+		} else if volume.cascades as usize > MAX_TRACED_CASCADES {
+			warn!(
+				"GiVolume {:?} requests {} cascades, but the cone tracer can only bind {}, the rest are voxelized but never traced",
+				entity, volume.cascades, MAX_TRACED_CASCADES,
+			);
+		}
+	}
+}
+
+/// largest resolution a cascade can have on this device, as each cascade is a single 3d texture
+pub fn max_gi_resolution(render_device: &RenderDevice) -> u32 {
+	render_device.wgpu_device().limits().max_texture_dimension_3d
+}
+
+/// warns when a volume asks for a larger resolution than the device can make 3d textures for
+pub fn check_gi_resolution_limit(
+	render_device: Res<RenderDevice>,
+	volumes: Query<(Entity, &GiVolume), Changed<GiVolume>>,
+) {
+	let max_size = max_gi_resolution(&render_device);
+
+	for (entity, volume) in volumes.iter() {
+		if volume.resolution.max_element() > max_size {
+			warn!(
+				"GiVolume {:?} requests a resolution of {}, but the max 3d texture size is {}, it will be clamped to {}",
+				entity, volume.resolution, max_size, volume.resolution.min(UVec3::splat(max_size)),
+			);
+		}
+	}
+}
+
+/// writes the settings of the `GiQuality` to `GiSettings` when it changes, and the resolution and cascades to every volume
+///
+/// without a quality the volumes are left alone, so they keep the resolution and cascades they were made with
+/// the volumes are only written to when the resolution or cascades of the settings change, or the volume is new
+/// the rest of the settings are read by the tracer every frame, so changing how it looks doesn't voxelize everything again
+pub fn apply_gi_quality(
+	quality: Option<Res<GiQuality>>,
+	mut settings: ResMut<GiSettings>,
+	// the resolution and cascades that were last written to the volumes
+	mut applied: Local<Option<(u32, u8)>>,
+	mut volumes: Query<(&mut GiVolume, ChangeTrackers<GiVolume>)>,
+) {
+	let quality = match quality {
+		Some(quality) => quality,
+		None => return,
+	};
+
+	if quality.is_changed() {
+		quality.apply(&mut settings);
+	}
+
+	let shape = (settings.resolution, settings.cascades);
+	let shape_changed = *applied != Some(shape);
+	*applied = Some(shape);
+
+	for (mut volume, tracker) in volumes.iter_mut() {
+		if !shape_changed && !tracker.is_added() {
+			continue;
+		}
+
+		// only written when it's different, so a volume that already has this shape isn't marked as changed
+		let mut shaped = *volume;
+		settings.apply(&mut shaped);
+		if shaped.resolution != volume.resolution || shaped.cascades != volume.cascades || shaped.extent != volume.extent {
+			*volume = shaped;
+		}
+	}
+}
+
 pub fn extract_gi_cascades(
     mut commands: Commands,
-    volumes: Query<(Entity, &GiVolume, &GlobalTransform)>,
+	limit: Res<GiCascadeLimit>,
+	settings: Res<GiSettings>,
+	render_device: Res<RenderDevice>,
+    volumes: Query<(Entity, &GiVolume, &GlobalTransform, Option<&GiTraceBias>, Option<&GiTraceResolution>)>,
 ) {
+	let max_size = max_gi_resolution(&render_device);
 	
 	// we only need 1
-    for (_, volume, transform) in volumes.iter().take(1) {
+    for (_, volume, transform, bias, trace_resolution) in volumes.iter().take(1) {
         // here we get all active volumes
         // each cascade actually needs to render 3 times, with 3 different projections
         // these are calculated in prepare, this is just to find all active volumes, and get the cascade
 		commands.insert_resource(ExtractedGiVolume {
 			transform: *transform,
-			resolution: volume.resolution as u32,
-			cascades: volume.cascades,
-			size: volume.size,
+			// check_gi_resolution_limit already warned about this
+			resolution: volume.resolution.min(UVec3::splat(max_size)),
+			// check_gi_cascade_limit already warned about this
+			cascades: (volume.cascades as u32).min(limit.0) as u8,
+			extent: volume.extent,
+			storage: volume.storage,
+			bias: bias.copied().unwrap_or_default(),
+			temporal_blend: volume.temporal_blend,
+			trace_resolution: trace_resolution.copied().unwrap_or_default(),
+			settings: *settings,
 		});
         
     }
@@ -293,37 +570,232 @@ pub fn extract_gi_cascades(
 }
 
 // Views are needed for every camera that renders, so here we need to store everything
-/*
-pub struct ViewGiVolume {
-    pub volume_texture: Texture,
-    pub volume_texture_view: TextureView,
+
+/// a single cascade in gpu memory
+///
+/// each cascade is it's own 3d texture, so it gets it's own mip chain
+/// stacking them in one texture made mip N of one cascade bleed into the next
+pub struct ViewGiCascade {
+	pub texture: Texture,
+	/// all mips, for sampling
+	pub texture_view: TextureView,
+	/// every mip on it's own, as storage textures can only be bound with a single mip
+	/// the first is written to during voxelization, and every next one is made from the one before it
+	pub mip_views: Vec<TextureView>,
+}
+
+/// a cascade, or a slab of layers of one, voxelized with a single dispatch for every mesh
+pub struct GiVoxelizePass {
+	pub cascade: usize,
+	pub first_layer: u32,
+	pub layers: u32,
+	/// into `GiCascadeMeta::voxelize_params`
+	pub params_offset: u32,
 }
-*/
+
+/// storage layout for all cascades of a volume
+///
+/// `GpuGiCascade::texture_index` is the index into `cascades`, which is empty for the sparse storages
 pub struct ViewGiVolumes {
-	pub volume_texture: Texture,
-    pub volume_texture_view: TextureView,
+	pub cascades: Vec<ViewGiCascade>,
 	pub gpu_volume_binding_index: u32,
+	pub voxelize_passes: Vec<GiVoxelizePass>,
 }
 
+impl ViewGiVolumes {
+	pub fn cascade(&self, texture_index: u32) -> &ViewGiCascade {
+		&self.cascades[texture_index as usize]
+	}
+}
 
-
-#[derive(Default)]
 pub struct GiCascadeMeta {
-    pub view_cascades: DynamicUniformVec<GpuGiCascades>,
+    pub view_cascades: GpuGiCascades,
+	/// `GpuGiSettings`, written every frame so they can be changed live
+	pub settings: Option<Buffer>,
 	pub bind_group: Option<BindGroup>,
+	pub voxelize_params: DynamicUniformVec<GpuVoxelizeParams>,
+	pub voxelize_bind_group: Option<BindGroup>,
+	/// what the triangles add up to in a slab, shared by every pass as they run one after the other
+	pub voxel_sums: Option<Buffer>,
+	pub voxel_sums_size: u64,
+	/// the cascades voxelized this frame, and the ones skipped as the static layer already has everything in them
+	pub cascades_rebuilt: u32,
+	pub cascades_skipped: u32,
+	/// bound in place of what a pass doesn't use, as every binding needs a buffer
+	pub unused_sums: Buffer,
+	pub unused_fragments: Buffer,
+	pub unused_fragment_count: Buffer,
+	pub unused_triangle_counter: Buffer,
+	pub unused_nodes: Buffer,
+}
+
+impl FromWorld for GiCascadeMeta {
+	fn from_world(world: &mut World) -> Self {
+		let limit = world.get_resource::<GiCascadeLimit>().unwrap();
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let unused_buffer = |size: u64| {
+			render_device.create_buffer(&BufferDescriptor {
+				label: None,
+				size,
+				usage: BufferUsage::STORAGE,
+				mapped_at_creation: false,
+			})
+		};
+
+		Self {
+			view_cascades: GpuGiCascades::new(limit.0),
+			settings: None,
+			bind_group: None,
+			voxelize_params: DynamicUniformVec::default(),
+			voxelize_bind_group: None,
+			voxel_sums: None,
+			voxel_sums_size: 0,
+			cascades_rebuilt: 0,
+			cascades_skipped: 0,
+			unused_sums: unused_buffer(VOXEL_SUM_SIZE),
+			unused_fragments: unused_buffer(FRAGMENT_SIZE),
+			unused_fragment_count: unused_buffer(4),
+			unused_triangle_counter: unused_buffer(4),
+			unused_nodes: unused_buffer(4),
+		}
+	}
+}
+
+/// how many layers of a cascade the sums hold at once
+pub fn voxel_sum_layers(resolution: UVec3) -> u32 {
+	let layer_bytes = resolution.x as u64 * resolution.y as u64 * VOXEL_SUM_SIZE;
+	((MAX_VOXEL_SUM_BYTES / layer_bytes.max(1)) as u32).clamp(1, resolution.z.max(1))
+}
+
+/// the cube a sparse storage covers, centered on the volume, as it's size in the space of the volume and it's voxels along each axis
+///
+/// the sparse storages are voxelized and traced as a single cascade of this size
+pub fn sparse_cube(volume: &ExtractedGiVolume) -> (f32, u32) {
+	let largest_extent = volume.extent * cascade_scale(volume.cascades.max(1) as u32 - 1);
+
+	match volume.storage {
+		// with the voxel size of the smallest cascade, rounded up to a power of two voxels
+		GiStorage::SparseOctree => {
+			let voxels = 1 << octree_depth(volume.resolution, volume.cascades);
+			let voxel_size = (volume.extent / volume.resolution.max(UVec3::ONE).as_f32()).max_element();
+
+			(voxels as f32 * voxel_size, voxels)
+		}
+		// the resolution is in bricks
+		GiStorage::BrickMap => (
+			largest_extent.max_element(),
+			volume.resolution.max_element().clamp(1, MAX_BRICK_MAP_GRID) * BRICK_SIZE,
+		),
+		GiStorage::Dense => (largest_extent.max_element(), volume.resolution.max_element()),
+	}
 }
 
-// and it's format
-const VOLUME_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+/// number of mips needed for a full chain down to a single voxel
+pub fn cascade_mip_count(resolution: UVec3) -> u32 {
+	32 - resolution.max_element().max(1).leading_zeros()
+}
+
+/// memory taken by the texture of a single cascade, with all it's mips
+pub fn cascade_texture_bytes(resolution: UVec3, format: GiVolumeFormat) -> u64 {
+	texture_bytes(resolution, cascade_mip_count(resolution), format.bytes_per_voxel() as u64)
+}
+
+/// memory taken by a 3d texture with `mip_count` mips
+pub fn texture_bytes(size: UVec3, mip_count: u32, bytes_per_texel: u64) -> u64 {
+	(0..mip_count)
+		.map(|mip| {
+			let texels = [size.x, size.y, size.z]
+				.iter()
+				.map(|&size| (size >> mip).max(1) as u64)
+				.product::<u64>();
+
+			texels * bytes_per_texel
+		})
+		.sum()
+}
+
+/// allocates the texture for a single cascade, and the views needed for it
+///
+/// the texture cache hands out a new texture when the resolution or format changes, and drops the old one
+/// the resolution is already clamped to `max_gi_resolution` during extraction
+fn allocate_cascade(
+	texture_cache: &mut TextureCache,
+	render_device: &RenderDevice,
+	resolution: UVec3,
+	format: GiVolumeFormat,
+) -> ViewGiCascade {
+	let texture = texture_cache.get(
+		render_device,
+		TextureDescriptor {
+			size: Extent3d {
+				width: resolution.x,
+				height: resolution.y,
+				depth_or_array_layers: resolution.z,
+			},
+			mip_level_count: cascade_mip_count(resolution),
+			sample_count: 1,
+			dimension: TextureDimension::D3,
+			format: volume_texture_format(format),
+			// copies are for the static layer and readback
+			usage: TextureUsage::SAMPLED | TextureUsage::STORAGE | TextureUsage::COPY_SRC | TextureUsage::COPY_DST,
+			label: None,
+		},
+	);
+
+	let texture_view = texture.texture.create_view(&TextureViewDescriptor {
+		label: None,
+		format: None,
+		dimension: Some(TextureViewDimension::D3),
+		aspect: TextureAspect::All,
+		base_mip_level: 0,
+		mip_level_count: None,
+		base_array_layer: 0,
+		array_layer_count: None,
+	});
+
+	// storage textures can only be bound with a single mip
+	let mip_views = (0..cascade_mip_count(resolution))
+		.map(|mip| {
+			texture.texture.create_view(&TextureViewDescriptor {
+				label: None,
+				format: None,
+				dimension: Some(TextureViewDimension::D3),
+				aspect: TextureAspect::All,
+				base_mip_level: mip,
+				mip_level_count: std::num::NonZeroU32::new(1),
+				base_array_layer: 0,
+				array_layer_count: None,
+			})
+		})
+		.collect();
+
+	ViewGiCascade {
+		texture: texture.texture,
+		texture_view,
+		mip_views,
+	}
+}
 
 pub fn prepare_gi_cascades(
     mut commands: Commands,
     mut texture_cache: ResMut<TextureCache>,
     render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
     views: Query<Entity, With<RenderPhase<Transparent3dPhase>>>,
     mut cascade_meta: ResMut<GiCascadeMeta>,
-    volume: Res<ExtractedGiVolume>,
+    volume: Option<Res<ExtractedGiVolume>>,
+	static_layer: Option<Res<ExtractedGiStaticLayer>>,
+	extracted_meshes: Res<ExtractedGiMeshes>,
+	extracted_skins: Res<ExtractedGiSkins>,
+	mesh_meta: Res<GiMeshMeta>,
 ) {
+	// no volume, so no gi
+	let volume = match volume {
+		Some(volume) => volume,
+		None => return,
+	};
+
     // reserve the right amount of space for the cascades
     cascade_meta
         .view_cascades
@@ -331,81 +803,375 @@ pub fn prepare_gi_cascades(
 
     // TODO: I assume I also need to get all lights here if I want to pass that to the voxelization shader?
 
-	for entity in views.iter() {
+	// already limited during extraction
+	let num_cascades = volume.cascades as usize;
+
+	// every cascade has the same resolution, so they're all split into the same slabs
+	let slab_layers = voxel_sum_layers(volume.resolution);
+	let slabs = (volume.resolution.z + slab_layers - 1) / slab_layers;
+
+	// a cascade the static layer is copied into only has to be voxelized when a dynamic mesh reaches into it
+	// skinned meshes move on the gpu, and meshes that just came in may not have their triangles yet, so those always do
+	let skipped: Vec<bool> = (0..num_cascades)
+		.map(|cascade| {
+			if volume.storage != GiStorage::Dense
+				|| !static_layer.as_ref().map_or(false, |static_layer| static_layer.covers(&volume, cascade))
+			{
+				return false;
+			}
+
+			let projection = cascade_projection(volume.extent, &volume.transform, cascade as u32);
+			let cascade_extent = volume.extent * cascade_scale(cascade as u32) * volume.transform.scale;
+			let margin = Vec3::splat(SKIP_MARGIN_VOXELS) / volume.resolution.as_f32();
+
+			!extracted_meshes
+				.meshes
+				.iter()
+				.filter(|mesh| mesh.occluder || mesh.emitter)
+				.any(|mesh| {
+					let skinned = extracted_skins.skins.iter().any(|skin| skin.entity == mesh.entity);
+					let thickness = match mesh.thin_geometry {
+						GiThinGeometry::Solidify(thickness) => thickness,
+						_ => 0.0,
+					};
+
+					match mesh_meta.geometry.get(&mesh.voxel_mesh) {
+						Some(geometry) if !skinned => box_in_cascade(
+							&projection,
+							&mesh.transform,
+							geometry.min,
+							geometry.max,
+							margin + Vec3::splat(thickness) / cascade_extent,
+						),
+						_ => true,
+					}
+				})
+		})
+		.collect();
+
+	let skipped_cascades = skipped.iter().filter(|skipped| **skipped).count();
+	cascade_meta.cascades_skipped = skipped_cascades as u32;
+	cascade_meta.cascades_rebuilt = match volume.storage {
+		GiStorage::Dense => (num_cascades - skipped_cascades) as u32,
+		_ => 1,
+	};
+
+	// the sparse storages are voxelized in a single pass, as they're shared by every view
+	let num_passes = match volume.storage {
+		GiStorage::Dense => views.iter().count() * (num_cascades - skipped_cascades) * slabs as usize,
+		_ => 1,
+	};
 
-		// get the volume texture, with the right amount of memory allocated
-		let volume_texture = texture_cache.get(
-			&render_device,
-			TextureDescriptor {
-				size: Extent3d { // TODO CHECK IF SIZE IS ALLOWED
-					width: volume.resolution,
-					height: volume.resolution,
-					depth_or_array_layers: volume.resolution * (MAX_CASCADE_NUM as u32).min(volume.cascades as u32),
-				},
-				mip_level_count: volume.resolution.next_power_of_two(), // this is the same as the log of the resolution
-				sample_count: 1,
-				dimension: TextureDimension::D3,
-				format: VOLUME_TEXTURE_FORMAT,
-				usage: TextureUsage::SAMPLED | TextureUsage::STORAGE,
-				label: None,
-			},
-		);
+	cascade_meta
+		.voxelize_params
+		.reserve_and_clear(num_passes, &render_device);
 
-		// get the view for it
-		let volume_texture_view = volume_texture.texture.create_view(&TextureViewDescriptor {
-			label: None,
-			format: None,
-			dimension: Some(TextureViewDimension::D3),
-			aspect: TextureAspect::All,
-			base_mip_level: 0,
-			mip_level_count: None,
-			base_array_layer: 0,
-			array_layer_count: None,
-		});
+	for (view, entity) in views.iter().enumerate() {
 
 		// store our view cascades
-		let mut gpu_cascades = GpuGiCascades {
-			num_cascades: (MAX_CASCADE_NUM as u32).min(volume.cascades as u32),
-			cascades: [GpuGiCascade::default(); MAX_CASCADE_NUM],
-		};
+		let mut gpu_cascades = Vec::with_capacity(num_cascades);
 
 		// go over all cascades
-		// we need a seperate texture for all cascades due to size
+		// we need a seperate texture for all cascades due to size, and so mips don't bleed between cascades
 		// this is roughly similar to how light does it but not really
-		for cascade in 0..((volume.cascades as usize).min(MAX_CASCADE_NUM)) {
+		let mut cascades = Vec::with_capacity(num_cascades);
+
+		// the sparse storages have their own, so they only need a single cascade to voxelize and trace them with
+		let dense_cascades = match volume.storage {
+			GiStorage::Dense => num_cascades,
+			_ => 0,
+		};
 
+		for cascade in 0..dense_cascades {
 
 			// get the projection matrix
-			// TODO: FIX
-			let projection = volume.transform;
+			// the resolution stays the same for every cascade, so the voxels stay cubic
+			// this is shared with the cpu voxelizer, so both end up with the same voxels
+			let cascade_extent = volume.extent * cascade_scale(cascade as u32);
+			let projection = cascade_projection(volume.extent, &volume.transform, cascade as u32);
 
 			// store it into the gpu gi cascades
-			gpu_cascades.cascades[cascade] = GpuGiCascade {
-				projection: Mat4::default(),
-				resolution: volume.resolution,
-				texture_index: cascade as u32,
-			};
+			gpu_cascades.push(GpuGiCascade {
+				projection,
+				resolution: volume.resolution.as_f32(),
+				texture_index: cascades.len() as u32,
+				extent: cascade_extent * volume.transform.scale,
+			});
+
+			// get the volume texture, with the right amount of memory allocated
+			cascades.push(allocate_cascade(&mut texture_cache, &render_device, volume.resolution, volume.settings.format));
+		}
+
+		// the static layer is copied into the cascades it covers before voxelizing, so the empty voxels keep it
+		let mut voxelize_passes = Vec::with_capacity(cascades.len() * slabs as usize);
+		for cascade in 0..cascades.len() {
+			if skipped[cascade] {
+				continue;
+			}
+
+			let keep_existing = static_layer
+				.as_ref()
+				.map_or(false, |static_layer| static_layer.covers(&volume, cascade));
+
+			for first_layer in (0..volume.resolution.z).step_by(slab_layers as usize) {
+				let layers = slab_layers.min(volume.resolution.z - first_layer);
+				// the first pass of the first view, as every cascade may be skipped but the ones after it
+				let count_triangles = view == 0 && voxelize_passes.is_empty();
+
+				voxelize_passes.push(GiVoxelizePass {
+					cascade,
+					first_layer,
+					layers,
+					params_offset: cascade_meta.voxelize_params.push(GpuVoxelizeParams {
+						cascade: cascade as u32,
+						first_layer,
+						layers,
+						keep_existing: keep_existing as u32,
+						fragments: 0,
+						count_triangles: count_triangles as u32,
+					}),
+				});
+			}
+		}
+
+		if volume.storage != GiStorage::Dense {
+			let (size, voxels) = sparse_cube(&volume);
+
+			gpu_cascades.push(GpuGiCascade {
+				projection: cascade_projection(Vec3::splat(size), &volume.transform, 0),
+				resolution: Vec3::splat(voxels as f32),
+				texture_index: 0,
+				extent: size * volume.transform.scale,
+			});
+
+			// the whole cube at once, as there are no sums, the fragments are appended to the list of the storage
+			if view == 0 {
+				voxelize_passes.push(GiVoxelizePass {
+					cascade: 0,
+					first_layer: 0,
+					layers: voxels,
+					params_offset: cascade_meta.voxelize_params.push(GpuVoxelizeParams {
+						cascade: 0,
+						first_layer: 0,
+						layers: voxels,
+						keep_existing: 0,
+						fragments: 1,
+						count_triangles: 1,
+					}),
+				});
+			}
 		}
 
 		// and add it to the commands
 		commands.entity(entity).insert(ViewGiVolumes {
-			volume_texture: volume_texture.texture,
-			volume_texture_view,
-			gpu_volume_binding_index: cascade_meta.view_cascades.push(gpu_cascades)
+			cascades,
+			gpu_volume_binding_index: cascade_meta.view_cascades.push(&gpu_cascades, volume.bias, volume.storage),
+			voxelize_passes,
 		});
 	}
 
 	cascade_meta
 		.view_cascades
+		.write_buffer(&render_queue);
+
+	// copied to the uniform buffer by the voxelize node
+	cascade_meta
+		.voxelize_params
 		.write_to_staging_buffer(&render_device);
 
+	// only as large as a single slab, the passes run one after the other, and resolving clears it for the next
+	let sum_size = volume.resolution.x as u64 * volume.resolution.y as u64 * slab_layers as u64 * VOXEL_SUM_SIZE;
+	if volume.storage == GiStorage::Dense && (cascade_meta.voxel_sums.is_none() || cascade_meta.voxel_sums_size < sum_size) {
+		cascade_meta.voxel_sums = Some(render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: sum_size,
+			usage: BufferUsage::STORAGE,
+			mapped_at_creation: false,
+		}));
+		cascade_meta.voxel_sums_size = sum_size;
+	}
+
+	let settings = cascade_meta.settings.get_or_insert_with(|| {
+		render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: GpuGiSettings::std140_size_static() as u64,
+			usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		})
+	});
+
+	render_queue.write_buffer(settings, 0, GpuGiSettings::from(&volume.settings).as_std140().as_bytes());
+
+}
+
+/// bind groups for writing to the cascades of a view, indexed by `GpuGiCascade::texture_index`
+pub struct ViewGiVolumeBindGroups {
+	/// the first mip, to resolve the voxels into
+	pub cascades: Vec<BindGroup>,
+	/// a mip and the one below it, for every mip but the first
+	pub mips: Vec<Vec<BindGroup>>,
+}
+
+pub fn queue_gi_cascade_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	gi_shaders: Res<GiShaders>,
+	mut cascade_meta: ResMut<GiCascadeMeta>,
+	volume: Option<Res<ExtractedGiVolume>>,
+	counters: Option<Res<GiCounters>>,
+	fragment_meta: Res<VoxelFragmentMeta>,
+	octree_meta: Res<SparseOctreeMeta>,
+	brick_map_meta: Res<BrickMapMeta>,
+	views: Query<(Entity, &ViewGiVolumes)>,
+) {
+	let cascade_meta = &mut *cascade_meta;
+
+	// nothing to bind if there are no cascades this frame
+	let (cascades_binding, settings) = match (cascade_meta.view_cascades.binding(), &cascade_meta.settings) {
+		(Some(binding), Some(settings)) => (binding, settings),
+		_ => return,
+	};
+
+	// what the cone tracer finds the bricks of a sparse storage through
+	let nodes = match (&octree_meta.octree, &brick_map_meta.brick_map) {
+		(Some(octree), _) => &octree.nodes,
+		(_, Some(brick_map)) => &brick_map.cells,
+		_ => &cascade_meta.unused_nodes,
+	};
+
+	// this one is shared between all views, the dynamic offset picks the view
+	cascade_meta.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: cascades_binding.clone(),
+			},
+			BindGroupEntry {
+				binding: 1,
+				resource: settings.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 2,
+				resource: nodes.as_entire_binding(),
+			},
+		],
+		label: None,
+		layout: &gi_shaders.cascades_layout,
+	}));
+
+	let triangle_counter = counters
+		.as_ref()
+		.map_or(&cascade_meta.unused_triangle_counter, |counters| &counters.buffer);
+
+	// dense cascades add to the sums, the sparse storages append to the fragments
+	let sums = cascade_meta.voxel_sums.as_ref().unwrap_or(&cascade_meta.unused_sums);
+	let (fragments, fragment_count) = match &fragment_meta.fragments {
+		Some(fragments) => (&fragments.fragments, &fragments.fragment_count),
+		None => (&cascade_meta.unused_fragments, &cascade_meta.unused_fragment_count),
+	};
+
+	// also shared, the dynamic offsets pick the view and the pass
+	cascade_meta.voxelize_bind_group = cascade_meta.voxelize_params.binding().map(|params_binding| {
+		render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: cascades_binding,
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: params_binding,
+				},
+				BindGroupEntry {
+					binding: 2,
+					resource: triangle_counter.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 3,
+					resource: sums.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 4,
+					resource: fragments.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 5,
+					resource: fragment_count.as_entire_binding(),
+				},
+			],
+			label: None,
+			layout: &gi_shaders.voxelize_layout,
+		})
+	});
+
+	// and some per cascade to write to, which depend on the format of the cascades
+	let volume_pipelines = match volume {
+		Some(volume) => gi_shaders.volume_pipelines(volume.settings.format),
+		None => return,
+	};
+
+	for (entity, view_volumes) in views.iter() {
+
+		let cascades = view_volumes.cascades.iter().map(|cascade| {
+			render_device.create_bind_group(&BindGroupDescriptor {
+				entries: &[
+					BindGroupEntry {
+						binding: 1,
+						resource: BindingResource::TextureView(&cascade.mip_views[0]),
+					},
+				],
+				label: None,
+				layout: &volume_pipelines.volume_layout,
+			})
+		}).collect();
+
+		let mips = view_volumes.cascades.iter().map(|cascade| {
+			cascade.mip_views.windows(2).map(|views| {
+				render_device.create_bind_group(&BindGroupDescriptor {
+					entries: &[
+						BindGroupEntry {
+							binding: 0,
+							resource: BindingResource::TextureView(&views[0]),
+						},
+						BindGroupEntry {
+							binding: 1,
+							resource: BindingResource::TextureView(&views[1]),
+						},
+					],
+					label: None,
+					layout: &volume_pipelines.mip_layout,
+				})
+			}).collect()
+		}).collect();
+
+		commands.entity(entity).insert(ViewGiVolumeBindGroups { cascades, mips });
+	}
 }
 
-pub struct VoxelizePhase;
+/// makes every mip of the cascades of a view from the first one, also after the bounces wrote it
+pub fn mipmap_cascades(
+	encoder: &mut CommandEncoder,
+	pipelines: &GiVolumePipelines,
+	bind_groups: &ViewGiVolumeBindGroups,
+	resolution: UVec3,
+) {
+	let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_mipmap") });
+
+	pass.set_pipeline(&pipelines.mipmap_pipeline);
+
+	// every mip is made from the one above it, so they go in order
+	for cascade_mips in bind_groups.mips.iter() {
+		for (mip, mip_bind_group) in cascade_mips.iter().enumerate() {
+			let size = |size: u32| ((size >> (mip + 1)).max(1) + 3) / 4;
+
+			pass.set_bind_group(0, mip_bind_group, &[]);
+			pass.dispatch(size(resolution.x), size(resolution.y), size(resolution.z));
+		}
+	}
+}
 
 pub struct VoxelizePassNode {
-	main_view_query: QueryState<&'static ViewGiVolumes>,
-	view_volume_query: QueryState<(&'static ViewGiVolumes, &'static RenderPhase<VoxelizePhase>)>,
+	view_query: QueryState<(&'static ViewGiVolumes, &'static ViewGiVolumeBindGroups)>,
 }
 
 impl VoxelizePassNode {
@@ -415,8 +1181,7 @@ impl VoxelizePassNode {
 	pub fn new(world: &mut World) -> Self {
 
 		Self {
-			main_view_query: QueryState::new(world),
-			view_volume_query: QueryState::new(world),
+			view_query: QueryState::new(world),
 		}
 	}
 }
@@ -428,75 +1193,122 @@ impl Node for VoxelizePassNode {
 	}
 
 	fn update(&mut self, world: &mut World) {
-		self.main_view_query.update_archetypes(world);
-		self.view_volume_query.update_archetypes(world);
+		self.view_query.update_archetypes(world);
 	}
 
 	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
 
 		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
 
-		if let Ok(view_volume) = self.main_view_query.get_manual(world, view_entity) {
-
-			//let view
-
-			// TODO: only need to get one compute pass done, because we can do all volumes + cascades in one go
-			// TODO: figure out how this works
-			// TODO: first, run only one compute pass on all triangles, and per triangle, write to the volume
-			// THEN: generate mipmaps for the volume
-			let pass_descriptor = ComputePassDescriptor { label: None };
-
-			let draw_functions = world.get_resource::<DrawFunctions>().unwrap();
-
-			// start the compute pass for voxelization
-			let compute_pass = render_context
-				.command_encoder
-				.begin_compute_pass(&pass_descriptor);
-
-			let mut draw_functions = draw_functions.write();
-
-			//let mut tracked_pass = TrackedComputePass TODO
-
-			//for drawable in RenderPhase<VoxelizePhase>::defa
-
-			// go over all drawable objects
-
-				// draw
-
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let cascade_meta = world.get_resource::<GiCascadeMeta>().unwrap();
+		let mesh_meta = world.get_resource::<GiMeshMeta>().unwrap();
+		let extracted_meshes = world.get_resource::<ExtractedGiMeshes>().unwrap();
+		let skinning_meta = world.get_resource::<GiSkinningMeta>().unwrap();
+
+		if let (Ok((view_volumes, bind_groups)), Some(volume)) = (
+			self.view_query.get_manual(world, view_entity),
+			world.get_resource::<ExtractedGiVolume>(),
+		) {
+
+			// the dynamic meshes are added on top of the static layer
+			restore_gi_static_layer(world, &mut render_context.command_encoder, view_volumes);
+
+			// voxelization is the first to use these, the cone tracer reads the transforms too
+			mesh_meta
+				.transform_uniforms
+				.write_to_uniform_buffer(&mut render_context.command_encoder);
+			mesh_meta
+				.material_uniforms
+				.write_to_uniform_buffer(&mut render_context.command_encoder);
+			cascade_meta
+				.voxelize_params
+				.write_to_uniform_buffer(&mut render_context.command_encoder);
+
+			if let (Some(voxelize_bind_group), Some(transform_bind_group), Some(material_bind_group)) = (
+				&cascade_meta.voxelize_bind_group,
+				&mesh_meta.transform_bind_group,
+				&mesh_meta.material_bind_group,
+			) {
+				let pipelines = gi_shaders.volume_pipelines(volume.settings.format);
+				let resolution = volume.resolution;
+
+				// meshes that only receive gi, or whose triangles aren't on the gpu yet, don't add anything
+				// skinned meshes are read from where skinning wrote them
+				let meshes: Vec<_> = extracted_meshes
+					.meshes
+					.iter()
+					.filter(|mesh| mesh.occluder || mesh.emitter)
+					.filter_map(|mesh| match skinning_meta.skinned.get(&mesh.entity) {
+						Some(skinned) => Some((mesh, skinned.geometry_bind_group.as_ref()?, skinned.triangle_count)),
+						None => {
+							let geometry = mesh_meta.geometry.get(&mesh.voxel_mesh)?;
+							Some((mesh, geometry.bind_group.as_ref()?, geometry.triangle_count))
+						}
+					})
+					.collect();
+
+				// the triangles of every mesh add to the sums of the slab, which are then averaged into the cascade
+				// or to the fragments, which the sparse storages are built from by their own nodes
+				// averaging the sums is where the light goes into the voxels, so it's in it's own pass to time it as injection
+				for voxelize_pass in view_volumes.voxelize_passes.iter() {
+					begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+
+					{
+						let mut pass = render_context
+							.command_encoder
+							.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_voxelize") });
+
+						pass.set_pipeline(&pipelines.voxelize_pipeline);
+						pass.set_bind_group(
+							0,
+							voxelize_bind_group,
+							&[view_volumes.gpu_volume_binding_index, voxelize_pass.params_offset],
+						);
+
+						for (mesh, geometry_bind_group, triangle_count) in meshes.iter() {
+							pass.set_bind_group(1, transform_bind_group, &[mesh.transform_binding_offset]);
+							pass.set_bind_group(2, material_bind_group, &[mesh.material_binding_offset]);
+							pass.set_bind_group(3, geometry_bind_group, &[]);
+							pass.dispatch((triangle_count + VOXELIZE_WORKGROUP_SIZE - 1) / VOXELIZE_WORKGROUP_SIZE, 1, 1);
+						}
+					}
+
+					end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+
+					if let Some(cascade_bind_group) = bind_groups.cascades.get(voxelize_pass.cascade) {
+						begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Inject);
+
+						{
+							let mut pass = render_context
+								.command_encoder
+								.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_inject") });
+
+							pass.set_pipeline(&pipelines.resolve_pipeline);
+							pass.set_bind_group(
+								0,
+								voxelize_bind_group,
+								&[view_volumes.gpu_volume_binding_index, voxelize_pass.params_offset],
+							);
+							pass.set_bind_group(1, cascade_bind_group, &[]);
+							pass.dispatch(
+								(resolution.x + 3) / 4,
+								(resolution.y + 3) / 4,
+								(voxelize_pass.layers + 3) / 4,
+							);
+						}
+
+						end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Inject);
+					}
+				}
+
+				begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Mip);
+				mipmap_cascades(&mut render_context.command_encoder, pipelines, bind_groups, resolution);
+				end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Mip);
+			}
 		}
 
 		Ok(())
 
 	}
 }
-
-type VoxelizeMeshParams<'s, 'w> = (
-	Res<'w, GiShaders>,
-	Res<'w, ExtractedMeshes>,
-	Res<'w, GiCascadeMeta>,
-	Res<'w, MeshMeta>,
-	Res<'w, RenderAssets<Mesh>>,
-);
-
-pub struct VoxelizeMesh {
-	params: SystemState<VoxelizeMeshParams<'static, 'static>>,
-}
-
-impl VoxelizeMesh {
-	pub fn new(world: &mut World) -> Self {
-		Self { 
-			params: SystemState::new(world), 
-		}
-	}
-}
-/*
-impl Draw for VoxelizeMesh {
-
-	fn draw<'w>(
-		&mut self,
-		world: &'w World,
-		pass: &mut 
-	)
-
-}
-*/
\ No newline at end of file
@@ -0,0 +1,364 @@
+// HOW IT WORKS
+// when the volume has a lower trace resolution, the cone trace node only renders the gbuffer
+// this node then traces specular for every pixel, and diffuse for one pixel in every block, into it's own target
+// that target is upsampled into the traced diffuse target, guided by the depth and normals in the gbuffer
+// see LOW RESOLUTION in cone_trace.wgsl, the upsampled result goes through the temporal resolve like everything else
+
+use crevice::std140::{AsStd140, Std140};
+
+use crate::bundle::GiTraceResolution;
+
+use super::cone_trace::{
+	ConeTraceMeta, ConeTraceShaders, ViewGiTraceBindGroup, ViewGiTraceTargets, TRACE_OUTPUT_FORMAT, TRACE_WORKGROUP_SIZE,
+};
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+use super::gi_volume::{ExtractedGiVolume, GiCascadeMeta, GiShaders, ViewGiVolumes};
+use super::probes::GiProbeMeta;
+
+use bevy::ecs::prelude::*;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+	texture::TextureCache,
+	view::{ExtractedView, ViewUniformOffset},
+};
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiLowRes {
+	scale: u32,
+}
+
+pub struct GiLowResShaders {
+	pub specular_pipeline: ComputePipeline,
+	pub trace_pipeline: ComputePipeline,
+	pub upsample_pipeline: ComputePipeline,
+	/// for writing the low res target while tracing
+	pub write_layout: BindGroupLayout,
+	/// for reading it when upsampling
+	pub read_layout: BindGroupLayout,
+}
+
+fn uniform_entry() -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding: 4,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Uniform,
+			has_dynamic_offset: false,
+			min_binding_size: BufferSize::new(GpuGiLowRes::std140_size_static() as u64),
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for GiLowResShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let trace_shaders = world.get_resource::<ConeTraceShaders>().unwrap();
+
+		let write_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				uniform_entry(),
+				BindGroupLayoutEntry {
+					binding: 5,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::StorageTexture {
+						access: StorageTextureAccess::WriteOnly,
+						format: TRACE_OUTPUT_FORMAT,
+						view_dimension: TextureViewDimension::D2,
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let read_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				uniform_entry(),
+				BindGroupLayoutEntry {
+					binding: 6,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Texture {
+						multisampled: false,
+						sample_type: TextureSampleType::Float { filterable: false },
+						view_dimension: TextureViewDimension::D2,
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		// in the same shader as the cone tracer, so they can share the tracing
+		let shader = Shader::from_wgsl(include_str!("cone_trace.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let pipeline = |low_res_layout: Option<&BindGroupLayout>, entry_point: &str| {
+			let mut bind_group_layouts = vec![
+				&trace_shaders.view_layout,
+				&gi_shaders.cascades_layout,
+				&trace_shaders.trace_layout,
+			];
+			bind_group_layouts.extend(low_res_layout);
+
+			let layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+				label: None,
+				push_constant_ranges: &[],
+				bind_group_layouts: &bind_group_layouts,
+			});
+
+			render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+				label: None,
+				layout: Some(&layout),
+				entry_point,
+				module: &shader_module,
+			})
+		};
+
+		let specular_pipeline = pipeline(None, "trace_specular");
+		let trace_pipeline = pipeline(Some(&write_layout), "trace_low_res");
+		let upsample_pipeline = pipeline(Some(&read_layout), "upsample");
+
+		GiLowResShaders {
+			specular_pipeline,
+			trace_pipeline,
+			upsample_pipeline,
+			write_layout,
+			read_layout,
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct GiLowResMeta {
+	/// only made once a volume asks for a lower resolution
+	pub uniform: Option<Buffer>,
+}
+
+/// the low res target of a view, only there when the volume traces at a lower resolution
+pub struct ViewGiLowResTargets {
+	pub width: u32,
+	pub height: u32,
+	/// rgb is the indirect diffuse light, a the ambient occlusion, same as `ViewGiTraceTargets::indirect_diffuse`
+	pub diffuse: TextureView,
+}
+
+pub struct ViewGiLowResBindGroups {
+	pub write: BindGroup,
+	pub read: BindGroup,
+}
+
+pub fn prepare_gi_low_res_targets(
+	mut commands: Commands,
+	mut texture_cache: ResMut<TextureCache>,
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut low_res_meta: ResMut<GiLowResMeta>,
+	views: Query<(Entity, &ExtractedView)>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	// nothing to do at full resolution
+	let scale = match volume {
+		Some(volume) if volume.trace_resolution != GiTraceResolution::Full => {
+			volume.trace_resolution.scale()
+		}
+		_ => return,
+	};
+
+	let uniform = low_res_meta.uniform.get_or_insert_with(|| {
+		render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: GpuGiLowRes::std140_size_static() as u64,
+			usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		})
+	});
+
+	render_queue.write_buffer(uniform, 0, GpuGiLowRes { scale }.as_std140().as_bytes());
+
+	for (entity, view) in views.iter() {
+		// rounded up, so the last pixels have a block too
+		let width = (view.width + scale - 1) / scale;
+		let height = (view.height + scale - 1) / scale;
+
+		let diffuse = texture_cache
+			.get(
+				&render_device,
+				TextureDescriptor {
+					size: Extent3d {
+						width,
+						height,
+						depth_or_array_layers: 1,
+					},
+					mip_level_count: 1,
+					sample_count: 1,
+					dimension: TextureDimension::D2,
+					format: TRACE_OUTPUT_FORMAT,
+					usage: TextureUsage::STORAGE | TextureUsage::SAMPLED,
+					label: None,
+				},
+			)
+			.default_view;
+
+		commands.entity(entity).insert(ViewGiLowResTargets { width, height, diffuse });
+	}
+}
+
+pub fn queue_gi_low_res_bind_groups(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	low_res_shaders: Res<GiLowResShaders>,
+	low_res_meta: Res<GiLowResMeta>,
+	views: Query<(Entity, &ViewGiLowResTargets)>,
+) {
+	let uniform = match &low_res_meta.uniform {
+		Some(uniform) => uniform,
+		None => return,
+	};
+
+	for (entity, targets) in views.iter() {
+		let write = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 4,
+					resource: uniform.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 5,
+					resource: BindingResource::TextureView(&targets.diffuse),
+				},
+			],
+			label: None,
+			layout: &low_res_shaders.write_layout,
+		});
+
+		let read = render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 4,
+					resource: uniform.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 6,
+					resource: BindingResource::TextureView(&targets.diffuse),
+				},
+			],
+			label: None,
+			layout: &low_res_shaders.read_layout,
+		});
+
+		commands.entity(entity).insert(ViewGiLowResBindGroups { write, read });
+	}
+}
+
+/// traces at a lower resolution and upsamples, between the gbuffer of the cone trace node and the temporal resolve
+pub struct GiLowResTracePassNode {
+	view_query: QueryState<(
+		&'static ViewUniformOffset,
+		&'static ViewGiVolumes,
+		&'static ViewGiTraceTargets,
+		&'static ViewGiTraceBindGroup,
+		&'static ViewGiLowResTargets,
+		&'static ViewGiLowResBindGroups,
+	)>,
+}
+
+impl GiLowResTracePassNode {
+	pub const IN_VIEW: &'static str = "view";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiLowResTracePassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(GiLowResTracePassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+
+		// at full resolution the views don't have low res targets, so there's nothing to do
+		let (view_uniform_offset, view_volumes, targets, trace_bind_group, low_res_targets, low_res_bind_groups) =
+			match self.view_query.get_manual(world, view_entity) {
+				Ok(query) => query,
+				Err(_) => return Ok(()),
+			};
+
+		// the probes replace tracing altogether
+		let probe_meta = world.get_resource::<GiProbeMeta>().unwrap();
+		if probe_meta.probes.is_some() {
+			return Ok(());
+		}
+
+		let low_res_shaders = world.get_resource::<GiLowResShaders>().unwrap();
+		let trace_meta = world.get_resource::<ConeTraceMeta>().unwrap();
+		let cascade_meta = world.get_resource::<GiCascadeMeta>().unwrap();
+
+		let (view_bind_group, cascades_bind_group) = match (&trace_meta.view_bind_group, &cascade_meta.bind_group) {
+			(Some(view), Some(cascades)) => (view, cascades),
+			_ => return Ok(()),
+		};
+
+		let workgroups = |width: u32, height: u32| {
+			(
+				(width + TRACE_WORKGROUP_SIZE - 1) / TRACE_WORKGROUP_SIZE,
+				(height + TRACE_WORKGROUP_SIZE - 1) / TRACE_WORKGROUP_SIZE,
+			)
+		};
+
+		let pixel_workgroups = workgroups(targets.width, targets.height);
+		let low_res_workgroups = workgroups(low_res_targets.width, low_res_targets.height);
+
+		// tracing and upsampling are part of tracing
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Trace);
+
+		// the low res target is written in the first pass and read in the second
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_trace_low_res") });
+
+			pass.set_pipeline(&low_res_shaders.specular_pipeline);
+			pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+			pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+			pass.set_bind_group(2, &trace_bind_group.bind_group, &[]);
+			pass.dispatch(pixel_workgroups.0, pixel_workgroups.1, 1);
+
+			pass.set_pipeline(&low_res_shaders.trace_pipeline);
+			pass.set_bind_group(3, &low_res_bind_groups.write, &[]);
+			pass.dispatch(low_res_workgroups.0, low_res_workgroups.1, 1);
+		}
+
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_upsample") });
+
+			pass.set_pipeline(&low_res_shaders.upsample_pipeline);
+			pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+			pass.set_bind_group(1, cascades_bind_group, &[view_volumes.gpu_volume_binding_index]);
+			pass.set_bind_group(2, &trace_bind_group.bind_group, &[]);
+			pass.set_bind_group(3, &low_res_bind_groups.read, &[]);
+			pass.dispatch(pixel_workgroups.0, pixel_workgroups.1, 1);
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Trace);
+
+		Ok(())
+	}
+}
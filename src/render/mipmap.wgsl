@@ -0,0 +1,22 @@
+// generates one mip of a 3d texture, by averaging 2x2x2 texels of the mip above it
+
+[[group(0), binding(0)]]
+var source: texture_storage_3d<rgba32float, read_write>;
+
+[[group(0), binding(1)]]
+var destination: texture_storage_3d<rgba32float, read_write>;
+
+[[stage(compute), workgroup_size(4, 4, 4)]]
+fn mipmap([[builtin(global_invocation_id)]] texel: vec3<u32>) {
+    if (any(vec3<i32>(texel) >= textureDimensions(destination))) {
+        return;
+    }
+
+    var sum = vec4<f32>(0.0);
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        sum = sum + textureLoad(source, vec3<i32>(texel * 2u + offset));
+    }
+
+    textureStore(destination, vec3<i32>(texel), sum / 8.0);
+}
@@ -1 +1,538 @@
+pub mod bounce;
+pub mod brick_map;
+pub mod composite;
+pub mod cone_trace;
+pub mod debug_output;
+pub mod debug_view;
+pub mod diagnostics;
+pub mod gi_meshes;
 pub mod gi_volume;
+pub mod low_res;
+pub mod probes;
+pub mod readback;
+pub mod skinning;
+pub mod sparse_octree;
+pub mod static_layer;
+pub mod temporal;
+pub mod voxel_fragments;
+
+use bevy::app::{App, Plugin};
+use bevy::asset::AddAsset;
+use bevy::ecs::prelude::*;
+use bevy::log::warn;
+use bevy::render2::{render_graph::RenderGraph, RenderStage};
+
+use crate::bake::{self, GiBakedLayer, GiBakedLayerLoader};
+use crate::bundle::GiSettings;
+use crate::proxy;
+
+use bounce::{GiBounceMeta, GiBouncePassNode, GiBounceShaders};
+use brick_map::{BrickMapMeta, BrickMapPassNode, BrickMapShaders};
+use composite::{GiCompositeNode, GiCompositeShaders};
+use cone_trace::{ConeTraceMeta, ConeTracePassNode, ConeTraceShaders, MAX_TRACED_CASCADES};
+use debug_output::{GiDebugOutput, GiDebugOutputMeta, GiDebugOutputNode, GiDebugOutputShaders};
+use debug_view::{GiDebugView, GiDebugViewMeta, GiDebugViewNode, GiDebugViewShaders};
+use gi_meshes::{ExtractedGiMeshes, GiMeshMeta, GiMeshShaders};
+use gi_volume::{GiCascadeLimit, GiCascadeMeta, GiShaders, VoxelizePassNode, MAX_GI_CASCADES};
+use low_res::{GiLowResMeta, GiLowResShaders, GiLowResTracePassNode};
+use probes::{GiProbeMeta, GiProbeShaders};
+use readback::{ExtractedGiCascadeExport, GiCascadeExport};
+use skinning::{ExtractedGiSkins, GiSkinningMeta, GiSkinningPassNode, GiSkinningShaders};
+use sparse_octree::{SparseOctreeMeta, SparseOctreePassNode, SparseOctreeShaders};
+use temporal::{GiTemporalMeta, GiTemporalPassNode, GiTemporalShaders};
+use voxel_fragments::VoxelFragmentMeta;
+
+pub mod draw_3d_graph {
+    pub mod node {
+        pub const GI_SKINNING_PASS: &str = "gi_skinning_pass";
+        pub const VOXELIZE_PASS: &str = "voxelize_pass";
+        pub const SPARSE_OCTREE_PASS: &str = "sparse_octree_pass";
+        pub const BRICK_MAP_PASS: &str = "brick_map_pass";
+        pub const GI_BOUNCE_PASS: &str = "gi_bounce_pass";
+        pub const GI_COUNT_PASS: &str = "gi_count_pass";
+        pub const GI_DEBUG_VIEW_PASS: &str = "gi_debug_view_pass";
+        pub const CONE_TRACE_PASS: &str = "cone_trace_pass";
+        pub const GI_LOW_RES_TRACE_PASS: &str = "gi_low_res_trace_pass";
+        pub const GI_TEMPORAL_PASS: &str = "gi_temporal_pass";
+        pub const GI_COMPOSITE_PASS: &str = "gi_composite_pass";
+        pub const GI_DEBUG_OUTPUT_PASS: &str = "gi_debug_output_pass";
+    }
+}
+
+/// Renders global illumination for the `GiVolume` in the world
+pub struct GiPlugin {
+    /// max number of cascades a volume can have, volumes asking for more are limited to this
+    ///
+    /// the cone tracer only reads the first `cone_trace::MAX_TRACED_CASCADES`, and warns when this is more
+    /// limited to `gi_volume::MAX_GI_CASCADES`, and warns when clamping
+    pub max_cascades: u32,
+}
+
+impl Default for GiPlugin {
+    fn default() -> Self {
+        Self { max_cascades: 8 }
+    }
+}
+
+impl Plugin for GiPlugin {
+    fn build(&self, app: &mut App) {
+        if self.max_cascades > MAX_GI_CASCADES {
+            warn!(
+                "GiPlugin allows {} cascades, but at most {} are supported, it will be clamped to {}",
+                self.max_cascades, MAX_GI_CASCADES, MAX_GI_CASCADES,
+            );
+        }
+
+        let limit = GiCascadeLimit(self.max_cascades.min(MAX_GI_CASCADES));
+
+        if limit.0 as usize > MAX_TRACED_CASCADES {
+            warn!(
+                "GiPlugin allows {} cascades, but the cone tracer can only bind {}, the rest are voxelized but never traced",
+                limit.0, MAX_TRACED_CASCADES,
+            );
+        }
+
+        app.insert_resource(limit)
+            .init_resource::<GiDebugView>()
+            .init_resource::<GiDebugOutput>()
+            .init_resource::<GiCascadeExport>()
+            .init_resource::<GiSettings>()
+            .add_asset::<GiBakedLayer>()
+            .init_asset_loader::<GiBakedLayerLoader>()
+            .add_system(gi_volume::apply_gi_quality.system())
+            .add_system(gi_volume::check_gi_cascade_limit.system())
+            .add_system(gi_volume::check_gi_resolution_limit.system())
+            .add_system(bake::apply_baked_layers.system())
+            .add_system(bake::bake_static_layers.system())
+            .add_system(proxy::generate_gi_proxies.system());
+
+        let render_app = app.sub_app_mut(0);
+        render_app
+            .insert_resource(limit)
+            .add_system_to_stage(
+                RenderStage::Extract,
+                gi_volume::extract_gi_cascades.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                gi_meshes::extract_gi_meshes.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                skinning::extract_gi_skins.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                debug_view::extract_gi_debug_view.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                debug_output::extract_gi_debug_output.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                static_layer::extract_gi_static_layer.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                readback::extract_gi_cascade_export.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Extract,
+                probes::extract_gi_probes.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                gi_volume::prepare_gi_cascades.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                voxel_fragments::prepare_voxel_fragments.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                sparse_octree::prepare_sparse_octree.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                brick_map::prepare_brick_map.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                gi_meshes::prepare_gi_meshes.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                cone_trace::prepare_cone_trace_targets.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                low_res::prepare_gi_low_res_targets.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                bounce::prepare_gi_bounces.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                debug_view::prepare_gi_debug_view.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                debug_output::prepare_gi_debug_output.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                probes::prepare_gi_probes.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                skinning::prepare_gi_skins.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                static_layer::prepare_gi_static_layer.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                gi_volume::queue_gi_cascade_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                sparse_octree::queue_sparse_octree_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                brick_map::queue_brick_map_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                gi_meshes::queue_gi_mesh_bind_group.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                cone_trace::queue_cone_trace_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                low_res::queue_gi_low_res_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                bounce::queue_gi_bounce_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                debug_view::queue_gi_debug_view_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                composite::queue_gi_composite_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                debug_output::queue_gi_debug_output_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                probes::queue_gi_probe_bind_groups.system(),
+            )
+            .add_system_to_stage(
+                RenderStage::Queue,
+                skinning::queue_gi_skin_bind_groups.system(),
+            )
+            // after rendering, so the cascades are filled in
+            .add_system_to_stage(
+                RenderStage::Cleanup,
+                readback::export_gi_cascades.system(),
+            )
+            // voxelization uses the layouts of the meshes
+            .init_resource::<GiMeshShaders>()
+            .init_resource::<GiShaders>()
+            .init_resource::<GiCascadeMeta>()
+            .init_resource::<VoxelFragmentMeta>()
+            .init_resource::<SparseOctreeShaders>()
+            .init_resource::<SparseOctreeMeta>()
+            .init_resource::<BrickMapShaders>()
+            .init_resource::<BrickMapMeta>()
+            .init_resource::<GiDebugViewShaders>()
+            .init_resource::<GiDebugViewMeta>()
+            .init_resource::<ExtractedGiMeshes>()
+            .init_resource::<GiMeshMeta>()
+            .init_resource::<ConeTraceShaders>()
+            .init_resource::<ConeTraceMeta>()
+            .init_resource::<GiLowResShaders>()
+            .init_resource::<GiLowResMeta>()
+            .init_resource::<GiBounceShaders>()
+            .init_resource::<GiBounceMeta>()
+            .init_resource::<GiTemporalShaders>()
+            .init_resource::<GiTemporalMeta>()
+            .init_resource::<GiProbeShaders>()
+            .init_resource::<GiProbeMeta>()
+            .init_resource::<GiSkinningShaders>()
+            .init_resource::<GiSkinningMeta>()
+            .init_resource::<ExtractedGiSkins>()
+            .init_resource::<GiCompositeShaders>()
+            .init_resource::<GiDebugOutputShaders>()
+            .init_resource::<GiDebugOutputMeta>()
+            .init_resource::<ExtractedGiCascadeExport>();
+
+        let voxelize_pass_node = VoxelizePassNode::new(&mut render_app.world);
+        let debug_view_node = GiDebugViewNode::new(&mut render_app.world);
+        let cone_trace_node = ConeTracePassNode::new(&mut render_app.world);
+        let low_res_trace_node = GiLowResTracePassNode::new(&mut render_app.world);
+        let bounce_node = GiBouncePassNode::new(&mut render_app.world);
+        let temporal_node = GiTemporalPassNode::new(&mut render_app.world);
+        let composite_node = GiCompositeNode::new(&mut render_app.world);
+        let debug_output_node = GiDebugOutputNode::new(&mut render_app.world);
+        let mut graph = render_app.world.get_resource_mut::<RenderGraph>().unwrap();
+
+        // voxelize before the main pass, so it can sample the volume
+        let draw_3d_graph = graph
+            .get_sub_graph_mut(bevy_core_pipeline::draw_3d_graph::NAME)
+            .unwrap();
+        draw_3d_graph.add_node(draw_3d_graph::node::VOXELIZE_PASS, voxelize_pass_node);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::VOXELIZE_PASS,
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::VOXELIZE_PASS,
+                VoxelizePassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // skinned meshes have to be skinned before they can be voxelized
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_SKINNING_PASS, GiSkinningPassNode);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::GI_SKINNING_PASS,
+                draw_3d_graph::node::VOXELIZE_PASS,
+            )
+            .unwrap();
+
+        // the octree is built from the fragments voxelization wrote
+        draw_3d_graph.add_node(draw_3d_graph::node::SPARSE_OCTREE_PASS, SparseOctreePassNode);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::VOXELIZE_PASS,
+                draw_3d_graph::node::SPARSE_OCTREE_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::SPARSE_OCTREE_PASS,
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::SPARSE_OCTREE_PASS,
+                SparseOctreePassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // and so is the brick map
+        draw_3d_graph.add_node(draw_3d_graph::node::BRICK_MAP_PASS, BrickMapPassNode);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::VOXELIZE_PASS,
+                draw_3d_graph::node::BRICK_MAP_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::BRICK_MAP_PASS,
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::BRICK_MAP_PASS,
+                BrickMapPassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // the light bounces between the voxels once they're voxelized
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_BOUNCE_PASS, bounce_node);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::VOXELIZE_PASS,
+                draw_3d_graph::node::GI_BOUNCE_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::GI_BOUNCE_PASS,
+                GiBouncePassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // cone tracing needs the volume, in whatever storage it's in
+        draw_3d_graph.add_node(draw_3d_graph::node::CONE_TRACE_PASS, cone_trace_node);
+        for node in [
+            draw_3d_graph::node::VOXELIZE_PASS,
+            draw_3d_graph::node::SPARSE_OCTREE_PASS,
+            draw_3d_graph::node::BRICK_MAP_PASS,
+            draw_3d_graph::node::GI_BOUNCE_PASS,
+        ] {
+            draw_3d_graph
+                .add_node_edge(node, draw_3d_graph::node::CONE_TRACE_PASS)
+                .unwrap();
+        }
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::CONE_TRACE_PASS,
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::CONE_TRACE_PASS,
+                ConeTracePassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // tracing at a lower resolution is its own pass, as it needs the gbuffer of the full trace pass
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_LOW_RES_TRACE_PASS, low_res_trace_node);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::CONE_TRACE_PASS,
+                draw_3d_graph::node::GI_LOW_RES_TRACE_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::GI_LOW_RES_TRACE_PASS,
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::GI_LOW_RES_TRACE_PASS,
+                GiLowResTracePassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // and the history is blended in once everything is traced
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_TEMPORAL_PASS, temporal_node);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::GI_LOW_RES_TRACE_PASS,
+                draw_3d_graph::node::GI_TEMPORAL_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::GI_TEMPORAL_PASS,
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::GI_TEMPORAL_PASS,
+                GiTemporalPassNode::IN_VIEW,
+            )
+            .unwrap();
+
+        // the indirect light is added to what the main pass lit
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_COMPOSITE_PASS, composite_node);
+        draw_3d_graph
+            .add_node_edge(
+                bevy_core_pipeline::draw_3d_graph::node::MAIN_PASS,
+                draw_3d_graph::node::GI_COMPOSITE_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::GI_COMPOSITE_PASS,
+                GiCompositeNode::IN_VIEW,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::RENDER_TARGET,
+                draw_3d_graph::node::GI_COMPOSITE_PASS,
+                GiCompositeNode::IN_COLOR_ATTACHMENT,
+            )
+            .unwrap();
+
+        // the debug output replaces everything the main pass drew
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_DEBUG_OUTPUT_PASS, debug_output_node);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::GI_COMPOSITE_PASS,
+                draw_3d_graph::node::GI_DEBUG_OUTPUT_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::GI_DEBUG_OUTPUT_PASS,
+                GiDebugOutputNode::IN_VIEW,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::RENDER_TARGET,
+                draw_3d_graph::node::GI_DEBUG_OUTPUT_PASS,
+                GiDebugOutputNode::IN_COLOR_ATTACHMENT,
+            )
+            .unwrap();
+
+        // and the debug view draws on top of that
+        draw_3d_graph.add_node(draw_3d_graph::node::GI_DEBUG_VIEW_PASS, debug_view_node);
+        draw_3d_graph
+            .add_node_edge(
+                draw_3d_graph::node::GI_DEBUG_OUTPUT_PASS,
+                draw_3d_graph::node::GI_DEBUG_VIEW_PASS,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::VIEW_ENTITY,
+                draw_3d_graph::node::GI_DEBUG_VIEW_PASS,
+                GiDebugViewNode::IN_VIEW,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::RENDER_TARGET,
+                draw_3d_graph::node::GI_DEBUG_VIEW_PASS,
+                GiDebugViewNode::IN_COLOR_ATTACHMENT,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                bevy_core_pipeline::draw_3d_graph::input::DEPTH,
+                draw_3d_graph::node::GI_DEBUG_VIEW_PASS,
+                GiDebugViewNode::IN_DEPTH,
+            )
+            .unwrap();
+    }
+}
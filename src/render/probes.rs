@@ -0,0 +1,340 @@
+// HOW IT WORKS
+// the probes are a small 3d texture that's kept between frames, every probe has a few texels with 4 floats of it's coefficients each
+// the texels are coefficient major, so the grid is repeated along x once per texel, and texel t of a probe is at probe.x + t * resolution.x
+// each frame a few probes trace cones through the cascades, and write their spherical harmonics, see PROBES in cone_trace.wgsl
+// then instead of tracing cones from every pixel, the pixels read the probes, and write to the same targets as the cone tracer
+// the update and read both use the bindings of the cone tracer, with the probes in an extra bind group
+// the pbr shader of bevy can't be changed from here, so the probes are read trilinearly in a compute pass, not while shading
+// the read irradiance is added to the lit image after the main pass, like the traced light, see composite.rs
+
+use crevice::std140::{AsStd140, Std140};
+
+use crate::bundle::{GiProbeGrid, GiShOrder, GiVolume};
+use crate::voxelize::cascade_projection;
+
+use super::cone_trace::ConeTraceShaders;
+use super::gi_volume::GiShaders;
+
+use bevy::ecs::prelude::*;
+use bevy::math::{Mat4, UVec3, Vec3};
+use bevy::render2::{
+	render_resource::*,
+	renderer::{RenderDevice, RenderQueue},
+	shader::Shader,
+};
+use bevy::transform::components::GlobalTransform;
+
+const PROBE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+// same as in cone_trace.wgsl
+pub const PROBE_WORKGROUP_SIZE: u32 = 64;
+
+pub struct ExtractedGiProbeGrid {
+	pub settings: GiProbeGrid,
+	pub world_to_grid: Mat4,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiProbeGrid {
+	world_to_grid: Mat4,
+	grid_to_world: Mat4,
+	resolution: Vec3,
+	texels_per_probe: u32,
+	coefficients: u32,
+	first_probe: u32,
+	probe_count: u32,
+}
+
+/// rgb for every coefficient, packed in rgba texels
+pub fn texels_per_probe(order: GiShOrder) -> u32 {
+	(order.coefficients() * 3 + 3) / 4
+}
+
+pub struct GiProbeShaders {
+	pub update_pipeline: ComputePipeline,
+	pub resolve_pipeline: ComputePipeline,
+	/// for writing the probes while updating
+	pub write_layout: BindGroupLayout,
+	/// for reading them when resolving
+	pub read_layout: BindGroupLayout,
+	pub sampler: Sampler,
+}
+
+fn uniform_entry() -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding: 0,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Uniform,
+			has_dynamic_offset: false,
+			min_binding_size: BufferSize::new(GpuGiProbeGrid::std140_size_static() as u64),
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for GiProbeShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let gi_shaders = world.get_resource::<GiShaders>().unwrap();
+		let trace_shaders = world.get_resource::<ConeTraceShaders>().unwrap();
+
+		let write_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				uniform_entry(),
+				BindGroupLayoutEntry {
+					binding: 1,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::StorageTexture {
+						access: StorageTextureAccess::WriteOnly,
+						format: PROBE_FORMAT,
+						view_dimension: TextureViewDimension::D3,
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let read_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				uniform_entry(),
+				BindGroupLayoutEntry {
+					binding: 2,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Texture {
+						multisampled: false,
+						sample_type: TextureSampleType::Float { filterable: true },
+						view_dimension: TextureViewDimension::D3,
+					},
+					count: None,
+				},
+				BindGroupLayoutEntry {
+					binding: 3,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Sampler {
+						filtering: true,
+						comparison: false,
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		// the probes are in the same shader as the cone tracer, so they can share the tracing
+		let shader = Shader::from_wgsl(include_str!("cone_trace.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let pipeline = |probe_layout: &BindGroupLayout, entry_point: &str| {
+			let layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+				label: None,
+				push_constant_ranges: &[],
+				bind_group_layouts: &[
+					&trace_shaders.view_layout,
+					&gi_shaders.cascades_layout,
+					&trace_shaders.trace_layout,
+					probe_layout,
+				],
+			});
+
+			render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+				label: None,
+				layout: Some(&layout),
+				entry_point,
+				module: &shader_module,
+			})
+		};
+
+		let update_pipeline = pipeline(&write_layout, "update_probes");
+		let resolve_pipeline = pipeline(&read_layout, "resolve_probes");
+
+		let sampler = render_device.create_sampler(&SamplerDescriptor {
+			mag_filter: FilterMode::Linear,
+			min_filter: FilterMode::Linear,
+			..Default::default()
+		});
+
+		GiProbeShaders {
+			update_pipeline,
+			resolve_pipeline,
+			write_layout,
+			read_layout,
+			sampler,
+		}
+	}
+}
+
+/// the probes on the gpu, kept between frames so only a few need to be updated
+pub struct GpuGiProbes {
+	pub resolution: UVec3,
+	pub order: GiShOrder,
+	pub texture: Texture,
+	pub texture_view: TextureView,
+	pub uniform: Buffer,
+	/// where the update starts next frame
+	pub next_probe: u32,
+	/// probes updated this frame
+	pub probe_count: u32,
+}
+
+impl GpuGiProbes {
+	fn new(render_device: &RenderDevice, resolution: UVec3, order: GiShOrder) -> Self {
+		let texture = render_device.create_texture(&TextureDescriptor {
+			size: Extent3d {
+				width: resolution.x * texels_per_probe(order),
+				height: resolution.y,
+				depth_or_array_layers: resolution.z,
+			},
+			mip_level_count: 1,
+			sample_count: 1,
+			dimension: TextureDimension::D3,
+			format: PROBE_FORMAT,
+			usage: TextureUsage::SAMPLED | TextureUsage::STORAGE,
+			label: None,
+		});
+
+		let uniform = render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: GpuGiProbeGrid::std140_size_static() as u64,
+			usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		});
+
+		Self {
+			resolution,
+			order,
+			texture_view: texture.create_view(&TextureViewDescriptor::default()),
+			texture,
+			uniform,
+			next_probe: 0,
+			probe_count: 0,
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct GiProbeMeta {
+	pub probes: Option<GpuGiProbes>,
+	pub write_bind_group: Option<BindGroup>,
+	pub read_bind_group: Option<BindGroup>,
+}
+
+pub fn extract_gi_probes(
+	mut commands: Commands,
+	volumes: Query<(&GiVolume, &GiProbeGrid, &GlobalTransform)>,
+) {
+	// same volume as extract_gi_cascades
+	match volumes.iter().next() {
+		Some((volume, settings, transform)) => commands.insert_resource(ExtractedGiProbeGrid {
+			settings: *settings,
+			// the probes cover the smallest cascade
+			world_to_grid: Mat4::from_scale(settings.resolution.max(UVec3::ONE).as_f32())
+				* cascade_projection(volume.extent, transform, 0),
+		}),
+		None => commands.remove_resource::<ExtractedGiProbeGrid>(),
+	}
+}
+
+pub fn prepare_gi_probes(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	probe_grid: Option<Res<ExtractedGiProbeGrid>>,
+	mut probe_meta: ResMut<GiProbeMeta>,
+) {
+	let probe_grid = match probe_grid {
+		Some(probe_grid) => probe_grid,
+		None => {
+			probe_meta.probes = None;
+			return;
+		}
+	};
+
+	let settings = probe_grid.settings;
+	let resolution = settings.resolution.max(UVec3::ONE);
+	let total = resolution.x * resolution.y * resolution.z;
+
+	// a new grid has nothing in it yet, so all probes are updated the first frame
+	let probes = match &mut probe_meta.probes {
+		Some(probes) if probes.resolution == resolution && probes.order == settings.order => {
+			probes.probe_count = settings.probes_per_frame.min(total);
+			probes
+		}
+		probes => {
+			let mut new_probes = GpuGiProbes::new(&render_device, resolution, settings.order);
+			new_probes.probe_count = total;
+			probes.insert(new_probes)
+		}
+	};
+
+	render_queue.write_buffer(
+		&probes.uniform,
+		0,
+		GpuGiProbeGrid {
+			world_to_grid: probe_grid.world_to_grid,
+			grid_to_world: probe_grid.world_to_grid.inverse(),
+			resolution: resolution.as_f32(),
+			texels_per_probe: texels_per_probe(settings.order),
+			coefficients: settings.order.coefficients(),
+			first_probe: probes.next_probe,
+			probe_count: probes.probe_count,
+		}
+		.as_std140()
+		.as_bytes(),
+	);
+
+	probes.next_probe = (probes.next_probe + probes.probe_count) % total;
+}
+
+pub fn queue_gi_probe_bind_groups(
+	render_device: Res<RenderDevice>,
+	probe_shaders: Res<GiProbeShaders>,
+	mut probe_meta: ResMut<GiProbeMeta>,
+) {
+	let probes = match &probe_meta.probes {
+		Some(probes) => probes,
+		None => {
+			probe_meta.write_bind_group = None;
+			probe_meta.read_bind_group = None;
+			return;
+		}
+	};
+
+	let write_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: probes.uniform.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 1,
+				resource: BindingResource::TextureView(&probes.texture_view),
+			},
+		],
+		label: None,
+		layout: &probe_shaders.write_layout,
+	});
+
+	let read_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: probes.uniform.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 2,
+				resource: BindingResource::TextureView(&probes.texture_view),
+			},
+			BindGroupEntry {
+				binding: 3,
+				resource: BindingResource::Sampler(&probe_shaders.sampler),
+			},
+		],
+		label: None,
+		layout: &probe_shaders.read_layout,
+	});
+
+	probe_meta.write_bind_group = Some(write_bind_group);
+	probe_meta.read_bind_group = Some(read_bind_group);
+}
@@ -0,0 +1,294 @@
+// HOW IT WORKS
+// requests are made in the app world, and moved to the render world during extraction
+// after the frame is rendered, the first mip of the cascade is copied to a buffer and read on the cpu
+// this waits for the gpu, so it's only meant for debugging
+//
+// GiAsyncReadback is for small buffers that are read every frame, like the timestamps for the diagnostics
+// it copies to one of a few staging buffers and maps it, and picks up the data once the gpu is done, a few frames later
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::bundle::GiVolumeFormat;
+use crate::vox::{save_vox, VoxError, MAX_VOX_SIZE};
+use crate::voxelize::VoxelGrid;
+
+use super::gi_volume::{ExtractedGiVolume, ViewGiCascade, ViewGiVolumes};
+
+use bevy::ecs::prelude::*;
+use bevy::log::{info, warn};
+use bevy::math::UVec3;
+use bevy::render2::{
+	render_resource::{BufferAsyncError, Maintain, *},
+	renderer::{RenderDevice, RenderQueue},
+};
+
+// rows copied to a buffer have to start at a multiple of this
+const COPY_BYTES_PER_ROW_ALIGNMENT: u32 = 256;
+
+/// cascades to write to a MagicaVoxel file at the end of the frame, only works for dense cascades
+///
+/// push to `requests`, it's emptied every frame
+#[derive(Default)]
+pub struct GiCascadeExport {
+	pub requests: Vec<GiCascadeExportRequest>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GiCascadeExportRequest {
+	pub cascade: u32,
+	pub path: PathBuf,
+}
+
+#[derive(Default)]
+pub struct ExtractedGiCascadeExport {
+	pub requests: Vec<GiCascadeExportRequest>,
+}
+
+pub fn extract_gi_cascade_export(mut commands: Commands, mut export: ResMut<GiCascadeExport>) {
+	commands.insert_resource(ExtractedGiCascadeExport {
+		requests: std::mem::take(&mut export.requests),
+	});
+}
+
+/// copies the first mip of a cascade back to the cpu, waiting until the gpu is done with it
+pub fn read_cascade(
+	render_device: &RenderDevice,
+	render_queue: &RenderQueue,
+	cascade: &ViewGiCascade,
+	resolution: UVec3,
+	format: GiVolumeFormat,
+) -> VoxelGrid {
+	let row_size = resolution.x * format.bytes_per_voxel();
+	let bytes_per_row = (row_size + COPY_BYTES_PER_ROW_ALIGNMENT - 1) / COPY_BYTES_PER_ROW_ALIGNMENT * COPY_BYTES_PER_ROW_ALIGNMENT;
+
+	let buffer = render_device.create_buffer(&BufferDescriptor {
+		label: Some("gi_cascade_readback"),
+		size: bytes_per_row as u64 * resolution.y as u64 * resolution.z as u64,
+		usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+		mapped_at_creation: false,
+	});
+
+	let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+	encoder.copy_texture_to_buffer(
+		ImageCopyTexture {
+			texture: &cascade.texture,
+			mip_level: 0,
+			origin: Origin3d::ZERO,
+		},
+		ImageCopyBuffer {
+			buffer: &buffer,
+			layout: ImageDataLayout {
+				offset: 0,
+				bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+				rows_per_image: std::num::NonZeroU32::new(resolution.y),
+			},
+		},
+		Extent3d {
+			width: resolution.x,
+			height: resolution.y,
+			depth_or_array_layers: resolution.z,
+		},
+	);
+	render_queue.submit(vec![encoder.finish()]);
+
+	let slice = buffer.slice(..);
+	render_device.map_buffer(&slice, MapMode::Read);
+
+	let grid = {
+		let data = slice.get_mapped_range();
+
+		// skip the padding at the end of every row
+		let bytes: Vec<u8> = data
+			.chunks_exact(bytes_per_row as usize)
+			.flat_map(|row| row[..row_size as usize].iter().copied())
+			.collect();
+
+		VoxelGrid::from_bytes(resolution, &bytes, format)
+	};
+	buffer.unmap();
+
+	grid
+}
+
+/// runs after the frame is rendered, so the cascades are done
+pub fn export_gi_cascades(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	export: Res<ExtractedGiCascadeExport>,
+	volume: Option<Res<ExtractedGiVolume>>,
+	views: Query<&ViewGiVolumes>,
+) {
+	if export.requests.is_empty() {
+		return;
+	}
+
+	// every view has the same voxels, so any of them works
+	let (volume, view_volumes) = match (volume, views.iter().next()) {
+		(Some(volume), Some(view_volumes)) => (volume, view_volumes),
+		_ => {
+			warn!("can't export gi cascades, as there is no volume being rendered");
+			return;
+		}
+	};
+
+	// checked before reading anything back, as that waits for the gpu
+	if volume.resolution.max_element() > MAX_VOX_SIZE {
+		warn!("can't export gi cascades: {}", VoxError::TooLarge(volume.resolution));
+		return;
+	}
+
+	for request in export.requests.iter() {
+		let cascade = match view_volumes.cascades.get(request.cascade as usize) {
+			Some(cascade) => cascade,
+			None => {
+				warn!(
+					"can't export gi cascade {}, the volume has {} dense cascades",
+					request.cascade,
+					view_volumes.cascades.len(),
+				);
+				continue;
+			}
+		};
+
+		let grid = read_cascade(&render_device, &render_queue, cascade, volume.resolution, volume.settings.format);
+
+		match save_vox(&request.path, &grid) {
+			Ok(()) => info!("exported gi cascade {} to {}", request.cascade, request.path.display()),
+			Err(error) => warn!("failed to export gi cascade {} to {}: {}", request.cascade, request.path.display(), error),
+		}
+	}
+}
+
+// how many frames a readback can be behind, before frames are skipped
+const MAX_READBACKS_IN_FLIGHT: usize = 3;
+
+type MapFuture = Pin<Box<dyn Future<Output = Result<(), BufferAsyncError>> + Send>>;
+
+enum ReadbackState {
+	Free,
+	/// copied to this frame, and mapped once that's submitted
+	Copied,
+	/// the future is only Send, so it's behind a mutex to keep the resource Sync
+	Mapping(Mutex<MapFuture>),
+}
+
+struct ReadbackBuffer<T> {
+	buffer: Buffer,
+	state: ReadbackState,
+	/// given back with the data, to say what's in it
+	tag: T,
+	frame: u64,
+}
+
+/// reads a small buffer back to the cpu every frame, without waiting for the gpu
+///
+/// copy to `next_buffer` during the frame, call `map` once that's submitted, and `poll` to get what's done
+/// the data comes a few frames late, and when the gpu is too far behind frames are skipped
+pub struct GiAsyncReadback<T = ()> {
+	label: &'static str,
+	size: u64,
+	buffers: Vec<ReadbackBuffer<T>>,
+	frame: u64,
+}
+
+impl<T: Default> GiAsyncReadback<T> {
+	pub fn new(label: &'static str, size: u64) -> Self {
+		Self {
+			label,
+			size,
+			buffers: Vec::new(),
+			frame: 0,
+		}
+	}
+
+	/// a staging buffer to copy to this frame, or None when all of them are still being read
+	pub fn next_buffer(&mut self, render_device: &RenderDevice, tag: T) -> Option<&Buffer> {
+		let index = match self.buffers.iter().position(|buffer| matches!(buffer.state, ReadbackState::Free)) {
+			Some(index) => index,
+			None if self.buffers.len() < MAX_READBACKS_IN_FLIGHT => {
+				self.buffers.push(ReadbackBuffer {
+					buffer: render_device.create_buffer(&BufferDescriptor {
+						label: Some(self.label),
+						size: self.size,
+						usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+						mapped_at_creation: false,
+					}),
+					state: ReadbackState::Free,
+					tag: T::default(),
+					frame: 0,
+				});
+				self.buffers.len() - 1
+			}
+			None => return None,
+		};
+
+		self.frame += 1;
+
+		let buffer = &mut self.buffers[index];
+		buffer.state = ReadbackState::Copied;
+		buffer.tag = tag;
+		buffer.frame = self.frame;
+
+		Some(&buffer.buffer)
+	}
+
+	/// starts mapping the buffers that were copied to, only after the copy is submitted
+	pub fn map(&mut self) {
+		for buffer in self.buffers.iter_mut() {
+			if let ReadbackState::Copied = buffer.state {
+				let future = buffer.buffer.slice(..).map_async(MapMode::Read);
+				buffer.state = ReadbackState::Mapping(Mutex::new(Box::pin(future)));
+			}
+		}
+	}
+
+	/// the data and tag of the newest buffer that finished since the last poll
+	pub fn poll(&mut self, render_device: &RenderDevice) -> Option<(Vec<u8>, T)> {
+		render_device.wgpu_device().poll(Maintain::Poll);
+
+		// nothing waits on the futures, they're only checked once a frame
+		let waker = noop_waker();
+		let mut context = Context::from_waker(&waker);
+
+		let mut newest: Option<(u64, Vec<u8>, T)> = None;
+
+		for buffer in self.buffers.iter_mut() {
+			let result = match &mut buffer.state {
+				ReadbackState::Mapping(future) => match future.get_mut().unwrap().as_mut().poll(&mut context) {
+					Poll::Ready(result) => result,
+					Poll::Pending => continue,
+				},
+				_ => continue,
+			};
+
+			if result.is_ok() {
+				let data = buffer.buffer.slice(..).get_mapped_range().to_vec();
+				buffer.buffer.unmap();
+
+				if newest.as_ref().map_or(true, |(frame, _, _)| buffer.frame > *frame) {
+					newest = Some((buffer.frame, data, std::mem::take(&mut buffer.tag)));
+				}
+			}
+
+			buffer.state = ReadbackState::Free;
+		}
+
+		newest.map(|(_, data, tag)| (data, tag))
+	}
+}
+
+fn noop_waker() -> Waker {
+	fn clone(_: *const ()) -> RawWaker {
+		RawWaker::new(std::ptr::null(), &VTABLE)
+	}
+	fn noop(_: *const ()) {}
+
+	static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+	// none of the functions use the pointer
+	unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
@@ -0,0 +1,496 @@
+// HOW IT WORKS
+// meshes with a GiSkin or GiMorphWeights are skinned by gi itself, as bevy doesn't do it yet
+// the vertices a mesh is skinned from are read from the mesh asset once, and kept on the gpu until the mesh changes
+// every frame, the joint matrices and morph weights are extracted, and skinning.wgsl writes the skinned positions to a buffer per entity
+// voxelization reads those positions instead of the vertex buffer of the mesh, see GiSkinningMeta::skinned
+// proxies skip all of that, the boxes around the joints are moved on the cpu and written to the same buffer
+
+use crate::bundle::{GiMorphWeights, GiProxy, GiSkin};
+use crate::skinning::{joint_proxy_triangles, SkinSource, SkinVertex, MAX_MORPH_TARGETS};
+
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+use super::gi_meshes::{GiMeshMeta, GiMeshShaders};
+
+use bevy::asset::{AssetEvent, Assets, Handle};
+use bevy::ecs::prelude::*;
+use bevy::math::{Mat4, Vec3, Vec4};
+use bevy::render2::{
+	mesh::Mesh,
+	render_graph::{Node, NodeRunError, RenderGraphContext},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+};
+use bevy::transform::components::GlobalTransform;
+use bevy::utils::HashMap;
+
+use crevice::std140::{AsStd140, Std140};
+
+// same as in skinning.wgsl
+const SKIN_WORKGROUP_SIZE: u32 = 64;
+
+// a vec4 per position
+const POSITION_SIZE: u64 = 16;
+
+/// what a skinned mesh is skinned from, cached in the app world so the mesh is only read once
+#[derive(Default)]
+pub struct GiSkinSources {
+	sources: HashMap<Handle<Mesh>, SkinSource>,
+}
+
+pub struct ExtractedGiSkin {
+	pub entity: Entity,
+	pub mesh: Handle<Mesh>,
+	pub joint_matrices: Vec<Mat4>,
+	pub morph_weights: Vec4,
+	/// the moved boxes around the joints, when the skin is a proxy
+	pub proxy_triangles: Option<Vec<[Vec3; 3]>>,
+}
+
+#[derive(Default)]
+pub struct ExtractedGiSkins {
+	pub skins: Vec<ExtractedGiSkin>,
+	/// vertices of meshes that are new or changed since the last frame
+	pub sources: Vec<(Handle<Mesh>, Vec<SkinVertex>)>,
+	/// meshes that changed, so the render world drops what it has for them
+	pub changed: Vec<Handle<Mesh>>,
+}
+
+pub fn extract_gi_skins(
+	mut commands: Commands,
+	mut skin_sources: Local<GiSkinSources>,
+	mut mesh_events: EventReader<AssetEvent<Mesh>>,
+	mesh_assets: Res<Assets<Mesh>>,
+	meshes: Query<
+		(Entity, &GlobalTransform, &Handle<Mesh>, Option<&GiSkin>, Option<&GiMorphWeights>),
+		// a proxy is voxelized instead, so skinning would be wasted
+		(Or<(With<GiSkin>, With<GiMorphWeights>)>, Without<GiProxy>),
+	>,
+	joints: Query<&GlobalTransform>,
+) {
+	let mut changed = Vec::new();
+	for event in mesh_events.iter() {
+		match event {
+			AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+				if skin_sources.sources.remove(handle).is_some() {
+					changed.push(handle.clone_weak());
+				}
+			}
+			AssetEvent::Created { .. } => {}
+		}
+	}
+
+	let mut skins = Vec::new();
+	let mut sources = Vec::new();
+
+	for (entity, transform, handle, skin, morph_weights) in meshes.iter() {
+		if !skin_sources.sources.contains_key(handle) {
+			let source = match mesh_assets.get(handle).and_then(SkinSource::from_mesh) {
+				Some(source) => source,
+				// not loaded yet
+				None => continue,
+			};
+
+			sources.push((handle.clone_weak(), source.vertices()));
+			skin_sources.sources.insert(handle.clone_weak(), source);
+		}
+
+		let joint_matrices = match skin {
+			Some(skin) => skin.joint_matrices(transform, |joint| joints.get(joint).ok().copied()),
+			None => Vec::new(),
+		};
+
+		let mut weights = [0.0; MAX_MORPH_TARGETS];
+		if let Some(morph_weights) = morph_weights {
+			for (weight, morph_weight) in weights.iter_mut().zip(morph_weights.0.iter()) {
+				*weight = *morph_weight;
+			}
+		}
+
+		let proxy_triangles = match skin {
+			Some(skin) if skin.proxy => {
+				let source = &skin_sources.sources[handle];
+				Some(joint_proxy_triangles(&source.joint_bounds(joint_matrices.len()), &joint_matrices))
+			}
+			_ => None,
+		};
+
+		skins.push(ExtractedGiSkin {
+			entity,
+			mesh: handle.clone_weak(),
+			joint_matrices,
+			morph_weights: Vec4::from(weights),
+			proxy_triangles,
+		});
+	}
+
+	commands.insert_resource(ExtractedGiSkins {
+		skins,
+		sources,
+		changed,
+	});
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+struct GpuSkinParams {
+	morph_weights: Vec4,
+	vertex_count: u32,
+	joint_count: u32,
+}
+
+pub struct GiSkinningShaders {
+	pipeline: ComputePipeline,
+	layout: BindGroupLayout,
+}
+
+fn storage_entry(binding: u32, read_only: bool, min_binding_size: u64) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Storage { read_only },
+			has_dynamic_offset: false,
+			min_binding_size: BufferSize::new(min_binding_size),
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for GiSkinningShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: false,
+						min_binding_size: BufferSize::new(GpuSkinParams::std140_size_static() as u64),
+					},
+					count: None,
+				},
+				// vertices
+				storage_entry(1, true, SkinVertex::SIZE),
+				// joints
+				storage_entry(2, true, 64),
+				// skinned positions
+				storage_entry(3, false, POSITION_SIZE),
+			],
+			label: None,
+		});
+
+		let shader = Shader::from_wgsl(include_str!("skinning.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&layout],
+		});
+
+		let pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+			label: None,
+			layout: Some(&pipeline_layout),
+			entry_point: "skin",
+			module: &shader_module,
+		});
+
+		GiSkinningShaders { pipeline, layout }
+	}
+}
+
+/// the vertices a mesh is skinned from
+pub struct GpuGiSkinSource {
+	pub vertices: Buffer,
+	pub vertex_count: u32,
+}
+
+/// the skinned positions of an entity
+pub struct GpuGiSkinnedMesh {
+	/// a vec4 for every vertex, in the space of the mesh
+	pub positions: Buffer,
+	pub vertex_count: u32,
+	/// skinned meshes use the index buffer of the mesh, proxies are a plain triangle list
+	pub indexed: bool,
+	/// the positions with the indices, in the layout of `GiMeshShaders::geometry_layout`
+	pub geometry_bind_group: Option<BindGroup>,
+	pub triangle_count: u32,
+	mesh: Handle<Mesh>,
+	params: Buffer,
+	joints: Buffer,
+	joint_count: u32,
+	/// the triangle list of a proxy as indices, as voxelization always reads them
+	proxy_indices: Option<Buffer>,
+	bind_group: Option<BindGroup>,
+}
+
+#[derive(Default)]
+pub struct GiSkinningMeta {
+	pub sources: HashMap<Handle<Mesh>, GpuGiSkinSource>,
+	/// what voxelization should read instead of the vertex buffer, for every skinned entity
+	pub skinned: HashMap<Entity, GpuGiSkinnedMesh>,
+}
+
+fn create_skinned_mesh(
+	render_device: &RenderDevice,
+	mesh: &Handle<Mesh>,
+	vertex_count: u32,
+	joint_count: u32,
+	indexed: bool,
+) -> GpuGiSkinnedMesh {
+	let buffer = |size: u64, usage: BufferUsage| {
+		render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size,
+			usage,
+			mapped_at_creation: false,
+		})
+	};
+
+	let proxy_indices = match indexed {
+		true => None,
+		false => {
+			let indices: Vec<u8> = (0..vertex_count.max(1)).flat_map(|index| index.to_le_bytes()).collect();
+			Some(render_device.create_buffer_with_data(&BufferInitDescriptor {
+				label: None,
+				contents: &indices,
+				usage: BufferUsage::STORAGE,
+			}))
+		}
+	};
+
+	GpuGiSkinnedMesh {
+		positions: buffer(vertex_count.max(1) as u64 * POSITION_SIZE, BufferUsage::STORAGE | BufferUsage::VERTEX | BufferUsage::COPY_DST),
+		vertex_count,
+		indexed,
+		geometry_bind_group: None,
+		triangle_count: 0,
+		mesh: mesh.clone_weak(),
+		params: buffer(GpuSkinParams::std140_size_static() as u64, BufferUsage::UNIFORM | BufferUsage::COPY_DST),
+		// storage buffers can't be empty
+		joints: buffer(joint_count.max(1) as u64 * 64, BufferUsage::STORAGE | BufferUsage::COPY_DST),
+		joint_count,
+		proxy_indices,
+		bind_group: None,
+	}
+}
+
+pub fn prepare_gi_skins(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	extracted_skins: Res<ExtractedGiSkins>,
+	mut skinning_meta: ResMut<GiSkinningMeta>,
+) {
+	let skinning_meta = &mut *skinning_meta;
+
+	for handle in extracted_skins.changed.iter() {
+		skinning_meta.sources.remove(handle);
+	}
+
+	for (handle, vertices) in extracted_skins.sources.iter() {
+		let contents: Vec<u8> = vertices.iter().flat_map(|vertex| vertex.to_bytes()).collect();
+
+		// an empty mesh still gets a buffer, so it doesn't have to be checked for later
+		let contents = match contents.is_empty() {
+			true => vec![0; SkinVertex::SIZE as usize],
+			false => contents,
+		};
+
+		skinning_meta.sources.insert(
+			handle.clone_weak(),
+			GpuGiSkinSource {
+				vertices: render_device.create_buffer_with_data(&BufferInitDescriptor {
+					label: None,
+					contents: &contents,
+					usage: BufferUsage::STORAGE,
+				}),
+				vertex_count: vertices.len() as u32,
+			},
+		);
+	}
+
+	// entities that stopped being skinned
+	skinning_meta
+		.skinned
+		.retain(|entity, _| extracted_skins.skins.iter().any(|skin| skin.entity == *entity));
+
+	for skin in extracted_skins.skins.iter() {
+		let source_vertex_count = match skinning_meta.sources.get(&skin.mesh) {
+			Some(source) => source.vertex_count,
+			None => continue,
+		};
+
+		let (vertex_count, indexed) = match &skin.proxy_triangles {
+			Some(triangles) => (triangles.len() as u32 * 3, false),
+			None => (source_vertex_count, true),
+		};
+		let joint_count = skin.joint_matrices.len() as u32;
+
+		// made again when anything about the buffers changed
+		let up_to_date = matches!(
+			skinning_meta.skinned.get(&skin.entity),
+			Some(skinned) if skinned.mesh == skin.mesh
+				&& skinned.vertex_count == vertex_count
+				&& skinned.joint_count == joint_count
+				&& skinned.indexed == indexed
+		);
+		if !up_to_date {
+			skinning_meta.skinned.insert(
+				skin.entity,
+				create_skinned_mesh(&render_device, &skin.mesh, vertex_count, joint_count, indexed),
+			);
+		}
+		let skinned = &skinning_meta.skinned[&skin.entity];
+
+		if let Some(triangles) = &skin.proxy_triangles {
+			let positions: Vec<u8> = triangles
+				.iter()
+				.flat_map(|triangle| triangle.iter())
+				.flat_map(|position| position.extend(1.0).to_array())
+				.flat_map(|value| value.to_le_bytes())
+				.collect();
+
+			if !positions.is_empty() {
+				render_queue.write_buffer(&skinned.positions, 0, &positions);
+			}
+			continue;
+		}
+
+		render_queue.write_buffer(
+			&skinned.params,
+			0,
+			GpuSkinParams {
+				morph_weights: skin.morph_weights,
+				vertex_count,
+				joint_count,
+			}
+			.as_std140()
+			.as_bytes(),
+		);
+
+		if joint_count > 0 {
+			let joints: Vec<u8> = skin
+				.joint_matrices
+				.iter()
+				.flat_map(|matrix| matrix.to_cols_array())
+				.flat_map(|value| value.to_le_bytes())
+				.collect();
+			render_queue.write_buffer(&skinned.joints, 0, &joints);
+		}
+	}
+}
+
+pub fn queue_gi_skin_bind_groups(
+	render_device: Res<RenderDevice>,
+	skinning_shaders: Res<GiSkinningShaders>,
+	mesh_shaders: Res<GiMeshShaders>,
+	mesh_meta: Res<GiMeshMeta>,
+	mut skinning_meta: ResMut<GiSkinningMeta>,
+) {
+	let skinning_meta = &mut *skinning_meta;
+
+	for skinned in skinning_meta.skinned.values_mut() {
+		// the skinned positions with the indices of the mesh, which voxelization only has for meshes it voxelizes
+		let (indices, triangle_count) = match (&skinned.proxy_indices, mesh_meta.geometry.get(&skinned.mesh)) {
+			(Some(proxy_indices), _) => (Some(proxy_indices), skinned.vertex_count / 3),
+			(None, Some(geometry)) => (Some(&geometry.indices), geometry.triangle_count),
+			(None, None) => (None, 0),
+		};
+
+		skinned.triangle_count = triangle_count;
+		skinned.geometry_bind_group = indices.map(|indices| {
+			render_device.create_bind_group(&BindGroupDescriptor {
+				entries: &[
+					BindGroupEntry {
+						binding: 0,
+						resource: skinned.positions.as_entire_binding(),
+					},
+					BindGroupEntry {
+						binding: 1,
+						resource: indices.as_entire_binding(),
+					},
+				],
+				label: None,
+				layout: &mesh_shaders.geometry_layout,
+			})
+		});
+
+		// proxies aren't skinned on the gpu, so they don't need one
+		if !skinned.indexed {
+			continue;
+		}
+
+		let source = match skinning_meta.sources.get(&skinned.mesh) {
+			Some(source) => source,
+			None => {
+				skinned.bind_group = None;
+				continue;
+			}
+		};
+
+		skinned.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+			entries: &[
+				BindGroupEntry {
+					binding: 0,
+					resource: skinned.params.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 1,
+					resource: source.vertices.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 2,
+					resource: skinned.joints.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 3,
+					resource: skinned.positions.as_entire_binding(),
+				},
+			],
+			label: None,
+			layout: &skinning_shaders.layout,
+		}));
+	}
+}
+
+/// skins every skinned mesh, before voxelization reads them
+pub struct GiSkinningPassNode;
+
+impl Node for GiSkinningPassNode {
+
+	fn run(&self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let skinning_meta = world.get_resource::<GiSkinningMeta>().unwrap();
+		let skinning_shaders = world.get_resource::<GiSkinningShaders>().unwrap();
+
+		if skinning_meta.skinned.is_empty() {
+			return Ok(());
+		}
+
+		// skinning is part of voxelization
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_skinning") });
+
+			pass.set_pipeline(&skinning_shaders.pipeline);
+
+			for skinned in skinning_meta.skinned.values() {
+				if let Some(bind_group) = &skinned.bind_group {
+					pass.set_bind_group(0, bind_group, &[]);
+					pass.dispatch((skinned.vertex_count + SKIN_WORKGROUP_SIZE - 1) / SKIN_WORKGROUP_SIZE, 1, 1);
+				}
+			}
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+
+		Ok(())
+	}
+}
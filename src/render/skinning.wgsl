@@ -0,0 +1,96 @@
+// HOW IT WORKS
+// bevy doesn't skin meshes, so gi does it itself before voxelizing them
+// every vertex first gets the morph targets added to it, and is then moved by up to 4 joints
+// the result is written to a buffer of positions, in the space of the mesh, which voxelization reads instead of the vertex buffer
+// this is the same as skinning.rs does on the cpu
+
+// same as SkinVertex in skinning.rs
+struct SkinVertex {
+    position: vec4<f32>;
+    joints: vec4<u32>;
+    weights: vec4<f32>;
+    // offsets of the position for every morph target
+    morph_0: vec4<f32>;
+    morph_1: vec4<f32>;
+    morph_2: vec4<f32>;
+    morph_3: vec4<f32>;
+};
+
+[[block]]
+struct SkinVertices {
+    data: [[stride(112)]] array<SkinVertex>;
+};
+
+// from the mesh in bind pose to the mesh now, for every joint
+[[block]]
+struct Joints {
+    data: [[stride(64)]] array<mat4x4<f32>>;
+};
+
+[[block]]
+struct SkinnedPositions {
+    data: [[stride(16)]] array<vec4<f32>>;
+};
+
+[[block]]
+struct SkinParams {
+    morph_weights: vec4<f32>;
+    vertex_count: u32;
+    joint_count: u32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> params: SkinParams;
+
+[[group(0), binding(1)]]
+var<storage, read> vertices: SkinVertices;
+
+[[group(0), binding(2)]]
+var<storage, read> joints: Joints;
+
+[[group(0), binding(3)]]
+var<storage, read_write> positions: SkinnedPositions;
+
+fn joint_matrix(joint: u32) -> mat4x4<f32> {
+    // joints that don't exist leave the vertex where it is
+    if (joint >= params.joint_count) {
+        return mat4x4<f32>(
+            vec4<f32>(1.0, 0.0, 0.0, 0.0),
+            vec4<f32>(0.0, 1.0, 0.0, 0.0),
+            vec4<f32>(0.0, 0.0, 1.0, 0.0),
+            vec4<f32>(0.0, 0.0, 0.0, 1.0),
+        );
+    }
+
+    return joints.data[joint];
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn skin([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= params.vertex_count) {
+        return;
+    }
+
+    let vertex = vertices.data[id.x];
+
+    let morphed = vertex.position.xyz
+        + vertex.morph_0.xyz * params.morph_weights.x
+        + vertex.morph_1.xyz * params.morph_weights.y
+        + vertex.morph_2.xyz * params.morph_weights.z
+        + vertex.morph_3.xyz * params.morph_weights.w;
+
+    // vertices without weights aren't skinned, so morph only meshes work too
+    let total = vertex.weights.x + vertex.weights.y + vertex.weights.z + vertex.weights.w;
+    if (total <= 0.0) {
+        positions.data[id.x] = vec4<f32>(morphed, 1.0);
+        return;
+    }
+
+    let skin = joint_matrix(vertex.joints.x) * vertex.weights.x
+        + joint_matrix(vertex.joints.y) * vertex.weights.y
+        + joint_matrix(vertex.joints.z) * vertex.weights.z
+        + joint_matrix(vertex.joints.w) * vertex.weights.w;
+
+    let skinned = skin * vec4<f32>(morphed, 1.0);
+    positions.data[id.x] = vec4<f32>(skinned.xyz / total, 1.0);
+}
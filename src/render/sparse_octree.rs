@@ -0,0 +1,460 @@
+// HOW IT WORKS
+// voxelization appends fragments to a list, instead of writing them to a dense cascade (see voxel_fragments.rs)
+// the octree is then built from that list, one level at a time
+// see sparse_octree.wgsl for the passes, and octree.rs for the same thing on the cpu
+
+use crevice::std140::AsStd140;
+
+use crate::bundle::GiStorage;
+use crate::octree::MAX_OCTREE_DEPTH;
+
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+use super::gi_volume::{texture_bytes, ExtractedGiVolume};
+use super::voxel_fragments::VoxelFragmentMeta;
+
+use bevy::ecs::prelude::*;
+use bevy::math::UVec3;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+};
+
+/// max number of nodes in the octree, every node has a 2x2x2 brick
+pub const MAX_OCTREE_NODES: u32 = 1 << 18;
+
+// the brick pool is a grid of bricks, enough for all nodes
+const BRICKS_PER_AXIS: u32 = 64;
+
+// r, g, b, a and count for the 8 octants of a node
+const LEAF_SUMS_PER_NODE: u64 = 8 * 5;
+
+// node_count and the start of every level
+const COUNTERS_SIZE: u64 = 4 * (1 + 12);
+
+// size of the arguments for a single dispatch_indirect
+const DISPATCH_ARGS_SIZE: u64 = 4 * 3;
+
+const BRICK_POOL_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+
+// bytes in a texel of BRICK_POOL_FORMAT
+const BRICK_POOL_TEXEL_SIZE: u64 = 16;
+
+// the level currently being built
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuOctreeLevel {
+	level: u32,
+	depth: u32,
+	max_nodes: u32,
+}
+
+pub struct SparseOctreeShaders {
+	prepare_fragment_args_pipeline: ComputePipeline,
+	flag_nodes_pipeline: ComputePipeline,
+	allocate_nodes_pipeline: ComputePipeline,
+	finish_level_pipeline: ComputePipeline,
+	write_leaves_pipeline: ComputePipeline,
+	resolve_leaves_pipeline: ComputePipeline,
+	mipmap_level_pipeline: ComputePipeline,
+	octree_layout: BindGroupLayout,
+	level_layout: BindGroupLayout,
+}
+
+fn storage_buffer_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Storage { read_only },
+			has_dynamic_offset: false,
+			min_binding_size: None,
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for SparseOctreeShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+		let shader = Shader::from_wgsl(include_str!("sparse_octree.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let octree_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				// fragments
+				storage_buffer_entry(0, true),
+				// fragment count
+				storage_buffer_entry(1, true),
+				// counters
+				storage_buffer_entry(2, false),
+				// nodes
+				storage_buffer_entry(3, false),
+				// leaf sums
+				storage_buffer_entry(4, false),
+				// brick pool
+				BindGroupLayoutEntry {
+					binding: 5,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::StorageTexture {
+						access: StorageTextureAccess::ReadWrite,
+						format: BRICK_POOL_FORMAT,
+						view_dimension: TextureViewDimension::D3,
+					},
+					count: None,
+				},
+				// dispatch args
+				storage_buffer_entry(6, false),
+			],
+			label: None,
+		});
+
+		let level_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				BindGroupLayoutEntry {
+					binding: 0,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: true,
+						min_binding_size: BufferSize::new(GpuOctreeLevel::std140_size_static() as u64),
+					},
+					count: None,
+				},
+			],
+			label: None,
+		});
+
+		let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&octree_layout, &level_layout],
+		});
+
+		let pipeline = |entry_point| {
+			render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+				label: Some(entry_point),
+				layout: Some(&pipeline_layout),
+				entry_point,
+				module: &shader_module,
+			})
+		};
+
+		SparseOctreeShaders {
+			prepare_fragment_args_pipeline: pipeline("prepare_fragment_args"),
+			flag_nodes_pipeline: pipeline("flag_nodes"),
+			allocate_nodes_pipeline: pipeline("allocate_nodes"),
+			finish_level_pipeline: pipeline("finish_level"),
+			write_leaves_pipeline: pipeline("write_leaves"),
+			resolve_leaves_pipeline: pipeline("resolve_leaves"),
+			mipmap_level_pipeline: pipeline("mipmap_level"),
+			octree_layout,
+			level_layout,
+		}
+	}
+}
+
+/// the octree on the gpu, only allocated when the volume uses `GiStorage::SparseOctree`
+pub struct GpuSparseOctree {
+	pub depth: u32,
+	pub counters: Buffer,
+	pub nodes: Buffer,
+	pub leaf_sums: Buffer,
+	pub dispatch_args: Buffer,
+	pub brick_pool: Texture,
+	pub brick_pool_view: TextureView,
+}
+
+impl GpuSparseOctree {
+	fn new(render_device: &RenderDevice, depth: u32) -> Self {
+		let buffer = |size, usage| {
+			render_device.create_buffer(&BufferDescriptor {
+				label: None,
+				size,
+				usage,
+				mapped_at_creation: false,
+			})
+		};
+
+		let brick_pool = render_device.create_texture(&TextureDescriptor {
+			size: Extent3d {
+				width: BRICKS_PER_AXIS * 2,
+				height: BRICKS_PER_AXIS * 2,
+				depth_or_array_layers: BRICKS_PER_AXIS * 2,
+			},
+			mip_level_count: 1,
+			sample_count: 1,
+			dimension: TextureDimension::D3,
+			format: BRICK_POOL_FORMAT,
+			usage: TextureUsage::SAMPLED | TextureUsage::STORAGE,
+			label: None,
+		});
+
+		let brick_pool_view = brick_pool.create_view(&TextureViewDescriptor::default());
+
+		Self {
+			depth,
+			counters: buffer(COUNTERS_SIZE, BufferUsage::STORAGE | BufferUsage::COPY_DST),
+			nodes: buffer(MAX_OCTREE_NODES as u64 * 4, BufferUsage::STORAGE | BufferUsage::COPY_DST),
+			leaf_sums: buffer(
+				MAX_OCTREE_NODES as u64 * LEAF_SUMS_PER_NODE * 4,
+				BufferUsage::STORAGE | BufferUsage::COPY_DST,
+			),
+			// one for the fragments, and one for every level
+			dispatch_args: buffer(
+				DISPATCH_ARGS_SIZE * (MAX_OCTREE_DEPTH as u64 + 2),
+				BufferUsage::STORAGE | BufferUsage::INDIRECT,
+			),
+			brick_pool,
+			brick_pool_view,
+		}
+	}
+
+	/// memory taken by the buffers and the brick pool
+	pub fn memory_bytes(&self) -> u64 {
+		COUNTERS_SIZE
+			+ MAX_OCTREE_NODES as u64 * 4
+			+ MAX_OCTREE_NODES as u64 * LEAF_SUMS_PER_NODE * 4
+			+ DISPATCH_ARGS_SIZE * (MAX_OCTREE_DEPTH as u64 + 2)
+			+ texture_bytes(UVec3::splat(BRICKS_PER_AXIS * 2), 1, BRICK_POOL_TEXEL_SIZE)
+	}
+
+	// offset into dispatch_args for the nodes of a level
+	fn level_args_offset(level: u32) -> u64 {
+		(level as u64 + 1) * DISPATCH_ARGS_SIZE
+	}
+}
+
+#[derive(Default)]
+pub struct SparseOctreeMeta {
+	pub octree: Option<GpuSparseOctree>,
+	pub levels: DynamicUniformVec<GpuOctreeLevel>,
+	pub level_offsets: Vec<u32>,
+	pub octree_bind_group: Option<BindGroup>,
+	pub level_bind_group: Option<BindGroup>,
+}
+
+/// depth of the octree for a volume, so it's as wide as the largest cascade, with voxels as small as the smallest cascade
+///
+/// the octree is a cube, so this fits the longest axis
+pub fn octree_depth(resolution: UVec3, cascades: u8) -> u32 {
+	let base_depth = 32 - (resolution.max_element().max(1).next_power_of_two() - 1).leading_zeros();
+
+	(base_depth + cascades.max(1) as u32 - 1).clamp(1, MAX_OCTREE_DEPTH)
+}
+
+pub fn prepare_sparse_octree(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut octree_meta: ResMut<SparseOctreeMeta>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	// only needed when the volume actually uses it
+	let volume = match volume {
+		Some(volume) if volume.storage == GiStorage::SparseOctree => volume,
+		_ => {
+			octree_meta.octree = None;
+			return;
+		}
+	};
+
+	let depth = octree_depth(volume.resolution, volume.cascades);
+
+	// the buffers don't depend on the depth, so they can be kept around
+	if octree_meta.octree.is_none() {
+		octree_meta.octree = Some(GpuSparseOctree::new(&render_device, depth));
+	}
+
+	let octree = octree_meta.octree.as_mut().unwrap();
+	octree.depth = depth;
+
+	// reset, so there's only the root node
+	// the rest of the nodes are cleared when they are allocated
+	let mut counters = [0u32; COUNTERS_SIZE as usize / 4];
+	counters[0] = 1; // node_count
+	counters[2] = 1; // level_start[1]
+	let counters = counters.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+
+	render_queue.write_buffer(&octree.counters, 0, &counters);
+	render_queue.write_buffer(&octree.nodes, 0, &0u32.to_le_bytes());
+	render_queue.write_buffer(&octree.leaf_sums, 0, &[0u8; LEAF_SUMS_PER_NODE as usize * 4]);
+
+	// and the uniforms for each level
+	octree_meta.levels.reserve_and_clear(depth as usize, &render_device);
+	octree_meta.level_offsets.clear();
+
+	for level in 0..depth {
+		let offset = octree_meta.levels.push(GpuOctreeLevel {
+			level,
+			depth,
+			max_nodes: MAX_OCTREE_NODES,
+		});
+
+		octree_meta.level_offsets.push(offset);
+	}
+
+	octree_meta.levels.write_to_staging_buffer(&render_device);
+}
+
+pub fn queue_sparse_octree_bind_groups(
+	render_device: Res<RenderDevice>,
+	octree_shaders: Res<SparseOctreeShaders>,
+	fragment_meta: Res<VoxelFragmentMeta>,
+	mut octree_meta: ResMut<SparseOctreeMeta>,
+) {
+	let octree_meta = &mut *octree_meta;
+
+	let (octree, fragments, level_binding) = match (
+		&octree_meta.octree,
+		&fragment_meta.fragments,
+		octree_meta.levels.binding(),
+	) {
+		(Some(octree), Some(fragments), Some(level_binding)) => (octree, fragments, level_binding),
+		_ => return,
+	};
+
+	octree_meta.octree_bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: fragments.fragments.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 1,
+				resource: fragments.fragment_count.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 2,
+				resource: octree.counters.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 3,
+				resource: octree.nodes.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 4,
+				resource: octree.leaf_sums.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 5,
+				resource: BindingResource::TextureView(&octree.brick_pool_view),
+			},
+			BindGroupEntry {
+				binding: 6,
+				resource: octree.dispatch_args.as_entire_binding(),
+			},
+		],
+		label: None,
+		layout: &octree_shaders.octree_layout,
+	}));
+
+	octree_meta.level_bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+		entries: &[
+			BindGroupEntry {
+				binding: 0,
+				resource: level_binding,
+			},
+		],
+		label: None,
+		layout: &octree_shaders.level_layout,
+	}));
+}
+
+/// builds the octree from the fragments written during voxelization
+pub struct SparseOctreePassNode;
+
+impl SparseOctreePassNode {
+	pub const IN_VIEW: &'static str = "view";
+}
+
+impl Node for SparseOctreePassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(SparseOctreePassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn run(&self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let octree_meta = world.get_resource::<SparseOctreeMeta>().unwrap();
+		let octree_shaders = world.get_resource::<SparseOctreeShaders>().unwrap();
+
+		let (octree, octree_bind_group, level_bind_group) = match (
+			&octree_meta.octree,
+			&octree_meta.octree_bind_group,
+			&octree_meta.level_bind_group,
+		) {
+			(Some(octree), Some(octree_bind_group), Some(level_bind_group)) => (octree, octree_bind_group, level_bind_group),
+			_ => return Ok(()),
+		};
+
+		octree_meta
+			.levels
+			.write_to_uniform_buffer(&mut render_context.command_encoder);
+
+		let leaf_level = octree.depth - 1;
+
+		// building the octree is part of voxelization
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("build_sparse_octree") });
+
+			pass.set_bind_group(0, octree_bind_group, &[]);
+			pass.set_bind_group(1, level_bind_group, &[octree_meta.level_offsets[0]]);
+
+			// the fragment count is only known on the gpu
+			pass.set_pipeline(&octree_shaders.prepare_fragment_args_pipeline);
+			pass.dispatch(1, 1, 1);
+
+			// subdivide, one level at a time
+			for level in 0..leaf_level {
+				pass.set_bind_group(1, level_bind_group, &[octree_meta.level_offsets[level as usize]]);
+
+				pass.set_pipeline(&octree_shaders.flag_nodes_pipeline);
+				pass.dispatch_indirect(&octree.dispatch_args, 0);
+
+				pass.set_pipeline(&octree_shaders.allocate_nodes_pipeline);
+				pass.dispatch_indirect(&octree.dispatch_args, GpuSparseOctree::level_args_offset(level));
+
+				pass.set_pipeline(&octree_shaders.finish_level_pipeline);
+				pass.dispatch(1, 1, 1);
+			}
+
+			// write the fragments to the leaves
+			pass.set_bind_group(1, level_bind_group, &[octree_meta.level_offsets[leaf_level as usize]]);
+
+			pass.set_pipeline(&octree_shaders.write_leaves_pipeline);
+			pass.dispatch_indirect(&octree.dispatch_args, 0);
+
+			pass.set_pipeline(&octree_shaders.resolve_leaves_pipeline);
+			pass.dispatch_indirect(&octree.dispatch_args, GpuSparseOctree::level_args_offset(leaf_level));
+		}
+
+		// a pass of its own, so it can be timed on its own
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Voxelize);
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Mip);
+
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("mipmap_sparse_octree") });
+
+			pass.set_bind_group(0, octree_bind_group, &[]);
+
+			// and mipmap, bottom up
+			pass.set_pipeline(&octree_shaders.mipmap_level_pipeline);
+			for level in (0..leaf_level).rev() {
+				pass.set_bind_group(1, level_bind_group, &[octree_meta.level_offsets[level as usize]]);
+				pass.dispatch_indirect(&octree.dispatch_args, GpuSparseOctree::level_args_offset(level));
+			}
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Mip);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,315 @@
+// HOW IT WORKS
+// voxelization writes a list of fragments instead of writing to a dense texture
+// then, one level at a time:
+//   flag the nodes that have a fragment in them
+//   allocate 8 children for every flagged node
+// write the fragments into the bricks of the leaves
+// mipmap the bricks, bottom up
+// this is the same as octree.rs does on the cpu
+
+// same as VoxelFragment::pack
+struct Fragment {
+    position: u32;
+    albedo: u32;
+};
+
+[[block]]
+struct Fragments {
+    data: [[stride(8)]] array<Fragment>;
+};
+
+// the number of fragments voxelization wrote
+[[block]]
+struct FragmentCounter {
+    fragment_count: u32;
+};
+
+[[block]]
+struct Counters {
+    node_count: atomic<u32>;
+    // first node of each level, and the node count at the end
+    level_start: [[stride(4)]] array<u32, 12>;
+};
+
+// child index of each node, with the highest bit set if the node needs to be subdivided
+[[block]]
+struct Nodes {
+    data: [[stride(4)]] array<u32>;
+};
+
+// r, g, b, a and count for every octant of every node
+[[block]]
+struct LeafSums {
+    data: [[stride(4)]] array<atomic<u32>>;
+};
+
+// x, y and z for a dispatch_indirect, first for the fragments, then for the nodes of every level
+[[block]]
+struct DispatchArgs {
+    data: [[stride(4)]] array<u32>;
+};
+
+[[block]]
+struct OctreeLevel {
+    level: u32;
+    depth: u32;
+    max_nodes: u32;
+};
+
+[[group(0), binding(0)]]
+var<storage, read> fragments: Fragments;
+
+[[group(0), binding(1)]]
+var<storage, read> fragment_counter: FragmentCounter;
+
+[[group(0), binding(2)]]
+var<storage, read_write> counters: Counters;
+
+[[group(0), binding(3)]]
+var<storage, read_write> nodes: Nodes;
+
+[[group(0), binding(4)]]
+var<storage, read_write> leaf_sums: LeafSums;
+
+[[group(0), binding(5)]]
+var brick_pool: texture_storage_3d<rgba32float, read_write>;
+
+[[group(0), binding(6)]]
+var<storage, read_write> dispatch_args: DispatchArgs;
+
+[[group(1), binding(0)]]
+var<uniform> octree_level: OctreeLevel;
+
+let NO_CHILDREN: u32 = 0u;
+let NO_NODE: u32 = 0xffffffffu;
+let SUBDIVIDE_FLAG: u32 = 0x80000000u;
+let WORKGROUP_SIZE: u32 = 64u;
+
+// bricks are 2x2x2, laid out in a grid of this many bricks along each axis
+let BRICKS_PER_AXIS: u32 = 64u;
+
+fn fragment_position(fragment: Fragment) -> vec3<u32> {
+    return vec3<u32>(
+        fragment.position & 0x3ffu,
+        (fragment.position >> 10u) & 0x3ffu,
+        (fragment.position >> 20u) & 0x3ffu,
+    );
+}
+
+fn fragment_albedo(fragment: Fragment) -> vec4<u32> {
+    return vec4<u32>(
+        fragment.albedo & 0xffu,
+        (fragment.albedo >> 8u) & 0xffu,
+        (fragment.albedo >> 16u) & 0xffu,
+        fragment.albedo >> 24u,
+    );
+}
+
+// which of the 8 children of a node at level contains position
+fn octant(position: vec3<u32>, level: u32, depth: u32) -> u32 {
+    let shift = depth - 1u - level;
+    let bits = (position >> vec3<u32>(shift)) & vec3<u32>(1u);
+    return bits.x | (bits.y << 1u) | (bits.z << 2u);
+}
+
+// finds the node at level that contains position, or NO_NODE if it's not allocated
+fn find_node(position: vec3<u32>, level: u32, depth: u32) -> u32 {
+    var node = 0u;
+
+    for (var parent_level = 0u; parent_level < level; parent_level = parent_level + 1u) {
+        let child = nodes.data[node] & ~SUBDIVIDE_FLAG;
+        if (child == NO_CHILDREN) {
+            return NO_NODE;
+        }
+
+        node = child + octant(position, parent_level, depth);
+    }
+
+    return node;
+}
+
+fn brick_texel(node: u32, octant: u32) -> vec3<i32> {
+    let brick = vec3<u32>(
+        node % BRICKS_PER_AXIS,
+        (node / BRICKS_PER_AXIS) % BRICKS_PER_AXIS,
+        node / (BRICKS_PER_AXIS * BRICKS_PER_AXIS),
+    );
+    let offset = vec3<u32>(octant & 1u, (octant >> 1u) & 1u, (octant >> 2u) & 1u);
+
+    return vec3<i32>(brick * 2u + offset);
+}
+
+// voxelization keeps counting once the list is full, so this can't be larger than the list
+fn fragment_count() -> u32 {
+    return min(fragment_counter.fragment_count, arrayLength(&fragments.data));
+}
+
+fn dispatch_size(count: u32) -> u32 {
+    return (count + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+}
+
+// sets up the dispatches for the fragments and the root level
+[[stage(compute), workgroup_size(1)]]
+fn prepare_fragment_args() {
+    dispatch_args.data[0] = dispatch_size(fragment_count());
+    dispatch_args.data[1] = 1u;
+    dispatch_args.data[2] = 1u;
+
+    dispatch_args.data[3] = 1u;
+    dispatch_args.data[4] = 1u;
+    dispatch_args.data[5] = 1u;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn flag_nodes([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= fragment_count()) {
+        return;
+    }
+
+    let position = fragment_position(fragments.data[id.x]);
+    let node = find_node(position, octree_level.level, octree_level.depth);
+
+    // all threads write the same thing, so there's no need for atomics here
+    if (node != NO_NODE) {
+        nodes.data[node] = nodes.data[node] | SUBDIVIDE_FLAG;
+    }
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn allocate_nodes([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let node = counters.level_start[octree_level.level] + id.x;
+    if (node >= counters.level_start[octree_level.level + 1u]) {
+        return;
+    }
+
+    if ((nodes.data[node] & SUBDIVIDE_FLAG) == 0u) {
+        return;
+    }
+
+    let child = atomicAdd(&counters.node_count, 8u);
+
+    // out of nodes, so this part of the volume stays empty
+    if (child + 8u > octree_level.max_nodes) {
+        nodes.data[node] = NO_CHILDREN;
+        return;
+    }
+
+    // clear the children, as they might have been used last frame
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        nodes.data[child + i] = NO_CHILDREN;
+
+        for (var j = 0u; j < 40u; j = j + 1u) {
+            atomicStore(&leaf_sums.data[(child + i) * 40u + j], 0u);
+        }
+    }
+
+    nodes.data[node] = child;
+}
+
+// stores where the next level starts, and how large the dispatch for it is
+[[stage(compute), workgroup_size(1)]]
+fn finish_level() {
+    let level = octree_level.level;
+    let node_count = min(atomicLoad(&counters.node_count), octree_level.max_nodes);
+
+    counters.level_start[level + 2u] = node_count;
+
+    let args = (level + 2u) * 3u;
+    dispatch_args.data[args] = dispatch_size(node_count - counters.level_start[level + 1u]);
+    dispatch_args.data[args + 1u] = 1u;
+    dispatch_args.data[args + 2u] = 1u;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn write_leaves([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= fragment_count()) {
+        return;
+    }
+
+    let fragment = fragments.data[id.x];
+    let position = fragment_position(fragment);
+    let leaf_level = octree_level.depth - 1u;
+    let node = find_node(position, leaf_level, octree_level.depth);
+
+    if (node == NO_NODE) {
+        return;
+    }
+
+    let albedo = fragment_albedo(fragment);
+    let sum = (node * 8u + octant(position, leaf_level, octree_level.depth)) * 5u;
+
+    atomicAdd(&leaf_sums.data[sum], albedo.r);
+    atomicAdd(&leaf_sums.data[sum + 1u], albedo.g);
+    atomicAdd(&leaf_sums.data[sum + 2u], albedo.b);
+    atomicAdd(&leaf_sums.data[sum + 3u], albedo.a);
+    atomicAdd(&leaf_sums.data[sum + 4u], 1u);
+}
+
+// averages the fragments in every leaf voxel, and writes them to the brick pool
+[[stage(compute), workgroup_size(64)]]
+fn resolve_leaves([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let node = counters.level_start[octree_level.level] + id.x;
+    if (node >= counters.level_start[octree_level.level + 1u]) {
+        return;
+    }
+
+    for (var octant = 0u; octant < 8u; octant = octant + 1u) {
+        let sum = (node * 8u + octant) * 5u;
+        let count = atomicLoad(&leaf_sums.data[sum + 4u]);
+
+        var value = vec4<f32>(0.0);
+        if (count > 0u) {
+            let albedo = vec4<f32>(
+                f32(atomicLoad(&leaf_sums.data[sum])),
+                f32(atomicLoad(&leaf_sums.data[sum + 1u])),
+                f32(atomicLoad(&leaf_sums.data[sum + 2u])),
+                f32(atomicLoad(&leaf_sums.data[sum + 3u])),
+            ) / (255.0 * f32(count));
+
+            // premultiplied, so mipmapping can just average
+            value = vec4<f32>(albedo.rgb * albedo.a, albedo.a);
+        }
+
+        textureStore(brick_pool, brick_texel(node, octant), value);
+    }
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn mipmap_level([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let node = counters.level_start[octree_level.level] + id.x;
+    if (node >= counters.level_start[octree_level.level + 1u]) {
+        return;
+    }
+
+    let child = nodes.data[node];
+
+    for (var octant = 0u; octant < 8u; octant = octant + 1u) {
+        var value = vec4<f32>(0.0);
+
+        if (child != NO_CHILDREN) {
+            for (var i = 0u; i < 8u; i = i + 1u) {
+                value = value + textureLoad(brick_pool, brick_texel(child + octant, i));
+            }
+        }
+
+        textureStore(brick_pool, brick_texel(node, octant), value / 8.0);
+    }
+}
+
+// for cone tracing
+// gets the voxel at position (0 to 1 over the whole octree), where mip 0 is the finest level
+fn sample_octree(position: vec3<f32>, mip: u32, depth: u32) -> vec4<f32> {
+    if (mip >= depth || any(position < vec3<f32>(0.0)) || any(position >= vec3<f32>(1.0))) {
+        return vec4<f32>(0.0);
+    }
+
+    let voxel = vec3<u32>(position * f32(1u << depth));
+    let level = depth - 1u - mip;
+    let node = find_node(voxel, level, depth);
+
+    if (node == NO_NODE) {
+        return vec4<f32>(0.0);
+    }
+
+    return textureLoad(brick_pool, brick_texel(node, octant(voxel, level, depth)));
+}
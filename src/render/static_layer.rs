@@ -0,0 +1,178 @@
+// HOW IT WORKS
+// the static layer of a volume is voxelized on the cpu once, and uploaded to a texture per cascade when it changes
+// every frame the voxelize node copies those into the first mip of the dense cascades, and voxelization adds the dynamic meshes on top
+// the grids are only turned into bytes again when the layer, the resolution or the format of the cascades changes
+
+use crate::bundle::{GiSettings, GiStaticLayer, GiVolume, GiVolumeFormat};
+
+use super::gi_volume::{volume_texture_format, ExtractedGiVolume, ViewGiVolumes};
+
+use bevy::ecs::prelude::*;
+use bevy::log::warn;
+use bevy::math::UVec3;
+use bevy::render2::{
+	render_resource::*,
+	renderer::{RenderDevice, RenderQueue},
+};
+
+/// the static layer of the extracted volume, as the bytes of a texture per cascade
+pub struct ExtractedGiStaticLayer {
+	pub resolution: UVec3,
+	pub format: GiVolumeFormat,
+	pub cascades: Vec<Vec<u8>>,
+}
+
+impl ExtractedGiStaticLayer {
+	/// whether it's copied into `cascade` this frame, it isn't when the volume changed size or format and the layer wasn't rebuilt yet
+	pub fn covers(&self, volume: &ExtractedGiVolume, cascade: usize) -> bool {
+		volume.resolution == self.resolution && volume.settings.format == self.format && cascade < self.cascades.len()
+	}
+}
+
+pub fn extract_gi_static_layer(
+	mut commands: Commands,
+	settings: Res<GiSettings>,
+	layers: Query<(&GiVolume, &GiStaticLayer)>,
+	changed: Query<Entity, (With<GiVolume>, Changed<GiStaticLayer>)>,
+	mut extracted: Local<Option<(UVec3, GiVolumeFormat)>>,
+) {
+	// same volume as extract_gi_cascades
+	let (volume, layer) = match layers.iter().next() {
+		Some(layer) => layer,
+		None => {
+			*extracted = None;
+			commands.remove_resource::<ExtractedGiStaticLayer>();
+			return;
+		}
+	};
+
+	// the render world keeps the last one around, the rest of the settings can be tuned live without building it again
+	let shape = (volume.resolution, settings.format);
+	if changed.iter().next().is_none() && *extracted == Some(shape) {
+		return;
+	}
+	*extracted = Some(shape);
+
+	if let Some(grid) = layer.cascades.iter().find(|grid| grid.resolution != volume.resolution) {
+		warn!(
+			"GiStaticLayer has a resolution of {}, but the GiVolume has {}, so it's ignored",
+			grid.resolution, volume.resolution,
+		);
+		commands.remove_resource::<ExtractedGiStaticLayer>();
+		return;
+	}
+
+	commands.insert_resource(ExtractedGiStaticLayer {
+		resolution: volume.resolution,
+		format: settings.format,
+		cascades: layer.cascades.iter().map(|grid| grid.to_bytes(settings.format)).collect(),
+	});
+}
+
+/// the static layer on the gpu, a texture with only the first mip for every cascade it has
+pub struct GpuGiStaticLayer {
+	pub cascades: Vec<Texture>,
+}
+
+/// uploads the static layer once, when extraction replaced it
+pub fn prepare_gi_static_layer(
+	mut commands: Commands,
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	static_layer: Option<Res<ExtractedGiStaticLayer>>,
+) {
+	let static_layer = match static_layer {
+		Some(static_layer) => static_layer,
+		None => {
+			commands.remove_resource::<GpuGiStaticLayer>();
+			return;
+		}
+	};
+
+	if !static_layer.is_changed() {
+		return;
+	}
+
+	let resolution = static_layer.resolution;
+	let size = Extent3d {
+		width: resolution.x,
+		height: resolution.y,
+		depth_or_array_layers: resolution.z,
+	};
+
+	let cascades = static_layer
+		.cascades
+		.iter()
+		.map(|bytes| {
+			let texture = render_device.create_texture(&TextureDescriptor {
+				size,
+				mip_level_count: 1,
+				sample_count: 1,
+				dimension: TextureDimension::D3,
+				format: volume_texture_format(static_layer.format),
+				usage: TextureUsage::COPY_SRC | TextureUsage::COPY_DST,
+				label: Some("gi_static_layer"),
+			});
+
+			render_queue.write_texture(
+				ImageCopyTexture {
+					texture: &texture,
+					mip_level: 0,
+					origin: Origin3d::ZERO,
+				},
+				bytes,
+				ImageDataLayout {
+					offset: 0,
+					bytes_per_row: std::num::NonZeroU32::new(resolution.x * static_layer.format.bytes_per_voxel()),
+					rows_per_image: std::num::NonZeroU32::new(resolution.y),
+				},
+				size,
+			);
+
+			texture
+		})
+		.collect();
+
+	commands.insert_resource(GpuGiStaticLayer { cascades });
+}
+
+/// copies the static layer into the first mip of the cascades of a view, before voxelization adds to them
+///
+/// sparse storages have no cascade textures, so nothing is copied for them
+pub fn restore_gi_static_layer(world: &World, encoder: &mut CommandEncoder, view_volumes: &ViewGiVolumes) {
+	let (volume, static_layer, gpu_static_layer) = match (
+		world.get_resource::<ExtractedGiVolume>(),
+		world.get_resource::<ExtractedGiStaticLayer>(),
+		world.get_resource::<GpuGiStaticLayer>(),
+	) {
+		(Some(volume), Some(static_layer), Some(gpu_static_layer)) => (volume, static_layer, gpu_static_layer),
+		_ => return,
+	};
+
+	// the volume changed size or format, and the layer wasn't rebuilt yet
+	if !static_layer.covers(volume, 0) {
+		return;
+	}
+
+	let resolution = static_layer.resolution;
+
+	for (cascade, texture) in view_volumes.cascades.iter().zip(gpu_static_layer.cascades.iter()) {
+		encoder.copy_texture_to_texture(
+			ImageCopyTexture {
+				texture,
+				mip_level: 0,
+				origin: Origin3d::ZERO,
+			},
+			ImageCopyTexture {
+				texture: &cascade.texture,
+				mip_level: 0,
+				origin: Origin3d::ZERO,
+			},
+			Extent3d {
+				width: resolution.x,
+				height: resolution.y,
+				depth_or_array_layers: resolution.z,
+			},
+		);
+	}
+}
@@ -0,0 +1,361 @@
+// HOW IT WORKS
+// the indirect light of last frame is kept per view, in two sets of textures that swap every frame
+// the gbuffer pass writes how far every pixel moved since last frame, from the transforms of the meshes and the view last frame
+// after tracing, every pixel looks up where it was last frame, and blends what was there with what was just traced
+// the history is clamped to the colors around the pixel first, so it can't drag along light that isn't there anymore
+// with history, the tracer only traces one of the diffuse cones per pixel, and picks a different one every frame
+
+use crevice::std140::{AsStd140, Std140};
+
+use super::cone_trace::{
+	ConeTraceMeta, ConeTraceShaders, ViewGiTraceBindGroup, ViewGiTraceTargets, TRACE_OUTPUT_FORMAT, TRACE_WORKGROUP_SIZE,
+};
+use super::diagnostics::{begin_gi_timer, end_gi_timer, GiTimedPass};
+
+use bevy::ecs::prelude::*;
+use bevy::math::Mat4;
+use bevy::render2::{
+	render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+	render_resource::*,
+	renderer::{RenderContext, RenderDevice, RenderQueue},
+	shader::Shader,
+	view::{ExtractedView, ViewUniformOffset},
+};
+use bevy::utils::{HashMap, HashSet};
+
+/// distance to the camera of every pixel, to find pixels that were hidden last frame
+pub const HISTORY_DEPTH_FORMAT: TextureFormat = TextureFormat::R32Float;
+
+/// how far every pixel moved on screen since last frame in xy, and it's depth last frame in z
+pub const MOTION_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+/// the same for the gbuffer, the tracer and the resolve
+#[repr(C)]
+#[derive(Copy, Clone, AsStd140, Default, Debug)]
+pub struct GpuGiTemporal {
+	previous_view_proj: Mat4,
+	blend: f32,
+	frame: u32,
+	/// 0 when there's nothing in the history yet
+	history_valid: u32,
+}
+
+pub struct GiTemporalShaders {
+	pub resolve_pipeline: ComputePipeline,
+	pub resolve_layout: BindGroupLayout,
+	pub sampler: Sampler,
+}
+
+pub fn temporal_uniform_entry(binding: u32, visibility: ShaderStage) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility,
+		ty: BindingType::Buffer {
+			ty: BufferBindingType::Uniform,
+			has_dynamic_offset: false,
+			min_binding_size: BufferSize::new(GpuGiTemporal::std140_size_static() as u64),
+		},
+		count: None,
+	}
+}
+
+fn texture_entry(binding: u32, filterable: bool) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::Texture {
+			multisampled: false,
+			sample_type: TextureSampleType::Float { filterable },
+			view_dimension: TextureViewDimension::D2,
+		},
+		count: None,
+	}
+}
+
+fn output_entry(binding: u32, format: TextureFormat) -> BindGroupLayoutEntry {
+	BindGroupLayoutEntry {
+		binding,
+		visibility: ShaderStage::COMPUTE,
+		ty: BindingType::StorageTexture {
+			access: StorageTextureAccess::WriteOnly,
+			format,
+			view_dimension: TextureViewDimension::D2,
+		},
+		count: None,
+	}
+}
+
+impl FromWorld for GiTemporalShaders {
+	fn from_world(world: &mut World) -> Self {
+		let render_device = world.get_resource::<RenderDevice>().unwrap();
+		let trace_shaders = world.get_resource::<ConeTraceShaders>().unwrap();
+
+		// the gbuffer and what was just traced, then the history, then the new history
+		let resolve_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			entries: &[
+				temporal_uniform_entry(0, ShaderStage::COMPUTE),
+				texture_entry(1, false),
+				texture_entry(2, false),
+				texture_entry(3, false),
+				texture_entry(4, false),
+				texture_entry(5, true),
+				texture_entry(6, true),
+				texture_entry(7, false),
+				BindGroupLayoutEntry {
+					binding: 8,
+					visibility: ShaderStage::COMPUTE,
+					ty: BindingType::Sampler {
+						filtering: true,
+						comparison: false,
+					},
+					count: None,
+				},
+				output_entry(9, TRACE_OUTPUT_FORMAT),
+				output_entry(10, TRACE_OUTPUT_FORMAT),
+				output_entry(11, HISTORY_DEPTH_FORMAT),
+			],
+			label: None,
+		});
+
+		let shader = Shader::from_wgsl(include_str!("temporal.wgsl"));
+		let shader_module = render_device.create_shader_module(&shader);
+
+		let layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+			label: None,
+			push_constant_ranges: &[],
+			bind_group_layouts: &[&trace_shaders.view_layout, &resolve_layout],
+		});
+
+		let resolve_pipeline = render_device.create_compute_pipeline(&ComputePipelineDescriptor {
+			label: None,
+			layout: Some(&layout),
+			entry_point: "resolve",
+			module: &shader_module,
+		});
+
+		let sampler = render_device.create_sampler(&SamplerDescriptor {
+			mag_filter: FilterMode::Linear,
+			min_filter: FilterMode::Linear,
+			..Default::default()
+		});
+
+		GiTemporalShaders {
+			resolve_pipeline,
+			resolve_layout,
+			sampler,
+		}
+	}
+}
+
+/// the indirect light of one frame
+pub struct GiHistoryFrame {
+	pub diffuse: TextureView,
+	pub specular: TextureView,
+	pub depth: TextureView,
+}
+
+/// the history of a single view, kept between frames
+///
+/// the texture cache can hand out any texture with the same size, so these are made here instead
+pub struct GiViewHistory {
+	pub width: u32,
+	pub height: u32,
+	frames: [GiHistoryFrame; 2],
+	/// the frame written to this frame, the other one is last frame
+	current: usize,
+	pub uniform: Buffer,
+	previous_view_proj: Mat4,
+	frame: u32,
+	/// false until a frame has been written
+	valid: bool,
+}
+
+impl GiViewHistory {
+	fn new(render_device: &RenderDevice, width: u32, height: u32) -> Self {
+		let target = |format: TextureFormat| {
+			render_device
+				.create_texture(&TextureDescriptor {
+					size: Extent3d {
+						width,
+						height,
+						depth_or_array_layers: 1,
+					},
+					mip_level_count: 1,
+					sample_count: 1,
+					dimension: TextureDimension::D2,
+					format,
+					usage: TextureUsage::STORAGE | TextureUsage::SAMPLED,
+					label: None,
+				})
+				.create_view(&TextureViewDescriptor::default())
+		};
+
+		let frame = || GiHistoryFrame {
+			diffuse: target(TRACE_OUTPUT_FORMAT),
+			specular: target(TRACE_OUTPUT_FORMAT),
+			depth: target(HISTORY_DEPTH_FORMAT),
+		};
+
+		let frames = [frame(), frame()];
+
+		let uniform = render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: GpuGiTemporal::std140_size_static() as u64,
+			usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		});
+
+		Self {
+			width,
+			height,
+			frames,
+			current: 0,
+			uniform,
+			previous_view_proj: Mat4::IDENTITY,
+			frame: 0,
+			valid: false,
+		}
+	}
+
+	/// written to this frame, this is the indirect light everything else reads
+	pub fn current(&self) -> &GiHistoryFrame {
+		&self.frames[self.current]
+	}
+
+	/// what was written last frame
+	pub fn previous(&self) -> &GiHistoryFrame {
+		&self.frames[1 - self.current]
+	}
+}
+
+/// the history of every view
+#[derive(Default)]
+pub struct GiTemporalMeta {
+	pub views: HashMap<Entity, GiViewHistory>,
+}
+
+impl GiTemporalMeta {
+	/// swaps the history of a view, and writes it's uniform for this frame
+	///
+	/// the history is made again when the size of the view changes, and then starts out empty
+	pub fn prepare_view(
+		&mut self,
+		entity: Entity,
+		view: &ExtractedView,
+		blend: f32,
+		render_device: &RenderDevice,
+		render_queue: &RenderQueue,
+	) -> &GiViewHistory {
+		let resized = self
+			.views
+			.get(&entity)
+			.map_or(true, |history| history.width != view.width || history.height != view.height);
+
+		if resized {
+			self.views.insert(entity, GiViewHistory::new(render_device, view.width, view.height));
+		}
+
+		let history = self.views.get_mut(&entity).unwrap();
+		if !resized {
+			history.current = 1 - history.current;
+		}
+
+		let view_proj = view.projection * view.transform.compute_matrix().inverse();
+
+		render_queue.write_buffer(
+			&history.uniform,
+			0,
+			GpuGiTemporal {
+				// the first frame has no last frame, so nothing moved
+				previous_view_proj: if history.valid { history.previous_view_proj } else { view_proj },
+				blend: blend.clamp(0.0, 1.0),
+				frame: history.frame,
+				history_valid: history.valid as u32,
+			}
+			.as_std140()
+			.as_bytes(),
+		);
+
+		history.previous_view_proj = view_proj;
+		history.frame = history.frame.wrapping_add(1);
+		history.valid = true;
+
+		history
+	}
+
+	/// drops the history of views that are gone
+	pub fn retain_views(&mut self, views: &HashSet<Entity>) {
+		self.views.retain(|entity, _| views.contains(entity));
+	}
+}
+
+/// blends what was traced with the history, after all tracing is done
+pub struct GiTemporalPassNode {
+	view_query: QueryState<(
+		&'static ViewUniformOffset,
+		&'static ViewGiTraceTargets,
+		&'static ViewGiTraceBindGroup,
+	)>,
+}
+
+impl GiTemporalPassNode {
+	pub const IN_VIEW: &'static str = "view";
+
+	pub fn new(world: &mut World) -> Self {
+		Self {
+			view_query: QueryState::new(world),
+		}
+	}
+}
+
+impl Node for GiTemporalPassNode {
+
+	fn input(&self) -> Vec<SlotInfo> {
+		vec![SlotInfo::new(GiTemporalPassNode::IN_VIEW, SlotType::Entity)]
+	}
+
+	fn update(&mut self, world: &mut World) {
+		self.view_query.update_archetypes(world);
+	}
+
+	fn run(&self, graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError> {
+
+		let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+
+		let (view_uniform_offset, targets, trace_bind_group) = match self.view_query.get_manual(world, view_entity) {
+			Ok(query) => query,
+			Err(_) => return Ok(()),
+		};
+
+		let temporal_shaders = world.get_resource::<GiTemporalShaders>().unwrap();
+		let trace_meta = world.get_resource::<ConeTraceMeta>().unwrap();
+
+		let view_bind_group = match &trace_meta.view_bind_group {
+			Some(view_bind_group) => view_bind_group,
+			None => return Ok(()),
+		};
+
+		// the temporal resolve is part of tracing
+		begin_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Trace);
+
+		// always runs, even without history, as this is what writes the targets everything else reads
+		{
+			let mut pass = render_context
+				.command_encoder
+				.begin_compute_pass(&ComputePassDescriptor { label: Some("gi_temporal_resolve") });
+
+			pass.set_pipeline(&temporal_shaders.resolve_pipeline);
+			pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+			pass.set_bind_group(1, &trace_bind_group.resolve_bind_group, &[]);
+			pass.dispatch(
+				(targets.width + TRACE_WORKGROUP_SIZE - 1) / TRACE_WORKGROUP_SIZE,
+				(targets.height + TRACE_WORKGROUP_SIZE - 1) / TRACE_WORKGROUP_SIZE,
+				1,
+			);
+		}
+
+		end_gi_timer(world, &mut render_context.command_encoder, GiTimedPass::Trace);
+
+		Ok(())
+	}
+}
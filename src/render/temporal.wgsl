@@ -0,0 +1,136 @@
+// HOW IT WORKS
+// every pixel finds where it was last frame with the motion from the gbuffer
+// the history there is only used if it was the same surface, so the distance to the camera has to match
+// it's clamped to the range of what was traced around the pixel this frame, so light that moved away doesn't linger
+// then it's blended with what was traced, and written as the history for next frame
+
+[[block]]
+struct View {
+    view_proj: mat4x4<f32>;
+    world_position: vec3<f32>;
+};
+
+// same layout as GpuGiTemporal
+[[block]]
+struct Temporal {
+    previous_view_proj: mat4x4<f32>;
+    // how much of the history is kept
+    blend: f32;
+    frame: u32;
+    history_valid: u32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> view: View;
+
+[[group(1), binding(0)]]
+var<uniform> temporal: Temporal;
+
+[[group(1), binding(1)]]
+var gbuffer_position: texture_2d<f32>;
+
+// xy is how far the pixel moved in uv since last frame, z the distance to the camera last frame
+[[group(1), binding(2)]]
+var gbuffer_motion: texture_2d<f32>;
+
+// what was traced this frame
+[[group(1), binding(3)]]
+var traced_diffuse: texture_2d<f32>;
+
+[[group(1), binding(4)]]
+var traced_specular: texture_2d<f32>;
+
+// last frame
+[[group(1), binding(5)]]
+var history_diffuse: texture_2d<f32>;
+
+[[group(1), binding(6)]]
+var history_specular: texture_2d<f32>;
+
+[[group(1), binding(7)]]
+var history_depth: texture_2d<f32>;
+
+[[group(1), binding(8)]]
+var history_sampler: sampler;
+
+// this frame, and the history of next frame
+[[group(1), binding(9)]]
+var indirect_diffuse: texture_storage_2d<rgba16float, write>;
+
+[[group(1), binding(10)]]
+var indirect_specular: texture_storage_2d<rgba16float, write>;
+
+[[group(1), binding(11)]]
+var depth: texture_storage_2d<r32float, write>;
+
+// how far the distance to the camera can be off, relative to the distance, and still be the same surface
+let DEPTH_TOLERANCE: f32 = 0.05;
+
+[[stage(compute), workgroup_size(8, 8)]]
+fn resolve([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy);
+    let size = textureDimensions(gbuffer_position);
+    if (any(pixel >= size)) {
+        return;
+    }
+
+    let position = textureLoad(gbuffer_position, pixel, 0);
+    let diffuse = textureLoad(traced_diffuse, pixel, 0);
+    let specular = textureLoad(traced_specular, pixel, 0);
+
+    // nothing was drawn here, so there's nothing to keep either
+    if (position.w == 0.0) {
+        textureStore(indirect_diffuse, pixel, diffuse);
+        textureStore(indirect_specular, pixel, specular);
+        textureStore(depth, pixel, vec4<f32>(0.0));
+        return;
+    }
+
+    let pixel_depth = (view.view_proj * vec4<f32>(position.xyz, 1.0)).w;
+    textureStore(depth, pixel, vec4<f32>(pixel_depth));
+
+    // the range of what was traced around the pixel, only counting pixels where something was drawn
+    var diffuse_min = diffuse;
+    var diffuse_max = diffuse;
+    var specular_min = specular;
+    var specular_max = specular;
+
+    for (var y = -1; y <= 1; y = y + 1) {
+        for (var x = -1; x <= 1; x = x + 1) {
+            let neighbour = clamp(pixel + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            if (textureLoad(gbuffer_position, neighbour, 0).w != 0.0) {
+                let neighbour_diffuse = textureLoad(traced_diffuse, neighbour, 0);
+                let neighbour_specular = textureLoad(traced_specular, neighbour, 0);
+
+                diffuse_min = min(diffuse_min, neighbour_diffuse);
+                diffuse_max = max(diffuse_max, neighbour_diffuse);
+                specular_min = min(specular_min, neighbour_specular);
+                specular_max = max(specular_max, neighbour_specular);
+            }
+        }
+    }
+
+    let motion = textureLoad(gbuffer_motion, pixel, 0);
+    let uv = (vec2<f32>(pixel) + 0.5) / vec2<f32>(size);
+    let previous_uv = uv - motion.xy;
+
+    // off screen last frame, or hidden behind something else
+    var valid = temporal.history_valid != 0u && temporal.blend > 0.0 && all(previous_uv >= vec2<f32>(0.0)) && all(previous_uv <= vec2<f32>(1.0));
+    if (valid) {
+        let previous_pixel = clamp(vec2<i32>(previous_uv * vec2<f32>(size)), vec2<i32>(0), size - 1);
+        let previous_depth = textureLoad(history_depth, previous_pixel, 0).r;
+        valid = abs(previous_depth - motion.z) <= DEPTH_TOLERANCE * motion.z;
+    }
+
+    if (!valid) {
+        textureStore(indirect_diffuse, pixel, diffuse);
+        textureStore(indirect_specular, pixel, specular);
+        return;
+    }
+
+    let previous_diffuse = clamp(textureSampleLevel(history_diffuse, history_sampler, previous_uv, 0.0), diffuse_min, diffuse_max);
+    let previous_specular = clamp(textureSampleLevel(history_specular, history_sampler, previous_uv, 0.0), specular_min, specular_max);
+
+    textureStore(indirect_diffuse, pixel, mix(diffuse, previous_diffuse, temporal.blend));
+    textureStore(indirect_specular, pixel, mix(specular, previous_specular, temporal.blend));
+}
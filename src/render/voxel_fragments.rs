@@ -0,0 +1,72 @@
+// the fragment list voxelization appends to, instead of writing to a dense cascade
+// the sparse storages (octree, brick map) are built from it
+// a fragment is a packed position and albedo, see VoxelFragment::pack
+// voxelization binds them in it's own bind group, see GiCascadeMeta::voxelize_bind_group
+
+use crate::bundle::GiStorage;
+
+use super::gi_volume::ExtractedGiVolume;
+
+use bevy::ecs::prelude::*;
+use bevy::render2::{
+	render_resource::*,
+	renderer::{RenderDevice, RenderQueue},
+};
+
+/// max number of fragments voxelization can write in a frame, the rest are dropped
+pub const MAX_VOXEL_FRAGMENTS: u32 = 1 << 22;
+
+/// position and albedo, both packed in an u32
+pub const FRAGMENT_SIZE: u64 = 8;
+
+pub struct GpuVoxelFragments {
+	pub fragments: Buffer,
+	/// the number of fragments written, this keeps counting once the list is full
+	pub fragment_count: Buffer,
+}
+
+impl GpuVoxelFragments {
+	/// memory taken by the list and the count
+	pub fn memory_bytes(&self) -> u64 {
+		MAX_VOXEL_FRAGMENTS as u64 * FRAGMENT_SIZE + 4
+	}
+}
+
+#[derive(Default)]
+pub struct VoxelFragmentMeta {
+	pub fragments: Option<GpuVoxelFragments>,
+}
+
+pub fn prepare_voxel_fragments(
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut fragment_meta: ResMut<VoxelFragmentMeta>,
+	volume: Option<Res<ExtractedGiVolume>>,
+) {
+	// dense cascades are written to directly
+	match volume {
+		Some(volume) if volume.storage != GiStorage::Dense => (),
+		_ => {
+			fragment_meta.fragments = None;
+			return;
+		}
+	}
+
+	let fragments = fragment_meta.fragments.get_or_insert_with(|| GpuVoxelFragments {
+		fragments: render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: MAX_VOXEL_FRAGMENTS as u64 * FRAGMENT_SIZE,
+			usage: BufferUsage::STORAGE,
+			mapped_at_creation: false,
+		}),
+		fragment_count: render_device.create_buffer(&BufferDescriptor {
+			label: None,
+			size: 4,
+			usage: BufferUsage::STORAGE | BufferUsage::COPY_DST,
+			mapped_at_creation: false,
+		}),
+	});
+
+	// start with an empty list every frame
+	render_queue.write_buffer(&fragments.fragment_count, 0, &0u32.to_le_bytes());
+}
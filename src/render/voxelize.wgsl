@@ -1,6 +1,444 @@
-// TODO
-// vertex and index buffers
+// HOW IT WORKS
+// voxelize runs once for every triangle of a mesh, and rasterizes it into the voxels of one cascade, the same way CpuVoxelizer does
+// the premultiplied albedo of every voxel a triangle covers is added to a buffer of sums, with fixed point atomics
+// resolve_voxels then averages the sums into the first mip of the cascade, and clears them again for the next cascade
+// the sums only hold a slab of layers of the cascade at once, so large cascades are voxelized a slab at a time
+// sparse storages don't have cascades, so the voxels are appended as fragments instead, see voxel_fragments.rs
 
-// mesh uniform
+// the material of the mesh, from ExtractedGiMesh
+struct GiMaterial {
+    // linear, not premultiplied
+    base_color: vec4<f32>;
+    // one of the ALPHA_MODE_ constants, same as GiAlphaMode
+    alpha_mode: u32;
+    alpha_cutoff: f32;
+    // one of the THIN_GEOMETRY_ constants, same as GiThinGeometry
+    thin_geometry: u32;
+    // how far to extrude along the normal when solidifying, in world space
+    thickness: f32;
+    // the CONTRIBUTION_ flags, from GiContribution
+    contribution: u32;
+    // only for compositing, see gbuffer.wgsl
+    metallic: f32;
+};
 
-// view uniform
\ No newline at end of file
+let ALPHA_MODE_OPAQUE: u32 = 0u;
+let ALPHA_MODE_MASK: u32 = 1u;
+let ALPHA_MODE_BLEND: u32 = 2u;
+
+// keep needs the center of the voxel covered, dilate uses conservative rasterization, solidify extrudes
+let THIN_GEOMETRY_KEEP: u32 = 0u;
+let THIN_GEOMETRY_DILATE: u32 = 1u;
+let THIN_GEOMETRY_SOLIDIFY: u32 = 2u;
+
+// an occluder writes the opacity of the voxel, an emitter the color
+let CONTRIBUTION_OCCLUDES: u32 = 1u;
+let CONTRIBUTION_EMITS: u32 = 2u;
+
+// the premultiplied voxel a texel of a surface writes, same as GiAlphaMode::opacity
+// nothing should be written when it's all 0, as the texel is masked out, or the surface doesn't contribute
+fn surface_voxel(material: GiMaterial, texel: vec4<f32>) -> vec4<f32> {
+    let color = material.base_color * texel;
+
+    var opacity = 1.0;
+    if (material.alpha_mode == ALPHA_MODE_MASK) {
+        opacity = select(0.0, 1.0, color.a >= material.alpha_cutoff);
+    } elseif (material.alpha_mode == ALPHA_MODE_BLEND) {
+        opacity = clamp(color.a, 0.0, 1.0);
+    }
+
+    // an emitter that doesn't occlude lets the light through, and an occluder that doesn't emit is black
+    let emits = (material.contribution & CONTRIBUTION_EMITS) != 0u;
+    let occludes = (material.contribution & CONTRIBUTION_OCCLUDES) != 0u;
+
+    return vec4<f32>(
+        select(vec3<f32>(0.0), color.rgb * opacity, emits),
+        select(0.0, opacity, occludes),
+    );
+}
+
+// cascades, same layout as GpuGiCascade
+struct GiCascade {
+    // world space to 0 - 1 over the cascade
+    projection: mat4x4<f32>;
+    resolution: vec3<f32>;
+    texture_index: u32;
+    // size of the cascade in world space
+    extent: vec3<f32>;
+};
+
+// the max number of cascades is set by the plugin, so this is a runtime sized array
+[[block]]
+struct GiCascades {
+    num_cascades: u32;
+    // in voxels of the first cascade, from GiTraceBias
+    normal_offset: f32;
+    self_intersection_bias: f32;
+    // how the voxels are stored, same as in cone_trace.wgsl
+    storage: u32;
+    cascades: [[stride(96)]] array<GiCascade>;
+};
+
+// same as GpuVoxelizeParams
+[[block]]
+struct VoxelizeParams {
+    cascade: u32;
+    // the slab of layers along z the sums hold
+    first_layer: u32;
+    layers: u32;
+    // leave the voxels no triangle covered alone, as the static layer was copied there
+    keep_existing: u32;
+    // append fragments instead of adding to the sums
+    fragments: u32;
+    // only one pass a frame counts the triangles, so every triangle is counted once
+    count_triangles: u32;
+};
+
+[[group(0), binding(0)]]
+var<storage, read> gi_cascades: GiCascades;
+
+[[group(0), binding(1)]]
+var<uniform> params: VoxelizeParams;
+
+// the first u32 of GiCounters with GiDiagnosticsPlugin::gpu_counters, otherwise a buffer nobody reads
+[[block]]
+struct TriangleCounter {
+    triangles: atomic<u32>;
+};
+
+[[group(0), binding(2)]]
+var<storage, read_write> triangle_counter: TriangleCounter;
+
+// the premultiplied albedo and opacity in fixed point, followed by the number of triangles, for every voxel of the slab
+[[block]]
+struct VoxelSums {
+    data: array<atomic<u32>>;
+};
+
+[[group(0), binding(3)]]
+var<storage, read_write> sums: VoxelSums;
+
+// u32s for every voxel, same as VOXEL_SUM_SIZE
+let VOXEL_SUM_SIZE: u32 = 5u;
+
+// plenty of precision, and room for a million triangles in a voxel before the sums overflow
+let SUM_SCALE: f32 = 1024.0;
+
+// when the volume uses a sparse storage, fragments are appended here instead of added to the sums
+// same layout as in sparse_octree.wgsl
+struct Fragment {
+    position: u32;
+    albedo: u32;
+};
+
+[[block]]
+struct Fragments {
+    data: [[stride(8)]] array<Fragment>;
+};
+
+[[block]]
+struct FragmentCounter {
+    fragment_count: atomic<u32>;
+};
+
+[[group(0), binding(4)]]
+var<storage, read_write> fragments: Fragments;
+
+[[group(0), binding(5)]]
+var<storage, read_write> fragment_counter: FragmentCounter;
+
+fn emit_fragment(position: vec3<u32>, albedo: vec4<f32>) {
+    let index = atomicAdd(&fragment_counter.fragment_count, 1u);
+
+    // full, drop it
+    if (index >= arrayLength(&fragments.data)) {
+        return;
+    }
+
+    fragments.data[index].position = (position.x & 0x3ffu) | ((position.y & 0x3ffu) << 10u) | ((position.z & 0x3ffu) << 20u);
+    fragments.data[index].albedo = pack4x8unorm(albedo);
+}
+
+// the mesh, with the same layouts as GiMeshShaders
+[[block]]
+struct MeshTransform {
+    transform: mat4x4<f32>;
+};
+
+[[group(1), binding(0)]]
+var<uniform> mesh: MeshTransform;
+
+[[block]]
+struct MeshMaterial {
+    material: GiMaterial;
+};
+
+[[group(2), binding(0)]]
+var<uniform> mesh_material: MeshMaterial;
+
+// the positions are vec4s, so skinned positions can be read the same way
+[[block]]
+struct Positions {
+    data: [[stride(16)]] array<vec4<f32>>;
+};
+
+[[block]]
+struct Indices {
+    data: [[stride(4)]] array<u32>;
+};
+
+[[group(3), binding(0)]]
+var<storage, read> positions: Positions;
+
+[[group(3), binding(1)]]
+var<storage, read> indices: Indices;
+
+// the cascade to resolve into, in the format of the cascades, which is changed before compiling
+[[group(1), binding(1)]]
+var volume: texture_storage_3d<rgba32float, read_write>;
+
+// same as VOXELIZE_WORKGROUP_SIZE
+let WORKGROUP_SIZE: u32 = 64u;
+
+fn add_voxel(position: vec3<u32>, resolution: vec3<u32>, voxel: vec4<f32>) {
+    // sparse storages have no slabs, the whole cube is in fragment positions
+    if (params.fragments != 0u) {
+        // the fragments aren't premultiplied, emitters that don't occlude have nothing to divide by
+        emit_fragment(position, vec4<f32>(select(voxel.rgb, voxel.rgb / voxel.a, voxel.a > 0.0), voxel.a));
+        return;
+    }
+
+    if (position.z < params.first_layer || position.z >= params.first_layer + params.layers) {
+        return;
+    }
+
+    let index = (((position.z - params.first_layer) * resolution.y + position.y) * resolution.x + position.x) * VOXEL_SUM_SIZE;
+    let fixed = vec4<u32>(voxel * SUM_SCALE + 0.5);
+
+    var previous: u32;
+    previous = atomicAdd(&sums.data[index], fixed.r);
+    previous = atomicAdd(&sums.data[index + 1u], fixed.g);
+    previous = atomicAdd(&sums.data[index + 2u], fixed.b);
+    previous = atomicAdd(&sums.data[index + 3u], fixed.a);
+    previous = atomicAdd(&sums.data[index + 4u], 1u);
+}
+
+// which side of the edge from a to b the point is on
+fn edge(a: vec2<f32>, b: vec2<f32>, point: vec2<f32>) -> f32 {
+    return (b.x - a.x) * (point.y - a.y) - (b.y - a.y) * (point.x - a.x);
+}
+
+// moves the axis the triangle is projected along to z, and the two that make up the plane to x and y
+fn to_plane(position: vec3<f32>, axis: u32) -> vec3<f32> {
+    if (axis == 0u) {
+        return position.yzx;
+    } elseif (axis == 1u) {
+        return position.zxy;
+    }
+    return position;
+}
+
+fn from_plane(position: vec3<u32>, axis: u32) -> vec3<u32> {
+    if (axis == 0u) {
+        return position.zxy;
+    } elseif (axis == 1u) {
+        return position.yzx;
+    }
+    return position;
+}
+
+// a triangle in voxels, turned so it's projected along z
+struct PlaneTriangle {
+    a: vec3<f32>;
+    b: vec3<f32>;
+    c: vec3<f32>;
+    normal: vec3<f32>;
+    axis: u32;
+    // so the edge functions are positive inside, whichever way the triangle winds
+    winding: f32;
+};
+
+fn plane_triangle(a: vec3<f32>, b: vec3<f32>, c: vec3<f32>) -> PlaneTriangle {
+    let normal = cross(b - a, c - a);
+    let abs_normal = abs(normal);
+
+    // the axis to project along, same as rasterize_with in voxelize.rs
+    var axis = 2u;
+    if (abs_normal.x >= abs_normal.y && abs_normal.x >= abs_normal.z) {
+        axis = 0u;
+    } elseif (abs_normal.y >= abs_normal.z) {
+        axis = 1u;
+    }
+
+    var triangle: PlaneTriangle;
+    triangle.a = to_plane(a, axis);
+    triangle.b = to_plane(b, axis);
+    triangle.c = to_plane(c, axis);
+    triangle.normal = to_plane(normal, axis);
+    triangle.axis = axis;
+    triangle.winding = sign(edge(triangle.a.xy, triangle.b.xy, triangle.c.xy));
+    return triangle;
+}
+
+// how far the edge function can go below 0 while still touching the column
+fn edge_slack(a: vec2<f32>, b: vec2<f32>, conservative: bool) -> f32 {
+    if (conservative) {
+        return 0.5 * (abs(b.x - a.x) + abs(b.y - a.y));
+    }
+    return 0.0;
+}
+
+fn column_inside(triangle: PlaneTriangle, point: vec2<f32>, conservative: bool) -> bool {
+    return edge(triangle.a.xy, triangle.b.xy, point) * triangle.winding >= -edge_slack(triangle.a.xy, triangle.b.xy, conservative)
+        && edge(triangle.b.xy, triangle.c.xy, point) * triangle.winding >= -edge_slack(triangle.b.xy, triangle.c.xy, conservative)
+        && edge(triangle.c.xy, triangle.a.xy, point) * triangle.winding >= -edge_slack(triangle.c.xy, triangle.a.xy, conservative);
+}
+
+// where the plane of the triangle is along the axis
+fn plane_depth(triangle: PlaneTriangle, point: vec2<f32>) -> f32 {
+    return triangle.a.z - (triangle.normal.x * (point.x - triangle.a.x) + triangle.normal.y * (point.y - triangle.a.y)) / triangle.normal.z;
+}
+
+// the same as rasterize_with in voxelize.rs, but the triangle is also extruded along the axis by `offset` in `steps` copies
+// a voxel only counts once for every triangle, the layer the copies hit only ever goes one way, so only the last one can be hit again
+fn rasterize(triangle: PlaneTriangle, offset: vec3<f32>, steps: u32, resolution: vec3<u32>, conservative: bool, voxel: vec4<f32>) {
+    // degenerate, so it doesn't cover anything
+    if (triangle.normal.z == 0.0) {
+        return;
+    }
+
+    let plane_resolution = vec3<u32>(to_plane(vec3<f32>(resolution), triangle.axis));
+    let plane_offset = to_plane(offset, triangle.axis);
+
+    let low = min(min(triangle.a, triangle.b), triangle.c);
+    let high = max(max(triangle.a, triangle.b), triangle.c);
+
+    // the columns of every copy
+    let min_column = max(floor(min(low.xy, low.xy + plane_offset.xy)), vec2<f32>(0.0));
+    let max_column = min(ceil(max(high.xy, high.xy + plane_offset.xy)), vec2<f32>(plane_resolution.xy));
+
+    for (var column_v = u32(min_column.y); column_v < u32(max_column.y); column_v = column_v + 1u) {
+        for (var column_u = u32(min_column.x); column_u < u32(max_column.x); column_u = column_u + 1u) {
+            let center = vec2<f32>(f32(column_u) + 0.5, f32(column_v) + 0.5);
+
+            var last_layer = -1;
+            for (var step = 0u; step <= steps; step = step + 1u) {
+                let step_offset = plane_offset * f32(step) / f32(max(steps, 1u));
+
+                // moving the triangle is the same as moving the column the other way
+                let point = center - step_offset.xy;
+                if (!column_inside(triangle, point, conservative)) {
+                    continue;
+                }
+
+                // the plane can cross more than one voxel in the column, the most at the corners of it
+                var near = plane_depth(triangle, point);
+                var far = near;
+                if (conservative) {
+                    let corners = vec4<f32>(
+                        plane_depth(triangle, point + vec2<f32>(-0.5, -0.5)),
+                        plane_depth(triangle, point + vec2<f32>(0.5, -0.5)),
+                        plane_depth(triangle, point + vec2<f32>(-0.5, 0.5)),
+                        plane_depth(triangle, point + vec2<f32>(0.5, 0.5)),
+                    );
+                    near = max(min(min(corners.x, corners.y), min(corners.z, corners.w)), low.z);
+                    far = min(max(max(corners.x, corners.y), max(corners.z, corners.w)), high.z);
+                }
+                near = near + step_offset.z;
+                far = far + step_offset.z;
+
+                if (far < 0.0 || near >= f32(plane_resolution.z)) {
+                    continue;
+                }
+
+                let first = i32(max(near, 0.0));
+                let last = i32(min(u32(far), plane_resolution.z - 1u));
+
+                for (var layer = first; layer <= last; layer = layer + 1) {
+                    if (layer != last_layer) {
+                        add_voxel(from_plane(vec3<u32>(column_u, column_v, u32(layer)), triangle.axis), resolution, voxel);
+                        last_layer = layer;
+                    }
+                }
+            }
+        }
+    }
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn voxelize([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let triangle_count = arrayLength(&indices.data) / 3u;
+    if (id.x >= triangle_count) {
+        return;
+    }
+
+    // masked out
+    let material = mesh_material.material;
+    let voxel = surface_voxel(material, vec4<f32>(1.0));
+    if (all(voxel == vec4<f32>(0.0))) {
+        return;
+    }
+
+    let vertex_count = arrayLength(&positions.data);
+    let index_a = indices.data[id.x * 3u];
+    let index_b = indices.data[id.x * 3u + 1u];
+    let index_c = indices.data[id.x * 3u + 2u];
+    if (index_a >= vertex_count || index_b >= vertex_count || index_c >= vertex_count) {
+        return;
+    }
+
+    if (params.count_triangles != 0u) {
+        let previous = atomicAdd(&triangle_counter.triangles, 1u);
+    }
+
+    let world_a = (mesh.transform * vec4<f32>(positions.data[index_a].xyz, 1.0)).xyz;
+    let world_b = (mesh.transform * vec4<f32>(positions.data[index_b].xyz, 1.0)).xyz;
+    let world_c = (mesh.transform * vec4<f32>(positions.data[index_c].xyz, 1.0)).xyz;
+
+    // straight to voxels of the cascade
+    let cascade = gi_cascades.cascades[params.cascade];
+    let a = (cascade.projection * vec4<f32>(world_a, 1.0)).xyz * cascade.resolution;
+    let b = (cascade.projection * vec4<f32>(world_b, 1.0)).xyz * cascade.resolution;
+    let c = (cascade.projection * vec4<f32>(world_c, 1.0)).xyz * cascade.resolution;
+    let resolution = vec3<u32>(cascade.resolution);
+
+    let triangle = plane_triangle(a, b, c);
+
+    if (material.thin_geometry == THIN_GEOMETRY_SOLIDIFY) {
+        let cross_normal = cross(world_b - world_a, world_c - world_a);
+        let normal = cross_normal / max(length(cross_normal), 0.000001);
+        let offset = (cascade.projection * vec4<f32>(-normal * material.thickness, 0.0)).xyz * cascade.resolution;
+
+        // copies of the triangle at most half a voxel apart, so there are no gaps between them
+        let steps = u32(ceil(length(offset) * 2.0));
+        rasterize(triangle, offset, steps, resolution, false, voxel);
+    } else {
+        rasterize(triangle, vec3<f32>(0.0), 0u, resolution, material.thin_geometry == THIN_GEOMETRY_DILATE, voxel);
+    }
+}
+
+// one invocation per voxel of the slab, averages what the triangles added and clears the sums for the next cascade
+[[stage(compute), workgroup_size(4, 4, 4)]]
+fn resolve_voxels([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let resolution = vec3<u32>(gi_cascades.cascades[params.cascade].resolution);
+    if (any(id >= vec3<u32>(resolution.xy, params.layers))) {
+        return;
+    }
+
+    let index = ((id.z * resolution.y + id.y) * resolution.x + id.x) * VOXEL_SUM_SIZE;
+    let texel = vec3<i32>(id + vec3<u32>(0u, 0u, params.first_layer));
+
+    let count = atomicExchange(&sums.data[index + 4u], 0u);
+    let sum = vec4<f32>(
+        f32(atomicExchange(&sums.data[index], 0u)),
+        f32(atomicExchange(&sums.data[index + 1u], 0u)),
+        f32(atomicExchange(&sums.data[index + 2u], 0u)),
+        f32(atomicExchange(&sums.data[index + 3u], 0u)),
+    );
+
+    if (count > 0u) {
+        textureStore(volume, texel, sum / (SUM_SCALE * f32(count)));
+    } elseif (params.keep_existing == 0u) {
+        textureStore(volume, texel, vec4<f32>(0.0));
+    }
+}
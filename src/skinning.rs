@@ -0,0 +1,235 @@
+//! Skinned and morphed meshes for gi
+//!
+//! bevy doesn't skin meshes yet, so gi does it itself before voxelizing, from the attributes below and a `GiSkin`
+//! this is the reference for what skinning.wgsl writes, and can be used with `voxelize::CpuVoxelizer` directly
+
+use bevy::math::{Mat4, Vec3, Vec4};
+use bevy::render2::mesh::{Mesh, VertexAttributeValues};
+
+/// indices of the 4 joints that move a vertex, as `Uint16x4`
+pub const ATTRIBUTE_JOINT_INDEX: &str = "Vertex_JointIndex";
+
+/// how much each joint moves a vertex, as `Float32x4`
+pub const ATTRIBUTE_JOINT_WEIGHT: &str = "Vertex_JointWeight";
+
+pub const MAX_MORPH_TARGETS: usize = 4;
+
+/// offsets of the positions for each morph target, as `Float32x3`
+pub const ATTRIBUTE_MORPH_POSITIONS: [&str; MAX_MORPH_TARGETS] = [
+    "Vertex_MorphPosition0",
+    "Vertex_MorphPosition1",
+    "Vertex_MorphPosition2",
+    "Vertex_MorphPosition3",
+];
+
+/// a vertex as skinning.wgsl reads it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SkinVertex {
+    pub position: [f32; 4],
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+    pub morph: [[f32; 4]; MAX_MORPH_TARGETS],
+}
+
+impl SkinVertex {
+    pub const SIZE: u64 = 112;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.position.iter().flat_map(|value| value.to_le_bytes()).collect();
+        bytes.extend(self.joints.iter().flat_map(|value| value.to_le_bytes()));
+        bytes.extend(self.weights.iter().flat_map(|value| value.to_le_bytes()));
+        bytes.extend(self.morph.iter().flatten().flat_map(|value| value.to_le_bytes()));
+        bytes
+    }
+}
+
+/// everything of a mesh that's needed to skin it
+#[derive(Clone, Debug, Default)]
+pub struct SkinSource {
+    pub positions: Vec<Vec3>,
+    /// empty when the mesh isn't skinned
+    pub joints: Vec<[u32; 4]>,
+    pub weights: Vec<Vec4>,
+    /// offsets of every position, for each morph target the mesh has
+    pub morph_targets: Vec<Vec<Vec3>>,
+}
+
+impl SkinSource {
+    /// reads the positions and skinning attributes, or nothing if the mesh has no positions
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        let positions: Vec<Vec3> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions.iter().map(|position| Vec3::from(*position)).collect(),
+            _ => return None,
+        };
+
+        let joints: Vec<[u32; 4]> = match mesh.attribute(ATTRIBUTE_JOINT_INDEX) {
+            Some(VertexAttributeValues::Uint16x4(joints)) => joints
+                .iter()
+                .map(|joint| [joint[0] as u32, joint[1] as u32, joint[2] as u32, joint[3] as u32])
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let weights: Vec<Vec4> = match mesh.attribute(ATTRIBUTE_JOINT_WEIGHT) {
+            Some(VertexAttributeValues::Float32x4(weights)) => weights.iter().map(|weight| Vec4::from(*weight)).collect(),
+            _ => Vec::new(),
+        };
+
+        // both or neither, and one for every vertex
+        let (joints, weights) = if joints.len() == positions.len() && weights.len() == positions.len() {
+            (joints, weights)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        // targets are used in order, up to the first one that's missing
+        let mut morph_targets = Vec::new();
+        for name in ATTRIBUTE_MORPH_POSITIONS.iter() {
+            match mesh.attribute(*name) {
+                Some(VertexAttributeValues::Float32x3(offsets)) if offsets.len() == positions.len() => {
+                    morph_targets.push(offsets.iter().map(|offset| Vec3::from(*offset)).collect());
+                }
+                _ => break,
+            }
+        }
+
+        Some(Self {
+            positions,
+            joints,
+            weights,
+            morph_targets,
+        })
+    }
+
+    pub fn is_skinned(&self) -> bool {
+        !self.joints.is_empty()
+    }
+
+    /// the vertices for skinning.wgsl
+    pub fn vertices(&self) -> Vec<SkinVertex> {
+        (0..self.positions.len())
+            .map(|i| {
+                let mut vertex = SkinVertex {
+                    position: self.positions[i].extend(1.0).to_array(),
+                    ..Default::default()
+                };
+
+                if self.is_skinned() {
+                    vertex.joints = self.joints[i];
+                    vertex.weights = self.weights[i].to_array();
+                }
+
+                for (morph, target) in vertex.morph.iter_mut().zip(self.morph_targets.iter()) {
+                    *morph = target[i].extend(0.0).to_array();
+                }
+
+                vertex
+            })
+            .collect()
+    }
+
+    /// the positions after morphing and skinning, in the space of the mesh
+    ///
+    /// `joint_matrices` go from the mesh in bind pose to the mesh now, like in `GiSkin::joint_matrices`
+    pub fn skin(&self, joint_matrices: &[Mat4], morph_weights: &[f32]) -> Vec<Vec3> {
+        (0..self.positions.len())
+            .map(|i| {
+                let morphed = self
+                    .morph_targets
+                    .iter()
+                    .zip(morph_weights.iter())
+                    .fold(self.positions[i], |position, (target, weight)| position + target[i] * *weight);
+
+                if !self.is_skinned() {
+                    return morphed;
+                }
+
+                let weights = self.weights[i].to_array();
+                let total: f32 = weights.iter().sum();
+                if total <= 0.0 {
+                    return morphed;
+                }
+
+                // joints that don't exist leave the vertex where it is
+                let skinned = self.joints[i].iter().zip(weights.iter()).fold(Vec3::ZERO, |sum, (joint, weight)| {
+                    let matrix = joint_matrices.get(*joint as usize).copied().unwrap_or(Mat4::IDENTITY);
+                    sum + matrix.transform_point3(morphed) * *weight
+                });
+
+                skinned / total
+            })
+            .collect()
+    }
+
+    /// bounds of the vertices each joint has the most weight on, in bind pose
+    ///
+    /// joints without any vertices have no bounds
+    pub fn joint_bounds(&self, joint_count: usize) -> Vec<Option<(Vec3, Vec3)>> {
+        let mut bounds = vec![None; joint_count];
+
+        for (i, position) in self.positions.iter().enumerate().filter(|_| self.is_skinned()) {
+            let weights = self.weights[i].to_array();
+            let strongest = (0..4).fold(0, |strongest, j| if weights[j] > weights[strongest] { j } else { strongest });
+
+            let joint = self.joints[i][strongest] as usize;
+            if let Some(joint_bounds) = bounds.get_mut(joint) {
+                *joint_bounds = Some(match *joint_bounds {
+                    Some((min, max)) => (position.min(min), position.max(max)),
+                    None => (*position, *position),
+                });
+            }
+        }
+
+        bounds
+    }
+}
+
+/// a box around the vertices of every joint, moved along with the joint
+///
+/// much cheaper to voxelize than the whole skinned mesh, and close enough when voxels are larger than a limb
+pub fn joint_proxy_triangles(joint_bounds: &[Option<(Vec3, Vec3)>], joint_matrices: &[Mat4]) -> Vec<[Vec3; 3]> {
+    joint_bounds
+        .iter()
+        .zip(joint_matrices.iter())
+        .filter_map(|(bounds, matrix)| bounds.map(|(min, max)| (min, max, matrix)))
+        .flat_map(|(min, max, matrix)| {
+            box_triangles(min, max).iter().map(|triangle| {
+                [
+                    matrix.transform_point3(triangle[0]),
+                    matrix.transform_point3(triangle[1]),
+                    matrix.transform_point3(triangle[2]),
+                ]
+            }).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// the 12 triangles of a box
+pub fn box_triangles(min: Vec3, max: Vec3) -> [[Vec3; 3]; 12] {
+    let corner = |i: usize| {
+        Vec3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        )
+    };
+
+    // two triangles for each side, as corners of the box, facing out
+    const SIDES: [[usize; 4]; 6] = [
+        [4, 6, 2, 0],
+        [3, 7, 5, 1],
+        [1, 5, 4, 0],
+        [6, 7, 3, 2],
+        [2, 3, 1, 0],
+        [5, 7, 6, 4],
+    ];
+
+    let mut triangles = [[Vec3::ZERO; 3]; 12];
+    for (side, quad) in SIDES.iter().enumerate() {
+        triangles[side * 2] = [corner(quad[0]), corner(quad[1]), corner(quad[2])];
+        triangles[side * 2 + 1] = [corner(quad[0]), corner(quad[2]), corner(quad[3])];
+    }
+
+    triangles
+}
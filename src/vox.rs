@@ -0,0 +1,472 @@
+//! MagicaVoxel .vox files
+//!
+//! for looking at voxelization results in other tools, and for loading hand made voxels as static occluders
+//! MagicaVoxel has z up, so y and z are swapped when reading and writing, and colors are stored in srgb
+//!
+//! only the first model of a file is read, and voxels are either fully opaque or empty
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bevy::math::{UVec3, Vec4};
+use bevy::render2::color::Color;
+
+use crate::voxelize::VoxelGrid;
+
+const VERSION: u32 = 150;
+
+/// max size of a model along each axis
+pub const MAX_VOX_SIZE: u32 = 256;
+
+/// index 0 means empty, so there are only 255 colors
+const PALETTE_SIZE: usize = 255;
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    /// doesn't start with "VOX "
+    NotVox,
+    /// the file ended in the middle of a chunk, or a chunk is missing
+    Truncated,
+    /// the grid doesn't fit in a vox model
+    TooLarge(UVec3),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxError::Io(error) => write!(f, "{}", error),
+            VoxError::NotVox => write!(f, "not a vox file"),
+            VoxError::Truncated => write!(f, "vox file is missing data"),
+            VoxError::TooLarge(resolution) => write!(
+                f,
+                "grid of {} is larger than the max vox size of {}",
+                resolution, MAX_VOX_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<io::Error> for VoxError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => VoxError::Truncated,
+            _ => VoxError::Io(error),
+        }
+    }
+}
+
+pub fn save_vox(path: impl AsRef<Path>, grid: &VoxelGrid) -> Result<(), VoxError> {
+    write_vox(BufWriter::new(File::create(path)?), grid)
+}
+
+pub fn load_vox(path: impl AsRef<Path>) -> Result<VoxelGrid, VoxError> {
+    read_vox(BufReader::new(File::open(path)?))
+}
+
+// voxel positions in the vox file, z up
+fn to_vox(position: UVec3, resolution: UVec3) -> UVec3 {
+    UVec3::new(position.x, resolution.z - 1 - position.z, position.y)
+}
+
+fn from_vox(position: UVec3, resolution: UVec3) -> UVec3 {
+    UVec3::new(position.x, position.z, resolution.z - 1 - position.y)
+}
+
+fn write_chunk(
+    writer: &mut impl Write,
+    id: &[u8; 4],
+    content: &[u8],
+    children: &[u8],
+) -> io::Result<()> {
+    writer.write_all(id)?;
+    writer.write_all(&(content.len() as u32).to_le_bytes())?;
+    writer.write_all(&(children.len() as u32).to_le_bytes())?;
+    writer.write_all(content)?;
+    writer.write_all(children)
+}
+
+/// writes every voxel with any opacity, with a palette made from the albedo
+///
+/// voxels are premultiplied, so the albedo is divided by the opacity first
+pub fn write_vox(mut writer: impl Write, grid: &VoxelGrid) -> Result<(), VoxError> {
+    let resolution = grid.resolution;
+    if resolution.cmpgt(UVec3::splat(MAX_VOX_SIZE)).any() {
+        return Err(VoxError::TooLarge(resolution));
+    }
+
+    let mut positions = Vec::new();
+    let mut colors = Vec::new();
+    for z in 0..resolution.z {
+        for y in 0..resolution.y {
+            for x in 0..resolution.x {
+                let voxel = grid.get(UVec3::new(x, y, z));
+                if voxel.w > 0.0 {
+                    positions.push(to_vox(UVec3::new(x, y, z), resolution));
+                    colors.push(to_srgb8(voxel.truncate().extend(1.0) / voxel.w));
+                }
+            }
+        }
+    }
+
+    let (palette, indices) = quantize(&colors);
+
+    let mut size = Vec::with_capacity(12);
+    for axis in [resolution.x, resolution.z, resolution.y] {
+        size.extend_from_slice(&axis.to_le_bytes());
+    }
+
+    let mut xyzi = Vec::with_capacity(4 + positions.len() * 4);
+    xyzi.extend_from_slice(&(positions.len() as u32).to_le_bytes());
+    for (position, index) in positions.iter().zip(indices) {
+        xyzi.extend_from_slice(&[position.x as u8, position.y as u8, position.z as u8, index]);
+    }
+
+    // entry i is color index i + 1
+    let mut rgba = vec![0; 256 * 4];
+    for (i, color) in palette.iter().enumerate() {
+        rgba[i * 4..i * 4 + 3].copy_from_slice(color);
+        rgba[i * 4 + 3] = 255;
+    }
+
+    let mut children = Vec::new();
+    write_chunk(&mut children, b"SIZE", &size, &[])?;
+    write_chunk(&mut children, b"XYZI", &xyzi, &[])?;
+    write_chunk(&mut children, b"RGBA", &rgba, &[])?;
+
+    writer.write_all(b"VOX ")?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    write_chunk(&mut writer, b"MAIN", &[], &children)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// reads the first model in a vox file, every voxel is fully opaque
+pub fn read_vox(mut reader: impl Read) -> Result<VoxelGrid, VoxError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != b"VOX " {
+        return Err(VoxError::NotVox);
+    }
+    let _version = read_u32(&mut reader)?;
+
+    let mut size = None;
+    let mut voxels = None;
+    let mut palette = None;
+
+    // MAIN has no content, and everything else is a child of it, so the chunks can be read one after the other
+    loop {
+        let mut id = [0; 4];
+        match reader.read_exact(&mut id) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error.into()),
+        }
+
+        let content_size = read_u32(&mut reader)? as usize;
+        let _children_size = read_u32(&mut reader)?;
+
+        if &id == b"MAIN" {
+            continue;
+        }
+
+        // read with take, so a broken size can't make us allocate more than the file has
+        let mut content = Vec::new();
+        (&mut reader).take(content_size as u64).read_to_end(&mut content)?;
+        if content.len() != content_size {
+            return Err(VoxError::Truncated);
+        }
+
+        match &id {
+            b"SIZE" if size.is_none() && content.len() >= 12 => {
+                let axis = |i: usize| {
+                    u32::from_le_bytes([content[i], content[i + 1], content[i + 2], content[i + 3]])
+                };
+                let vox_size = UVec3::new(axis(0), axis(4), axis(8));
+                if vox_size.cmpgt(UVec3::splat(MAX_VOX_SIZE)).any() {
+                    return Err(VoxError::TooLarge(vox_size));
+                }
+                size = Some(vox_size);
+            }
+            b"XYZI" if voxels.is_none() && content.len() >= 4 => {
+                voxels = Some(
+                    content[4..]
+                        .chunks_exact(4)
+                        .map(|voxel| [voxel[0], voxel[1], voxel[2], voxel[3]])
+                        .collect::<Vec<_>>(),
+                );
+            }
+            b"RGBA" if content.len() >= 256 * 4 => {
+                // shift it so the color index can be used directly
+                let mut colors = [[0; 4]; 256];
+                for i in 0..255 {
+                    colors[i + 1].copy_from_slice(&content[i * 4..i * 4 + 4]);
+                }
+                palette = Some(colors);
+            }
+            // everything else is for the scene graph and materials, which don't matter here
+            _ => {}
+        }
+    }
+
+    let (vox_size, voxels) = match (size, voxels) {
+        (Some(size), Some(voxels)) => (size, voxels),
+        _ => return Err(VoxError::Truncated),
+    };
+    let palette = palette.unwrap_or_else(default_palette);
+
+    // back to y up
+    let resolution = UVec3::new(vox_size.x, vox_size.z, vox_size.y);
+    let mut grid = VoxelGrid::new(resolution);
+
+    for [x, y, z, index] in voxels {
+        let position = UVec3::new(x as u32, y as u32, z as u32);
+        if position.cmpge(vox_size).any() {
+            continue;
+        }
+
+        let [r, g, b, _] = palette[index as usize];
+        let albedo = Color::rgb_u8(r, g, b).as_linear_rgba_f32();
+        grid.set(
+            from_vox(position, resolution),
+            Vec4::new(albedo[0], albedo[1], albedo[2], 1.0),
+        );
+    }
+
+    Ok(grid)
+}
+
+// linear to srgb bytes
+fn to_srgb8(color: Vec4) -> [u8; 3] {
+    let srgb = Color::rgba_linear(color.x, color.y, color.z, 1.0).as_rgba_f32();
+    let byte = |value: f32| (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+
+    [byte(srgb[0]), byte(srgb[1]), byte(srgb[2])]
+}
+
+/// makes a palette of at most 255 colors with median cut, and gives the palette index of every color
+///
+/// palette indices start at 1, as 0 is empty in vox files
+fn quantize(colors: &[[u8; 3]]) -> (Vec<[u8; 3]>, Vec<u8>) {
+    if colors.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+
+    let mut counts = HashMap::new();
+    for color in colors {
+        *counts.entry(*color).or_insert(0u32) += 1;
+    }
+
+    let mut boxes = vec![counts.into_iter().collect::<Vec<_>>()];
+
+    // split the box with the widest range of colors, until there are enough or every box is a single color
+    while boxes.len() < PALETTE_SIZE {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| {
+                let (channel, range) = widest_channel(colors);
+                (i, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range);
+
+        let (i, channel, _) = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+
+        // median by number of voxels, but always leave at least one color on each side
+        let total: u32 = colors.iter().map(|(_, count)| count).sum();
+        let mut seen = 0;
+        let mut split = colors.len() - 1;
+        for (j, (_, count)) in colors.iter().enumerate() {
+            seen += count;
+            if seen * 2 >= total {
+                split = (j + 1).min(colors.len() - 1);
+                break;
+            }
+        }
+
+        let high = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(high);
+    }
+
+    let mut palette = Vec::with_capacity(boxes.len());
+    let mut lookup = HashMap::new();
+    for (i, colors) in boxes.iter().enumerate() {
+        let mut sum = [0u64; 3];
+        let mut total = 0u64;
+        for (color, count) in colors {
+            for channel in 0..3 {
+                sum[channel] += color[channel] as u64 * *count as u64;
+            }
+            total += *count as u64;
+            lookup.insert(*color, i as u8 + 1);
+        }
+
+        palette.push([
+            ((sum[0] + total / 2) / total) as u8,
+            ((sum[1] + total / 2) / total) as u8,
+            ((sum[2] + total / 2) / total) as u8,
+        ]);
+    }
+
+    let indices = colors.iter().map(|color| lookup[color]).collect();
+
+    (palette, indices)
+}
+
+fn widest_channel(colors: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let min = colors
+                .iter()
+                .map(|(color, _)| color[channel])
+                .min()
+                .unwrap();
+            let max = colors
+                .iter()
+                .map(|(color, _)| color[channel])
+                .max()
+                .unwrap();
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+/// the palette MagicaVoxel uses for files without one, indexed by color index
+///
+/// a 6x6x6 color cube, followed by ramps of red, green, blue and gray
+fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0; 4]; 256];
+    let mut index = 1;
+
+    // the last one of the cube is black, which is left out
+    for r in CUBE {
+        for g in CUBE {
+            for b in CUBE {
+                if index < 216 {
+                    palette[index] = [r, g, b, 0xff];
+                    index += 1;
+                }
+            }
+        }
+    }
+
+    for channel in 0..4 {
+        for value in RAMP {
+            let mut color = [0, 0, 0, 0xff];
+            if channel == 3 {
+                color = [value, value, value, 0xff];
+            } else {
+                color[channel] = value;
+            }
+            palette[index] = color;
+            index += 1;
+        }
+    }
+
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // not cubic, so swapping y and z the wrong way changes the size
+        let resolution = UVec3::new(4, 6, 3);
+        let mut grid = VoxelGrid::new(resolution);
+        let red = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        let blue = Vec4::new(0.0, 0.0, 1.0, 1.0);
+        grid.set(UVec3::new(0, 0, 0), red);
+        grid.set(UVec3::new(3, 5, 2), blue);
+        grid.set(UVec3::new(1, 4, 0), blue);
+
+        let mut bytes = Vec::new();
+        write_vox(&mut bytes, &grid).unwrap();
+        let loaded = read_vox(bytes.as_slice()).unwrap();
+
+        assert_eq!(loaded.resolution, resolution);
+        assert_eq!(loaded.filled(), 3);
+        for position in [UVec3::new(0, 0, 0), UVec3::new(3, 5, 2), UVec3::new(1, 4, 0)] {
+            let expected = grid.get(position);
+            let voxel = loaded.get(position);
+            assert!((voxel - expected).abs().max_element() < 0.01, "{} is {}, not {}", position, voxel, expected);
+        }
+    }
+
+    #[test]
+    fn swaps_y_and_z() {
+        let resolution = UVec3::new(2, 3, 4);
+        let mut grid = VoxelGrid::new(resolution);
+        grid.set(UVec3::new(1, 2, 0), Vec4::ONE);
+
+        let mut bytes = Vec::new();
+        write_vox(&mut bytes, &grid).unwrap();
+
+        // z up in the file, and y is flipped so the model isn't mirrored
+        let position = to_vox(UVec3::new(1, 2, 0), resolution);
+        assert_eq!(position, UVec3::new(1, 3, 2));
+        assert_eq!(from_vox(position, resolution), UVec3::new(1, 2, 0));
+
+        // SIZE is the first chunk in MAIN, after the header and MAIN's own header
+        let size = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        assert_eq!(&bytes[20..24], b"SIZE");
+        assert_eq!(UVec3::new(size(32), size(36), size(40)), UVec3::new(2, 4, 3));
+    }
+
+    #[test]
+    fn quantizes_to_palette() {
+        // more colors than fit in the palette
+        let colors = (0..1000u32)
+            .map(|i| [(i % 256) as u8, (i / 4 % 256) as u8, (i * 7 % 256) as u8])
+            .collect::<Vec<_>>();
+        let (palette, indices) = quantize(&colors);
+
+        assert_eq!(palette.len(), PALETTE_SIZE);
+        assert_eq!(indices.len(), colors.len());
+        assert!(indices.iter().all(|index| *index >= 1));
+
+        // few colors are kept exactly
+        let colors = [[255, 0, 0], [0, 255, 0], [255, 0, 0]];
+        let (palette, indices) = quantize(&colors);
+        assert_eq!(palette.len(), 2);
+        assert_eq!(indices[0], indices[2]);
+        for (color, index) in colors.iter().zip(indices) {
+            assert_eq!(palette[index as usize - 1], *color);
+        }
+    }
+
+    #[test]
+    fn broken_chunk_size() {
+        let mut bytes = Vec::new();
+        write_vox(&mut bytes, &VoxelGrid::new(UVec3::splat(2))).unwrap();
+
+        // SIZE claims to be 4gb
+        bytes[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(read_vox(bytes.as_slice()), Err(VoxError::Truncated)));
+    }
+}
@@ -0,0 +1,832 @@
+//! Dense voxelization on the cpu
+//!
+//! this is the reference for what voxelization on the gpu should write to a dense cascade, so it can be checked without a gpu
+//! every triangle is rasterized along the axis it faces the most, the same way the 3 orthographic projections on the gpu do it
+
+use bevy::math::{Mat4, UVec3, Vec2, Vec3, Vec4};
+use bevy::render2::{
+    color::Color,
+    mesh::{Indices, Mesh, VertexAttributeValues},
+};
+use bevy::transform::components::GlobalTransform;
+
+use crate::bundle::{GiAlphaMode, GiThinGeometry, GiVolume, GiVolumeFormat};
+
+/// how much larger a cascade is than the first one
+///
+/// a float power instead of a shift, so volumes with many cascades don't overflow
+pub fn cascade_scale(cascade: u32) -> f32 {
+    2f32.powi(cascade as i32)
+}
+
+/// maps world space to 0 - 1 over a cascade of a volume
+///
+/// each cascade is twice as large as the one before it, centered on the volume
+pub fn cascade_projection(extent: Vec3, transform: &GlobalTransform, cascade: u32) -> Mat4 {
+    let cascade_extent = extent * cascade_scale(cascade);
+
+    Mat4::from_scale(cascade_extent.recip())
+        * Mat4::from_translation(cascade_extent * 0.5)
+        * transform.compute_matrix().inverse()
+}
+
+/// whether a box, in the space `transform` maps to the world, reaches into the cascade `projection` is the `cascade_projection` of
+///
+/// `margin` is in the 0 - 1 space of the cascade, for what voxelization adds around the triangles
+pub fn box_in_cascade(projection: &Mat4, transform: &Mat4, min: Vec3, max: Vec3, margin: Vec3) -> bool {
+    let to_cascade = *projection * *transform;
+
+    let mut cascade_min = Vec3::splat(f32::MAX);
+    let mut cascade_max = Vec3::splat(f32::MIN);
+    for corner in 0..8 {
+        let position = Vec3::new(
+            if corner & 1 == 0 { min.x } else { max.x },
+            if corner & 2 == 0 { min.y } else { max.y },
+            if corner & 4 == 0 { min.z } else { max.z },
+        );
+        let position = to_cascade.transform_point3(position);
+
+        cascade_min = cascade_min.min(position);
+        cascade_max = cascade_max.max(position);
+    }
+
+    cascade_max.cmpge(-margin).all() && cascade_min.cmple(Vec3::ONE + margin).all()
+}
+
+/// a dense grid of voxels, the same as a single mip of a dense cascade
+///
+/// voxel values are premultiplied by opacity
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxelGrid {
+    pub resolution: UVec3,
+    pub voxels: Vec<Vec4>,
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: UVec3) -> Self {
+        Self {
+            resolution,
+            voxels: vec![Vec4::ZERO; (resolution.x * resolution.y * resolution.z) as usize],
+        }
+    }
+
+    fn index(&self, position: UVec3) -> usize {
+        (position.x
+            + position.y * self.resolution.x
+            + position.z * self.resolution.x * self.resolution.y) as usize
+    }
+
+    /// gets a voxel, or nothing if it's outside of the grid
+    pub fn get(&self, position: UVec3) -> Vec4 {
+        if position.cmpge(self.resolution).any() {
+            return Vec4::ZERO;
+        }
+
+        self.voxels[self.index(position)]
+    }
+
+    pub fn set(&mut self, position: UVec3, value: Vec4) {
+        let index = self.index(position);
+        self.voxels[index] = value;
+    }
+
+    /// number of voxels with any opacity
+    pub fn filled(&self) -> usize {
+        self.voxels.iter().filter(|voxel| voxel.w > 0.0).count()
+    }
+
+    /// the next mip, where every voxel is the average of the 2x2x2 voxels below it
+    ///
+    /// same as mipmap.wgsl, so voxels outside of the grid count as empty
+    pub fn downsample(&self) -> VoxelGrid {
+        let resolution = (self.resolution / 2).max(UVec3::ONE);
+        let mut mip = VoxelGrid::new(resolution);
+
+        for z in 0..resolution.z {
+            for y in 0..resolution.y {
+                for x in 0..resolution.x {
+                    let position = UVec3::new(x, y, z);
+                    let sum = (0..8).fold(Vec4::ZERO, |sum, i| {
+                        let offset = UVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+                        sum + self.get(position * 2 + offset)
+                    });
+
+                    mip.set(position, sum / 8.0);
+                }
+            }
+        }
+
+        mip
+    }
+
+    /// the voxels as the bytes of a texture with this format, without any padding between rows
+    pub fn to_bytes(&self, format: GiVolumeFormat) -> Vec<u8> {
+        let values = self.voxels.iter().flat_map(|voxel| voxel.to_array());
+
+        match format {
+            GiVolumeFormat::Rgba16Float => values.flat_map(|value| f32_to_f16(value).to_le_bytes()).collect(),
+            GiVolumeFormat::Rgba32Float => values.flat_map(|value| value.to_le_bytes()).collect(),
+        }
+    }
+
+    /// reads the bytes of a texture with this format, without any padding between rows
+    pub fn from_bytes(resolution: UVec3, bytes: &[u8], format: GiVolumeFormat) -> Self {
+        let values: Vec<f32> = match format {
+            GiVolumeFormat::Rgba16Float => bytes
+                .chunks_exact(2)
+                .map(|value| f16_to_f32(u16::from_le_bytes([value[0], value[1]])))
+                .collect(),
+            GiVolumeFormat::Rgba32Float => bytes
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect(),
+        };
+
+        let mut grid = VoxelGrid::new(resolution);
+        for (voxel, value) in grid.voxels.iter_mut().zip(values.chunks_exact(4)) {
+            *voxel = Vec4::new(value[0], value[1], value[2], value[3]);
+        }
+
+        grid
+    }
+}
+
+/// to a half float, rounded to the nearest, anything too small for one becomes 0
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    // infinity stays infinity, and nan stays nan
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if exponent <= 0 {
+        // too small even for a subnormal
+        if exponent < -10 {
+            return sign;
+        }
+
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let rounded = (mantissa + (1 << (shift - 1))) >> shift;
+        return sign | rounded as u16;
+    }
+
+    // rounding can carry into the exponent, which is still the right half float
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    sign | (half + ((mantissa >> 12) & 1)) as u16
+}
+
+pub fn f16_to_f32(value: u16) -> f32 {
+    let sign = ((value & 0x8000) as u32) << 16;
+    let exponent = ((value >> 10) & 0x1f) as u32;
+    let mantissa = (value & 0x3ff) as u32;
+
+    match exponent {
+        0 => {
+            let magnitude = mantissa as f32 / (1 << 24) as f32;
+            if sign != 0 { -magnitude } else { magnitude }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    }
+}
+
+/// the triangles of a mesh, in the space of the mesh
+///
+/// meshes without positions give no triangles, and meshes without indices are read as a triangle list
+pub fn mesh_triangles(mesh: &Mesh) -> Vec<[Vec3; 3]> {
+    let positions: Vec<Vec3> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        Some(VertexAttributeValues::Float32x3(positions)) => positions.iter().map(|position| Vec3::from(*position)).collect(),
+        _ => return Vec::new(),
+    };
+
+    mesh_triangles_with_positions(mesh, &positions)
+}
+
+/// the triangles of a mesh, with other positions than the ones in the mesh, like the skinned ones from `skinning::SkinSource::skin`
+///
+/// indices that point past the positions are skipped
+pub fn mesh_triangles_with_positions(mesh: &Mesh, positions: &[Vec3]) -> Vec<[Vec3; 3]> {
+    let indices: Vec<usize> = match mesh.indices() {
+        Some(Indices::U16(indices)) => indices.iter().map(|index| *index as usize).collect(),
+        Some(Indices::U32(indices)) => indices.iter().map(|index| *index as usize).collect(),
+        None => (0..positions.len()).collect(),
+    };
+
+    indices
+        .chunks_exact(3)
+        .filter(|triangle| triangle.iter().all(|index| *index < positions.len()))
+        .map(|triangle| [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]])
+        .collect()
+}
+
+/// voxelizes meshes into every cascade of a volume
+///
+/// when multiple triangles end up in the same voxel their albedo and opacity are averaged
+pub struct CpuVoxelizer {
+    extent: Vec3,
+    transform: GlobalTransform,
+    // sum of the premultiplied albedo, and the opacity in w
+    sums: Vec<VoxelGrid>,
+    // number of triangles in every voxel
+    counts: Vec<Vec<u32>>,
+}
+
+impl CpuVoxelizer {
+    pub fn new(volume: &GiVolume, transform: &GlobalTransform) -> Self {
+        Self {
+            extent: volume.extent,
+            transform: *transform,
+            sums: (0..volume.cascades)
+                .map(|_| VoxelGrid::new(volume.resolution))
+                .collect(),
+            counts: (0..volume.cascades)
+                .map(|_| vec![0; (volume.resolution.x * volume.resolution.y * volume.resolution.z) as usize])
+                .collect(),
+        }
+    }
+
+    /// adds a mesh, with the alpha of `albedo` used as `alpha_mode` says
+    pub fn add_mesh(
+        &mut self,
+        mesh: &Mesh,
+        transform: &GlobalTransform,
+        albedo: Color,
+        alpha_mode: GiAlphaMode,
+        thin_geometry: GiThinGeometry,
+    ) {
+        let albedo = Vec4::from(albedo.as_linear_rgba_f32());
+
+        // masked out
+        let opacity = match alpha_mode.opacity(albedo.w) {
+            Some(opacity) => opacity,
+            None => return,
+        };
+
+        self.add_triangles(
+            &mesh_triangles(mesh),
+            transform.compute_matrix(),
+            albedo.truncate().extend(opacity),
+            thin_geometry,
+        );
+    }
+
+    /// adds triangles, transformed to world space by `transform`
+    ///
+    /// `albedo` isn't premultiplied, and w is the opacity
+    pub fn add_triangles(
+        &mut self,
+        triangles: &[[Vec3; 3]],
+        transform: Mat4,
+        albedo: Vec4,
+        thin_geometry: GiThinGeometry,
+    ) {
+        let mut voxels = Vec::new();
+
+        for cascade in 0..self.sums.len() {
+            let resolution = self.sums[cascade].resolution;
+
+            // straight to voxels of this cascade
+            let world_to_voxels = Mat4::from_scale(resolution.as_f32())
+                * cascade_projection(self.extent, &self.transform, cascade as u32);
+
+            for triangle in triangles {
+                let world = [
+                    transform.transform_point3(triangle[0]),
+                    transform.transform_point3(triangle[1]),
+                    transform.transform_point3(triangle[2]),
+                ];
+                let triangle = [
+                    world_to_voxels.transform_point3(world[0]),
+                    world_to_voxels.transform_point3(world[1]),
+                    world_to_voxels.transform_point3(world[2]),
+                ];
+
+                voxels.clear();
+                match thin_geometry {
+                    GiThinGeometry::Keep => rasterize(&triangle, resolution, |voxel| voxels.push(voxel)),
+                    GiThinGeometry::Dilate => rasterize_conservative(&triangle, resolution, |voxel| voxels.push(voxel)),
+                    GiThinGeometry::Solidify(thickness) => {
+                        let normal = (world[1] - world[0]).cross(world[2] - world[0]).normalize_or_zero();
+                        let offset = world_to_voxels.transform_vector3(-normal * thickness);
+
+                        // copies of the triangle at most half a voxel apart, so there are no gaps between them
+                        let steps = (offset.length() * 2.0).ceil() as u32;
+                        for step in 0..=steps {
+                            let offset = offset * step as f32 / steps.max(1) as f32;
+                            let triangle = [triangle[0] + offset, triangle[1] + offset, triangle[2] + offset];
+                            rasterize(&triangle, resolution, |voxel| voxels.push(voxel));
+                        }
+                    }
+                }
+
+                // a voxel only counts once for every triangle
+                voxels.sort_unstable_by_key(|voxel| voxel.to_array());
+                voxels.dedup();
+
+                for voxel in voxels.iter() {
+                    add_voxel(&mut self.sums[cascade], &mut self.counts[cascade], *voxel, albedo);
+                }
+            }
+        }
+    }
+
+    /// adds every filled voxel of a grid, as a box of the same size in every cascade
+    ///
+    /// the voxels of the grid are premultiplied, like the ones `finish` gives
+    ///
+    /// `transform` goes from the voxels of the grid to world space, so `Mat4::from_scale` with the voxel size places it at the origin
+    pub fn add_grid(&mut self, grid: &VoxelGrid, transform: Mat4) {
+        for cascade in 0..self.sums.len() {
+            let resolution = self.sums[cascade].resolution;
+
+            let to_voxels = Mat4::from_scale(resolution.as_f32())
+                * cascade_projection(self.extent, &self.transform, cascade as u32)
+                * transform;
+
+            for z in 0..grid.resolution.z {
+                for y in 0..grid.resolution.y {
+                    for x in 0..grid.resolution.x {
+                        let position = UVec3::new(x, y, z);
+                        let voxel = grid.get(position);
+                        if voxel.w <= 0.0 {
+                            continue;
+                        }
+
+                        // bounds of the voxel in this cascade, from all 8 corners in case it's rotated
+                        let (min, max) = (0..8).fold(
+                            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                            |(min, max), i| {
+                                let corner = position.as_f32()
+                                    + Vec3::new(
+                                        (i & 1) as f32,
+                                        ((i >> 1) & 1) as f32,
+                                        ((i >> 2) & 1) as f32,
+                                    );
+                                let corner = to_voxels.transform_point3(corner);
+                                (min.min(corner), max.max(corner))
+                            },
+                        );
+
+                        // voxels whose center is inside the box, but at least the one the center of the box is in
+                        let center = (min + max) * 0.5;
+                        let min = (min - Vec3::splat(0.5)).ceil().min(center.floor());
+                        let max = (max - Vec3::splat(0.5)).floor().max(center.floor());
+                        let min = min.max(Vec3::ZERO);
+                        let max = max.min(resolution.as_f32() - Vec3::ONE);
+
+                        let albedo = (voxel.truncate() / voxel.w).extend(voxel.w.min(1.0));
+                        for voxel_z in min.z as i32..=max.z as i32 {
+                            for voxel_y in min.y as i32..=max.y as i32 {
+                                for voxel_x in min.x as i32..=max.x as i32 {
+                                    let target =
+                                        UVec3::new(voxel_x as u32, voxel_y as u32, voxel_z as u32);
+                                    add_voxel(&mut self.sums[cascade], &mut self.counts[cascade], target, albedo);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// the voxelized cascades, from the smallest to the largest
+    pub fn finish(self) -> Vec<VoxelGrid> {
+        self.sums
+            .into_iter()
+            .zip(self.counts)
+            .map(|(mut grid, counts)| {
+                for (voxel, count) in grid.voxels.iter_mut().zip(counts) {
+                    if count > 0 {
+                        *voxel /= count as f32;
+                    }
+                }
+
+                grid
+            })
+            .collect()
+    }
+}
+
+// adds a not premultiplied albedo to the sums
+fn add_voxel(sums: &mut VoxelGrid, counts: &mut [u32], position: UVec3, albedo: Vec4) {
+    let index = sums.index(position);
+    sums.voxels[index] += (albedo.truncate() * albedo.w).extend(albedo.w);
+    counts[index] += 1;
+}
+
+// which side of the edge from a to b the point is on
+fn edge(a: Vec2, b: Vec2, point: Vec2) -> f32 {
+    (b.x - a.x) * (point.y - a.y) - (b.y - a.y) * (point.x - a.x)
+}
+
+/// calls `voxel` for every voxel the triangle covers, the triangle is in voxels
+///
+/// the triangle is projected along the axis it faces the most, and a voxel is covered when the center of it's column is inside the projection
+pub fn rasterize(triangle: &[Vec3; 3], resolution: UVec3, voxel: impl FnMut(UVec3)) {
+    rasterize_with(triangle, resolution, false, voxel);
+}
+
+/// calls `voxel` for every voxel the triangle touches, the triangle is in voxels
+///
+/// the same as `rasterize`, but a column is covered when any of it overlaps the projection, and every voxel the plane passes through in it is
+pub fn rasterize_conservative(triangle: &[Vec3; 3], resolution: UVec3, voxel: impl FnMut(UVec3)) {
+    rasterize_with(triangle, resolution, true, voxel);
+}
+
+fn rasterize_with(triangle: &[Vec3; 3], resolution: UVec3, conservative: bool, mut voxel: impl FnMut(UVec3)) {
+    let normal = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+    let abs_normal = normal.abs();
+
+    // the axis to project along, and the two that make up the plane
+    let (axis, u, v) = if abs_normal.x >= abs_normal.y && abs_normal.x >= abs_normal.z {
+        (0, 1, 2)
+    } else if abs_normal.y >= abs_normal.z {
+        (1, 2, 0)
+    } else {
+        (2, 0, 1)
+    };
+
+    // degenerate, so it doesn't cover anything
+    if normal[axis] == 0.0 {
+        return;
+    }
+
+    let projected = [
+        Vec2::new(triangle[0][u], triangle[0][v]),
+        Vec2::new(triangle[1][u], triangle[1][v]),
+        Vec2::new(triangle[2][u], triangle[2][v]),
+    ];
+
+    let min = projected[0].min(projected[1]).min(projected[2]).floor().max(Vec2::ZERO);
+    let max = projected[0]
+        .max(projected[1])
+        .max(projected[2])
+        .ceil()
+        .min(Vec2::new(resolution[u] as f32, resolution[v] as f32));
+
+    // so the edge functions are positive inside, whichever way the triangle winds
+    let winding = edge(projected[0], projected[1], projected[2]).signum();
+
+    // how far the edge function can go below 0 while still touching the column, for each edge
+    let slack = |a: Vec2, b: Vec2| match conservative {
+        true => 0.5 * ((b.x - a.x).abs() + (b.y - a.y).abs()),
+        false => 0.0,
+    };
+    let slacks = [
+        slack(projected[0], projected[1]),
+        slack(projected[1], projected[2]),
+        slack(projected[2], projected[0]),
+    ];
+
+    // where the plane of the triangle is along the axis
+    let depth = |point: Vec2| {
+        triangle[0][axis]
+            - (normal[u] * (point.x - triangle[0][u]) + normal[v] * (point.y - triangle[0][v])) / normal[axis]
+    };
+
+    let min_depth = triangle[0][axis].min(triangle[1][axis]).min(triangle[2][axis]);
+    let max_depth = triangle[0][axis].max(triangle[1][axis]).max(triangle[2][axis]);
+
+    for column_v in min.y as u32..max.y as u32 {
+        for column_u in min.x as u32..max.x as u32 {
+            let center = Vec2::new(column_u as f32 + 0.5, column_v as f32 + 0.5);
+
+            let inside = edge(projected[0], projected[1], center) * winding >= -slacks[0]
+                && edge(projected[1], projected[2], center) * winding >= -slacks[1]
+                && edge(projected[2], projected[0], center) * winding >= -slacks[2];
+
+            if !inside {
+                continue;
+            }
+
+            // the plane can cross more than one voxel in the column, the most at the corners of it
+            let (near, far) = if conservative {
+                let corners = [
+                    depth(center + Vec2::new(-0.5, -0.5)),
+                    depth(center + Vec2::new(0.5, -0.5)),
+                    depth(center + Vec2::new(-0.5, 0.5)),
+                    depth(center + Vec2::new(0.5, 0.5)),
+                ];
+                let near = corners.iter().fold(f32::MAX, |near, corner| near.min(*corner));
+                let far = corners.iter().fold(f32::MIN, |far, corner| far.max(*corner));
+                (near.max(min_depth), far.min(max_depth))
+            } else {
+                let depth = depth(center);
+                (depth, depth)
+            };
+
+            if far < 0.0 || near >= resolution[axis] as f32 {
+                continue;
+            }
+
+            let first = near.max(0.0) as u32;
+            let last = (far as u32).min(resolution[axis] - 1);
+
+            for layer in first..=last {
+                let mut position = UVec3::ZERO;
+                position[axis] = layer;
+                position[u] = column_u;
+                position[v] = column_v;
+
+                voxel(position);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::GiStorage;
+    use bevy::math::Quat;
+    use bevy::render2::mesh::shape;
+    use std::f32::consts::FRAC_PI_2;
+
+    // the volume of the demo in main.rs
+    fn demo_volume() -> (GiVolume, GlobalTransform) {
+        let volume = GiVolume::from_voxel_size(Vec3::splat(10.0), 10.0 / 64.0, 3, GiStorage::Dense);
+        (volume, GlobalTransform::from_translation(Vec3::new(0.0, 2.5, 0.0)))
+    }
+
+    fn placed(translation: Vec3, rotation: Quat) -> GlobalTransform {
+        GlobalTransform {
+            translation,
+            rotation,
+            scale: Vec3::ONE,
+        }
+    }
+
+    // the plane, walls, cube and sphere of the demo, with the same thin geometry
+    fn demo_scene() -> Vec<VoxelGrid> {
+        let (volume, transform) = demo_volume();
+        let mut voxelizer = CpuVoxelizer::new(&volume, &transform);
+
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Plane { size: 10.0 }),
+            &GlobalTransform::default(),
+            Color::WHITE,
+            GiAlphaMode::Opaque,
+            GiThinGeometry::Solidify(0.2),
+        );
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Box::new(5.0, 0.15, 5.0)),
+            &placed(Vec3::new(2.5, 2.5, 0.0), Quat::from_rotation_z(FRAC_PI_2)),
+            Color::RED,
+            GiAlphaMode::Opaque,
+            GiThinGeometry::Dilate,
+        );
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Box::new(5.0, 0.15, 5.0)),
+            &placed(Vec3::new(0.0, 2.5, -2.5), Quat::from_rotation_x(FRAC_PI_2)),
+            Color::GREEN,
+            GiAlphaMode::Opaque,
+            GiThinGeometry::Dilate,
+        );
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Cube { size: 1.0 }),
+            &GlobalTransform::from_translation(Vec3::new(0.0, 0.5, 0.0)),
+            Color::BLUE,
+            GiAlphaMode::Opaque,
+            GiThinGeometry::Keep,
+        );
+        voxelizer.add_mesh(
+            &Mesh::from(shape::UVSphere {
+                radius: 0.5,
+                ..Default::default()
+            }),
+            &GlobalTransform::from_translation(Vec3::new(1.5, 1.0, 1.5)),
+            Color::WHITE,
+            GiAlphaMode::Opaque,
+            GiThinGeometry::Keep,
+        );
+
+        voxelizer.finish()
+    }
+
+    fn voxel_at(grids: &[VoxelGrid], cascade: usize, position: Vec3) -> Vec4 {
+        let (volume, transform) = demo_volume();
+        let grid = &grids[cascade];
+        let uvw = cascade_projection(volume.extent, &transform, cascade as u32).transform_point3(position);
+
+        grid.get((uvw * grid.resolution.as_f32()).as_u32())
+    }
+
+    #[test]
+    fn plane_covers_one_layer_of_every_cascade() {
+        let (volume, transform) = demo_volume();
+        let mut voxelizer = CpuVoxelizer::new(&volume, &transform);
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Plane { size: 10.0 }),
+            &GlobalTransform::default(),
+            Color::WHITE,
+            GiAlphaMode::Opaque,
+            GiThinGeometry::Keep,
+        );
+
+        // the plane is as wide as the first cascade, and half as wide as the next
+        let grids = voxelizer.finish();
+        assert_eq!(grids[0].filled(), 64 * 64);
+        assert_eq!(grids[1].filled(), 32 * 32);
+        assert_eq!(grids[2].filled(), 16 * 16);
+
+        for grid in grids.iter() {
+            assert!(grid.voxels.iter().all(|voxel| *voxel == Vec4::ZERO || *voxel == Vec4::ONE));
+        }
+    }
+
+    #[test]
+    fn boxes_only_reach_into_the_cascades_around_them() {
+        let (volume, transform) = demo_volume();
+        let projection = |cascade| cascade_projection(volume.extent, &transform, cascade);
+        let unit_box = |translation: Vec3, cascade, margin: f32| {
+            box_in_cascade(
+                &projection(cascade),
+                &Mat4::from_translation(translation),
+                Vec3::splat(-0.5),
+                Vec3::splat(0.5),
+                Vec3::splat(margin),
+            )
+        };
+
+        // the first cascade goes from -5 to 5 around the volume, and every cascade after it is twice as wide
+        assert!(unit_box(Vec3::new(0.0, 2.5, 0.0), 0, 0.0));
+        assert!(!unit_box(Vec3::new(12.0, 2.5, 0.0), 0, 0.0));
+        assert!(!unit_box(Vec3::new(12.0, 2.5, 0.0), 1, 0.0));
+        assert!(unit_box(Vec3::new(12.0, 2.5, 0.0), 2, 0.0));
+
+        // just outside, until the margin reaches it
+        assert!(!unit_box(Vec3::new(5.6, 2.5, 0.0), 0, 0.0));
+        assert!(unit_box(Vec3::new(5.6, 2.5, 0.0), 0, 0.05));
+
+        // rotated, the corners reach further
+        let rotated = Mat4::from_rotation_translation(Quat::from_rotation_y(FRAC_PI_2 * 0.5), Vec3::new(5.6, 2.5, 0.0));
+        assert!(box_in_cascade(&projection(0), &rotated, Vec3::splat(-0.5), Vec3::splat(0.5), Vec3::ZERO));
+    }
+
+    #[test]
+    fn demo_scene_has_every_surface() {
+        let grids = demo_scene();
+
+        // the plane is made solid downwards, and nothing is below that
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(-3.0, -0.1, 3.0)), Vec4::ONE);
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(-3.0, -0.6, 3.0)), Vec4::ZERO);
+
+        // the walls have their own color
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(2.5, 2.5, 0.3)), Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(0.3, 2.5, -2.5)), Vec4::new(0.0, 1.0, 0.0, 1.0));
+
+        // the cube and the sphere are hollow
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(0.0, 0.95, 0.0)), Vec4::new(0.0, 0.0, 1.0, 1.0));
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(0.0, 0.5, 0.0)), Vec4::ZERO);
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(1.5, 1.45, 1.5)), Vec4::ONE);
+        assert_eq!(voxel_at(&grids, 0, Vec3::new(1.5, 1.0, 1.5)), Vec4::ZERO);
+
+        // the larger cascades have the same scene, with larger voxels
+        for cascade in 1..3 {
+            let wall = voxel_at(&grids, cascade, Vec3::new(2.5, 2.5, 0.3));
+            assert!(wall.x > 0.0 && wall.w > 0.0, "{} {:?}", cascade, wall);
+            assert!(voxel_at(&grids, cascade, Vec3::new(-3.0, 0.0, 3.0)).w > 0.0);
+        }
+    }
+
+    #[test]
+    fn sphere_surface_is_closed() {
+        let (volume, transform) = demo_volume();
+        let center = Vec3::new(1.5, 1.0, 1.5);
+        let voxel_size = 10.0 / 64.0;
+        let voxels_to_world = cascade_projection(volume.extent, &transform, 0).inverse() * Mat4::from_scale(Vec3::splat(1.0 / 64.0));
+
+        let directions = (0..27)
+            .map(|i| Vec3::new((i % 3) as f32 - 1.0, ((i / 3) % 3) as f32 - 1.0, (i / 9) as f32 - 1.0))
+            .filter(|direction| *direction != Vec3::ZERO)
+            .map(|direction| direction.normalize())
+            .collect::<Vec<_>>();
+
+        for thin_geometry in [GiThinGeometry::Keep, GiThinGeometry::Dilate].iter() {
+            let mut voxelizer = CpuVoxelizer::new(&volume, &transform);
+            voxelizer.add_mesh(
+                &Mesh::from(shape::UVSphere {
+                    radius: 0.5,
+                    ..Default::default()
+                }),
+                &GlobalTransform::from_translation(center),
+                Color::WHITE,
+                GiAlphaMode::Opaque,
+                *thin_geometry,
+            );
+            let grids = voxelizer.finish();
+
+            // every filled voxel is on the surface, a bit further out when dilated
+            let max_distance = match thin_geometry {
+                GiThinGeometry::Keep => voxel_size * 0.87,
+                _ => voxel_size * 1.74,
+            };
+
+            for z in 0..64 {
+                for y in 0..64 {
+                    for x in 0..64 {
+                        let position = voxels_to_world.transform_point3(Vec3::new(x as f32, y as f32, z as f32) + Vec3::splat(0.5));
+                        let distance = (position - center).length();
+                        if grids[0].get(UVec3::new(x, y, z)).w > 0.0 {
+                            assert!((distance - 0.5).abs() < max_distance, "{:?} {}", position, distance);
+                        }
+                    }
+                }
+            }
+
+            // nothing gets out along the axes without passing a filled voxel, and when dilated, not along the diagonals either
+            for direction in directions.iter() {
+                let diagonal = direction.abs().max_element() < 1.0;
+                if diagonal && *thin_geometry == GiThinGeometry::Keep {
+                    continue;
+                }
+
+                let hit = (0..40).any(|step| voxel_at(&grids, 0, center + *direction * step as f32 * 0.025).w > 0.0);
+                assert!(hit, "{:?} {:?}", thin_geometry, direction);
+            }
+        }
+    }
+
+    #[test]
+    fn masked_out_meshes_add_nothing() {
+        let (volume, transform) = demo_volume();
+        let mut voxelizer = CpuVoxelizer::new(&volume, &transform);
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Cube { size: 1.0 }),
+            &GlobalTransform::default(),
+            Color::rgba_linear(1.0, 1.0, 1.0, 0.4),
+            GiAlphaMode::Mask(0.5),
+            GiThinGeometry::Keep,
+        );
+        assert!(voxelizer.finish().iter().all(|grid| grid.filled() == 0));
+
+        // blended ones are as opaque as their alpha, and premultiplied
+        let mut voxelizer = CpuVoxelizer::new(&volume, &transform);
+        voxelizer.add_mesh(
+            &Mesh::from(shape::Cube { size: 1.0 }),
+            &GlobalTransform::default(),
+            Color::rgba_linear(0.0, 0.0, 1.0, 0.4),
+            GiAlphaMode::Blend,
+            GiThinGeometry::Keep,
+        );
+        let grids = voxelizer.finish();
+        assert!(grids[0].filled() > 0);
+        for voxel in grids[0].voxels.iter().filter(|voxel| voxel.w > 0.0) {
+            assert!((*voxel - Vec4::new(0.0, 0.0, 0.4, 0.4)).abs().max_element() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn thin_geometry_only_adds_voxels() {
+        let (volume, transform) = demo_volume();
+        let triangle = [[Vec3::new(-1.0, 0.1, -0.7), Vec3::new(1.3, 0.6, -0.2), Vec3::new(-0.2, 1.7, 0.9)]];
+
+        let voxelize = |thin_geometry| {
+            let mut voxelizer = CpuVoxelizer::new(&volume, &transform);
+            voxelizer.add_triangles(&triangle, Mat4::IDENTITY, Vec4::ONE, thin_geometry);
+            voxelizer.finish().remove(0)
+        };
+
+        let keep = voxelize(GiThinGeometry::Keep);
+        let dilate = voxelize(GiThinGeometry::Dilate);
+        let solidify = voxelize(GiThinGeometry::Solidify(0.5));
+
+        // dilating and solidifying keep everything the triangle covered, and add more around it
+        for (index, voxel) in keep.voxels.iter().enumerate() {
+            if voxel.w > 0.0 {
+                assert!(dilate.voxels[index].w > 0.0);
+                assert!(solidify.voxels[index].w > 0.0);
+            }
+        }
+        assert!(dilate.filled() > keep.filled());
+        assert!(solidify.filled() >= keep.filled() * 3);
+    }
+
+    #[test]
+    fn grids_keep_their_voxels_in_every_format() {
+        let mut grid = VoxelGrid::new(UVec3::new(3, 2, 2));
+        grid.set(UVec3::new(1, 1, 0), Vec4::new(0.5, 0.25, 1.0, 1.0));
+
+        for format in GiVolumeFormat::ALL.iter() {
+            let bytes = grid.to_bytes(*format);
+            assert_eq!(bytes.len() as u32, 12 * format.bytes_per_voxel());
+            assert_eq!(VoxelGrid::from_bytes(grid.resolution, &bytes, *format), grid);
+        }
+
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+    }
+}